@@ -0,0 +1,1013 @@
+// Table-driven automaton
+//
+// Serialises the merged prefix tree and the RHS table into a byte array,
+// and emits it together with a fixed Rust interpreter that walks it over
+// the Cranelift DFG at runtime. The interpreter is the same for every
+// rule set, so adding rules only changes the data.
+//
+// The array starts with the offset of the RHS section (u32), followed by
+// the tree nodes in depth-first order. Each node is
+//
+//   kind: u8, a: u8, b: u8, children: u16,
+//   [value: i64, for CONST and the checks], [rhs offset: u32, if kind
+//   has ACTION], child offsets: u32 * children
+//
+// A rule with conditions besides its LHS tree (the values its path
+// condition table says are the same, see `pctable`, and its
+// `matcher::LeafCheck`s) has them as a chain of check nodes below its
+// last LHS node, and the last check holds the action. A check names an
+// LHS operand by slot in `a` and index in `b`; SAME and DIFFER name the
+// other one in the low two bytes of the value, SHIFT_AMOUNT has the
+// width the amount has to be below as the value.
+//
+// and each RHS is a count of insts (u8) followed by, for every inst,
+//
+//   opcode: u8, format: u8, cond: u8, width: u8, operands: u8,
+//   operands: LHS (slot: u8, index: u8) | CREATED (index: u8) | CONST (i64)
+//
+// All multi-byte fields are little endian. Like `interpreter`, the
+// runtime backtracks to the next sibling on a mismatch and checks that
+// a repeated var matches the same value.
+
+use cliftapi::CliftApi;
+use cliftinstbuilder::{self, CtonOpcode, CLIFT_OPCODES};
+use lhspatternmatcher::{Node, NodeType};
+use matcher::LeafCheck;
+use mergedtree::MergedArena;
+use pctable;
+use processrhs::CliftInstWithArgs;
+use std::collections::HashMap;
+
+// Node kinds
+const NOP: u8 = 0;
+const INST_TYPE: u8 = 1;
+const VAR: u8 = 2;
+const OPCODE: u8 = 3;
+const COND: u8 = 4;
+const ARG: u8 = 5;
+const RESULT: u8 = 6;
+const ANY: u8 = 7;
+const PLAIN_CONST: u8 = 8;
+const CONST: u8 = 9;
+const FAIL: u8 = 10;
+const SAME: u8 = 11;
+const DIFFER: u8 = 12;
+const SHIFT_AMOUNT: u8 = 13;
+const ACTION: u8 = 0x80;
+
+// `b` of an ARG node that is not `args[i]`
+const ARG_VALUE: u8 = 0xfe;
+const ARG_IMM: u8 = 0xff;
+
+// RHS operand tags
+const OPERAND_LHS: u8 = 0;
+const OPERAND_CREATED: u8 = 1;
+const OPERAND_CONST: u8 = 2;
+
+// Opcode of the RHS result record, and the flag of the inst that
+// replaces the matched one
+const RESULT_OPCODE: u8 = 0xff;
+const REPLACE: u8 = 0x80;
+const NO_COND: u8 = 0xff;
+
+/// InstructionData formats, in the order of their codes
+const FORMAT_NAMES: [&str; 7] = [
+    "Unary", "UnaryImm", "Binary", "BinaryImm", "IntCompare", "IntCompareImm", "Ternary",
+];
+
+/// Conditions, in the order of their codes
+const COND_NAMES: [&str; 6] = ["eq", "ne", "slt", "ult", "sle", "ule"];
+
+/// kind, a, b and the value of a node record
+type NodeFields = (u8, u8, u8, Option<i64>);
+
+struct Encoder<'a> {
+    nodes: HashMap<usize, &'a Node>,
+    rhs: &'a HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &'a HashMap<usize, HashMap<String, usize>>,
+    checks: &'a HashMap<usize, Vec<LeafCheck>>,
+    api: &'a dyn CliftApi,
+    // highest `arg_N` and var number seen, to size the runtime state
+    max_slot: usize,
+    max_var: usize,
+}
+
+impl<'a> Encoder<'a> {
+    /// kind, a, b and the constant of a node
+    fn encode_node(&mut self, node: &Node) -> (u8, u8, u8, Option<i64>) {
+        match node.node_type {
+            NodeType::MatchRoot | NodeType::MatchInstData |
+            NodeType::MatchOpcode | NodeType::MatchCond => (NOP, 0, 0, None),
+            NodeType::InstType => {
+                if node.node_value == "Var" {
+                    return match node.var_id {
+                        Some(var) => {
+                            let var = self.to_u8(var as usize, "var number");
+                            self.max_var = self.max_var.max(var as usize);
+                            (VAR, var, self.to_u8(node.width as usize, "width"), None)
+                        }
+                        None => (FAIL, 0, 0, None),
+                    };
+                }
+                match (get_format(&node.node_value), get_slot(&node.arg_name)) {
+                    (Some(format), Some(slot)) => {
+                        self.max_slot = self.max_slot.max(slot as usize);
+                        (INST_TYPE, format, slot, None)
+                    }
+                    _ => (FAIL, 0, 0, None),
+                }
+            }
+            NodeType::Opcode => {
+                if node.node_value == "Var" {
+                    return (NOP, 0, 0, None);
+                }
+                match self.get_opcode(&node.node_value) {
+                    Some(opcode) => (OPCODE, opcode, 0, None),
+                    None => (FAIL, 0, 0, None),
+                }
+            }
+            NodeType::Cond => match get_cond(&node.node_value) {
+                Some(cond) => (COND, cond, 0, None),
+                None => (FAIL, 0, 0, None),
+            },
+            NodeType::MatchArgs => {
+                let which = match node.node_value.as_ref() {
+                    "arg" => Some(ARG_VALUE),
+                    "imm" => Some(ARG_IMM),
+                    arg => get_arg_index(arg),
+                };
+                match (get_slot(&node.arg_name), which) {
+                    (Some(slot), Some(which)) => (ARG, slot, which, None),
+                    _ => (FAIL, 0, 0, None),
+                }
+            }
+            NodeType::MatchValDef => match node.node_value.as_ref() {
+                "Result" => (RESULT, 0, 0, None),
+                "Any" => (ANY, 0, 0, None),
+                _ => (FAIL, 0, 0, None),
+            },
+            NodeType::MatchPlainConst => (PLAIN_CONST, 0, 0, None),
+            NodeType::MatchConst => match node.node_value.parse::<i128>() {
+                Ok(c) => (CONST, 0, 0, Some(c as i64)),
+                Err(_) => (FAIL, 0, 0, None),
+            },
+            NodeType::MatchNone => (FAIL, 0, 0, None),
+        }
+    }
+
+    fn to_u8(&self, n: usize, what: &str) -> u8 {
+        if n > 0xff {
+            panic!("ERROR: {} {} does not fit the automaton encoding", what, n);
+        }
+        n as u8
+    }
+
+    /// Index of a clif opcode name in the runtime's opcode table, if the
+    /// API has it
+    fn get_opcode(&self, name: &str) -> Option<u8> {
+        self.api.opcode_pattern(name)?;
+        CLIFT_OPCODES
+            .iter()
+            .position(|opcode| cliftinstbuilder::get_clift_opcode_name(opcode.clone()) == name)
+            .map(|pos| pos as u8)
+    }
+
+    /// The check nodes before the action of the rule whose last LHS node
+    /// is `id`, or None if some check has no encoding
+    fn encode_checks(&self, id: usize) -> Option<Vec<NodeFields>> {
+        let mut encoded = Vec::new();
+        let pairs = self.pcs.get(&id).map_or(Vec::new(), pctable::get_equal_args);
+        let checks = self.checks.get(&id).map_or(&[][..], |checks| checks.as_slice());
+        let differ = checks.iter().filter_map(|check| match check {
+            LeafCheck::Differ(a, b) => Some((a.clone(), b.clone())),
+            LeafCheck::ShiftAmount(..) => None,
+        });
+        let pairs = pairs.into_iter().map(|pair| (SAME, pair)).chain(differ.map(|pair| (DIFFER, pair)));
+        for (kind, (a, b)) in pairs {
+            let (slot, index) = get_lhs_operand(&a)?;
+            let (other_slot, other_index) = get_lhs_operand(&b)?;
+            let other = other_slot as i64 | (other_index as i64) << 8;
+            encoded.push((kind, slot, index, Some(other)));
+        }
+        for check in checks {
+            if let LeafCheck::ShiftAmount(value, width) = check {
+                self.get_opcode("iconst")?;
+                let (slot, index) = get_lhs_operand(value)?;
+                encoded.push((SHIFT_AMOUNT, slot, index, Some(*width as i64)));
+            }
+        }
+        Some(encoded)
+    }
+
+    /// Encodes the RHS of a rule, or returns None if some inst or
+    /// operand has no encoding
+    fn encode_rhs(&self, rhs: &[CliftInstWithArgs]) -> Option<Vec<u8>> {
+        let result = rhs.iter().find(|i| matches!(i.opcode, CtonOpcode::ResultInst))?;
+        let result_name = result.cops.first()?;
+        let body: Vec<&CliftInstWithArgs> = rhs
+            .iter()
+            .filter(|i| !matches!(i.opcode, CtonOpcode::ResultInst))
+            .collect();
+        let mut bytes = Vec::new();
+        let mut created: HashMap<String, u8> = HashMap::new();
+        for (n, rhs_inst) in body.iter().enumerate() {
+            let name = format!("rhs_inst_{}", rhs_inst.lhs_index);
+            let replace = n + 1 == body.len() && name == *result_name;
+            let opcode = self.get_opcode(&cliftinstbuilder::get_clift_opcode_name(rhs_inst.opcode.clone()))?;
+            let format = get_format(&cliftinstbuilder::get_clift_instdata_name(rhs_inst.kind.clone()))?;
+            let cond = match rhs_inst.cond {
+                Some(_) => get_cond(&cliftinstbuilder::get_clift_cond_name(rhs_inst.cond.clone()))?,
+                None => NO_COND,
+            };
+            bytes.push(if replace { opcode | REPLACE } else { opcode });
+            bytes.push(format);
+            bytes.push(cond);
+            bytes.push(self.to_u8(rhs_inst.width as usize, "width"));
+            bytes.push(self.to_u8(rhs_inst.cops.len(), "operand count"));
+            for op in &rhs_inst.cops {
+                bytes.extend(encode_operand(op, &created)?);
+            }
+            if replace {
+                return Some(with_count(bytes, n + 1));
+            }
+            created.insert(name, self.to_u8(n, "RHS inst"));
+        }
+        bytes.extend_from_slice(&[RESULT_OPCODE, RESULT_OPCODE, NO_COND]);
+        bytes.push(self.to_u8(result.width as usize, "width"));
+        bytes.push(1);
+        bytes.extend(encode_operand(result_name, &created)?);
+        Some(with_count(bytes, body.len() + 1))
+    }
+}
+
+/// Prepends the inst count to an encoded RHS
+fn with_count(bytes: Vec<u8>, count: usize) -> Vec<u8> {
+    let mut rhs_bytes = vec![count as u8];
+    rhs_bytes.extend(bytes);
+    rhs_bytes
+}
+
+/// Encodes an RHS operand name (`arg_1[0]`, `rhs_inst_4`, `3_u64 as i64`)
+fn encode_operand(name: &str, created: &HashMap<String, u8>) -> Option<Vec<u8>> {
+    if let Some(c) = name.strip_suffix("_u64 as i64") {
+        let c = c.parse::<i128>().ok()? as i64;
+        let mut bytes = vec![OPERAND_CONST];
+        bytes.extend_from_slice(&c.to_le_bytes());
+        return Some(bytes);
+    }
+    if let Some(&index) = created.get(name) {
+        return Some(vec![OPERAND_CREATED, index]);
+    }
+    let (slot, index) = get_lhs_operand(name)?;
+    Some(vec![OPERAND_LHS, slot, index])
+}
+
+/// `arg_1[0]` to the slot and index of the LHS operand, `arg_1` to its
+/// first operand
+fn get_lhs_operand(name: &str) -> Option<(u8, u8)> {
+    let (arg_name, index) = match name.find('[') {
+        Some(pos) => (&name[..pos], get_arg_index(&format!("args{}", &name[pos..]))?),
+        None => (name, 0),
+    };
+    Some((get_slot(arg_name)?, index))
+}
+
+fn get_format(kind: &str) -> Option<u8> {
+    FORMAT_NAMES.iter().position(|&f| f == kind).map(|pos| pos as u8)
+}
+
+fn get_cond(cond: &str) -> Option<u8> {
+    COND_NAMES.iter().position(|&c| c == cond).map(|pos| pos as u8)
+}
+
+/// `arg_N` to N
+fn get_slot(arg_name: &str) -> Option<u8> {
+    arg_name.strip_prefix("arg_")?.parse().ok()
+}
+
+/// `args[N]` to N
+fn get_arg_index(arg: &str) -> Option<u8> {
+    arg.strip_prefix("args[")?.strip_suffix(']')?.parse().ok()
+}
+
+/// A child of an encoded record: the record of a tree node, or one of
+/// the check records before an action
+#[derive(Clone, Copy)]
+enum Child {
+    Node(usize),
+    Record(usize),
+}
+
+/// The encoded tree and RHS table, and the sizes the runtime needs
+struct Automaton {
+    data: Vec<u8>,
+    nodes: usize,
+    actions: usize,
+    slots: usize,
+    vars: usize,
+}
+
+/// The bytes of a node record, without its child offsets
+fn encode_record(kind: u8, a: u8, b: u8, value: Option<i64>, children: usize, rhs: Option<u32>) -> Vec<u8> {
+    let mut bytes = vec![if rhs.is_some() { kind | ACTION } else { kind }, a, b];
+    bytes.extend_from_slice(&(children as u16).to_le_bytes());
+    if let Some(c) = value {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+    if let Some(rhs) = rhs {
+        bytes.extend_from_slice(&rhs.to_le_bytes());
+    }
+    bytes
+}
+
+/// Serialises the merged tree, the RHS table and the conditions checked
+/// before each action (see `matcher::get_leaf_guards`)
+fn encode_automaton(
+    arena: &MergedArena,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &HashMap<usize, HashMap<String, usize>>,
+    checks: &HashMap<usize, Vec<LeafCheck>>,
+    api: &dyn CliftApi,
+) -> Automaton {
+    let mut encoder = Encoder {
+        nodes: arena.merged_tree.iter().map(|node| (node.id, node)).collect(),
+        rhs,
+        pcs,
+        checks,
+        api,
+        max_slot: 0,
+        max_var: 0,
+    };
+
+    // Encode every node reachable from the root, depth first, leaving
+    // the child offsets to be filled in once all sizes are known. A
+    // shared subtree is encoded once and every parent points to it.
+    let mut records: Vec<(Vec<u8>, Vec<Child>)> = Vec::new();
+    let mut record_of: HashMap<usize, usize> = HashMap::new();
+    let mut rhs_bytes: Vec<u8> = Vec::new();
+    let mut actions = 0;
+    let mut stack = vec![0];
+    while let Some(id) = stack.pop() {
+        if record_of.contains_key(&id) {
+            continue;
+        }
+        let node = match encoder.nodes.get(&id) {
+            Some(&node) => node,
+            None => continue,
+        };
+        let (kind, a, b, value) = encoder.encode_node(node);
+        let action = match encoder.rhs.get(&id) {
+            Some(rhs_insts) => encoder.encode_rhs(rhs_insts).and_then(|action| Some((encoder.encode_checks(id)?, action))),
+            None => None,
+        };
+        record_of.insert(id, records.len());
+        let (checks, action) = match action {
+            Some(action) => action,
+            None => {
+                let children: Vec<usize> = node.next.iter().flatten().map(|next| next.index).collect();
+                let bytes = encode_record(kind, a, b, value, children.len(), None);
+                records.push((bytes, children.iter().map(|&child| Child::Node(child)).collect()));
+                stack.extend(children.iter().rev());
+                continue;
+            }
+        };
+        // an actionable node ends the walk, its children are unreachable
+        let rhs_offset = rhs_bytes.len() as u32;
+        rhs_bytes.extend(action);
+        actions += 1;
+        if checks.is_empty() {
+            records.push((encode_record(kind, a, b, value, 0, Some(rhs_offset)), Vec::new()));
+            continue;
+        }
+        records.push((encode_record(kind, a, b, value, 1, None), vec![Child::Record(records.len() + 1)]));
+        for (n, &(kind, a, b, value)) in checks.iter().enumerate() {
+            if n + 1 == checks.len() {
+                records.push((encode_record(kind, a, b, value, 0, Some(rhs_offset)), Vec::new()));
+            } else {
+                records.push((encode_record(kind, a, b, value, 1, None), vec![Child::Record(records.len() + 1)]));
+            }
+        }
+    }
+
+    let mut offsets = Vec::new();
+    let mut offset = 4;
+    for (bytes, children) in &records {
+        offsets.push(offset);
+        offset += bytes.len() + 4 * children.len();
+    }
+    let rhs_start = offset;
+    let mut data: Vec<u8> = (rhs_start as u32).to_le_bytes().to_vec();
+    for (bytes, children) in &records {
+        let mut bytes = bytes.clone();
+        // RHS offsets are relative to the RHS section until now
+        if bytes[0] & ACTION != 0 {
+            let at = bytes.len() - 4;
+            let rel = u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+            bytes[at..].copy_from_slice(&(rel + rhs_start as u32).to_le_bytes());
+        }
+        data.extend(bytes);
+        for child in children {
+            let record = match *child {
+                Child::Node(id) => record_of[&id],
+                Child::Record(record) => record,
+            };
+            data.extend_from_slice(&(offsets[record] as u32).to_le_bytes());
+        }
+    }
+    data.extend(rhs_bytes);
+
+    Automaton {
+        data,
+        nodes: records.len(),
+        actions,
+        slots: encoder.max_slot + 1,
+        vars: encoder.max_var + 1,
+    }
+}
+
+/// Returns the Rust source of the automaton for the given merged tree,
+/// RHS table and the conditions of each action: the byte array and the
+/// interpreter that walks it.
+pub fn generate_automaton(
+    arena: &MergedArena,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &HashMap<usize, HashMap<String, usize>>,
+    checks: &HashMap<usize, Vec<LeafCheck>>,
+    api: &dyn CliftApi,
+) -> String {
+    let automaton = encode_automaton(arena, rhs, pcs, checks, api);
+    let data = &automaton.data;
+    let mut automaton_str = format!(
+        "// Automaton: {} nodes, {} actions, {} bytes\n",
+        automaton.nodes,
+        automaton.actions,
+        data.len()
+    );
+    automaton_str += &format!("const SLOTS: usize = {};\n", automaton.slots);
+    automaton_str += &format!("const VARS: usize = {};\n\n", automaton.vars);
+    automaton_str += &format!("static AUTOMATON: [u8; {}] = [\n", data.len());
+    for line in data.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        automaton_str += &format!("    {},\n", bytes.join(", "));
+    }
+    automaton_str += "];\n\n";
+    automaton_str += &generate_interpreter(api);
+    automaton_str
+}
+
+/// The fixed part of the output: tables that depend on the Cranelift
+/// API, and the interpreter
+fn generate_interpreter(api: &dyn CliftApi) -> String {
+    let mut interp_str = String::new();
+    interp_str += &format!("const OPCODES: [Opcode; {}] = [\n", CLIFT_OPCODES.len());
+    for opcode in CLIFT_OPCODES.iter() {
+        let name = cliftinstbuilder::get_clift_opcode_name(opcode.clone());
+        // never referenced by the data, see Encoder::get_opcode
+        let pattern = api.opcode_pattern(&name).unwrap_or_else(|| "Opcode::Nop".to_string());
+        interp_str += &format!("    {},\n", pattern);
+    }
+    interp_str += "];\n\n";
+    // only referenced by SHIFT_AMOUNT nodes, see Encoder::encode_checks
+    let iconst = api.opcode_pattern("iconst").unwrap_or_else(|| "Opcode::Nop".to_string());
+    interp_str += &format!("const ICONST: Opcode = {};\n\n", iconst);
+    interp_str += &format!("const CONDS: [IntCC; {}] = [\n", COND_NAMES.len());
+    for cond in COND_NAMES.iter() {
+        interp_str += &format!("    {},\n", ::matcher::get_cond_name(cond.to_string()));
+    }
+    interp_str += "];\n\n";
+
+    interp_str += "fn width_to_type(width: u8) -> Type {\n    match width {\n";
+    for &width in &[1, 8, 16, 32, 64, 128] {
+        if let Some(ty) = api.clif_type(width) {
+            interp_str += &format!("        {} => {},\n", width, ty.to_uppercase());
+        }
+    }
+    interp_str += "        _ => INVALID,\n    }\n}\n\n";
+
+    interp_str += "fn make_const(pos: &mut FuncCursor, inst: Inst, width: u8, c: i64) {\n    match width {\n";
+    interp_str += &format!("        1 if c & 1 == 0 => {{\n            pos.func.dfg.replace(inst).{};\n        }}\n", api.const_inst(1, "0"));
+    interp_str += &format!("        1 => {{\n            pos.func.dfg.replace(inst).{};\n        }}\n", api.const_inst(1, "1"));
+    for &width in &[8, 16, 32, 64, 128] {
+        interp_str += &format!(
+            "        {} => {{\n            pos.func.dfg.replace(inst).{};\n        }}\n",
+            width,
+            api.const_inst(width, "c")
+        );
+    }
+    interp_str += "        _ => {}\n    }\n}\n\n";
+
+    interp_str += "fn alias_result(pos: &mut FuncCursor, inst: Inst, value: Value) {\n";
+    for line in api.alias_result("value").lines() {
+        interp_str += &format!("    {}\n", line);
+    }
+    interp_str += "}\n\n";
+
+    let view = |format: &str, cond: &str, args: &str, imm: &str| {
+        format!(
+            "InstView {{ format: {}, opcode, cond: {}, args: {}, imm: {} }}",
+            format, cond, args, imm
+        )
+    };
+    let arms = [
+        ("Unary", view("0", "None", "[Some(arg), None, None]", "None")),
+        ("UnaryImm", view("1", "None", "[None, None, None]", "Some(imm)")),
+        ("Binary", view("2", "None", "[Some(args[0]), Some(args[1]), None]", "None")),
+        ("BinaryImm", view("3", "None", "[Some(arg), None, None]", "Some(imm)")),
+        ("IntCompare", view("4", "Some(cond)", "[Some(args[0]), Some(args[1]), None]", "None")),
+        ("IntCompareImm", view("5", "Some(cond)", "[Some(arg), None, None]", "Some(imm)")),
+        ("Ternary", view("6", "None", "[Some(args[0]), Some(args[1]), Some(args[2])]", "None")),
+    ];
+    interp_str += "/// The fields of an InstructionData the automaton looks at\n";
+    interp_str += "#[derive(Clone, Copy)]\nstruct InstView {\n    format: u8,\n    opcode: Opcode,\n";
+    interp_str += "    cond: Option<IntCC>,\n    args: [Option<Value>; 3],\n    imm: Option<i64>,\n}\n\n";
+    interp_str += "fn decode_inst(pos: &FuncCursor, inst: Inst) -> Option<InstView> {\n";
+    interp_str += &format!("    let view = match {} {{\n", api.inst_data("inst"));
+    for (kind, arm) in arms.iter() {
+        let pattern = match api.inst_data_pattern(kind) {
+            Some(pattern) => pattern,
+            None => continue,
+        };
+        if arm.contains("Some(imm)") {
+            interp_str += &format!(
+                "        {} => {{\n            let imm: i64 = {};\n            {}\n        }}\n",
+                pattern,
+                api.imm_to_i64(),
+                arm
+            );
+        } else {
+            interp_str += &format!("        {} => {},\n", pattern, arm);
+        }
+    }
+    interp_str += "        _ => return None,\n    };\n    Some(view)\n}\n\n";
+    interp_str += RUNTIME;
+    interp_str
+}
+
+/// The interpreter; the same for every rule set and API
+const RUNTIME: &str = r#"const NOP: u8 = 0;
+const INST_TYPE: u8 = 1;
+const VAR: u8 = 2;
+const OPCODE: u8 = 3;
+const COND: u8 = 4;
+const ARG: u8 = 5;
+const RESULT: u8 = 6;
+const ANY: u8 = 7;
+const PLAIN_CONST: u8 = 8;
+const CONST: u8 = 9;
+const SAME: u8 = 11;
+const DIFFER: u8 = 12;
+const SHIFT_AMOUNT: u8 = 13;
+const ACTION: u8 = 0x80;
+const ARG_VALUE: u8 = 0xfe;
+const ARG_IMM: u8 = 0xff;
+const OPERAND_LHS: u8 = 0;
+const OPERAND_CREATED: u8 = 1;
+const RESULT_OPCODE: u8 = 0xff;
+const REPLACE: u8 = 0x80;
+const UNARY: u8 = 0;
+const UNARY_IMM: u8 = 1;
+const BINARY_IMM: u8 = 3;
+const INT_COMPARE: u8 = 4;
+const INT_COMPARE_IMM: u8 = 5;
+const TERNARY: u8 = 6;
+
+fn read_u16(at: usize) -> usize {
+    u16::from_le_bytes([AUTOMATON[at], AUTOMATON[at + 1]]) as usize
+}
+
+fn read_u32(at: usize) -> usize {
+    u32::from_le_bytes([AUTOMATON[at], AUTOMATON[at + 1], AUTOMATON[at + 2], AUTOMATON[at + 3]]) as usize
+}
+
+fn read_i64(at: usize) -> i64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&AUTOMATON[at..at + 8]);
+    i64::from_le_bytes(bytes)
+}
+
+fn mask(c: i64, bits: u32) -> i64 {
+    if bits == 0 || bits >= 64 {
+        c
+    } else {
+        c & ((1i64 << bits) - 1)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct MatchState {
+    // inst whose InstructionData the next nodes match
+    entity: Option<Inst>,
+    // operand selected by the last ARG node
+    operand: Option<Value>,
+    imm: Option<(i64, u32)>,
+    // insts bound to the `arg_N` slots of the LHS
+    slots: [Option<Inst>; SLOTS],
+    // values bound to the Souper vars
+    vars: [Option<Value>; VARS],
+}
+
+fn match_node(pos: &FuncCursor, kind: u8, a: u8, b: u8, value: i64, m: &mut MatchState) -> bool {
+    let view = m.entity.and_then(|inst| decode_inst(pos, inst));
+    match kind {
+        NOP => true,
+        INST_TYPE => match view {
+            Some(view) if view.format == a => {
+                m.slots[b as usize] = m.entity;
+                true
+            }
+            _ => false,
+        },
+        VAR => {
+            let v = match m.operand {
+                Some(v) => pos.func.dfg.resolve_aliases(v),
+                None => return false,
+            };
+            if pos.func.dfg.value_type(v) != width_to_type(b) {
+                return false;
+            }
+            match m.vars[a as usize] {
+                Some(bound) => bound == v,
+                None => {
+                    m.vars[a as usize] = Some(v);
+                    true
+                }
+            }
+        }
+        OPCODE => view.map_or(false, |view| view.opcode == OPCODES[a as usize]),
+        COND => view.map_or(false, |view| view.cond == Some(CONDS[a as usize])),
+        ARG => {
+            let inst = match m.slots[a as usize] {
+                Some(inst) => inst,
+                None => return false,
+            };
+            let view = match decode_inst(pos, inst) {
+                Some(view) => view,
+                None => return false,
+            };
+            m.operand = None;
+            m.imm = None;
+            if b == ARG_IMM {
+                // icmp_imm compares at the width of its value operand
+                let bits = match (view.format, view.args[0]) {
+                    (INT_COMPARE_IMM, Some(arg)) => pos.func.dfg.value_type(arg).bits() as u32,
+                    _ => pos.func.dfg.value_type(pos.func.dfg.first_result(inst)).bits() as u32,
+                };
+                m.imm = view.imm.map(|imm| (imm, bits));
+                m.imm.is_some()
+            } else {
+                let index = if b == ARG_VALUE { 0 } else { b as usize };
+                m.operand = view.args.get(index).cloned().unwrap_or(None);
+                m.operand.is_some()
+            }
+        }
+        RESULT => match m.operand {
+            Some(v) => match pos.func.dfg.value_def(pos.func.dfg.resolve_aliases(v)) {
+                ValueDef::Result(inst, _) => {
+                    m.entity = Some(inst);
+                    true
+                }
+                _ => false,
+            },
+            None => false,
+        },
+        ANY => {
+            m.entity = None;
+            true
+        }
+        PLAIN_CONST => m.imm.is_some(),
+        CONST => match m.imm {
+            Some((imm, bits)) => mask(imm, bits) == mask(value, bits),
+            None => false,
+        },
+        SAME | DIFFER => {
+            let first = lhs_operand(pos, a, b, m);
+            let other = lhs_operand(pos, value as u8, (value >> 8) as u8, m);
+            match (first, other) {
+                (Some(x), Some(y)) => {
+                    let same = pos.func.dfg.resolve_aliases(x) == pos.func.dfg.resolve_aliases(y);
+                    same == (kind == SAME)
+                }
+                _ => false,
+            }
+        }
+        // Souper and Cranelift only agree on shifts by an amount below
+        // the width, the rule holds for a constant one
+        SHIFT_AMOUNT => {
+            let amount = match lhs_operand(pos, a, b, m) {
+                Some(v) => pos.func.dfg.resolve_aliases(v),
+                None => return false,
+            };
+            match pos.func.dfg.value_def(amount) {
+                ValueDef::Result(inst, _) => match decode_inst(pos, inst) {
+                    Some(view) if view.opcode == ICONST => view.imm.map_or(false, |imm| imm >= 0 && imm < value),
+                    _ => false,
+                },
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Walks the node at `at` and its children, returning the RHS offset of
+/// the first rule that matches
+fn walk(pos: &FuncCursor, at: usize, mut m: MatchState) -> Option<(usize, MatchState)> {
+    let kind = AUTOMATON[at];
+    let children = read_u16(at + 3);
+    let mut next = at + 5;
+    let mut value = 0;
+    if let CONST | SAME | DIFFER | SHIFT_AMOUNT = kind & !ACTION {
+        value = read_i64(next);
+        next += 8;
+    }
+    if !match_node(pos, kind & !ACTION, AUTOMATON[at + 1], AUTOMATON[at + 2], value, &mut m) {
+        return None;
+    }
+    if kind & ACTION != 0 {
+        return Some((read_u32(next), m));
+    }
+    for child in 0..children {
+        if let Some(found) = walk(pos, read_u32(next + 4 * child), m) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy)]
+enum RhsOperand {
+    Value(Value),
+    Imm(i64),
+}
+
+fn lhs_operand(pos: &FuncCursor, slot: u8, index: u8, m: &MatchState) -> Option<Value> {
+    let inst = m.slots[slot as usize]?;
+    decode_inst(pos, inst)?.args.get(index as usize).cloned().unwrap_or(None)
+}
+
+fn read_operand(pos: &FuncCursor, at: &mut usize, m: &MatchState, created: &[Value]) -> Option<RhsOperand> {
+    let tag = AUTOMATON[*at];
+    let operand = match tag {
+        OPERAND_LHS => lhs_operand(pos, AUTOMATON[*at + 1], AUTOMATON[*at + 2], m).map(RhsOperand::Value),
+        OPERAND_CREATED => created.get(AUTOMATON[*at + 1] as usize).map(|&v| RhsOperand::Value(v)),
+        _ => Some(RhsOperand::Imm(read_i64(*at + 1))),
+    };
+    *at += match tag {
+        OPERAND_LHS => 3,
+        OPERAND_CREATED => 2,
+        _ => 9,
+    };
+    operand
+}
+
+/// Checks every LHS operand of the RHS is bound before changing the DFG
+fn rhs_operands_bound(pos: &FuncCursor, rhs: usize, m: &MatchState) -> bool {
+    let mut at = rhs + 1;
+    for _ in 0..AUTOMATON[rhs] {
+        let operands = AUTOMATON[at + 4];
+        at += 5;
+        for _ in 0..operands {
+            let tag = AUTOMATON[at];
+            if read_operand(pos, &mut at, m, &[]).is_none() && tag == OPERAND_LHS {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn build_inst<'f, T: InstBuilder<'f>>(
+    builder: T,
+    format: u8,
+    opcode: Opcode,
+    cond: Option<IntCC>,
+    ty: Type,
+    args: &[Value],
+    imm: i64,
+) -> Inst {
+    let imm = Imm64::new(imm);
+    match (format, cond) {
+        (UNARY, _) => builder.Unary(opcode, ty, args[0]).0,
+        (UNARY_IMM, _) => builder.UnaryImm(opcode, ty, imm).0,
+        (BINARY_IMM, _) => builder.BinaryImm64(opcode, ty, imm, args[0]).0,
+        (INT_COMPARE, Some(cond)) => builder.IntCompare(opcode, ty, cond, args[0], args[1]).0,
+        (INT_COMPARE_IMM, Some(cond)) => builder.IntCompareImm(opcode, ty, cond, imm, args[0]).0,
+        (TERNARY, _) => builder.Ternary(opcode, ty, args[0], args[1], args[2]).0,
+        _ => builder.Binary(opcode, ty, args[0], args[1]).0,
+    }
+}
+
+/// Builds the RHS at `rhs` in place of `inst`: the insts before the
+/// result are inserted before it, and it is replaced by the last one,
+/// or by a constant, or its result becomes an alias of an LHS value.
+fn apply_rhs(pos: &mut FuncCursor, inst: Inst, rhs: usize, m: &MatchState) -> bool {
+    if !rhs_operands_bound(pos, rhs, m) {
+        return false;
+    }
+    let mut at = rhs + 1;
+    let mut created: Vec<Value> = Vec::new();
+    for _ in 0..AUTOMATON[rhs] {
+        let opcode = AUTOMATON[at];
+        let format = AUTOMATON[at + 1];
+        let cond = CONDS.get(AUTOMATON[at + 2] as usize).cloned();
+        let width = AUTOMATON[at + 3];
+        let operands = AUTOMATON[at + 4];
+        at += 5;
+        let mut args = Vec::new();
+        let mut imm = 0;
+        for _ in 0..operands {
+            match read_operand(pos, &mut at, m, &created) {
+                Some(RhsOperand::Value(v)) => args.push(v),
+                Some(RhsOperand::Imm(c)) => imm = c,
+                None => return false,
+            }
+        }
+        if opcode == RESULT_OPCODE {
+            match args.first() {
+                Some(&value) => alias_result(pos, inst, value),
+                None => make_const(pos, inst, width, imm),
+            }
+            return true;
+        }
+        // compares are controlled by the compared type, select by
+        // the selected one
+        let ty = match (format, args.first(), args.get(1)) {
+            (INT_COMPARE, Some(&arg), _) | (INT_COMPARE_IMM, Some(&arg), _) |
+            (TERNARY, _, Some(&arg)) => pos.func.dfg.value_type(arg),
+            _ => width_to_type(width),
+        };
+        let op = OPCODES[(opcode & !REPLACE) as usize];
+        if opcode & REPLACE != 0 {
+            build_inst(pos.func.dfg.replace(inst), format, op, cond, ty, &args, imm);
+            return true;
+        }
+        let new_inst = build_inst(pos.ins(), format, op, cond, ty, &args, imm);
+        created.push(pos.func.dfg.first_result(new_inst));
+    }
+    false
+}
+
+fn matcher(pos: &mut FuncCursor, inst: Inst) {
+    let m = MatchState {
+        entity: Some(inst),
+        operand: None,
+        imm: None,
+        slots: [None; SLOTS],
+        vars: [None; VARS],
+    };
+    // the nodes start after the RHS section offset
+    if let Some((rhs, m)) = walk(pos, 4, m) {
+        apply_rhs(pos, inst, rhs, &m);
+    }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cliftapi;
+    use std::collections::BTreeMap;
+    use testrules::{compile, Rules};
+
+    fn encode(rules: &Rules, checks: &HashMap<usize, Vec<LeafCheck>>) -> Automaton {
+        let api = cliftapi::get_default_clift_api();
+        encode_automaton(&rules.arena, &rules.rhs, &rules.pcs, checks, &*api)
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Record {
+        kind: u8,
+        a: u8,
+        b: u8,
+        value: Option<i64>,
+        rhs: Option<usize>,
+        children: Vec<usize>,
+    }
+
+    fn read_u32(data: &[u8], at: usize) -> usize {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as usize
+    }
+
+    /// The records reachable from the root, by offset, read the way the
+    /// runtime's `walk` reads them
+    fn decode(data: &[u8]) -> BTreeMap<usize, Record> {
+        let mut records = BTreeMap::new();
+        let mut stack = vec![4];
+        while let Some(at) = stack.pop() {
+            if records.contains_key(&at) {
+                continue;
+            }
+            let kind = data[at];
+            let children = u16::from_le_bytes([data[at + 3], data[at + 4]]) as usize;
+            let mut next = at + 5;
+            let value = match kind & !ACTION {
+                CONST | SAME | DIFFER | SHIFT_AMOUNT => {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(&data[next..next + 8]);
+                    next += 8;
+                    Some(i64::from_le_bytes(bytes))
+                }
+                _ => None,
+            };
+            let rhs = if kind & ACTION != 0 {
+                next += 4;
+                Some(read_u32(data, next - 4))
+            } else {
+                None
+            };
+            let children: Vec<usize> = (0..children).map(|n| read_u32(data, next + 4 * n)).collect();
+            stack.extend(children.iter().cloned());
+            records.insert(at, Record { kind: kind & !ACTION, a: data[at + 1], b: data[at + 2], value, rhs, children });
+        }
+        records
+    }
+
+    /// The records from the root to the first action, following the
+    /// first child
+    fn path_to_action(records: &BTreeMap<usize, Record>) -> Vec<&Record> {
+        let mut path = vec![&records[&4]];
+        while path[path.len() - 1].rhs.is_none() {
+            let child = path[path.len() - 1].children[0];
+            path.push(&records[&child]);
+        }
+        path
+    }
+
+    const ADD_SAME: &str = "%0:i32 = var\n%1:i32 = add %0, %0\ninfer %1\n~>\n\
+                            %2:i32 = shl %0, 1:i32\nresult %2\n";
+    const ADD_COMMUTED: &str = "%0:i32 = var\n%1:i32 = var\n%2:i32 = add %0, %1\ninfer %2\n~>\n\
+                                %3:i32 = add %1, %0\nresult %3\n";
+
+    #[test]
+    fn action_without_checks_is_on_the_last_lhs_node() {
+        let rules = compile(&[ADD_COMMUTED]);
+        let automaton = encode(&rules, &HashMap::new());
+        let records = decode(&automaton.data);
+        assert_eq!(records.len(), automaton.nodes);
+        assert_eq!(automaton.actions, 1);
+        let path = path_to_action(&records);
+        let action = path[path.len() - 1];
+        // the nodes after the second var leaf test nothing
+        assert_eq!(action.kind, NOP);
+        assert_eq!(path.iter().rev().find(|record| record.kind != NOP).map(|record| record.kind), Some(VAR));
+        // one iadd that replaces the matched inst
+        let rhs = action.rhs.unwrap();
+        assert!(rhs >= read_u32(&automaton.data, 0));
+        assert_eq!(automaton.data[rhs], 1);
+        assert_eq!(automaton.data[rhs + 1] & REPLACE, REPLACE);
+    }
+
+    #[test]
+    fn repeated_value_is_checked_before_the_action() {
+        let rules = compile(&[ADD_SAME]);
+        let records = decode(&encode(&rules, &HashMap::new()).data);
+        let path = path_to_action(&records);
+        let last_lhs = path[path.len() - 2];
+        assert_eq!((last_lhs.kind, last_lhs.rhs, last_lhs.children.len()), (NOP, None, 1));
+        // arg_0[0] and arg_0[1]
+        assert_eq!(
+            path[path.len() - 1],
+            &Record { kind: SAME, a: 0, b: 0, value: Some(1 << 8), rhs: path[path.len() - 1].rhs, children: Vec::new() }
+        );
+    }
+
+    #[test]
+    fn checks_are_chained_before_the_action() {
+        let rules = compile(&[ADD_COMMUTED]);
+        let mut checks = HashMap::new();
+        checks.insert(rules.leaves[0], vec![
+            LeafCheck::ShiftAmount("arg_0[1]".to_string(), 32),
+            LeafCheck::Differ("arg_0[0]".to_string(), "arg_0[1]".to_string()),
+        ]);
+        let records = decode(&encode(&rules, &checks).data);
+        let path = path_to_action(&records);
+        let chain: Vec<(u8, u8, u8, Option<i64>, bool)> = path[path.len() - 2..]
+            .iter()
+            .map(|record| (record.kind, record.a, record.b, record.value, record.rhs.is_some()))
+            .collect();
+        // inequalities come before the shift amounts
+        assert_eq!(chain, vec![(DIFFER, 0, 0, Some(1 << 8), false), (SHIFT_AMOUNT, 0, 1, Some(32), true)]);
+        assert_eq!((path[path.len() - 3].kind, path[path.len() - 3].rhs), (NOP, None));
+    }
+
+    #[test]
+    fn unencodable_check_drops_the_action() {
+        let rules = compile(&[ADD_COMMUTED]);
+        let mut checks = HashMap::new();
+        checks.insert(rules.leaves[0], vec![LeafCheck::ShiftAmount("imm_0".to_string(), 32)]);
+        let automaton = encode(&rules, &checks);
+        assert_eq!(automaton.actions, 0);
+        assert!(decode(&automaton.data).values().all(|record| record.rhs.is_none()));
+    }
+
+    #[test]
+    fn runtime_kinds_match_the_encoder() {
+        for &(name, kind) in &[("CONST", CONST), ("SAME", SAME), ("DIFFER", DIFFER), ("SHIFT_AMOUNT", SHIFT_AMOUNT)] {
+            assert!(RUNTIME.contains(&format!("const {}: u8 = {};\n", name, kind)));
+        }
+        // the runtime reads the value of every kind that has one
+        assert!(RUNTIME.contains("if let CONST | SAME | DIFFER | SHIFT_AMOUNT = kind & !ACTION {"));
+        let api = cliftapi::get_default_clift_api();
+        assert!(generate_interpreter(&*api).contains("const ICONST: Opcode = Opcode::Iconst;\n"));
+    }
+}
@@ -1,8 +1,10 @@
 // Matcher
 
+use cliftapi::CliftApi;
 use cliftinstbuilder::{self, CtonOpcode};
 use processrhs::CliftInstWithArgs;
 use lhspatternmatcher::{Node, NodeType};
+use pctable;
 use std::collections::HashMap;
 
 #[derive(Clone)]
@@ -40,7 +42,7 @@ impl Opt {
     pub fn generate_header(&mut self, count: u32) {
         self.func_str.push_str("fn superopt_");
         self.func_str.push_str(&count.to_string());
-        self.func_str.push_str("(pos: &mut FuncCursor, inst: Inst)");
+        self.func_str.push_str("(pos: &mut FuncCursor, inst: Inst) -> bool");
     }
 
     pub fn append(&mut self, input: String) {
@@ -250,64 +252,26 @@ impl Opt {
         }
     }
 
-    pub fn get_relation_in_args(
+    pub fn take_action(
         &mut self,
-        table: HashMap<String, usize>,
-        arg1: String,
-        arg2: String) -> String {
-        let mut result = "".to_owned();
-        let idx1 = table[&arg1];
-        let idx2 = table[&arg2];
-        if idx1 == idx2 {
-            result.push_str(&arg1);
-            result.push_str(&" == ".to_string());
-            result.push_str(&arg2);
-        } else {
-            result.push_str(&arg1);
-            result.push_str(&" != ".to_string());
-            result.push_str(&arg2);
-        }
-        //////println!("*** Result str = {}\n", result);
-        result
-    }
-
-    pub fn generate_path_condition(&mut self, pctable: HashMap<String, usize>) -> String {
-        // first, collect all keys in vector
-        let mut pc_args = Vec::new();
-        for key in pctable.keys() {
-            pc_args.push(key.clone());
-        }
-        
-        // make all combinations
-        let mut pcs = Vec::new();
-        for i in 0..pc_args.len() {
-            for j in i+1..pc_args.len() {
-                //////println!("pcarg i = {}, j = {}\n", pc_args[i], pc_args[j]);
-                let pc = self.get_relation_in_args(
-                    pctable.clone(),
-                    pc_args[i].clone(),
-                    pc_args[j].clone());
-                pcs.push(pc);
-            }
+        rhs: Vec<CliftInstWithArgs>,
+        pctbl: HashMap<String, usize>,
+        guard: Option<&String>,
+        _level: usize,
+        api: &dyn CliftApi) {
+        let mut conds = Vec::new();
+        // Values the LHS uses more than once
+        if let Some(pc) = pctable::generate_equality_guard(&pctbl) {
+            conds.push(pc);
         }
-
-        let mut result = "".to_owned();
-        if pcs.len() > 0 {
-            result.push_str(&pcs[0]);
-        }
-
-        for i in 1..pcs.len() {
-            result.push_str(&" && ".to_string());
-            result.push_str(&pcs[i]);
+        // Values a path condition states are different, and shift
+        // amounts the rule relies on being in range
+        if let Some(guard) = guard {
+            conds.push(guard.clone());
         }
-        result
-    }
-
-    pub fn take_action(&mut self, rhs: Vec<CliftInstWithArgs>, pctbl: HashMap<String, usize>, _level: usize) {
-        let mut pc_str = "".to_owned();
-        if pctbl.len() > 1 {
-            pc_str += &"if ".to_owned();
-            pc_str += &self.generate_path_condition(pctbl.clone());
+        if !conds.is_empty() {
+            let mut pc_str = "if ".to_owned();
+            pc_str += &conds.join(" && ");
             self.func_str.push_str(&pc_str);
             // FIXED: You can't enter into scope without pushing them
             // on the stack with level number of the node on scope stack
@@ -321,33 +285,13 @@ impl Opt {
         if rhs.len() == 1 {
             let each_inst = rhs[0].clone();
             if each_inst.cops[0].contains("arg") | each_inst.cops[0].contains("rhs") {
-                replace_inst_str += &"let rep_insts = pos.func.dfg.inst_results(inst);\n".to_owned();
-                replace_inst_str += &"let rep_insts_0 = rep_insts[0];\n".to_owned();
-                replace_inst_str += &"pos.func.dfg.change_to_alias(".to_owned();
-                replace_inst_str += &each_inst.cops[0].to_owned();
-                replace_inst_str += &", rep_insts_0);".to_owned();
+                replace_inst_str += &api.alias_result(&each_inst.cops[0]);
             } else {
                 replace_inst_str += &"pos.func.dfg.replace(".to_owned();
                 replace_inst_str += &"inst".to_owned();
                 replace_inst_str += &").".to_owned();
                 let w = rhs[0].width;
-                if w == 1 {
-                    replace_inst_str += &"bconst(B1, ".to_owned();
-                    if each_inst.cops[0].clone() == String::from("1".to_string()) {
-                        replace_inst_str += &"true".to_owned();
-                    } else {
-                        replace_inst_str += &"false".to_owned();
-                    }
-                } else {
-                    replace_inst_str += &"iconst(I".to_owned();
-                    replace_inst_str += &w.to_string();
-                    replace_inst_str += &", ".to_owned();
-                    replace_inst_str += &each_inst.cops[0].to_owned();
-                    //if each_inst.cops[0].clone().contains("arg") {
-                    //} else {
-                    //    replace_inst_str += &"_u64 as i64".to_owned();
-                    //}
-                }
+                replace_inst_str += &api.const_inst(w, &each_inst.cops[0]);
                 // FIX: Add the rhs.cops vector string - should be one element only.
                 //for i in 0..each_inst.cops.len() {
                 //    if i > 0 {
@@ -355,7 +299,7 @@ impl Opt {
                 //    }
                 //    replace_inst_str += &each_inst.cops[i].to_owned();
                 //}
-                replace_inst_str += &"); ".to_owned();
+                replace_inst_str += "; ";
             }
             self.func_str.push_str(&replace_inst_str);
         } else {
@@ -374,27 +318,12 @@ impl Opt {
                 match each_inst.opcode.clone() {
                     CtonOpcode::Iconst => {
                         let w = each_inst.width;
-                        if w == 1 {
-                            insert_inst_str += &"bconst(B1, ".to_owned();
-                            if each_inst.cops[0].clone() == String::from("1".to_string()) {
-                                insert_inst_str += &"true".to_owned();
-                            } else {
-                                insert_inst_str += &"false".to_owned();
-                            }
-                        } else {
-                            insert_inst_str += &"iconst(I".to_owned();
-                            insert_inst_str += &w.to_string();
-                            insert_inst_str += &", ".to_owned();
-                            insert_inst_str += &each_inst.cops[0].to_owned();
-                            //if each_inst.cops[0].clone().contains("arg") {
-                            //} else {
-                            //    insert_inst_str += &"_u64 as i64".to_owned();
-                            //}
-                        }
-                        insert_inst_str += &");\n".to_owned();
+                        insert_inst_str += &api.const_inst(w, &each_inst.cops[0]);
+                        insert_inst_str += ";\n";
                     },
                     _ => {
-                        insert_inst_str += &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode.clone());
+                        insert_inst_str += &api.builder_method(
+                            &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode.clone()));
                         insert_inst_str += &"(".to_owned();
                         for i in 0..each_inst.cops.len() {
                             if i > 0 {
@@ -414,7 +343,8 @@ impl Opt {
                 // FIXME: fix the inst name here
                 replace_inst_str += &"inst".to_owned();
                 replace_inst_str += &").".to_owned();
-                replace_inst_str += &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode);
+                replace_inst_str += &api.builder_method(
+                    &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode));
                 replace_inst_str += &"(".to_owned();
 
                 // FIX: Insert args list of rhs inst
@@ -429,6 +359,8 @@ impl Opt {
                 self.func_str.push_str(&replace_inst_str);
             }
         }
+        // tell the driver this rule fired
+        self.func_str.push_str("\nreturn true;");
         // FIXED: This was added just as a hack earlier
         // to exit the scope for if (args[x] == args[y]) condition
         // Now, exit_scope() function can take care of it because
@@ -449,7 +381,7 @@ impl Opt {
     }
 }
 
-pub fn is_node_actionable(node_id: usize, table: HashMap<usize, Vec<CliftInstWithArgs>>) -> bool {
+pub fn is_node_actionable(node_id: usize, table: &HashMap<usize, Vec<CliftInstWithArgs>>) -> bool {
     if table.contains_key(&node_id) {
         true
     } else {
@@ -502,10 +434,12 @@ pub fn get_arg_name_dup(idx: usize, tbl: HashMap<usize, String>) -> String {
 
 pub fn generate_baseline_matcher(
     mut nodes: Vec<Node>,
-    rhs: HashMap<usize, Vec<CliftInstWithArgs>>,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
     count: u32,
     _idx_to_argname: HashMap<usize, String>,
-    pc_table: HashMap<String, usize>
+    pc_table: HashMap<String, usize>,
+    guard: Option<String>,
+    api: &dyn CliftApi
 ) -> String {
     //////for (id, rinsts) in &rhs {
     //////    println!("id = {} : \n", id);
@@ -523,7 +457,7 @@ pub fn generate_baseline_matcher(
     nodes.insert(0, opt_func.build_root_node());
 
     for node in 0..nodes.len() {
-        let action_flag = is_node_actionable(nodes[node].id, rhs.clone());
+        let action_flag = is_node_actionable(nodes[node].id, rhs);
         // dump: begin
         //////println!("Node ==== ======================");
         //////println!("\t\t Actionable? = {}", action_flag);
@@ -568,7 +502,7 @@ pub fn generate_baseline_matcher(
                 opt_func.set_level_of_all_child_nodes(&mut nodes, node, current_level);
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::MatchInstData => {
@@ -576,19 +510,16 @@ pub fn generate_baseline_matcher(
                 //set the level of root->next nodes to 0+1
                 opt_func.set_level_of_all_child_nodes(&mut nodes, node, current_level);
 
-                let opt_clone = opt_func.clone();
-                let ent = opt_clone.current_entity;
+                let ent = opt_func.current_entity.clone();
                 if !ent.is_empty() {
-                    opt_func.append(String::from("match pos.func.dfg"));
-                    opt_func.append(String::from("["));
+                    opt_func.append(String::from("match "));
                     // FIXME: Connect this ent string with RHS replacement part
-                    opt_func.append(ent);
-                    opt_func.append(String::from("]"));
+                    opt_func.append(api.inst_data(&ent));
                     opt_func.enter_scope(ScopeType::ScopeMatch, current_level);
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::InstType => {
@@ -603,12 +534,16 @@ pub fn generate_baseline_matcher(
                 if index != 0 {
                     opt_func.pop_and_exit_scope_from(index);
                 }
+                // InstructionData pattern of this format, the arms
+                // below bind its args and immediates
+                if let Some(pattern) = api.inst_data_pattern(&nodes[node].node_value) {
+                    opt_func.append(pattern);
+                }
                 match nodes[node].node_value.as_ref() {
                     "Var" => {}
                     "Binary" => {
                         // FIXME: "args" part, make a connection
                         // between actual args and string
-                        opt_func.append(String::from("InstructionData::Binary { opcode, args }"));
                         opt_func.enter_scope(ScopeType::ScopeCase, current_level);
                         opt_func.set_entity(String::from("opcode"));
                         // FIXED: Generate: "let args_<counter> = args;"
@@ -627,9 +562,6 @@ pub fn generate_baseline_matcher(
                     "IntCompare" => {
                         // FIXME: "args" part, make a connection
                         // between actual args and string
-                        opt_func.append(String::from(
-                            "InstructionData::IntCompare { opcode, cond, args }",
-                        ));
                         opt_func.enter_scope(ScopeType::ScopeCase, current_level);
                         opt_func.set_entity(String::from("opcode"));
                         // FIXED: Generate: "let args_<counter> = args;"
@@ -640,10 +572,16 @@ pub fn generate_baseline_matcher(
 
                         opt_func.append(String::from(" = args;\n"));
                     }
+                    "Ternary" => {
+                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
+                        opt_func.set_entity(String::from("opcode"));
+                        opt_func.append(String::from("let "));
+                        opt_func.append(nodes[node].arg_name.clone());
+                        opt_func.append(String::from(" = args;\n"));
+                    }
                     "Unary" => {
                         // FIXME: "arg" part, make a connection
                         // b/w actual args and string
-                        opt_func.append(String::from("InstructionData::Unary { opcode, arg }"));
                         opt_func.enter_scope(ScopeType::ScopeCase, current_level);
                         opt_func.set_entity(String::from("opcode"));
                         // FIXED: Generate: "let args_<counter> = arg;"
@@ -657,7 +595,6 @@ pub fn generate_baseline_matcher(
                         opt_func.append(String::from(" = arg;\n"));
                     }
                     "UnaryImm" => {
-                        opt_func.append(String::from("InstructionData::UnaryImm { opcode, imm }"));
                         opt_func.enter_scope(ScopeType::ScopeCase, current_level);
                         opt_func.set_entity(String::from("opcode"));
                         const_counter = opt_func.get_const_counter(const_counter);
@@ -665,15 +602,12 @@ pub fn generate_baseline_matcher(
                         rhs_arg.push_str(&const_counter.to_string());
                         opt_func.append(String::from("let "));
                         opt_func.append(String::from(rhs_arg.to_string()));
-                        opt_func.append(String::from(" : i64 = imm.into();\n"));
+                        opt_func.append(format!(" : i64 = {};\n", api.imm_to_i64()));
                         opt_func.push_to_const_stack(rhs_arg.to_string());
                     }
                     "BinaryImm" => {
                         // FIXME: "args" part, make a connection
                         // between actual args and string
-                        opt_func.append(String::from(
-                            "InstructionData::BinaryImm64 { opcode, arg, imm }",
-                        ));
                         opt_func.enter_scope(ScopeType::ScopeCase, current_level);
                         opt_func.set_entity(String::from("opcode"));
                         //opt_func.append(String::from("let args_"));
@@ -689,15 +623,12 @@ pub fn generate_baseline_matcher(
                         rhs_arg.push_str(&const_counter.to_string());
                         opt_func.append(String::from("let "));
                         opt_func.append(String::from(rhs_arg.to_string()));
-                        opt_func.append(String::from(" : i64 = imm.into();\n"));
+                        opt_func.append(format!(" : i64 = {};\n", api.imm_to_i64()));
                         opt_func.push_to_const_stack(rhs_arg.to_string());
                     }
                     "IntCompareImm" => {
                         // FIXME: "args" part, make a connection
                         // between actual args and string
-                        opt_func.append(String::from(
-                            "InstructionData::IntCompareImm { opcode, cond, arg, imm }",
-                        ));
                         opt_func.enter_scope(ScopeType::ScopeCase, current_level);
                         opt_func.set_entity(String::from("opcode"));
                         // opt_func.append(String::from("let args_"));
@@ -713,7 +644,7 @@ pub fn generate_baseline_matcher(
                         rhs_arg.push_str(&const_counter.to_string());
                         opt_func.append(String::from("let "));
                         opt_func.append(String::from(rhs_arg.to_string()));
-                        opt_func.append(String::from(" : i64 = imm.into();\n"));
+                        opt_func.append(format!(" : i64 = {};\n", api.imm_to_i64()));
                         opt_func.push_to_const_stack(rhs_arg.to_string());
                     }
                     _ => {
@@ -722,7 +653,7 @@ pub fn generate_baseline_matcher(
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::MatchValDef => {
@@ -738,7 +669,10 @@ pub fn generate_baseline_matcher(
                     opt_func.pop_and_exit_scope_from(index);
                 }
                 match nodes[node].node_value.as_ref() {
-                    "Param" => {
+                    "Any" => {
+                        // Var leaves are wildcards: they match a value
+                        // defined by either a block param or another
+                        // instruction, so no value_def check is emitted.
                         // Reset the argument match string to empty str
                         // so that for further args, it's not appended.
                         arg_str = String::from("");
@@ -758,20 +692,18 @@ pub fn generate_baseline_matcher(
                     _ => {
                         // FIXME - do we want error handling here
                         // for NoneType and ""
-                        println!("\t\t entering unknown valdef case\n");
                     }
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::MatchOpcode => {
                 let current_level = nodes[node].level;
                 //set the level of root->next nodes to 0+1
                 opt_func.set_level_of_all_child_nodes(&mut nodes, node, current_level);
-                let opt_clone = opt_func.clone();
-                let ent = opt_clone.current_entity;
+                let ent = opt_func.current_entity.clone();
                 // FIXME: Any purpose of ent here?
                 if !ent.is_empty() {
                     opt_func.append(String::from("match opcode"));
@@ -779,7 +711,7 @@ pub fn generate_baseline_matcher(
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::Opcode => {
@@ -797,132 +729,25 @@ pub fn generate_baseline_matcher(
                 // match the actual opcode types
                 match nodes[node].node_value.as_ref() {
                     "Var" => {}
-                    "iadd" => {
-                        opt_func.append(String::from("Opcode::Iadd"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "iadd_imm" => {
-                        opt_func.append(String::from("Opcode::IaddImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "imul" => {
-                        opt_func.append(String::from("Opcode::Imul"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "imul_imm" => {
-                        opt_func.append(String::from("Opcode::ImulImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "isub" => {
-                        opt_func.append(String::from("Opcode::Isub"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "irsub_imm" => {
-                        opt_func.append(String::from("Opcode::IrsubImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "icmp" => {
-                        opt_func.append(String::from("Opcode::Icmp"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "icmp_imm" => {
-                        opt_func.append(String::from("Opcode::IcmpImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "band" => {
-                        opt_func.append(String::from("Opcode::Band"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "band_imm" => {
-                        opt_func.append(String::from("Opcode::BandImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bor" => {
-                        opt_func.append(String::from("Opcode::Bor"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bor_imm" => {
-                        opt_func.append(String::from("Opcode::BorImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bxor" => {
-                        opt_func.append(String::from("Opcode::Bxor"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bxor_imm" => {
-                        opt_func.append(String::from("Opcode::BxorImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "band_not" => {
-                        opt_func.append(String::from("Opcode::BandNot"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bor_not" => {
-                        opt_func.append(String::from("Opcode::BorNot"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bxor_not" => {
-                        opt_func.append(String::from("Opcode::BxorNot"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bnot" => {
-                        opt_func.append(String::from("Opcode::Bnot"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ishl" => {
-                        opt_func.append(String::from("Opcode::Ishl"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ishl_imm" => {
-                        opt_func.append(String::from("Opcode::IshlImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "sshr" => {
-                        opt_func.append(String::from("Opcode::Sshr"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "sshr_imm" => {
-                        opt_func.append(String::from("Opcode::SshrImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ushr" => {
-                        opt_func.append(String::from("Opcode::Ushr"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ushr_imm" => {
-                        opt_func.append(String::from("Opcode::UshrImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "popcnt" => {
-                        opt_func.append(String::from("Opcode::Popcnt"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "clz" => {
-                        opt_func.append(String::from("Opcode::Clz"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ctz" => {
-                        opt_func.append(String::from("Opcode::Ctz"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "iconst" => {
-                        opt_func.append(String::from("Opcode::Iconst"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    _ => {
-                        panic!("Error: this opcode type is not yet handled");
-                    }
+                    opcode => match api.opcode_pattern(opcode) {
+                        Some(pattern) => {
+                            opt_func.append(pattern);
+                            opt_func.enter_scope(ScopeType::ScopeCase, current_level);
+                        }
+                        None => {
+                            panic!("Error: this opcode type is not yet handled");
+                        }
+                    },
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::MatchCond => {
                 let current_level = nodes[node].level;
                 opt_func.set_level_of_all_child_nodes(&mut nodes, node, current_level);
-                let opt_clone = opt_func.clone();
-                let ent = opt_clone.current_entity;
+                let ent = opt_func.current_entity.clone();
                 // FIXME: Any purpose of ent here?
                 if !ent.is_empty() {
                     opt_func.append(String::from("match cond"));
@@ -930,7 +755,7 @@ pub fn generate_baseline_matcher(
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::Cond => {
@@ -957,7 +782,7 @@ pub fn generate_baseline_matcher(
                 }
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::MatchArgs => {
@@ -967,8 +792,8 @@ pub fn generate_baseline_matcher(
                 // Create an optional argument matching string here
                 // we will decide later whether we need this match
                 // on args or not depending on if the argument type
-                // is Result or Param. Param
-                // type does not need this match part at all.
+                // is Result or Any. Any (a var leaf) matches every
+                // value and does not need this match part at all.
                 let arg_node_val = nodes[node].node_value.clone();
                 let mut optional_argstr = String::from("");
                 if arg_node_val.contains("arg") {
@@ -1002,7 +827,7 @@ pub fn generate_baseline_matcher(
                 opt_func.set_level_of_all_child_nodes(&mut nodes, node, current_level);
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             NodeType::MatchConst => {
@@ -1032,7 +857,7 @@ pub fn generate_baseline_matcher(
                 opt_func.enter_scope(ScopeType::ScopeIfCond, current_level);
                 if action_flag {
                     let found_rhs = &rhs[&nodes[node].id];
-                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), current_level);
+                    opt_func.take_action(found_rhs.to_vec(), pc_table.clone(), guard.as_ref(), current_level, api);
                 }
             }
             _ => {
@@ -1064,6 +889,10 @@ pub fn generate_baseline_matcher(
     //////}
     //////println!("********* Scope Stack End ***********");
     while let Some(elem) = opt_func.scope_stack.pop() {
+        if let ScopeType::ScopeFunc = elem.scope_type {
+            // no match
+            opt_func.append(String::from("\nfalse"));
+        }
         opt_func.exit_scope(elem.scope_type, elem.level);
         //let elem_ty = opt_func.scope_stack.pop();
         //match elem_ty {
@@ -1076,3 +905,20 @@ pub fn generate_baseline_matcher(
 
     opt_func.func_str
 }
+
+/// Entry point that calls the `superopt_N` functions in priority order
+/// and stops at the first one that rewrites `inst`
+pub fn generate_baseline_driver(functions: &[u32]) -> String {
+    let mut driver_str = "fn superopt(pos: &mut FuncCursor, inst: Inst) -> bool {\n".to_owned();
+    let calls: Vec<String> = functions
+        .iter()
+        .map(|function| format!("superopt_{}(pos, inst)", function))
+        .collect();
+    if calls.is_empty() {
+        driver_str += "false";
+    } else {
+        driver_str += &calls.join("\n|| ");
+    }
+    driver_str += "\n}";
+    driver_str
+}
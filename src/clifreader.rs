@@ -0,0 +1,312 @@
+// Reader for Cranelift `.clif` text
+//
+// Builds a small DFG model of each function: for every value, its bit
+// width and the instruction (cretonne opcode, condition and operands)
+// that defines it. Only the parts the Souper query harvesting needs are
+// modelled; block params and instructions with an opcode we don't know
+// are kept as opaque values.
+
+use cliftinstbuilder::{self, CtonCmpCond, CtonOpcode, CtonOperand};
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct ClifValue {
+    /// None for non-integer types (floats, vectors, ...)
+    pub width: Option<u32>,
+    /// None for block params and opaque instructions
+    pub opcode: Option<CtonOpcode>,
+    pub cond: Option<CtonCmpCond>,
+    /// Value operands hold the value number, immediates the constant
+    pub args: Vec<CtonOperand>,
+}
+
+pub struct ClifFunction {
+    pub name: String,
+    pub values: HashMap<usize, ClifValue>,
+    /// Instruction results, in the order they are defined
+    pub results: Vec<usize>,
+    aliases: HashMap<usize, usize>,
+}
+
+impl ClifFunction {
+    fn new(name: String) -> ClifFunction {
+        ClifFunction {
+            name,
+            values: HashMap::new(),
+            results: Vec::new(),
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Follows `vN -> vM` aliases to the value that is defined
+    pub fn resolve_alias(&self, value: usize) -> usize {
+        let mut value = value;
+        // bounded, in case of a malformed alias cycle
+        for _ in 0..self.aliases.len() {
+            match self.aliases.get(&value) {
+                Some(&original) => value = original,
+                None => break,
+            }
+        }
+        value
+    }
+
+    pub fn get_value(&self, value: usize) -> Option<&ClifValue> {
+        self.values.get(&self.resolve_alias(value))
+    }
+
+    fn get_width(&self, value: usize) -> Option<u32> {
+        self.get_value(value).and_then(|v| v.width)
+    }
+
+    fn parse_block_params(&mut self, line: &str) {
+        let params = match (line.find('('), line.rfind(')')) {
+            (Some(start), Some(end)) if start < end => &line[start + 1..end],
+            _ => return,
+        };
+        for param in params.split(',') {
+            let mut parts = param.splitn(2, ':');
+            let value = parts.next().and_then(|v| parse_value(v.trim()));
+            // old clif has value locations after the type: `v0: i32 [%rdi]`
+            let ty = parts.next().and_then(|t| t.split_whitespace().next());
+            if let Some(value) = value {
+                self.values.insert(
+                    value,
+                    ClifValue {
+                        width: ty.and_then(parse_type),
+                        opcode: None,
+                        cond: None,
+                        args: Vec::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn parse_inst(&mut self, results: &str, inst: &str) {
+        let results: Vec<usize> = match results
+            .split(',')
+            .map(|r| parse_value(r.trim()))
+            .collect::<Option<Vec<usize>>>()
+        {
+            Some(results) => results,
+            None => return,
+        };
+        let (opcode_str, operands) = match inst.find(char::is_whitespace) {
+            Some(pos) => (&inst[..pos], inst[pos..].trim()),
+            None => (inst, ""),
+        };
+        let (name, ty) = match opcode_str.find('.') {
+            Some(pos) => (&opcode_str[..pos], Some(&opcode_str[pos + 1..])),
+            None => (opcode_str, None),
+        };
+
+        let mut value = ClifValue {
+            width: ty.and_then(parse_type),
+            opcode: None,
+            cond: None,
+            args: Vec::new(),
+        };
+        // instructions with more than one result are opaque
+        if results.len() == 1 {
+            self.parse_operands(&mut value, name, operands);
+        }
+        if ty.is_none() {
+            value.width = match value.opcode {
+                Some(CtonOpcode::Icmp) | Some(CtonOpcode::IcmpImm) => Some(1),
+                Some(CtonOpcode::Select) => value.args.get(1).and_then(|op| op.idx_val).and_then(|v| self.get_width(v)),
+                Some(_) => value.args.first().and_then(|op| op.idx_val).and_then(|v| self.get_width(v)),
+                None => None,
+            };
+        }
+        // immediates are sign-extended from the type width; compares
+        // take it from the compared value
+        let imm_width = match value.opcode {
+            Some(CtonOpcode::Icmp) | Some(CtonOpcode::IcmpImm) => {
+                value.args.iter().filter_map(|op| op.idx_val).next().and_then(|v| self.get_width(v))
+            }
+            _ => value.width,
+        };
+        if let Some(width) = imm_width {
+            for arg in value.args.iter_mut() {
+                arg.const_val = arg.const_val.map(|c| sign_extend(c, width));
+            }
+        }
+        for result in results {
+            self.values.insert(result, value.clone());
+            self.results.push(result);
+        }
+    }
+
+    /// Fills in the opcode, condition and operands of `value`, leaving
+    /// it opaque if the instruction is not one we model
+    fn parse_operands(&self, value: &mut ClifValue, name: &str, operands: &str) {
+        // current Cranelift spells imin/imax as smin/smax
+        let (opcode, is_bconst) = match name {
+            "smin" => (Some(CtonOpcode::Imin), false),
+            "smax" => (Some(CtonOpcode::Imax), false),
+            "bconst" => (Some(CtonOpcode::Iconst), true),
+            _ => (cliftinstbuilder::get_clift_opcode(name), false),
+        };
+        let opcode = match opcode {
+            Some(opcode) => opcode,
+            None => return,
+        };
+        let mut operands = operands;
+        let mut swap = false;
+        if let CtonOpcode::Icmp | CtonOpcode::IcmpImm = opcode {
+            let (cond_str, rest) = match operands.find(char::is_whitespace) {
+                Some(pos) => (&operands[..pos], operands[pos..].trim()),
+                None => return,
+            };
+            // Souper has only less-than conditions, so greater-than
+            // compares become less-than with swapped operands
+            let (cond_str, swapped) = match cond_str {
+                "sgt" => ("slt", true),
+                "ugt" => ("ult", true),
+                "sge" => ("sle", true),
+                "uge" => ("ule", true),
+                _ => (cond_str, false),
+            };
+            value.cond = match cliftinstbuilder::get_clift_cond(cond_str) {
+                Some(cond) => Some(cond),
+                None => return,
+            };
+            operands = rest;
+            swap = swapped;
+        }
+        let mut args = Vec::new();
+        for operand in operands.split(',').map(|op| op.trim()) {
+            let arg = if is_bconst {
+                match operand {
+                    "true" => Some(const_operand(1)),
+                    "false" => Some(const_operand(0)),
+                    _ => None,
+                }
+            } else if let Some(v) = parse_value(operand) {
+                Some(CtonOperand {
+                    idx_val: Some(v),
+                    const_val: None,
+                })
+            } else {
+                parse_imm(operand).map(const_operand)
+            };
+            match arg {
+                Some(arg) => args.push(arg),
+                None => {
+                    // flags, memory operands, ... leave it opaque
+                    value.cond = None;
+                    return;
+                }
+            }
+        }
+        if swap {
+            args.reverse();
+        }
+        if is_bconst {
+            value.width = Some(1);
+        }
+        value.opcode = Some(opcode);
+        value.args = args;
+    }
+}
+
+/// Parses every function in the given clif text
+pub fn parse_clif(text: &str) -> Vec<ClifFunction> {
+    let mut funcs: Vec<ClifFunction> = Vec::new();
+    for line in text.lines() {
+        // drop comments
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("function") {
+            let name = rest
+                .trim()
+                .split('(')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_string();
+            funcs.push(ClifFunction::new(name));
+            continue;
+        }
+        let func = match funcs.last_mut() {
+            Some(func) => func,
+            None => continue,
+        };
+        // old clif prefixes instructions with their encoding: `[Op1rr#01]`
+        let line = match line.strip_prefix('[') {
+            Some(rest) => rest.split_once(']').map_or("", |(_, inst)| inst.trim()),
+            None => line,
+        };
+        if line.starts_with("block") && line.ends_with(':') {
+            func.parse_block_params(line);
+        } else if let Some(pos) = line.find(" -> ") {
+            // value alias: `v3 -> v2`
+            if let (Some(alias), Some(original)) =
+                (parse_value(line[..pos].trim()), parse_value(line[pos + 4..].trim()))
+            {
+                func.aliases.insert(alias, original);
+            }
+        } else if let Some(pos) = line.find(" = ") {
+            func.parse_inst(&line[..pos], line[pos + 3..].trim());
+        }
+    }
+    funcs
+}
+
+fn const_operand(c: i128) -> CtonOperand {
+    CtonOperand {
+        idx_val: None,
+        const_val: Some(c),
+    }
+}
+
+/// `vN` to N
+fn parse_value(s: &str) -> Option<usize> {
+    let num = s.strip_prefix('v')?;
+    if num.is_empty() || !num.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    num.parse().ok()
+}
+
+/// Integer types to their bit width; b1 is the only boolean Souper has
+fn parse_type(ty: &str) -> Option<u32> {
+    match ty {
+        "b1" => Some(1),
+        "i8" => Some(8),
+        "i16" => Some(16),
+        "i32" => Some(32),
+        "i64" => Some(64),
+        "i128" => Some(128),
+        _ => None,
+    }
+}
+
+/// Decimal or hex immediates, with `_` separators
+fn parse_imm(s: &str) -> Option<i128> {
+    let s = s.replace('_', "");
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest.to_string()),
+        None => (false, s),
+    };
+    let val = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+    Some(if neg { -val } else { val })
+}
+
+fn sign_extend(c: i128, width: u32) -> i128 {
+    if width == 1 {
+        // booleans stay 0 or 1
+        return c & 1;
+    }
+    if width >= 128 {
+        return c;
+    }
+    let shift = 128 - width;
+    (c << shift) >> shift
+}
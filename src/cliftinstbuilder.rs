@@ -19,7 +19,11 @@ pub struct CtonInst {
 #[derive(Clone)]
 pub enum CtonValueDef {
     Result,
-    Param,
+    /// Souper `var` leaf: a wildcard that matches any value, whether it
+    /// is a block param (ValueDef::Param) or an instruction result
+    /// (ValueDef::Result). Only explicit sub-patterns constrain the
+    /// defining instruction.
+    Any,
     NoneType, //added to deal with infer inst in souper IR
 }
 
@@ -33,6 +37,7 @@ pub enum CtonInstKind {
     Var,
     IntCompare,
     IntCompareImm,
+    Ternary,
     NoneType, //added for infer inst in souper IR
 }
 
@@ -69,6 +74,13 @@ pub enum CtonOpcode {
     BorNot,
     BxorNot,
     Bnot,
+    Ineg,
+    Iabs,
+    Imin,
+    Imax,
+    Umin,
+    Umax,
+    Select,
     NoneType,
 }
 
@@ -133,6 +145,13 @@ pub fn get_cton_inst_name(opcode: CtonOpcode) {
         CtonOpcode::BandNot => println!("CtonOpcode = BandNot"),
         CtonOpcode::BorNot => println!("CtonOpcode = BorNot"),
         CtonOpcode::BxorNot => println!("CtonOpcode = BxorNot"),
+        CtonOpcode::Ineg => println!("CtonOpcode = Ineg"),
+        CtonOpcode::Iabs => println!("CtonOpcode = Iabs"),
+        CtonOpcode::Imin => println!("CtonOpcode = Imin"),
+        CtonOpcode::Imax => println!("CtonOpcode = Imax"),
+        CtonOpcode::Umin => println!("CtonOpcode = Umin"),
+        CtonOpcode::Umax => println!("CtonOpcode = Umax"),
+        CtonOpcode::Select => println!("CtonOpcode = Select"),
         _ => {
             println!("CtonOpcode not yet handled");
         }
@@ -172,6 +191,13 @@ pub fn get_cton_opcode_name(opcode: CtonOpcode) {
         CtonOpcode::BandNot => println!("Cton::Opcode = BandNot"),
         CtonOpcode::BorNot => println!("Cton::Opcode = BorNot"),
         CtonOpcode::BxorNot => println!("Cton::Opcode = BorNot"),
+        CtonOpcode::Ineg => println!("Cton::Opcode = Ineg"),
+        CtonOpcode::Iabs => println!("Cton::Opcode = Iabs"),
+        CtonOpcode::Imin => println!("Cton::Opcode = Imin"),
+        CtonOpcode::Imax => println!("Cton::Opcode = Imax"),
+        CtonOpcode::Umin => println!("Cton::Opcode = Umin"),
+        CtonOpcode::Umax => println!("Cton::Opcode = Umax"),
+        CtonOpcode::Select => println!("Cton::Opcode = Select"),
         _ => println!("Cton: other type yet to be handled"),
     }
 }
@@ -179,7 +205,7 @@ pub fn get_cton_opcode_name(opcode: CtonOpcode) {
 pub fn get_clift_valdef_name(vdef: CtonValueDef) -> String {
     match vdef {
         CtonValueDef::Result => "Result".to_string(),
-        CtonValueDef::Param => "Param".to_string(),
+        CtonValueDef::Any => "Any".to_string(),
         CtonValueDef::NoneType => "None".to_string(),
     }
 }
@@ -192,6 +218,7 @@ pub fn get_clift_instdata_name(instdata: CtonInstKind) -> String {
         CtonInstKind::UnaryImm => "UnaryImm".to_string(),
         CtonInstKind::IntCompare => "IntCompare".to_string(),
         CtonInstKind::IntCompareImm => "IntCompareImm".to_string(),
+        CtonInstKind::Ternary => "Ternary".to_string(),
         CtonInstKind::Var => "Var".to_string(),
         _ => "".to_string(),
     }
@@ -242,10 +269,77 @@ pub fn get_clift_opcode_name<'a>(opcode: CtonOpcode) -> String {
         CtonOpcode::BandNot => "band_not".to_string(),
         CtonOpcode::BorNot => "bor_not".to_string(),
         CtonOpcode::BxorNot => "bxor_not".to_string(),
+        CtonOpcode::Ineg => "ineg".to_string(),
+        CtonOpcode::Iabs => "iabs".to_string(),
+        CtonOpcode::Imin => "imin".to_string(),
+        CtonOpcode::Imax => "imax".to_string(),
+        CtonOpcode::Umin => "umin".to_string(),
+        CtonOpcode::Umax => "umax".to_string(),
+        CtonOpcode::Select => "select".to_string(),
         _ => "".to_string(),
     }
 }
 
+/// Every opcode with a clif name, for the reverse lookups below
+pub const CLIFT_OPCODES: [CtonOpcode; 35] = [
+    CtonOpcode::Iconst, CtonOpcode::Iadd, CtonOpcode::IaddImm, CtonOpcode::Imul,
+    CtonOpcode::ImulImm, CtonOpcode::Isub, CtonOpcode::IsubImm, CtonOpcode::Band,
+    CtonOpcode::BandImm, CtonOpcode::Bor, CtonOpcode::BorImm, CtonOpcode::Bxor,
+    CtonOpcode::BxorImm, CtonOpcode::Ishl, CtonOpcode::IshlImm, CtonOpcode::Sshr,
+    CtonOpcode::SshrImm, CtonOpcode::Ushr, CtonOpcode::UshrImm, CtonOpcode::Popcnt,
+    CtonOpcode::Clz, CtonOpcode::Ctz, CtonOpcode::Icmp, CtonOpcode::IcmpImm,
+    CtonOpcode::BandNot, CtonOpcode::BorNot, CtonOpcode::BxorNot, CtonOpcode::Bnot,
+    CtonOpcode::Ineg, CtonOpcode::Iabs, CtonOpcode::Imin, CtonOpcode::Imax,
+    CtonOpcode::Umin, CtonOpcode::Umax, CtonOpcode::Select,
+];
+
+/// Returns the cretonne opcode for the given clif opcode name, the
+/// reverse of get_clift_opcode_name
+pub fn get_clift_opcode(name: &str) -> Option<CtonOpcode> {
+    CLIFT_OPCODES
+        .iter()
+        .find(|opcode| get_clift_opcode_name((*opcode).clone()) == name)
+        .cloned()
+}
+
+/// Returns the cretonne condition for the given clif condition name,
+/// the reverse of get_clift_cond_name
+pub fn get_clift_cond(name: &str) -> Option<CtonCmpCond> {
+    let conds = [
+        CtonCmpCond::Eq,
+        CtonCmpCond::Ne,
+        CtonCmpCond::Slt,
+        CtonCmpCond::Ult,
+        CtonCmpCond::Sle,
+        CtonCmpCond::Ule,
+    ];
+    conds.iter().find(|cond| get_clift_cond_name(Some((*cond).clone())) == name).cloned()
+}
+
+/// Returns the Souper inst name of the cretonne opcodes that have one,
+/// the reverse of mapping_souper_to_cton_isa. The `*_imm` forms map to
+/// the same Souper inst as the plain opcode; irsub_imm x, c is `sub c, x`.
+pub fn get_souper_inst_name(opcode: CtonOpcode, cond: Option<CtonCmpCond>) -> Option<String> {
+    let name = match opcode {
+        CtonOpcode::Iadd | CtonOpcode::IaddImm => "add",
+        CtonOpcode::Imul | CtonOpcode::ImulImm => "mul",
+        CtonOpcode::Isub | CtonOpcode::IsubImm => "sub",
+        CtonOpcode::Band | CtonOpcode::BandImm => "and",
+        CtonOpcode::Bor | CtonOpcode::BorImm => "or",
+        CtonOpcode::Bxor | CtonOpcode::BxorImm => "xor",
+        CtonOpcode::Ishl | CtonOpcode::IshlImm => "shl",
+        CtonOpcode::Ushr | CtonOpcode::UshrImm => "lshr",
+        CtonOpcode::Sshr | CtonOpcode::SshrImm => "ashr",
+        CtonOpcode::Popcnt => "ctpop",
+        CtonOpcode::Clz => "ctlz",
+        CtonOpcode::Ctz => "cttz",
+        CtonOpcode::Select => "select",
+        CtonOpcode::Icmp | CtonOpcode::IcmpImm => return cond.map(|cond| get_clift_cond_name(Some(cond))),
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
 pub fn build_clift_ops(souper_ops: Option<Vec<SouperOperand>>) -> Option<Vec<CtonOperand>> {
     let mut cton_ops: Vec<CtonOperand> = Vec::new();
     match souper_ops {
@@ -646,6 +740,16 @@ pub fn mapping_souper_to_cton_isa(souper_inst: Inst) -> CtonInst {
                         lhs_index: lhs_idx,
                     }
                 }
+                InstKind::Select => CtonInst {
+                    valuedef: CtonValueDef::Result,
+                    kind: CtonInstKind::Ternary,
+                    opcode: CtonOpcode::Select,
+                    cond: None,
+                    width: width,
+                    var_num: var_number,
+                    cops: build_clift_ops(ops),
+                    lhs_index: lhs_idx,
+                },
                 InstKind::Shl => {
                     let clift_ops = build_clift_ops(ops);
                     let mut inst_opcode = CtonOpcode::Ishl;
@@ -734,7 +838,7 @@ pub fn mapping_souper_to_cton_isa(souper_inst: Inst) -> CtonInst {
                     lhs_index: lhs_idx,
                 },
                 InstKind::Var => CtonInst {
-                    valuedef: CtonValueDef::Param,
+                    valuedef: CtonValueDef::Any,
                     kind: CtonInstKind::Var,
                     opcode: CtonOpcode::Var,
                     cond: None,
@@ -764,7 +868,7 @@ pub fn mapping_souper_to_cton_isa(souper_inst: Inst) -> CtonInst {
                     lhs_index: lhs_idx,
                 },
                 _ => CtonInst {
-                    valuedef: CtonValueDef::Param,
+                    valuedef: CtonValueDef::Any,
                     kind: CtonInstKind::Var,
                     opcode: CtonOpcode::Var,
                     cond: None,
@@ -786,3 +890,352 @@ pub fn transform_souper_to_clift_insts(souper_insts: Vec<Inst>) -> Vec<CtonInst>
     }
     cton_insts
 }
+
+/// Cranelift-only opcodes on the LHS side
+///
+/// Souper has no `ineg`, `bnot`, `iabs`, `imin`, ... so a rule LHS spells
+/// these out as Souper expansions. The table below is the one place that
+/// says which expansion each Cranelift opcode stands for; the LHS matcher
+/// uses it to also fire a rule on the single Cranelift instruction.
+///
+///   ineg x         <=> sub 0, x
+///   bnot x         <=> xor x, -1
+///   band_not x, y  <=> and x, (not y)
+///   bor_not x, y   <=> or x, (not y)
+///   bxor_not x, y  <=> xor x, (not y)
+///   iabs x         <=> select (slt x, 0), (sub 0, x), x
+///   imin x, y      <=> select (slt|sle x, y), x, y
+///   imax x, y      <=> select (slt|sle x, y), y, x
+///   umin x, y      <=> select (ult|ule x, y), x, y
+///   umax x, y      <=> select (ult|ule x, y), y, x
+pub fn get_clift_equivalent(insts: &[CtonInst], inst: &CtonInst) -> Option<CtonInst> {
+    match inst.opcode {
+        CtonOpcode::IsubImm => {
+            // parser canonicalizes `sub 0, x` to `irsub_imm x, 0`
+            if get_const_operand(inst, 1) == Some(0) {
+                let x = get_index_operand(inst, 0)?;
+                Some(build_equivalent_inst(inst, CtonInstKind::Unary, CtonOpcode::Ineg, vec![x]))
+            } else {
+                None
+            }
+        }
+        CtonOpcode::BxorImm => {
+            match get_const_operand(inst, 1) {
+                Some(c) if is_all_ones_const(c, inst.width) => {
+                    let x = get_index_operand(inst, 0)?;
+                    Some(build_equivalent_inst(inst, CtonInstKind::Unary, CtonOpcode::Bnot, vec![x]))
+                }
+                _ => None,
+            }
+        }
+        CtonOpcode::Band | CtonOpcode::Bor | CtonOpcode::Bxor => {
+            let opcode = match inst.opcode {
+                CtonOpcode::Band => CtonOpcode::BandNot,
+                CtonOpcode::Bor => CtonOpcode::BorNot,
+                _ => CtonOpcode::BxorNot,
+            };
+            let a = get_index_operand(inst, 0)?;
+            let b = get_index_operand(inst, 1)?;
+            // and/or/xor are commutative, so the inverted operand
+            // can be on either side
+            get_inverted_value(insts, b)
+                .map(|y| (a, y))
+                .or_else(|| get_inverted_value(insts, a).map(|y| (b, y)))
+                .map(|(x, y)| build_equivalent_inst(inst, CtonInstKind::Binary, opcode, vec![x, y]))
+        }
+        CtonOpcode::Select => {
+            let c = get_index_operand(inst, 0)?;
+            let t = get_index_operand(inst, 1)?;
+            let f = get_index_operand(inst, 2)?;
+            let cmp = &insts[c];
+            match cmp.opcode {
+                CtonOpcode::IcmpImm => {
+                    // iabs: select (slt x, 0), (sub 0, x), x
+                    match cmp.cond {
+                        Some(CtonCmpCond::Slt) => {}
+                        _ => return None,
+                    }
+                    let x = get_index_operand(cmp, 0)?;
+                    if get_const_operand(cmp, 1) == Some(0)
+                        && f == x
+                        && get_negated_value(insts, t) == Some(x)
+                    {
+                        Some(build_equivalent_inst(inst, CtonInstKind::Unary, CtonOpcode::Iabs, vec![x]))
+                    } else {
+                        None
+                    }
+                }
+                CtonOpcode::Icmp => {
+                    let x = get_index_operand(cmp, 0)?;
+                    let y = get_index_operand(cmp, 1)?;
+                    let (min, max) = match cmp.cond {
+                        Some(CtonCmpCond::Slt) | Some(CtonCmpCond::Sle) => {
+                            (CtonOpcode::Imin, CtonOpcode::Imax)
+                        }
+                        Some(CtonCmpCond::Ult) | Some(CtonCmpCond::Ule) => {
+                            (CtonOpcode::Umin, CtonOpcode::Umax)
+                        }
+                        _ => return None,
+                    };
+                    if t == x && f == y {
+                        Some(build_equivalent_inst(inst, CtonInstKind::Binary, min, vec![x, y]))
+                    } else if t == y && f == x {
+                        Some(build_equivalent_inst(inst, CtonInstKind::Binary, max, vec![x, y]))
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn get_index_operand(inst: &CtonInst, n: usize) -> Option<usize> {
+    inst.cops.as_ref().and_then(|ops| ops.get(n)).and_then(|op| op.idx_val)
+}
+
+pub fn get_const_operand(inst: &CtonInst, n: usize) -> Option<i128> {
+    inst.cops.as_ref().and_then(|ops| ops.get(n)).and_then(|op| op.const_val)
+}
+
+fn is_all_ones_const(c: i128, width: u32) -> bool {
+    c == -1 || (width < 128 && c == (1i128 << width) - 1)
+}
+
+/// Returns x if the inst at `idx` is `sub 0, x` or `ineg x`
+fn get_negated_value(insts: &[CtonInst], idx: usize) -> Option<usize> {
+    let inst = &insts[idx];
+    match inst.opcode {
+        CtonOpcode::Ineg => get_index_operand(inst, 0),
+        CtonOpcode::IsubImm if get_const_operand(inst, 1) == Some(0) => get_index_operand(inst, 0),
+        _ => None,
+    }
+}
+
+/// Returns x if the inst at `idx` is `not x`, `xor x, -1` or `bnot x`
+fn get_inverted_value(insts: &[CtonInst], idx: usize) -> Option<usize> {
+    let inst = &insts[idx];
+    match inst.opcode {
+        CtonOpcode::Bnot => get_index_operand(inst, 0),
+        CtonOpcode::BxorImm => match get_const_operand(inst, 1) {
+            Some(c) if is_all_ones_const(c, inst.width) => get_index_operand(inst, 0),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn build_equivalent_inst(
+    inst: &CtonInst,
+    kind: CtonInstKind,
+    opcode: CtonOpcode,
+    ops: Vec<usize>,
+) -> CtonInst {
+    let cops = ops
+        .into_iter()
+        .map(|idx| CtonOperand {
+            idx_val: Some(idx),
+            const_val: None,
+        })
+        .collect();
+    CtonInst {
+        valuedef: CtonValueDef::Result,
+        kind,
+        opcode,
+        cond: None,
+        width: inst.width,
+        var_num: None,
+        cops: Some(cops),
+        lhs_index: inst.lhs_index,
+    }
+}
+
+/// Upper bound on LHS variants built for one rule, in case a long LHS
+/// has many sites with a Cranelift-only equivalent.
+const MAX_LHS_VARIANTS: usize = 16;
+
+/// Returns the given instructions, followed by every variant of them in
+/// which LHS Souper expansions are replaced by their Cranelift-only
+/// equivalents (see `get_clift_equivalent`). All variants share the RHS.
+pub fn expand_lhs_equivalents(clift_insts: Vec<CtonInst>) -> Vec<Vec<CtonInst>> {
+    let mut variants = vec![clift_insts.clone()];
+    for i in 0..clift_insts.len() {
+        if let CtonOpcode::Infer = clift_insts[i].opcode {
+            break;
+        }
+        let mut new_variants = Vec::new();
+        for variant in &variants {
+            if let Some(equiv) = get_clift_equivalent(variant, &variant[i]) {
+                let mut new_variant = variant.clone();
+                new_variant[i] = equiv;
+                if rhs_operands_are_matched(&new_variant) {
+                    new_variants.push(new_variant);
+                }
+            }
+        }
+        variants.extend(new_variants);
+        if variants.len() >= MAX_LHS_VARIANTS {
+            variants.truncate(MAX_LHS_VARIANTS);
+            break;
+        }
+    }
+    variants
+}
+
+/// Folding an expansion into one instruction makes its inner values
+/// unreachable from the infer root. Such a variant is only usable if the
+/// RHS does not refer to any of those inner values.
+fn rhs_operands_are_matched(insts: &[CtonInst]) -> bool {
+    let infer_pos = match insts.iter().position(|i| matches!(i.opcode, CtonOpcode::Infer)) {
+        Some(pos) => pos,
+        None => return true,
+    };
+    let reachable = match get_index_operand(&insts[infer_pos], 0) {
+        Some(root) => get_reachable_insts(insts, root),
+        None => vec![false; insts.len()],
+    };
+    insts[infer_pos + 1..].iter().all(|inst| {
+        inst.cops
+            .iter()
+            .flatten()
+            .filter_map(|op| op.idx_val)
+            .all(|idx| idx > infer_pos || reachable[idx])
+    })
+}
+
+/// Marks the insts the value at `root` is computed from, `root` included
+pub fn get_reachable_insts(insts: &[CtonInst], root: usize) -> Vec<bool> {
+    let mut reachable = vec![false; insts.len()];
+    let mut worklist = vec![root];
+    while let Some(idx) = worklist.pop() {
+        if reachable[idx] {
+            continue;
+        }
+        reachable[idx] = true;
+        if let Some(ops) = &insts[idx].cops {
+            worklist.extend(ops.iter().filter_map(|op| op.idx_val));
+        }
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn variants(rule: &str) -> Vec<Vec<CtonInst>> {
+        expand_lhs_equivalents(transform_souper_to_clift_insts(parser::parse(rule)))
+    }
+
+    fn has_opcode(insts: &[CtonInst], f: fn(&CtonOpcode) -> bool) -> bool {
+        insts.iter().any(|i| f(&i.opcode))
+    }
+
+    /// Opcode names of the inst at `idx` in each variant of `rule`
+    fn opcodes_at(rule: &str, idx: usize) -> Vec<String> {
+        variants(rule).iter().map(|v| get_clift_opcode_name(v[idx].opcode.clone())).collect()
+    }
+
+    const IABS_RHS: &str = "infer %3\n~>\n%4:i32 = sub 0:i32, %0\nresult %4\n";
+
+    #[test]
+    fn iabs_from_slt_x_zero() {
+        let rule = format!("%0:i32 = var\n%1:i1 = slt %0, 0:i32\n\
+                            %2:i32 = sub 0:i32, %0\n%3:i32 = select %1, %2, %0\n{}",
+                           IABS_RHS);
+        let vs = variants(&rule);
+        assert!(vs.iter().any(|v| has_opcode(v, |o| matches!(o, CtonOpcode::Iabs))));
+    }
+
+    #[test]
+    fn no_iabs_from_slt_zero_x() {
+        // 0 < x selects -x for positive x: the negation of iabs
+        let rule = format!("%0:i32 = var\n%1:i1 = slt 0:i32, %0\n\
+                            %2:i32 = sub 0:i32, %0\n%3:i32 = select %1, %2, %0\n{}",
+                           IABS_RHS);
+        let vs = variants(&rule);
+        assert!(!vs.iter().any(|v| has_opcode(v, |o| matches!(o, CtonOpcode::Iabs))));
+        // the compare keeps its operand order, with the constant as an inst
+        let cmp = vs[0].iter().find(|i| matches!(i.opcode, CtonOpcode::Icmp)).unwrap();
+        assert!(matches!(cmp.cond, Some(CtonCmpCond::Slt)));
+        let lhs = get_index_operand(cmp, 0).unwrap();
+        assert!(matches!(vs[0][lhs].opcode, CtonOpcode::Iconst));
+        assert_eq!(vs[0][lhs].width, 32);
+        assert_eq!(get_index_operand(cmp, 1), Some(0));
+    }
+
+    #[test]
+    fn ineg_from_sub_zero() {
+        let rule = "%0:i32 = var\n%1:i32 = sub 0:i32, %0\ninfer %1\n~>\nresult %0\n";
+        assert_eq!(opcodes_at(rule, 1), vec!["irsub_imm", "ineg"]);
+        // any other constant has no ineg form
+        let rule = "%0:i32 = var\n%1:i32 = sub 1:i32, %0\ninfer %1\n~>\nresult %0\n";
+        assert_eq!(opcodes_at(rule, 1), vec!["irsub_imm"]);
+    }
+
+    #[test]
+    fn bnot_from_xor_all_ones() {
+        let rule = "%0:i8 = var\n%1:i8 = xor %0, -1:i8\ninfer %1\n~>\nresult %0\n";
+        assert_eq!(opcodes_at(rule, 1), vec!["bxor_imm", "bnot"]);
+        let rule = "%0:i8 = var\n%1:i8 = xor %0, 255:i8\ninfer %1\n~>\nresult %0\n";
+        assert_eq!(opcodes_at(rule, 1), vec!["bxor_imm", "bnot"]);
+        let rule = "%0:i8 = var\n%1:i8 = xor %0, 127:i8\ninfer %1\n~>\nresult %0\n";
+        assert_eq!(opcodes_at(rule, 1), vec!["bxor_imm"]);
+    }
+
+    #[test]
+    fn op_not_from_inverted_operand() {
+        for (op, op_not) in &[("and", "band_not"), ("or", "bor_not"), ("xor", "bxor_not")] {
+            // the inverted operand on either side
+            for operands in &["%0, %2", "%2, %0"] {
+                let rule = format!("%0:i32 = var\n%1:i32 = var\n%2:i32 = xor %1, -1:i32\n\
+                                    %3:i32 = {} {}\ninfer %3\n~>\nresult %0\n", op, operands);
+                let vs = variants(&rule);
+                let folded: Vec<&Vec<CtonInst>> = vs
+                    .iter()
+                    .filter(|v| get_clift_opcode_name(v[3].opcode.clone()) == *op_not)
+                    .collect();
+                // once with %2 as bxor_imm and once as bnot, which the folded inst no longer reads
+                assert_eq!(folded.len(), 2, "{} {}", op, operands);
+                for v in folded {
+                    // x op_not y, with y the value that was inverted
+                    assert_eq!(get_index_operand(&v[3], 0), Some(0));
+                    assert_eq!(get_index_operand(&v[3], 1), Some(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn min_max_from_select() {
+        let cases = [
+            ("slt", "%0, %1", "imin"), ("sle", "%0, %1", "imin"), ("slt", "%1, %0", "imax"),
+            ("ult", "%0, %1", "umin"), ("ule", "%0, %1", "umin"), ("ult", "%1, %0", "umax"),
+        ];
+        for (cond, arms, expected) in &cases {
+            let rule = format!("%0:i32 = var\n%1:i32 = var\n%2:i1 = {} %0, %1\n\
+                                %3:i32 = select %2, {}\ninfer %3\n~>\nresult %0\n", cond, arms);
+            assert_eq!(opcodes_at(&rule, 3), vec!["select".to_string(), expected.to_string()]);
+        }
+        // eq has no min/max form
+        let rule = "%0:i32 = var\n%1:i32 = var\n%2:i1 = eq %0, %1\n\
+                    %3:i32 = select %2, %0, %1\ninfer %3\n~>\nresult %0\n";
+        assert_eq!(opcodes_at(rule, 3), vec!["select"]);
+    }
+
+    #[test]
+    fn variants_are_capped() {
+        // five ineg sites give 2^5 variants, of which the first 16 are kept
+        let mut rule = String::from("%0:i32 = var\n");
+        for n in 1..6 {
+            rule += &format!("%{}:i32 = sub 0:i32, %{}\n", n, n - 1);
+        }
+        rule += "infer %5\n~>\nresult %0\n";
+        let vs = variants(&rule);
+        assert_eq!(vs.len(), MAX_LHS_VARIANTS);
+        assert!(!has_opcode(&vs[0], |o| matches!(o, CtonOpcode::Ineg)));
+    }
+}
@@ -0,0 +1,676 @@
+// Decision tree
+//
+// The merged prefix tree makes the tests of a rule in the order its LHS
+// was written down: the inst and its opcode, then each operand in turn,
+// going down into the inst that defines an operand before looking at
+// the next one, and it only merges rules in priority order. With
+// `--tree=decision` the matchers walk a tree that is built from the
+// tests of the rules instead, and that can check a constant operand
+// before the other operand is gone down into.
+//
+// The tests of a rule are its root inst (format, opcode and condition)
+// and, for every operand, the operand together with what defines it: a
+// constant, a var, or an inst. An operand can be tested once the inst
+// it belongs to has been. Below every node the next test is one the
+// first rule left has to make (see `choose_position`). The rules that
+// make the test go below it, grouped by what they expect, and the others
+// come after it as the next siblings. A rule only goes ahead of one it
+// came after when no inst can match both, so the rule that fires is
+// still the first in priority order that matches. A rule with a var
+// there matches whatever the groups expect, so it also goes below a
+// group that a rule after it is in.
+//
+// Which test is next is a guess that doesn't always pay, so below every
+// root opcode the tests are either in the order the LHSs were written
+// down in or the most discriminating first, whichever made fewer checks
+// on a profile of random DAGs.
+//
+// It only pays off when the rules are not in specificity order, the
+// default. Specificity already puts the rules that test the most first,
+// so the prefix tree makes about as few checks (8.54 per inst against
+// 8.46 on the markdown rules, 11.79 against 11.77 on clang); in cost
+// or source order it makes seven (markdown) to fifty (clang) times as
+// many.
+//
+// The `arg_N` names and var numbers of a rule follow the order its LHS
+// was written down in, so the same inst can have different names in two
+// rules once their tests are reordered. The tree names them in the order
+// its tests bind them instead, and the RHS of a rule is renamed to match.
+
+use cliftinstbuilder;
+use interpreter::{self, Interpreter};
+use lhspatternmatcher::{self, Node, NodeType};
+use mergedtree::MergedArena;
+use processrhs::CliftInstWithArgs;
+use random::Random;
+use selfcheck;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+/// Names accepted by `--tree`
+pub const TREE_NAMES: [&str; 2] = ["prefix", "decision"];
+
+/// What a test expects to find where it looks
+#[derive(Clone, PartialEq)]
+enum Outcome {
+    /// An inst of a format, opcode and condition
+    Inst(String),
+    /// An immediate
+    Const(i128),
+    /// Any value, for a var
+    Var,
+}
+
+impl Outcome {
+    /// Whether no inst can have both outcomes
+    fn excludes(&self, other: &Outcome) -> bool {
+        match (self, other) {
+            (Outcome::Inst(a), Outcome::Inst(b)) => a != b,
+            // immediates are compared at the width of their inst, which
+            // is at least 8 bits, so only the low bits are sure to differ
+            (Outcome::Const(a), Outcome::Const(b)) => (a - b) % 256 != 0,
+            _ => false,
+        }
+    }
+
+    fn is_var(&self) -> bool {
+        *self == Outcome::Var
+    }
+}
+
+/// How the tests below a root inst are ordered
+#[derive(Clone, Copy, PartialEq)]
+enum TestOrder {
+    /// the order the LHS was written down in
+    Written,
+    /// the test the most rules need a constant or an inst for first,
+    /// then the one with the fewest different tests there, which are
+    /// tried one after the other, then the one with the fewest checks
+    Discriminating,
+}
+
+/// A test of a rule, and the nodes that make it
+struct Test {
+    nodes: Vec<Node>,
+    outcome: Outcome,
+    // `arg_N` that has to be bound before the test, and the one it binds
+    needs: Option<String>,
+    binds: Option<String>,
+    // nodes that check something, see `interpreter::is_check`
+    checks: usize,
+}
+
+/// A rule below a node of the tree: the tests it still has to make, and
+/// the names the tests above gave its `arg_N` and vars
+#[derive(Clone)]
+struct RuleLeft {
+    rule: usize,
+    tests: Vec<usize>,
+    // the last test it made
+    made: usize,
+    args: HashMap<String, String>,
+    vars: HashMap<u32, u32>,
+}
+
+/// Rules left below a node of the tree, in priority order
+struct Pending {
+    // id of the node their tests go below
+    parent: usize,
+    rules: Vec<RuleLeft>,
+    // number of `arg_N` and vars the tests above have bound
+    args: usize,
+    vars: usize,
+    order: TestOrder,
+}
+
+/// A test a rule can make below a node, as the tree names it
+struct Placed<'a> {
+    // where it is in the tests the rule has left
+    at: usize,
+    test: &'a Test,
+    // `root`, or the arg name and the operand the test looks at
+    position: String,
+    nodes: Vec<Node>,
+    key: String,
+}
+
+/// Where the action of a rule is in the decision tree, and the names
+/// its `arg_N` have there. A rule that matches anything where other
+/// rules test something is below each of their tests, so it can have
+/// more than one.
+pub struct Leaf {
+    // id of the last node of the LHS
+    pub last: usize,
+    pub id: usize,
+    pub args: HashMap<String, String>,
+}
+
+/// What a node tests and binds. Nodes with the same key can be shared.
+fn get_node_key(node: &Node) -> String {
+    let mut key = format!(
+        "{} {} {}",
+        lhspatternmatcher::get_node_type(node.node_type.clone()),
+        node.node_value,
+        node.arg_name
+    );
+    if node.node_value == "Var" {
+        key += &format!(" {} {:?}", node.width, node.var_id);
+    }
+    key
+}
+
+/// Splits the nodes of an LHS into its tests. Every operand starts a
+/// test, and the root inst is the nodes before the first one.
+fn get_tests(nodes: &[Node]) -> Vec<Test> {
+    let mut starts: Vec<usize> = (0..nodes.len())
+        .filter(|&at| at == 0 || matches!(nodes[at].node_type, NodeType::MatchArgs))
+        .collect();
+    starts.push(nodes.len());
+    starts.windows(2).map(|bounds| get_test(&nodes[bounds[0]..bounds[1]])).collect()
+}
+
+/// The test made by `nodes`, and what it expects
+fn get_test(nodes: &[Node]) -> Test {
+    let needs = match nodes[0].node_type {
+        NodeType::MatchArgs => Some(nodes[0].arg_name.clone()),
+        _ => None,
+    };
+    let mut outcome = Outcome::Var;
+    let mut inst = Vec::new();
+    let mut binds = None;
+    for node in nodes {
+        match node.node_type {
+            NodeType::MatchConst => {
+                if let Ok(c) = node.node_value.parse() {
+                    outcome = Outcome::Const(c);
+                }
+            }
+            NodeType::InstType if node.node_value != "Var" => {
+                inst.push(node.node_value.clone());
+                if !node.arg_name.is_empty() {
+                    binds = Some(node.arg_name.clone());
+                }
+            }
+            NodeType::Opcode | NodeType::Cond if node.node_value != "Var" => inst.push(node.node_value.clone()),
+            _ => {}
+        }
+    }
+    if !inst.is_empty() {
+        outcome = Outcome::Inst(inst.join(" "));
+    }
+    Test {
+        nodes: nodes.to_vec(),
+        outcome,
+        needs,
+        binds,
+        checks: nodes.iter().filter(|node| interpreter::is_check(node)).count(),
+    }
+}
+
+/// The tests a rule has left that can be made below a node, the ones
+/// whose inst the tests above have bound
+fn get_available<'a>(tests: &'a [Vec<Test>], rule: &RuleLeft, args: usize, vars: usize) -> Vec<Placed<'a>> {
+    rule.tests
+        .iter()
+        .enumerate()
+        .filter_map(|(at, &n)| place_test(tests, rule, at, n, args, vars))
+        .collect()
+}
+
+/// Test `n` of a rule as the tree names it below a node, if the tests
+/// above have bound its inst. `args` and `vars` are how many of them the
+/// tests above bound, which numbers the ones the test binds.
+fn place_test<'a>(tests: &'a [Vec<Test>], rule: &RuleLeft, at: usize, n: usize, args: usize, vars: usize) -> Option<Placed<'a>> {
+    let test = &tests[rule.rule][n];
+    let position = match test.needs {
+        Some(ref name) => format!("{} {}", rule.args.get(name)?, test.nodes[0].node_value),
+        None => String::from("root"),
+    };
+    let mut nodes = test.nodes.clone();
+    for node in &mut nodes {
+        if !node.arg_name.is_empty() {
+            node.arg_name = match rule.args.get(&node.arg_name) {
+                Some(arg) => arg.clone(),
+                None => format!("arg_{}", args),
+            };
+        }
+        if let Some(var) = node.var_id {
+            node.var_id = Some(rule.vars.get(&var).cloned().unwrap_or(vars as u32));
+        }
+    }
+    let keys: Vec<String> = nodes.iter().map(get_node_key).collect();
+    Some(Placed {
+        at,
+        test,
+        position,
+        nodes,
+        key: keys.join(", "),
+    })
+}
+
+/// Adds copies of `nodes` below node `parent`, one below the other.
+/// Returns their ids.
+fn add_nodes(merged_arena: &mut MergedArena, parent: usize, nodes: &[Node], next_id: &mut usize) -> Vec<usize> {
+    let mut path = Vec::new();
+    for node in nodes {
+        let prev = match merged_arena.get_slot_of_id(path.last().cloned().unwrap_or(parent)) {
+            Some(slot) => slot,
+            None => panic!("Error: the node is expected to be found in merged arena"),
+        };
+        let mut new_node = node.clone();
+        new_node.id = *next_id;
+        new_node.next = None;
+        merged_arena.add_node_to_arena(new_node);
+        merged_arena.add_next_of_node(prev, *next_id);
+        path.push(*next_id);
+        *next_id += 1;
+    }
+    path
+}
+
+fn get_placed_at<'a, 'b>(placed: &'b [Placed<'a>], position: &str) -> Option<&'b Placed<'a>> {
+    placed.iter().find(|test| test.position == position)
+}
+
+/// Whether no inst can match both rules, going by the tests they can
+/// make below a node
+fn are_exclusive(a: &[Placed], b: &[Placed]) -> bool {
+    a.iter().any(|test_a| match get_placed_at(b, &test_a.position) {
+        Some(test_b) => test_a.test.outcome.excludes(&test_b.test.outcome),
+        None => false,
+    })
+}
+
+/// Where the next test below a node looks: a test the first rule has to
+/// make, chosen by `order`
+fn choose_position(placed: &[Vec<Placed>], order: TestOrder) -> String {
+    let chosen = match order {
+        TestOrder::Written => placed[0].iter().min_by_key(|test| test.at),
+        TestOrder::Discriminating => {
+            let mut needed: HashMap<&str, usize> = HashMap::new();
+            let mut branches: HashMap<&str, HashSet<&str>> = HashMap::new();
+            for test in placed.iter().flatten().filter(|test| !test.test.outcome.is_var()) {
+                *needed.entry(&test.position).or_insert(0) += 1;
+                branches.entry(&test.position).or_default().insert(&test.key);
+            }
+            placed[0].iter().min_by_key(|test| {
+                let position = test.position.as_str();
+                (
+                    test.test.outcome.is_var(),
+                    Reverse(needed.get(position).cloned().unwrap_or(0)),
+                    branches.get(position).map_or(0, |keys| keys.len()),
+                    test.test.checks,
+                    test.at,
+                )
+            })
+        }
+    };
+    match chosen {
+        Some(test) => test.position.clone(),
+        None => panic!("ERROR: no test of the rule can be made, its operands are never bound"),
+    }
+}
+
+/// Builds the decision tree of `rules`, the rule numbers and LHSs in
+/// priority order, giving its nodes ids from `first_id` on. Below each
+/// root opcode the tests are in the order that made fewer checks on
+/// random DAGs from `rng`, with `rhs` the actions by last LHS node.
+/// Returns the tree, and where the actions of the rules that can fire
+/// are.
+pub fn build_decision_tree(
+    rules: &[(u32, Vec<Node>)],
+    first_id: usize,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    checked: &HashSet<usize>,
+    rng: &mut Random,
+) -> (MergedArena, Vec<Leaf>) {
+    let tests: Vec<Vec<Test>> = rules.iter().map(|(_, nodes)| get_tests(nodes)).collect();
+    let orders = [TestOrder::Written, TestOrder::Discriminating];
+    let trees: Vec<(MergedArena, HashMap<usize, Vec<CliftInstWithArgs>>)> = orders
+        .iter()
+        .map(|&order| {
+            let (tree, leaves) = build_tree(rules, &tests, first_id, checked, &|_| order);
+            let actions = get_leaf_actions(&leaves, rhs);
+            (tree, actions)
+        })
+        .collect();
+    let profiled: Vec<(&MergedArena, &HashMap<usize, Vec<CliftInstWithArgs>>)> =
+        trees.iter().map(|(tree, actions)| (tree, actions)).collect();
+    let (_, checks) = count_checks(&profiled, rules, rng);
+    let order_of = |opcode: &str| {
+        let checks_with = |n: usize| checks[n].get(opcode).cloned().unwrap_or(0);
+        match (0..orders.len()).min_by_key(|&n| checks_with(n)) {
+            Some(n) => orders[n],
+            None => TestOrder::Written,
+        }
+    };
+    build_tree(rules, &tests, first_id, checked, &order_of)
+}
+
+/// The actions `rhs` has by last LHS node, by the nodes of the tree
+/// they are at, with the args renamed
+fn get_leaf_actions(leaves: &[Leaf], rhs: &HashMap<usize, Vec<CliftInstWithArgs>>) -> HashMap<usize, Vec<CliftInstWithArgs>> {
+    leaves
+        .iter()
+        .filter_map(|leaf| Some((leaf.id, rename_rhs(rhs.get(&leaf.last)?, &leaf.args))))
+        .collect()
+}
+
+/// Builds the decision tree, with `order_of` how the tests are ordered
+/// below each root opcode
+fn build_tree(
+    rules: &[(u32, Vec<Node>)],
+    tests: &[Vec<Test>],
+    first_id: usize,
+    checked: &HashSet<usize>,
+    order_of: &dyn Fn(&str) -> TestOrder,
+) -> (MergedArena, Vec<Leaf>) {
+    let mut merged_arena = MergedArena::new();
+    let root_node = merged_arena.build_root_node();
+    merged_arena.add_node_to_arena(root_node);
+    let mut next_id = first_id;
+    let mut leaves = Vec::new();
+
+    let mut pending = vec![Pending {
+        parent: 0,
+        rules: (0..rules.len())
+            .map(|rule| RuleLeft {
+                rule,
+                tests: (0..tests[rule].len()).collect(),
+                made: 0,
+                args: HashMap::new(),
+                vars: HashMap::new(),
+            })
+            .collect(),
+        args: 0,
+        vars: 0,
+        order: TestOrder::Written,
+    }];
+    while let Some(Pending { parent, rules: mut left, args, vars, order }) = pending.pop() {
+        let parent_slot = match merged_arena.get_slot_of_id(parent) {
+            Some(slot) => slot,
+            None => panic!("Error: the node is expected to be found in merged arena"),
+        };
+        while !left.is_empty() {
+            if left[0].tests.is_empty() {
+                // everything the rule tests holds here, and unless its
+                // action checks more than its LHS (see
+                // `matcher::LeafCheck`), the rules after it never fire.
+                // Below a node other rules go on from, it makes its last
+                // test again, which holds, for a node of its own.
+                let last = rules[left[0].rule].1.last();
+                let is_checked = last.is_some_and(|last| checked.contains(&last.id));
+                let mut leaf = parent;
+                if is_checked || merged_arena.node_has_any_connection(parent_slot) {
+                    let again = match place_test(tests, &left[0], 0, left[0].made, args, vars) {
+                        Some(again) => again,
+                        None => panic!("Error: a test the rule made is expected to be made again"),
+                    };
+                    let path = add_nodes(&mut merged_arena, parent, &again.nodes, &mut next_id);
+                    leaf = *path.last().unwrap_or(&parent);
+                }
+                if let Some(last) = last {
+                    leaves.push(Leaf {
+                        last: last.id,
+                        id: leaf,
+                        args: left[0].args.clone(),
+                    });
+                }
+                if is_checked {
+                    left.remove(0);
+                    continue;
+                }
+                break;
+            }
+
+            // the rules that make the test go below it, grouped by the
+            // nodes it takes, and a rule only goes ahead of the rules it
+            // came after that can't match the same inst
+            let placed: Vec<Vec<Placed>> = left.iter().map(|rule| get_available(tests, rule, args, vars)).collect();
+            let position = choose_position(&placed, order);
+            let first_var = get_placed_at(&placed[0], &position).is_none_or(|test| test.test.outcome.is_var());
+            let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+            let mut after: Vec<usize> = Vec::new();
+            // rules that match anything there. They come after the groups,
+            // and also go below a group that a rule after them is in.
+            let mut anywhere: Vec<usize> = Vec::new();
+            for n in 0..left.len() {
+                let test = get_placed_at(&placed[n], &position);
+                if !first_var && test.is_none_or(|test| test.test.outcome.is_var()) {
+                    anywhere.push(n);
+                    after.push(n);
+                    continue;
+                }
+                let group = test.and_then(|test| {
+                    let group = groups.iter().position(|(key, _)| *key == test.key).unwrap_or(groups.len());
+                    // when the first rule only has vars left to bind, it
+                    // is all but sure to fire, and only the rules that
+                    // bind the same var go with it
+                    if first_var && group > 0 {
+                        return None;
+                    }
+                    let goes_ahead = groups
+                        .iter()
+                        .skip(group + 1)
+                        .flat_map(|(_, rules)| rules.iter())
+                        .chain(after.iter())
+                        .filter(|other| !anywhere.contains(other))
+                        .all(|&other| are_exclusive(&placed[n], &placed[other]));
+                    if goes_ahead && group == groups.len() {
+                        groups.push((&test.key, Vec::new()));
+                    }
+                    if goes_ahead {
+                        Some(group)
+                    } else {
+                        None
+                    }
+                });
+                match group {
+                    Some(group) => {
+                        let rules = &mut groups[group].1;
+                        let since = anywhere.iter().position(|other| rules.last() < Some(other)).unwrap_or(anywhere.len());
+                        rules.extend_from_slice(&anywhere[since..]);
+                        rules.push(n);
+                    }
+                    None => after.push(n),
+                }
+            }
+
+            // the nodes made for the test of each group, and the test
+            let mut made: Vec<(Vec<usize>, &Placed)> = Vec::new();
+            for (key, group_rules) in &groups {
+                let makes_test = |n: &usize| get_placed_at(&placed[*n], &position).filter(|test| test.key == *key);
+                let test = match group_rules.iter().find_map(makes_test) {
+                    Some(test) => test,
+                    None => panic!("Error: a rule of a group is expected to make its test"),
+                };
+                // share the first nodes with an earlier group. The group
+                // then goes after the last one below the shared nodes,
+                // ahead of the ones after the first one below them that
+                // aren't, and it has to rule those out.
+                let mut shared: Option<(usize, usize)> = None;
+                for (earlier, (earlier_path, earlier_test)) in made.iter().enumerate() {
+                    let common = test
+                        .nodes
+                        .iter()
+                        .zip(earlier_test.nodes.iter())
+                        .take_while(|(a, b)| get_node_key(a) == get_node_key(b))
+                        .count()
+                        .min(test.nodes.len() - 1)
+                        .min(earlier_test.nodes.len() - 1);
+                    if common <= shared.map_or(0, |(_, len)| len) {
+                        continue;
+                    }
+                    let node = earlier_path[common - 1];
+                    let first = made.iter().position(|(path, _)| path.contains(&node)).unwrap_or(earlier);
+                    let goes_ahead = made[first + 1..]
+                        .iter()
+                        .filter(|(path, _)| !path.contains(&node))
+                        .all(|(_, other)| test.test.outcome.excludes(&other.test.outcome));
+                    if goes_ahead {
+                        shared = Some((earlier, common));
+                    }
+                }
+                let mut path = match shared {
+                    Some((earlier, len)) => made[earlier].0[..len].to_vec(),
+                    None => Vec::new(),
+                };
+                let below = path.last().cloned().unwrap_or(parent);
+                let added = add_nodes(&mut merged_arena, below, &test.nodes[path.len()..], &mut next_id);
+                path.extend(added);
+
+                // the rules go on below the test, with what it bound
+                let fresh_var = test.nodes.iter().filter_map(|node| node.var_id).any(|var| var == vars as u32);
+                let below: Vec<RuleLeft> = group_rules
+                    .iter()
+                    .map(|&n| {
+                        let mut rule = left[n].clone();
+                        // the rules that match anything there still
+                        // have their own test to make
+                        if let Some(done) = makes_test(&n) {
+                            rule.made = rule.tests.remove(done.at);
+                            if let Some(name) = &done.test.binds {
+                                rule.args.insert(name.clone(), format!("arg_{}", args));
+                            }
+                            for var in done.test.nodes.iter().filter_map(|node| node.var_id) {
+                                rule.vars.entry(var).or_insert(vars as u32);
+                            }
+                        }
+                        rule
+                    })
+                    .collect();
+                pending.push(Pending {
+                    parent: *path.last().unwrap_or(&parent),
+                    rules: below,
+                    args: if test.test.binds.is_some() { args + 1 } else { args },
+                    vars: if fresh_var { vars + 1 } else { vars },
+                    order: match test.nodes.iter().find(|node| matches!(node.node_type, NodeType::Opcode)) {
+                        Some(opcode) if position == "root" => order_of(&opcode.node_value),
+                        _ => order,
+                    },
+                });
+                made.push((path, test));
+            }
+            left = after.iter().map(|&n| left[n].clone()).collect();
+        }
+    }
+    (merged_arena, leaves)
+}
+
+/// Renames the `arg_N` (and `imm_N`) an RHS operand or a guard refers to
+/// by `args`
+pub fn rename_args(expr: &str, args: &HashMap<String, String>) -> String {
+    let mut renamed = String::new();
+    let mut rest = expr;
+    while !rest.is_empty() {
+        let at_word = !renamed.ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        let digits = rest.get(4..).map_or(0, |tail| tail.bytes().take_while(|b| b.is_ascii_digit()).count());
+        if at_word && digits > 0 && (rest.starts_with("arg_") || rest.starts_with("imm_")) {
+            let arg = format!("arg_{}", &rest[4..4 + digits]);
+            match args.get(&arg) {
+                Some(name) if rest.starts_with("imm_") => renamed += &name.replacen("arg", "imm", 1),
+                Some(name) => renamed += name,
+                None => renamed += &rest[..4 + digits],
+            }
+            rest = &rest[4 + digits..];
+        } else {
+            let c = rest.chars().next().unwrap_or(' ');
+            renamed.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    renamed
+}
+
+/// The RHS of a rule with its operands renamed by `args`
+pub fn rename_rhs(rhs: &[CliftInstWithArgs], args: &HashMap<String, String>) -> Vec<CliftInstWithArgs> {
+    rhs.iter()
+        .map(|inst| {
+            let mut inst = inst.clone();
+            inst.cops = inst.cops.iter().map(|op| rename_args(op, args)).collect();
+            inst
+        })
+        .collect()
+}
+
+/// Mean number of checks the matchers make per inst of the random DAGs
+/// `selfcheck` runs, walking each of `trees`. `rules` are the rule
+/// numbers and LHSs that went into the trees.
+pub fn get_checks_per_inst(
+    trees: &[&MergedArena],
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    rules: &[(u32, Vec<Node>)],
+    rng: &mut Random,
+) -> Vec<f64> {
+    let trees: Vec<(&MergedArena, &HashMap<usize, Vec<CliftInstWithArgs>>)> = trees.iter().map(|&tree| (tree, rhs)).collect();
+    let (insts, checks) = count_checks(&trees, rules, rng);
+    checks
+        .iter()
+        .map(|by_opcode| by_opcode.values().sum::<usize>() as f64 / insts.max(1) as f64)
+        .collect()
+}
+
+/// Checks the matchers make walking each of `trees` (with its actions)
+/// over the insts of the random DAGs `selfcheck` runs, by the opcode of
+/// the inst. Returns them and the number of insts.
+fn count_checks(
+    trees: &[(&MergedArena, &HashMap<usize, Vec<CliftInstWithArgs>>)],
+    rules: &[(u32, Vec<Node>)],
+    rng: &mut Random,
+) -> (usize, Vec<HashMap<String, usize>>) {
+    // only the tests of the trees are counted, the path conditions and
+    // checks at their leaves are the same
+    let (pcs, leaf_checks) = (HashMap::new(), HashMap::new());
+    let interps: Vec<Interpreter> = trees
+        .iter()
+        .map(|&(tree, rhs)| Interpreter::new(tree, rhs, &pcs, &leaf_checks))
+        .collect();
+    let mut checks = vec![HashMap::new(); trees.len()];
+    let (widths, consts) = selfcheck::get_widths_and_consts(rules);
+    let mut insts = 0;
+    for n in 0..selfcheck::DAGS {
+        let dfg = selfcheck::generate_random_dfg(&format!("dag{}", n), rng, &widths, &consts);
+        for &inst in &dfg.layout {
+            insts += 1;
+            let opcode = cliftinstbuilder::get_clift_opcode_name(dfg.insts[inst].opcode.clone());
+            for (interp, by_opcode) in interps.iter().zip(checks.iter_mut()) {
+                interp.rewrite_inst(&mut dfg.clone(), inst);
+                *by_opcode.entry(opcode.clone()).or_insert(0) += interp.take_checks();
+            }
+        }
+    }
+    (insts, checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(from, to)| (from.to_string(), to.to_string())).collect()
+    }
+
+    #[test]
+    fn renames_whole_arg_numbers() {
+        let args = names(&[("arg_1", "arg_3"), ("arg_10", "arg_2")]);
+        assert_eq!(rename_args("arg_1[0]", &args), "arg_3[0]");
+        assert_eq!(rename_args("arg_10[1]", &args), "arg_2[1]");
+        assert_eq!(rename_args("arg_1[0] == arg_10[0]", &args), "arg_3[0] == arg_2[0]");
+    }
+
+    #[test]
+    fn renames_imms_by_their_arg() {
+        let args = names(&[("arg_1", "arg_4")]);
+        assert_eq!(rename_args("imm_1", &args), "imm_4");
+        assert_eq!(rename_args("imm_1 == 0 && arg_1 != imm_1", &args), "imm_4 == 0 && arg_4 != imm_4");
+    }
+
+    #[test]
+    fn keeps_names_not_in_the_map() {
+        let args = names(&[("arg_1", "arg_4")]);
+        assert_eq!(rename_args("arg_2[0]", &args), "arg_2[0]");
+        assert_eq!(rename_args("imm_12", &args), "imm_12");
+        assert_eq!(rename_args("rep_arg_1 + sarg_1", &args), "rep_arg_1 + sarg_1");
+        assert_eq!(rename_args("arg_", &args), "arg_");
+    }
+}
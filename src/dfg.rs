@@ -0,0 +1,299 @@
+// Minimal data flow graph
+//
+// Just enough of Cranelift's DFG (values, instructions and ValueDef) to
+// run the merged prefix tree on it inside this crate. Values and insts
+// are plain indices; every inst has exactly one result.
+
+use clifreader::ClifFunction;
+use cliftinstbuilder::{self, CtonCmpCond, CtonInstKind, CtonOpcode};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ValueDef {
+    /// Result of the inst with the given index
+    Result(usize),
+    /// Block param, or the result of an inst we don't model
+    Param,
+}
+
+#[derive(Clone)]
+pub struct InstData {
+    pub kind: CtonInstKind,
+    pub opcode: CtonOpcode,
+    pub cond: Option<CtonCmpCond>,
+    pub width: u32,
+    /// Value operands, in order
+    pub args: Vec<usize>,
+    /// The immediate of the `*Imm` formats
+    pub imm: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct ValueData {
+    pub def: ValueDef,
+    pub width: u32,
+    alias: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct Dfg {
+    pub name: String,
+    pub insts: Vec<InstData>,
+    pub values: Vec<ValueData>,
+    pub params: Vec<usize>,
+    /// Insts in program order
+    pub layout: Vec<usize>,
+    results: Vec<usize>,
+}
+
+impl Dfg {
+    pub fn new(name: &str) -> Dfg {
+        Dfg {
+            name: name.to_string(),
+            insts: Vec::new(),
+            values: Vec::new(),
+            params: Vec::new(),
+            layout: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    fn make_value(&mut self, def: ValueDef, width: u32) -> usize {
+        self.values.push(ValueData {
+            def,
+            width,
+            alias: None,
+        });
+        self.values.len() - 1
+    }
+
+    #[allow(dead_code)]
+    pub fn append_param(&mut self, width: u32) -> usize {
+        let value = self.make_value(ValueDef::Param, width);
+        self.params.push(value);
+        value
+    }
+
+    /// Appends `data` to the end of the layout and returns its result
+    #[allow(dead_code)]
+    pub fn append_inst(&mut self, data: InstData) -> usize {
+        let inst = self.make_inst(data);
+        self.layout.push(inst);
+        self.results[inst]
+    }
+
+    /// Inserts `data` right before `before` and returns its result
+    pub fn insert_inst(&mut self, before: usize, data: InstData) -> usize {
+        let inst = self.make_inst(data);
+        let pos = self.layout.iter().position(|&i| i == before).unwrap_or(self.layout.len());
+        self.layout.insert(pos, inst);
+        self.results[inst]
+    }
+
+    fn make_inst(&mut self, data: InstData) -> usize {
+        let inst = self.insts.len();
+        let width = data.width;
+        self.insts.push(data);
+        let value = self.make_value(ValueDef::Result(inst), width);
+        self.results.push(value);
+        inst
+    }
+
+    pub fn inst_result(&self, inst: usize) -> usize {
+        self.results[inst]
+    }
+
+    /// Follows aliases to the value that is defined
+    pub fn resolve_aliases(&self, value: usize) -> usize {
+        let mut value = value;
+        while let Some(original) = self.values[value].alias {
+            value = original;
+        }
+        value
+    }
+
+    pub fn value_def(&self, value: usize) -> ValueDef {
+        self.values[self.resolve_aliases(value)].def
+    }
+
+    pub fn value_width(&self, value: usize) -> u32 {
+        self.values[self.resolve_aliases(value)].width
+    }
+
+    /// Rewrites `inst` in place, keeping its result value
+    pub fn replace(&mut self, inst: usize, data: InstData) {
+        self.insts[inst] = data;
+    }
+
+    /// Turns `value` into an alias of `original`
+    pub fn change_to_alias(&mut self, value: usize, original: usize) {
+        let original = self.resolve_aliases(original);
+        if original != value {
+            self.values[value].alias = Some(original);
+        }
+    }
+
+    /// clif-like text of the function
+    pub fn display(&self) -> String {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|&p| format!("v{}: i{}", p, self.values[p].width))
+            .collect();
+        let mut dfg_str = format!("function %{}({}) {{\n", self.name, params.join(", "));
+        for &inst in &self.layout {
+            let data = &self.insts[inst];
+            let mut ops: Vec<String> = data
+                .args
+                .iter()
+                .map(|&arg| format!("v{}", self.resolve_aliases(arg)))
+                .collect();
+            if let Some(imm) = data.imm {
+                ops.push(imm.to_string());
+            }
+            let cond = match data.cond.clone() {
+                Some(cond) => format!(" {}", cliftinstbuilder::get_clift_cond_name(Some(cond))),
+                None => "".to_string(),
+            };
+            dfg_str += &format!(
+                "    v{} = {}.i{}{} {}\n",
+                self.results[inst],
+                cliftinstbuilder::get_clift_opcode_name(data.opcode.clone()),
+                data.width,
+                cond,
+                ops.join(", ")
+            );
+        }
+        for (value, data) in self.values.iter().enumerate() {
+            if let Some(original) = data.alias {
+                dfg_str += &format!("    v{} -> v{}\n", value, original);
+            }
+        }
+        dfg_str += "}\n";
+        dfg_str
+    }
+}
+
+/// InstructionData format of the given opcode
+pub fn get_inst_kind(opcode: &CtonOpcode) -> CtonInstKind {
+    match opcode {
+        CtonOpcode::Iconst => CtonInstKind::UnaryImm,
+        CtonOpcode::IaddImm | CtonOpcode::ImulImm | CtonOpcode::IsubImm |
+        CtonOpcode::BandImm | CtonOpcode::BorImm | CtonOpcode::BxorImm |
+        CtonOpcode::IshlImm | CtonOpcode::SshrImm | CtonOpcode::UshrImm => CtonInstKind::BinaryImm,
+        CtonOpcode::Icmp => CtonInstKind::IntCompare,
+        CtonOpcode::IcmpImm => CtonInstKind::IntCompareImm,
+        CtonOpcode::Select => CtonInstKind::Ternary,
+        CtonOpcode::Popcnt | CtonOpcode::Clz | CtonOpcode::Ctz |
+        CtonOpcode::Bnot | CtonOpcode::Ineg | CtonOpcode::Iabs => CtonInstKind::Unary,
+        _ => CtonInstKind::Binary,
+    }
+}
+
+/// Builds the DFG of a function read from clif text, keeping its value
+/// numbers. Values without a modelled instruction become params.
+pub fn build_dfg_from_clif(func: &ClifFunction) -> Dfg {
+    let mut dfg = Dfg::new(func.name.trim_start_matches('%'));
+    let max_value = func.values.keys().cloned().max().map_or(0, |v| v + 1);
+    for value in 0..max_value {
+        let width = func.values.get(&value).and_then(|v| v.width).unwrap_or(0);
+        dfg.make_value(ValueDef::Param, width);
+    }
+    for &result in &func.results {
+        let value = match func.values.get(&result) {
+            Some(value) => value,
+            None => continue,
+        };
+        let opcode = match value.opcode.clone() {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+        let args: Vec<usize> = value
+            .args
+            .iter()
+            .filter_map(|op| op.idx_val)
+            .map(|v| func.resolve_alias(v))
+            .collect();
+        // operands defined later (or not at all) are left opaque
+        if args.iter().any(|&v| v >= max_value) {
+            continue;
+        }
+        // `icmp_imm sgt x, c` is read as `c slt x`, which has no
+        // IntCompareImm form
+        if let CtonOpcode::IcmpImm = opcode {
+            if value.args.first().is_none_or(|op| op.idx_val.is_none()) {
+                continue;
+            }
+        }
+        let inst = dfg.insts.len();
+        dfg.insts.push(InstData {
+            kind: get_inst_kind(&opcode),
+            opcode,
+            cond: value.cond.clone(),
+            width: value.width.unwrap_or(0),
+            args,
+            imm: value.args.iter().filter_map(|op| op.const_val).next().map(|c| c as i64),
+        });
+        dfg.results.push(result);
+        dfg.values[result].def = ValueDef::Result(inst);
+        dfg.layout.push(inst);
+    }
+    let mut params: Vec<usize> = func
+        .values
+        .keys()
+        .cloned()
+        .filter(|&v| dfg.values[v].def == ValueDef::Param)
+        .collect();
+    params.sort_unstable();
+    dfg.params = params;
+    for value in 0..max_value {
+        let original = func.resolve_alias(value);
+        if original != value {
+            dfg.values[value].alias = Some(original);
+        }
+    }
+    dfg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iadd(x: usize, y: usize) -> InstData {
+        InstData {
+            kind: CtonInstKind::Binary,
+            opcode: CtonOpcode::Iadd,
+            cond: None,
+            width: 32,
+            args: vec![x, y],
+            imm: None,
+        }
+    }
+
+    #[test]
+    fn insert_inst_goes_before() {
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        let a = dfg.append_inst(iadd(p, p));
+        let b = dfg.insert_inst(0, iadd(p, p));
+        assert_eq!(dfg.layout, vec![1, 0]);
+        assert!(dfg.value_def(a) == ValueDef::Result(0));
+        assert!(dfg.value_def(b) == ValueDef::Result(1));
+        assert!(dfg.value_def(p) == ValueDef::Param);
+    }
+
+    #[test]
+    fn aliases_resolve_to_the_defined_value() {
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(8);
+        let a = dfg.append_inst(iadd(p, p));
+        let b = dfg.append_inst(iadd(a, p));
+        dfg.change_to_alias(a, p);
+        dfg.change_to_alias(b, a);
+        assert_eq!(dfg.resolve_aliases(b), p);
+        assert!(dfg.value_def(b) == ValueDef::Param);
+        assert_eq!(dfg.value_width(b), 8);
+        // a value is never made an alias of itself
+        dfg.change_to_alias(p, b);
+        assert_eq!(dfg.resolve_aliases(p), p);
+    }
+}
@@ -0,0 +1,158 @@
+// Cranelift filetest emitter
+//
+// Turns one Souper rule into a function of a clif filetest, so that
+// wasmtime can check that the generated peephole fires:
+//
+//   function %superopt_1(i32) -> i32 {
+//   block0(v0: i32):
+//       v1 = iadd v0, v0
+//       v2 = iadd v0, v1
+//       return v2
+//   }
+//   ; check: $(v3=v[0-9]+) = imul_imm v0, 3
+//   ; check: return $v3
+//
+// The LHS vars become block params and its constants stay inlined as
+// immediates; the RHS becomes the `check:` lines.
+
+use cliftapi::CliftApi;
+use cliftinstbuilder::{self, CtonInst, CtonOpcode, CtonCmpCond};
+
+/// Header lines of the filetest, once per file
+pub fn generate_filetest_header(api: &dyn CliftApi, clif_target: &str) -> String {
+    format!("{}\nset opt_level=speed\ntarget {}\n", api.filetest_command(), clif_target)
+}
+
+/// Returns the filetest function for one rule (all its cranelift insts
+/// and its lowered RHS), or None if the rule has no clif form.
+pub fn generate_filetest(
+    all_insts: &[CtonInst],
+    rhs_insts: &[CtonInst],
+    count: u32,
+    souper_rule: &str,
+    api: &dyn CliftApi,
+) -> Option<String> {
+    let infer_pos = all_insts.iter().position(|i| matches!(i.opcode, CtonOpcode::Infer))?;
+    let root = cliftinstbuilder::get_index_operand(&all_insts[infer_pos], 0)?;
+    if let CtonOpcode::Var = all_insts[root].opcode {
+        return None;
+    }
+    let result = rhs_insts.iter().find(|i| matches!(i.opcode, CtonOpcode::ResultInst))?;
+
+    // A folded LHS variant leaves inner insts behind that the root no
+    // longer uses, those are not part of the function
+    let reachable = cliftinstbuilder::get_reachable_insts(all_insts, root);
+    let mut params = Vec::new();
+    let mut param_types = Vec::new();
+    let mut body = String::new();
+    for (idx, inst) in all_insts[..infer_pos].iter().enumerate() {
+        if !reachable[idx] {
+            continue;
+        }
+        let ty = api.clif_type(inst.width)?;
+        match inst.opcode {
+            CtonOpcode::Var => {
+                params.push(format!("{}: {}", get_value_name(idx), ty));
+                param_types.push(ty);
+            }
+            _ => {
+                let inst_str = get_inst_str(inst, api, &|idx| get_value_name(idx))?;
+                body += &format!("    {} = {}\n", get_value_name(idx), inst_str);
+            }
+        }
+    }
+    let root_ty = api.clif_type(all_insts[root].width)?;
+
+    let mut test_str = format!("; superopt_{}\n", count);
+    for line in souper_rule.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        test_str += &format!("; {}\n", line);
+    }
+    test_str += &format!(
+        "function %superopt_{}({}) -> {} {{\n",
+        count,
+        param_types.join(", "),
+        root_ty
+    );
+    test_str += &format!("block0({}):\n", params.join(", "));
+    test_str += &body;
+    test_str += &format!("    return {}\n}}\n", get_value_name(root));
+
+    // The optimized body numbers its new values itself, so the checks
+    // bind them to filecheck variables; LHS values keep their names.
+    let rhs_name = |idx: usize| {
+        if idx < infer_pos {
+            get_value_name(idx)
+        } else {
+            format!("${}", get_value_name(idx))
+        }
+    };
+    for inst in rhs_insts {
+        if let CtonOpcode::ResultInst = inst.opcode {
+            continue;
+        }
+        api.clif_type(inst.width)?;
+        let inst_str = get_inst_str(inst, api, &rhs_name)?;
+        test_str += &format!(
+            "; check: $({}=v[0-9]+) = {}\n",
+            get_value_name(inst.lhs_index),
+            inst_str
+        );
+    }
+    match cliftinstbuilder::get_index_operand(result, 0) {
+        Some(idx) => {
+            test_str += &format!("; check: return {}\n", rhs_name(idx));
+        }
+        None => {
+            // constant folded RHS
+            let c = cliftinstbuilder::get_const_operand(result, 0)?;
+            api.clif_type(result.width)?;
+            test_str += &format!("; check: $(result=v[0-9]+) = {}\n", api.clif_const(result.width, c));
+            test_str += "; check: return $result\n";
+        }
+    }
+    Some(test_str)
+}
+
+/// clif text of `inst` without its result, naming operand values with
+/// `value_name`
+fn get_inst_str(
+    inst: &CtonInst,
+    api: &dyn CliftApi,
+    value_name: &dyn Fn(usize) -> String,
+) -> Option<String> {
+    if let CtonOpcode::Iconst = inst.opcode {
+        let c = cliftinstbuilder::get_const_operand(inst, 0)?;
+        return Some(api.clif_const(inst.width, c));
+    }
+    let opcode = api.builder_method(&cliftinstbuilder::get_clift_opcode_name(inst.opcode.clone()));
+    if opcode.is_empty() {
+        return None;
+    }
+    let mut args = Vec::new();
+    for n in 0..inst.cops.as_ref().map_or(0, |ops| ops.len()) {
+        match cliftinstbuilder::get_index_operand(inst, n) {
+            Some(idx) => args.push(value_name(idx)),
+            None => args.push(cliftinstbuilder::get_const_operand(inst, n)?.to_string()),
+        }
+    }
+    match inst.cond.clone() {
+        Some(cond) => Some(format!("{} {} {}", opcode, get_clif_cond(cond), args.join(", "))),
+        None => Some(format!("{} {}", opcode, args.join(", "))),
+    }
+}
+
+fn get_value_name(idx: usize) -> String {
+    format!("v{}", idx)
+}
+
+fn get_clif_cond(cond: CtonCmpCond) -> String {
+    let cc = match cond {
+        CtonCmpCond::Eq => "eq",
+        CtonCmpCond::Ne => "ne",
+        CtonCmpCond::Slt => "slt",
+        CtonCmpCond::Ult => "ult",
+        CtonCmpCond::Sle => "sle",
+        CtonCmpCond::Ule => "ule",
+    };
+    cc.to_string()
+}
@@ -0,0 +1,612 @@
+// Merged prefix tree interpreter
+//
+// Walks a MergedArena and the RHS table directly over a `dfg::Dfg`, the
+// way the matcher generated by `matcher::generate_matcher` walks the
+// Cranelift DFG, and applies the RHS of the first LHS that matches.
+// This lets rules and tree merging be exercised without Cranelift.
+//
+// Like the generated code, where a failed `if` falls out of its block, a
+// mismatch below a node goes on with the next sibling of that node, and
+// a rule only fires when the values its LHS uses more than once (its path
+// condition table, see `pctable`) are the same, and its other checks (see
+// `matcher::LeafCheck`) hold. Repeated vars are checked as they are
+// bound. The tests below pin the emitted code to this order.
+
+use dfg::{Dfg, InstData, ValueDef};
+use cliftinstbuilder::{self, CtonOpcode};
+use lhspatternmatcher::{Node, NodeType};
+use matcher::LeafCheck;
+use mergedtree::MergedArena;
+use processrhs::CliftInstWithArgs;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Value(usize),
+    /// An immediate and the bit width it is compared at
+    Imm(i64, u32),
+}
+
+#[derive(Clone)]
+struct MatchState {
+    // inst whose InstructionData the next nodes match
+    entity: Option<usize>,
+    // operand selected by the last MatchArgs node
+    operand: Option<Operand>,
+    // `arg_N` names of the LHS to the insts they were bound to
+    args: HashMap<String, usize>,
+    // Souper var numbers to the values they matched
+    vars: HashMap<u32, usize>,
+}
+
+pub struct Interpreter<'a> {
+    nodes: HashMap<usize, &'a Node>,
+    rhs: &'a HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &'a HashMap<usize, HashMap<String, usize>>,
+    leaf_checks: &'a HashMap<usize, Vec<LeafCheck>>,
+    // checks made since the last `take_checks`
+    checks: Cell<usize>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(
+        arena: &'a MergedArena,
+        rhs: &'a HashMap<usize, Vec<CliftInstWithArgs>>,
+        pcs: &'a HashMap<usize, HashMap<String, usize>>,
+        leaf_checks: &'a HashMap<usize, Vec<LeafCheck>>,
+    ) -> Interpreter<'a> {
+        let nodes = arena.merged_tree.iter().map(|node| (node.id, node)).collect();
+        Interpreter {
+            nodes,
+            rhs,
+            pcs,
+            leaf_checks,
+            checks: Cell::new(0),
+        }
+    }
+
+    /// Number of checks (see `is_check`) the walks made since the last
+    /// call
+    pub fn take_checks(&self) -> usize {
+        self.checks.replace(0)
+    }
+
+    /// Matches `inst` against the tree and applies the RHS of the first
+    /// rule that matches. Returns the id of that rule's last LHS node.
+    pub fn rewrite_inst(&self, dfg: &mut Dfg, inst: usize) -> Option<usize> {
+        let state = MatchState {
+            entity: Some(inst),
+            operand: None,
+            args: HashMap::new(),
+            vars: HashMap::new(),
+        };
+        let (id, state) = self.walk(dfg, 0, state)?;
+        if apply_rhs(dfg, inst, &self.rhs[&id], &state) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Runs the tree on every inst of the function once, in layout
+    /// order. Returns the number of insts rewritten.
+    pub fn rewrite_function(&self, dfg: &mut Dfg) -> usize {
+        let mut count = 0;
+        for inst in dfg.layout.clone() {
+            if self.rewrite_inst(dfg, inst).is_some() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn walk(&self, dfg: &Dfg, id: usize, mut state: MatchState) -> Option<(usize, MatchState)> {
+        let node = self.nodes.get(&id)?;
+        if is_check(node) {
+            self.checks.set(self.checks.get() + 1);
+        }
+        if !match_node(dfg, node, &mut state) {
+            return None;
+        }
+        if self.rhs.contains_key(&id) {
+            let holds = self.pcs.get(&id).is_none_or(|pcs| path_condition_holds(dfg, pcs, &state))
+                && self.leaf_checks.get(&id).is_none_or(|checks| checks.iter().all(|check| check_holds(dfg, check, &state)));
+            return if holds { Some((id, state)) } else { None };
+        }
+        for next in node.next.iter().flatten() {
+            if let Some(found) = self.walk(dfg, next.index, state.clone()) {
+                return Some(found);
+            }
+        }
+        None
+    }
+}
+
+/// Whether the generated matcher emits an `if` for a node, rather than
+/// only binding what the nodes below it read
+pub fn is_check(node: &Node) -> bool {
+    match node.node_type {
+        NodeType::InstType | NodeType::Opcode => node.node_value != "Var",
+        NodeType::MatchValDef => node.node_value == "Result",
+        NodeType::Cond | NodeType::MatchConst => true,
+        _ => false,
+    }
+}
+
+/// Checks a single node against the DFG, updating the match state
+fn match_node(dfg: &Dfg, node: &Node, state: &mut MatchState) -> bool {
+    match node.node_type {
+        NodeType::MatchRoot | NodeType::MatchInstData |
+        NodeType::MatchOpcode | NodeType::MatchCond => true,
+        NodeType::InstType => {
+            if node.node_value == "Var" {
+                return match_var(dfg, node, state);
+            }
+            let inst = match state.entity {
+                Some(inst) => inst,
+                None => return false,
+            };
+            let kind = cliftinstbuilder::get_clift_instdata_name(dfg.insts[inst].kind.clone());
+            if kind != node.node_value {
+                return false;
+            }
+            state.args.insert(node.arg_name.clone(), inst);
+            true
+        }
+        NodeType::Opcode => {
+            if node.node_value == "Var" {
+                return true;
+            }
+            match state.entity {
+                Some(inst) => {
+                    cliftinstbuilder::get_clift_opcode_name(dfg.insts[inst].opcode.clone()) == node.node_value
+                }
+                None => false,
+            }
+        }
+        NodeType::Cond => match state.entity {
+            Some(inst) => cliftinstbuilder::get_clift_cond_name(dfg.insts[inst].cond.clone()) == node.node_value,
+            None => false,
+        },
+        NodeType::MatchArgs => {
+            let data = match state.args.get(&node.arg_name) {
+                Some(&inst) => &dfg.insts[inst],
+                None => return false,
+            };
+            state.operand = match node.node_value.as_ref() {
+                "arg" => data.args.first().map(|&v| Operand::Value(v)),
+                "imm" => {
+                    // icmp_imm compares at the width of its value operand
+                    let width = match data.opcode {
+                        CtonOpcode::IcmpImm => data.args.first().map_or(data.width, |&v| dfg.value_width(v)),
+                        _ => data.width,
+                    };
+                    data.imm.map(|imm| Operand::Imm(imm, width))
+                }
+                arg => get_arg_index(arg).and_then(|i| data.args.get(i)).map(|&v| Operand::Value(v)),
+            };
+            state.operand.is_some()
+        }
+        NodeType::MatchValDef => match node.node_value.as_ref() {
+            "Result" => match state.operand {
+                Some(Operand::Value(v)) => match dfg.value_def(v) {
+                    ValueDef::Result(inst) => {
+                        state.entity = Some(inst);
+                        true
+                    }
+                    ValueDef::Param => false,
+                },
+                _ => false,
+            },
+            // a var leaf matches any value
+            "Any" => {
+                state.entity = None;
+                true
+            }
+            _ => false,
+        },
+        NodeType::MatchPlainConst => matches!(state.operand, Some(Operand::Imm(..))),
+        NodeType::MatchConst => match (state.operand, node.node_value.parse::<i128>()) {
+            (Some(Operand::Imm(imm, width)), Ok(c)) => mask_to_width(imm as i128, width) == mask_to_width(c, width),
+            _ => false,
+        },
+        NodeType::MatchNone => false,
+    }
+}
+
+/// A var leaf: the same Souper var has to match the same value
+fn match_var(dfg: &Dfg, node: &Node, state: &mut MatchState) -> bool {
+    let value = match state.operand {
+        Some(Operand::Value(v)) => dfg.resolve_aliases(v),
+        _ => return false,
+    };
+    if dfg.value_width(value) != node.width {
+        return false;
+    }
+    match node.var_id {
+        Some(var) => match state.vars.get(&var) {
+            Some(&bound) => bound == value,
+            None => {
+                state.vars.insert(var, value);
+                true
+            }
+        },
+        None => true,
+    }
+}
+
+/// Whether the args of `pcs` that stand for the same Souper value
+/// matched the same value. Like the generated code, this only looks at
+/// the repeated ones: a shared action can be reached by paths that
+/// don't bind the others.
+fn path_condition_holds(dfg: &Dfg, pcs: &HashMap<String, usize>, state: &MatchState) -> bool {
+    let mut args_of_idx: HashMap<usize, Vec<&String>> = HashMap::new();
+    for (pcarg, idx) in pcs {
+        args_of_idx.entry(*idx).or_default().push(pcarg);
+    }
+    for args in args_of_idx.values().filter(|args| args.len() > 1) {
+        let mut values = args.iter().map(|pcarg| match get_rhs_operand(dfg, pcarg, state, &HashMap::new()) {
+            Some(Operand::Value(v)) => Some(dfg.resolve_aliases(v)),
+            _ => None,
+        });
+        let first = values.next().flatten();
+        if first.is_none() || values.any(|value| value != first) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether a check of a rule holds on the values its LHS matched
+fn check_holds(dfg: &Dfg, check: &LeafCheck, state: &MatchState) -> bool {
+    let value = |name: &str| match get_rhs_operand(dfg, name, state, &HashMap::new()) {
+        Some(Operand::Value(v)) => Some(dfg.resolve_aliases(v)),
+        _ => None,
+    };
+    match check {
+        LeafCheck::Differ(a, b) => match (value(a), value(b)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        },
+        LeafCheck::ShiftAmount(amount, width) => match value(amount).map(|v| dfg.value_def(v)) {
+            Some(ValueDef::Result(inst)) => {
+                let data = &dfg.insts[inst];
+                matches!(data.opcode, CtonOpcode::Iconst) && data.imm.is_some_and(|imm| imm >= 0 && imm < *width as i64)
+            }
+            _ => false,
+        },
+    }
+}
+
+/// `args[N]` to N
+fn get_arg_index(arg: &str) -> Option<usize> {
+    arg.strip_prefix("args[")?.strip_suffix(']')?.parse().ok()
+}
+
+fn mask_to_width(c: i128, width: u32) -> i128 {
+    if width == 0 || width >= 128 {
+        c
+    } else {
+        c & ((1i128 << width) - 1)
+    }
+}
+
+/// Looks up an RHS operand name (`arg_1[0]`, `rhs_inst_4`, `3_u64 as i64`)
+fn get_rhs_operand(
+    dfg: &Dfg,
+    name: &str,
+    state: &MatchState,
+    created: &HashMap<String, usize>,
+) -> Option<Operand> {
+    if let Some(c) = name.strip_suffix("_u64 as i64") {
+        return c.parse::<i128>().ok().map(|c| Operand::Imm(c as i64, 0));
+    }
+    if let Some(&value) = created.get(name) {
+        return Some(Operand::Value(value));
+    }
+    let (arg_name, index) = match name.find('[') {
+        Some(pos) => (&name[..pos], get_arg_index(&format!("args{}", &name[pos..]))?),
+        None => (name, 0),
+    };
+    let inst = *state.args.get(arg_name)?;
+    dfg.insts[inst].args.get(index).map(|&v| Operand::Value(v))
+}
+
+/// Applies the RHS the way `take_action` of the generated matcher does:
+/// the insts before the result are inserted before `inst`, and `inst`
+/// is replaced by the one the result refers to, or by a constant, or
+/// its result becomes an alias of an LHS value.
+fn apply_rhs(dfg: &mut Dfg, inst: usize, rhs: &[CliftInstWithArgs], state: &MatchState) -> bool {
+    let result = match rhs.iter().find(|i| matches!(i.opcode, CtonOpcode::ResultInst)) {
+        Some(result) => result,
+        None => return false,
+    };
+    let result_name = match result.cops.first() {
+        Some(name) => name.clone(),
+        None => return false,
+    };
+    let body: Vec<&CliftInstWithArgs> = rhs
+        .iter()
+        .filter(|i| !matches!(i.opcode, CtonOpcode::ResultInst))
+        .collect();
+    // check every operand can be found before changing the DFG
+    let mut created: HashMap<String, usize> = HashMap::new();
+    for rhs_inst in &body {
+        for op in &rhs_inst.cops {
+            if get_rhs_operand(dfg, op, state, &created).is_none() {
+                return false;
+            }
+        }
+        created.insert(format!("rhs_inst_{}", rhs_inst.lhs_index), 0);
+    }
+    if get_rhs_operand(dfg, &result_name, state, &created).is_none() {
+        return false;
+    }
+    created.clear();
+    for (n, rhs_inst) in body.iter().enumerate() {
+        let mut data = InstData {
+            kind: rhs_inst.kind.clone(),
+            opcode: rhs_inst.opcode.clone(),
+            cond: rhs_inst.cond.clone(),
+            width: rhs_inst.width,
+            args: Vec::new(),
+            imm: None,
+        };
+        for op in &rhs_inst.cops {
+            match get_rhs_operand(dfg, op, state, &created) {
+                Some(Operand::Value(v)) => data.args.push(v),
+                Some(Operand::Imm(imm, _)) => data.imm = Some(imm),
+                None => return false,
+            }
+        }
+        let name = format!("rhs_inst_{}", rhs_inst.lhs_index);
+        if n + 1 == body.len() && name == result_name {
+            dfg.replace(inst, data);
+            return true;
+        }
+        let value = dfg.insert_inst(inst, data);
+        created.insert(name, value);
+    }
+    let width = dfg.insts[inst].width;
+    match get_rhs_operand(dfg, &result_name, state, &created) {
+        Some(Operand::Value(v)) => {
+            let result = dfg.inst_result(inst);
+            dfg.change_to_alias(result, v);
+        }
+        Some(Operand::Imm(imm, _)) => {
+            let data = InstData {
+                kind: cliftinstbuilder::CtonInstKind::UnaryImm,
+                opcode: CtonOpcode::Iconst,
+                cond: None,
+                width,
+                args: Vec::new(),
+                imm: Some(imm),
+            };
+            dfg.replace(inst, data);
+        }
+        None => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cliftapi;
+    use cliftinstbuilder::{CtonCmpCond, CtonInstKind};
+    use matcher;
+    use testrules::{compile, Rules};
+
+    fn binary(opcode: CtonOpcode, x: usize, y: usize) -> InstData {
+        InstData {
+            kind: CtonInstKind::Binary,
+            opcode,
+            cond: None,
+            width: 32,
+            args: vec![x, y],
+            imm: None,
+        }
+    }
+
+    fn emit(rules: &Rules) -> String {
+        let api = cliftapi::get_default_clift_api();
+        matcher::generate_matcher(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks, &*api)
+    }
+
+    const ADD_SAME: &str = "%0:i32 = var\n%1:i32 = add %0, %0\ninfer %1\n~>\n\
+                            %2:i32 = shl %0, 1:i32\nresult %2\n";
+    const ADD_COMMUTED: &str = "%0:i32 = var\n%1:i32 = var\n%2:i32 = add %0, %1\ninfer %2\n~>\n\
+                                %3:i32 = add %1, %0\nresult %3\n";
+
+    #[test]
+    fn rewrites_repeated_var() {
+        let rules = compile(&[ADD_SAME]);
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        let v = dfg.append_inst(binary(CtonOpcode::Iadd, p, p));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), Some(rules.leaves[0]));
+        let data = &dfg.insts[inst];
+        assert!(matches!(data.opcode, CtonOpcode::IshlImm));
+        assert_eq!(data.args, vec![p]);
+        assert_eq!(data.imm, Some(1));
+        // rewritten in place, the result value stays
+        assert_eq!(dfg.inst_result(inst), v);
+        assert_eq!(dfg.layout.len(), 1);
+    }
+
+    #[test]
+    fn repeated_var_mismatch_keeps_inst() {
+        let rules = compile(&[ADD_SAME]);
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        let q = dfg.append_param(32);
+        dfg.append_inst(binary(CtonOpcode::Iadd, p, q));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), None);
+        assert!(matches!(dfg.insts[inst].opcode, CtonOpcode::Iadd));
+        assert_eq!(dfg.insts[inst].args, vec![p, q]);
+    }
+
+    #[test]
+    fn mismatch_falls_through_to_sibling() {
+        let rules = compile(&[ADD_SAME, ADD_COMMUTED]);
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        let q = dfg.append_param(32);
+        dfg.append_inst(binary(CtonOpcode::Iadd, p, q));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), Some(rules.leaves[1]));
+        assert!(matches!(dfg.insts[inst].opcode, CtonOpcode::Iadd));
+        assert_eq!(dfg.insts[inst].args, vec![q, p]);
+
+        // the first rule still wins where both match
+        let mut dfg = Dfg::new("g");
+        let p = dfg.append_param(32);
+        dfg.append_inst(binary(CtonOpcode::Iadd, p, p));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), Some(rules.leaves[0]));
+        assert!(matches!(dfg.insts[inst].opcode, CtonOpcode::IshlImm));
+    }
+
+    #[test]
+    fn rhs_of_a_value_aliases_the_result() {
+        let rules = compile(&["%0:i32 = var\n%1:i32 = sub %0, %0\n%2:i32 = add %1, %0\ninfer %2\n~>\nresult %0\n"]);
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        let d = dfg.append_inst(binary(CtonOpcode::Isub, p, p));
+        let v = dfg.append_inst(binary(CtonOpcode::Iadd, d, p));
+        let inst = dfg.layout[1];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), Some(rules.leaves[0]));
+        assert_eq!(dfg.resolve_aliases(v), p);
+        assert!(matches!(dfg.value_def(v), ValueDef::Param));
+    }
+
+    const EQ_OF_DIFFERENT: &str = "%0:i32 = var\n%1:i32 = var\n%2:i1 = ne %0, %1\npc %2 1:i1\n\
+                                   %3:i1 = eq %0, %1\ninfer %3\n~>\nresult 0:i1\n";
+    const SHIFT_BACK: &str = "%0:i32 = var\n%1:i32 = var\n%2:i32 = shl %0, %1\n%3:i32 = ashr %2, %1\n\
+                              %4:i32 = shl %3, %1\ninfer %4\n~>\nresult %2\n";
+
+    fn icmp_eq(x: usize, y: usize) -> InstData {
+        InstData {
+            kind: CtonInstKind::IntCompare,
+            opcode: CtonOpcode::Icmp,
+            cond: Some(CtonCmpCond::Eq),
+            width: 1,
+            args: vec![x, y],
+            imm: None,
+        }
+    }
+
+    fn iconst(c: i64) -> InstData {
+        InstData {
+            kind: CtonInstKind::UnaryImm,
+            opcode: CtonOpcode::Iconst,
+            cond: None,
+            width: 32,
+            args: Vec::new(),
+            imm: Some(c),
+        }
+    }
+
+    #[test]
+    fn stated_inequality_is_checked() {
+        let rules = compile(&[EQ_OF_DIFFERENT]);
+        let checks = &rules.checks[&rules.leaves[0]];
+        let (a, b) = match checks.as_slice() {
+            [LeafCheck::Differ(a, b)] => (a, b),
+            _ => panic!("ERROR: expected one inequality"),
+        };
+        assert!(emit(&rules).contains(&format!("if {} != {} {{\n", a, b)));
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        let q = dfg.append_param(32);
+        dfg.append_inst(icmp_eq(p, q));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), Some(rules.leaves[0]));
+        assert!(matches!(dfg.insts[inst].opcode, CtonOpcode::Iconst));
+
+        // the vars may match the same value, the rule then does not hold
+        let mut dfg = Dfg::new("g");
+        let p = dfg.append_param(32);
+        dfg.append_inst(icmp_eq(p, p));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), None);
+    }
+
+    #[test]
+    fn failed_check_falls_through_to_the_same_lhs() {
+        let eq_commuted = "%0:i32 = var\n%1:i32 = var\n%2:i1 = eq %0, %1\ninfer %2\n~>\n%3:i1 = eq %1, %0\nresult %3\n";
+        let rules = compile(&[EQ_OF_DIFFERENT, eq_commuted]);
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+        let mut dfg = Dfg::new("f");
+        let p = dfg.append_param(32);
+        dfg.append_inst(icmp_eq(p, p));
+        let inst = dfg.layout[0];
+        assert_eq!(interp.rewrite_inst(&mut dfg, inst), Some(rules.leaves[1]));
+        assert!(matches!(dfg.insts[inst].opcode, CtonOpcode::Icmp));
+        // the second action is tried after the guard of the first
+        let emitted = emit(&rules);
+        let guard = emitted.find(" != ").unwrap();
+        assert!(emitted[guard..].contains("icmp(arg_0[1], arg_0[0])"));
+    }
+
+    #[test]
+    fn shift_amount_is_checked() {
+        let rules = compile(&[SHIFT_BACK]);
+        assert_eq!(rules.checks[&rules.leaves[0]], vec![LeafCheck::ShiftAmount("arg_2[1]".to_string(), 32)]);
+        let interp = Interpreter::new(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks);
+        let rewrite = |amount: Option<i64>| {
+            let mut dfg = Dfg::new("f");
+            let p = dfg.append_param(32);
+            let c = match amount {
+                Some(c) => dfg.append_inst(iconst(c)),
+                None => dfg.append_param(32),
+            };
+            let shl = dfg.append_inst(binary(CtonOpcode::Ishl, p, c));
+            let sshr = dfg.append_inst(binary(CtonOpcode::Sshr, shl, c));
+            dfg.append_inst(binary(CtonOpcode::Ishl, sshr, c));
+            let inst = dfg.layout[dfg.layout.len() - 1];
+            interp.rewrite_inst(&mut dfg, inst)
+        };
+        assert_eq!(rewrite(Some(3)), Some(rules.leaves[0]));
+        // Cranelift masks 32 to 0, where the rule does not hold
+        assert_eq!(rewrite(Some(32)), None);
+        assert_eq!(rewrite(Some(-1)), None);
+        assert_eq!(rewrite(None), None);
+    }
+
+    #[test]
+    fn emitted_guard_is_the_checked_path_condition() {
+        let rules = compile(&[ADD_SAME, ADD_COMMUTED]);
+        let api = cliftapi::get_default_clift_api();
+        let guards = matcher::get_leaf_guards(&rules.pcs, &HashMap::new(), &*api);
+        // the repeated var is the only path condition
+        assert_eq!(guards.get(&rules.leaves[0]).map(|g| g.as_str()), Some("arg_0[0] == arg_0[1]"));
+        assert!(!guards.contains_key(&rules.leaves[1]));
+        let emitted = emit(&rules);
+        assert!(emitted.contains("if arg_0[0] == arg_0[1] {\n"));
+    }
+
+    #[test]
+    fn emitted_fallthrough_follows_the_interpreter() {
+        let rules = compile(&[ADD_SAME, ADD_COMMUTED]);
+        let emitted = emit(&rules);
+        // the guarded action of the first rule, then the end of its
+        // block, then the second rule: the order the interpreter tries
+        // them in when the guard fails
+        let guard = emitted.find("if arg_0[0] == arg_0[1] {").unwrap();
+        let first = emitted.find("ishl_imm(arg_0[0]").unwrap();
+        let second = emitted.find("iadd(arg_0[1], arg_0[0])").unwrap();
+        assert!(guard < first && first < second);
+        assert_eq!(emitted[first..second].matches('}').count(), 1);
+        assert!(emitted[first..second].contains("return true;\n}\n"));
+    }
+}
@@ -0,0 +1,281 @@
+// ISLE rule emitter
+//
+// Turns one Souper rule into a rule for the e-graph mid-end of current
+// Cranelift (`cranelift/codegen/src/opts/*.isle`):
+//
+//   (rule (simplify (iadd $I32 v0 (iadd $I32 v0 v0)))
+//         (imul $I32 v0 (iconst $I32 (imm64 3))))
+//
+// The mid-end only sees `iconst` operands, so the `*_imm` forms of the
+// builder are spelled as the plain opcode with an `iconst` operand, and
+// b1 values are i8 as in the current Cranelift API.
+
+use cliftinstbuilder::{self, CtonInst, CtonOpcode, CtonCmpCond};
+use std::collections::{HashMap, HashSet};
+
+pub struct IsleRule {
+    // LHS values the rule has to name: vars, values used more than once
+    // and values the RHS refers to
+    named: HashSet<usize>,
+    // named LHS values already bound by an earlier occurrence
+    bound: HashSet<usize>,
+    infer_pos: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Lhs,
+    Rhs,
+}
+
+impl IsleRule {
+    pub fn new(insts: &[CtonInst], infer_pos: usize, root: usize) -> IsleRule {
+        let mut uses: HashMap<usize, usize> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut worklist = vec![root];
+        while let Some(idx) = worklist.pop() {
+            if !seen.insert(idx) {
+                continue;
+            }
+            for op in get_index_operands(&insts[idx]) {
+                *uses.entry(op).or_insert(0) += 1;
+                worklist.push(op);
+            }
+        }
+        let mut named: HashSet<usize> = uses
+            .iter()
+            .filter(|&(_, &count)| count > 1)
+            .map(|(&idx, _)| idx)
+            .collect();
+        for inst in &insts[infer_pos + 1..] {
+            named.extend(get_index_operands(inst).into_iter().filter(|&idx| idx < infer_pos));
+        }
+        named.extend(
+            seen.iter()
+                .cloned()
+                .filter(|&idx| matches!(insts[idx].opcode, CtonOpcode::Var)),
+        );
+        IsleRule {
+            named,
+            bound: HashSet::new(),
+            infer_pos,
+        }
+    }
+
+    /// ISLE term for the value at `idx`, as a pattern on the LHS or as
+    /// an expression on the RHS
+    fn get_value_term(&mut self, insts: &[CtonInst], idx: usize, side: Side) -> Option<String> {
+        if idx < self.infer_pos && side == Side::Rhs {
+            // the LHS has bound every value the RHS refers to
+            return Some(get_value_name(idx));
+        }
+        if self.named.contains(&idx) && !self.bound.insert(idx) {
+            // repeated names are an equality constraint in ISLE
+            return Some(get_value_name(idx));
+        }
+        let inst = &insts[idx];
+        if let CtonOpcode::Var = inst.opcode {
+            return Some(get_value_name(idx));
+        }
+        let term = self.get_inst_term(insts, inst, side)?;
+        if self.named.contains(&idx) {
+            Some(format!("{} @ {}", get_value_name(idx), term))
+        } else {
+            Some(term)
+        }
+    }
+
+    fn get_inst_term(&mut self, insts: &[CtonInst], inst: &CtonInst, side: Side) -> Option<String> {
+        let ty = get_isle_type(inst.width);
+        let opcode = cliftinstbuilder::get_clift_opcode_name(inst.opcode.clone());
+        match inst.opcode {
+            CtonOpcode::Iconst => {
+                let c = cliftinstbuilder::get_const_operand(inst, 0)?;
+                get_iconst_term(inst.width, c, side)
+            }
+            CtonOpcode::IaddImm | CtonOpcode::ImulImm |
+            CtonOpcode::BandImm | CtonOpcode::BorImm | CtonOpcode::BxorImm |
+            CtonOpcode::IshlImm | CtonOpcode::UshrImm | CtonOpcode::SshrImm => {
+                let x = self.get_operand_term(insts, inst, 0, inst.width, side)?;
+                let c = self.get_operand_term(insts, inst, 1, inst.width, side)?;
+                Some(format!("({} {} {} {})", opcode.trim_end_matches("_imm"), ty, x, c))
+            }
+            CtonOpcode::IsubImm => {
+                // irsub_imm x, c is c - x
+                let x = self.get_operand_term(insts, inst, 0, inst.width, side)?;
+                let c = self.get_operand_term(insts, inst, 1, inst.width, side)?;
+                Some(format!("(isub {} {} {})", ty, c, x))
+            }
+            CtonOpcode::Icmp | CtonOpcode::IcmpImm => {
+                // the operands have the width of the compared value,
+                // not the width of the (b1) result
+                let x_width = cliftinstbuilder::get_index_operand(inst, 0)
+                    .map(|x| insts[x].width)
+                    .unwrap_or(inst.width);
+                let cc = get_isle_cond(inst.cond.clone()?);
+                let x = self.get_operand_term(insts, inst, 0, x_width, side)?;
+                let y = self.get_operand_term(insts, inst, 1, x_width, side)?;
+                Some(format!("(icmp {} {} {} {})", ty, cc, x, y))
+            }
+            CtonOpcode::Iadd | CtonOpcode::Isub | CtonOpcode::Imul |
+            CtonOpcode::Band | CtonOpcode::Bor | CtonOpcode::Bxor |
+            CtonOpcode::BandNot | CtonOpcode::BorNot | CtonOpcode::BxorNot |
+            CtonOpcode::Ishl | CtonOpcode::Ushr | CtonOpcode::Sshr |
+            CtonOpcode::Umin | CtonOpcode::Umax => {
+                let x = self.get_operand_term(insts, inst, 0, inst.width, side)?;
+                let y = self.get_operand_term(insts, inst, 1, inst.width, side)?;
+                Some(format!("({} {} {} {})", opcode, ty, x, y))
+            }
+            CtonOpcode::Imin | CtonOpcode::Imax => {
+                let name = match inst.opcode {
+                    CtonOpcode::Imin => "smin",
+                    _ => "smax",
+                };
+                let x = self.get_operand_term(insts, inst, 0, inst.width, side)?;
+                let y = self.get_operand_term(insts, inst, 1, inst.width, side)?;
+                Some(format!("({} {} {} {})", name, ty, x, y))
+            }
+            CtonOpcode::Bnot | CtonOpcode::Ineg | CtonOpcode::Iabs |
+            CtonOpcode::Popcnt | CtonOpcode::Clz | CtonOpcode::Ctz => {
+                let x = self.get_operand_term(insts, inst, 0, inst.width, side)?;
+                Some(format!("({} {} {})", opcode, ty, x))
+            }
+            CtonOpcode::Select => {
+                let c = self.get_operand_term(insts, inst, 0, 1, side)?;
+                let x = self.get_operand_term(insts, inst, 1, inst.width, side)?;
+                let y = self.get_operand_term(insts, inst, 2, inst.width, side)?;
+                Some(format!("(select {} {} {} {})", ty, c, x, y))
+            }
+            CtonOpcode::ResultInst => self.get_operand_term(insts, inst, 0, inst.width, side),
+            _ => None,
+        }
+    }
+
+    /// Operand `n` of `inst`; constant operands become an `iconst` of
+    /// the given width
+    fn get_operand_term(
+        &mut self,
+        insts: &[CtonInst],
+        inst: &CtonInst,
+        n: usize,
+        width: u32,
+        side: Side,
+    ) -> Option<String> {
+        match cliftinstbuilder::get_index_operand(inst, n) {
+            Some(idx) => self.get_value_term(insts, idx, side),
+            None => {
+                let c = cliftinstbuilder::get_const_operand(inst, n)?;
+                get_iconst_term(width, c, side)
+            }
+        }
+    }
+}
+
+/// Returns the ISLE rule for one rule (all its cranelift insts and its
+/// lowered RHS), or None if the rule has no ISLE form.
+pub fn generate_isle_rule(
+    all_insts: &[CtonInst],
+    rhs_insts: &[CtonInst],
+    count: u32,
+    souper_rule: &str,
+) -> Option<String> {
+    let infer_pos = all_insts.iter().position(|i| matches!(i.opcode, CtonOpcode::Infer))?;
+    let root = cliftinstbuilder::get_index_operand(&all_insts[infer_pos], 0)?;
+    if let CtonOpcode::Var = all_insts[root].opcode {
+        // simplify only rewrites instructions
+        return None;
+    }
+    // RHS insts refer to each other by index, so look them up in a copy
+    // of the rule where the lowered RHS has replaced the original one
+    let mut insts = all_insts.to_vec();
+    for inst in rhs_insts {
+        // the target lowering numbers its extra insts past the end
+        if inst.lhs_index >= insts.len() {
+            insts.resize(inst.lhs_index + 1, inst.clone());
+        }
+        insts[inst.lhs_index] = inst.clone();
+    }
+    if !insts.iter().all(|inst| has_isle_type(inst.width)) {
+        return None;
+    }
+    let result = rhs_insts.iter().find(|i| matches!(i.opcode, CtonOpcode::ResultInst))?;
+
+    let mut rule = IsleRule::new(&insts, infer_pos, root);
+    let lhs = rule.get_value_term(&insts, root, Side::Lhs)?;
+    // every LHS value the RHS uses must have been bound by the pattern
+    let rhs_uses_unbound = rhs_insts
+        .iter()
+        .flat_map(get_index_operands)
+        .any(|idx| idx < infer_pos && !rule.bound.contains(&idx));
+    if rhs_uses_unbound {
+        return None;
+    }
+    let rhs = rule.get_inst_term(&insts, result, Side::Rhs)?;
+
+    let mut isle_str = format!(";; superopt_{}\n", count);
+    for line in souper_rule.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        isle_str += &format!(";; {}\n", line);
+    }
+    isle_str += &format!("(rule (simplify {})\n      {})\n", lhs, rhs);
+    Some(isle_str)
+}
+
+fn get_index_operands(inst: &CtonInst) -> Vec<usize> {
+    inst.cops
+        .iter()
+        .flatten()
+        .filter_map(|op| op.idx_val)
+        .collect()
+}
+
+fn get_value_name(idx: usize) -> String {
+    format!("v{}", idx)
+}
+
+/// Souper widths without a Cranelift integer type have no ISLE form;
+/// zero is the width of infer and of a `result` of a value.
+fn has_isle_type(width: u32) -> bool {
+    matches!(width, 0 | 1 | 8 | 16 | 32 | 64 | 128)
+}
+
+/// b1 values are i8 in current Cranelift
+fn get_isle_type(width: u32) -> String {
+    if width == 1 {
+        "$I8".to_string()
+    } else {
+        format!("$I{}", width)
+    }
+}
+
+/// iconst immediates are zero-extended from the type width. iconst only
+/// has types up to $I64, so there is no term for an i128 constant.
+fn get_iconst_term(width: u32, c: i128, side: Side) -> Option<String> {
+    let bits = if width < 64 {
+        (c & ((1i128 << width.max(1)) - 1)) as u64
+    } else if width == 64 {
+        c as u64
+    } else {
+        return None;
+    };
+    let imm = if bits > 0xffff {
+        format!("0x{:x}", bits)
+    } else {
+        bits.to_string()
+    };
+    match side {
+        Side::Lhs => Some(format!("(iconst {} (u64_from_imm64 {}))", get_isle_type(width), imm)),
+        Side::Rhs => Some(format!("(iconst {} (imm64 {}))", get_isle_type(width), imm)),
+    }
+}
+
+fn get_isle_cond(cond: CtonCmpCond) -> String {
+    let cc = match cond {
+        CtonCmpCond::Eq => "Equal",
+        CtonCmpCond::Ne => "NotEqual",
+        CtonCmpCond::Slt => "SignedLessThan",
+        CtonCmpCond::Ult => "UnsignedLessThan",
+        CtonCmpCond::Sle => "SignedLessThanOrEqual",
+        CtonCmpCond::Ule => "UnsignedLessThanOrEqual",
+    };
+    format!("(IntCC.{})", cc)
+}
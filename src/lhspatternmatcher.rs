@@ -1,9 +1,12 @@
 // LHS Pattern matcher
 
 use cliftinstbuilder::{self, CtonInst, CtonInstKind, CtonOpcode, CtonOperand, CtonValueDef};
+use std::collections::HashMap;
 
 pub struct Arena {
     nodes: Vec<Node>,
+    // where the node with an id is in `nodes`
+    slots: HashMap<usize, NodeSlot>,
     clift_insts: Vec<CtonInst>,
     count: usize,
     instdata_count: usize,
@@ -44,10 +47,19 @@ pub struct NodeID {
     pub index: usize,
 }
 
-#[derive(Clone)]
-pub struct NodeIndex {
-    node: Node,
-    index: usize,
+/// Position of a node in the vector of an arena. Ids are global across
+/// rules, a slot only means something in the arena it came from.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeSlot(usize);
+
+impl NodeSlot {
+    pub fn new(index: usize) -> NodeSlot {
+        NodeSlot(index)
+    }
+
+    pub fn index(self) -> usize {
+        self.0
+    }
 }
 
 /// Helper functions
@@ -152,6 +164,7 @@ impl Arena {
     pub fn new(global_counter: usize) -> Arena {
         Arena {
             nodes: Vec::new(),
+            slots: HashMap::new(),
             clift_insts: Vec::new(),
             count: global_counter,
             instdata_count: 0,
@@ -198,7 +211,8 @@ impl Arena {
             // FIXME: Bug in this code for superopt_2 func in cranelift repo. case: 0 == 0?
             CtonInstKind::Unary | CtonInstKind::UnaryImm |
             CtonInstKind::Binary | CtonInstKind::BinaryImm |
-            CtonInstKind::IntCompare | CtonInstKind::IntCompareImm => {
+            CtonInstKind::IntCompare | CtonInstKind::IntCompareImm |
+            CtonInstKind::Ternary => {
                 arg_name.push_str("arg_");
                 arg_name.push_str(&self.instdata_count.to_string());
                 self.instdata_count += 1;
@@ -381,20 +395,13 @@ impl Arena {
         }
     }
 
-    pub fn get_node_with_id(&mut self, idx: usize) -> Option<NodeIndex> {
-        let mut ret_node = None;
-        for n in 0..self.nodes.clone().len() {
-            let node_id = self.nodes[n].clone().id;
-            if node_id == idx {
-                ret_node = Some(NodeIndex {
-                    node: self.nodes[n].clone(),
-                    index: n,
-                });
-            } else {
-                ret_node = None;
-            }
-        }
-        ret_node
+    pub fn get_node_with_id(&self, idx: usize) -> Option<NodeSlot> {
+        self.slots.get(&idx).cloned()
+    }
+
+    pub fn push_node(&mut self, node: Node) {
+        self.slots.insert(node.id, NodeSlot(self.nodes.len()));
+        self.nodes.push(node);
     }
 
     pub fn get_clift_op_type_from_arg_num(
@@ -461,11 +468,10 @@ impl Arena {
             let node_x = self.get_node_with_id(c - 1);
 
             match node_x {
-                Some(NodeIndex { mut node, index }) => {
+                Some(slot) => {
                     let mut next_ids: Vec<NodeID> = Vec::new();
                     next_ids.push(NodeID { index: self.count });
-                    node.next = Some(next_ids);
-                    self.nodes[index] = node;
+                    self.nodes[slot.index()].next = Some(next_ids);
                 }
                 None => {
                     panic!("No node with the id = {} found in arena", self.count - 1);
@@ -521,8 +527,8 @@ impl Arena {
             let updated_valdef_node =
                 self.set_next_of_current_node_by_default(arg_valdef_node.clone());
 
-            self.nodes.push(updated_named_arg_node);
-            self.nodes.push(updated_valdef_node);
+            self.push_node(updated_named_arg_node);
+            self.push_node(updated_valdef_node);
 
             // repeat the prefix tree build here again!
             //let cops = clift_inst.cops.clone();
@@ -540,7 +546,7 @@ impl Arena {
                                     let const_arg_node = self.build_constant_node(
                                                               constant);
                                     self.update_count();
-                                    self.nodes.push(const_arg_node);
+                                    self.push_node(const_arg_node);
                                 },
                                 None => {
                                     panic!("operand of a clift inst must have either an index value or a constant value")
@@ -559,7 +565,8 @@ impl Arena {
         }
     }
 
-    pub fn build_sequence_of_nodes(&mut self, clift_inst: &CtonInst) -> Vec<Node> {
+    /// Appends the nodes matching `clift_inst` and its operands
+    pub fn build_sequence_of_nodes(&mut self, clift_inst: &CtonInst) {
         let node_instdata = self.build_instdata_node(clift_inst);
         self.update_count();
 
@@ -594,22 +601,20 @@ impl Arena {
             let updated_cond =
                 self.set_next_of_prev_node(node_specific_cond.clone(), node_cond.clone());
 
-            self.nodes.push(updated_instdata.clone());
-            self.nodes.push(updated_spec_inst.clone());
-            self.nodes.push(updated_opcode.clone());
-            self.nodes.push(updated_spec_opcode.clone());
-            self.nodes.push(updated_cond.clone());
-            self.nodes.push(node_specific_cond.clone());
+            self.push_node(updated_instdata);
+            self.push_node(updated_spec_inst);
+            self.push_node(updated_opcode);
+            self.push_node(updated_spec_opcode);
+            self.push_node(updated_cond);
+            self.push_node(node_specific_cond);
         } else {
-            self.nodes.push(updated_instdata.clone());
-            self.nodes.push(updated_spec_inst.clone());
-            self.nodes.push(updated_opcode.clone());
-            self.nodes.push(node_specific_opcode);
+            self.push_node(updated_instdata);
+            self.push_node(updated_spec_inst);
+            self.push_node(updated_opcode);
+            self.push_node(node_specific_opcode);
         }
 
         self.build_args_node(clift_inst, node_specific_inst.clone().node_value);
-
-        self.nodes.clone()
     }
 }
 
@@ -625,7 +630,8 @@ pub fn generate_single_tree_patterns(
     // Create Arena and initialize it
     let mut arena = Arena::new(global_count);
     arena.clift_insts = clift_insts.clone();
-    let all_nodes = arena.build_sequence_of_nodes(inst_at_infer_op_idx);
+    arena.build_sequence_of_nodes(inst_at_infer_op_idx);
+    let all_nodes = arena.nodes;
 
     //////println!("--- LHS pattern Matcher module: list of nodes -----------");
     //////for n in 0 .. all_nodes.len() {
@@ -5,20 +5,43 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 
+mod automaton;
 mod baseline_matcher;
+mod cliftapi;
+mod clifreader;
 mod cliftinstbuilder;
+mod decisiontree;
+mod dfg;
+mod filetests;
+mod interpreter;
+mod islerules;
 mod lexer;
 mod lhspatternmatcher;
 mod processlhs;
 mod processrhs;
+mod random;
 mod matcher;
 mod mergedtree;
 mod parser;
+mod peepmaticrules;
+mod preconditions;
+mod priority;
 mod rhscliftinsts;
+mod rhslowering;
+mod rulegen;
+mod selfcheck;
+mod shiftsemantics;
+mod smtlib;
+mod souperinterp;
+mod souperquery;
 mod tablerhs;
+mod targetisa;
+#[cfg(test)]
+mod testrules;
 mod pctable;
+mod verify;
 
-use mergedtree::MergedArena;
+use lhspatternmatcher::Node;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -26,8 +49,9 @@ fn main() {
     if args.len() < 3 {
         panic!(
             "ERROR: Expecting arguments list \
-                '<file_name> <mode>'. mode can be \
-                'baseline' or 'fast'"
+                '<file_name> <mode> <count> [--target=<isa>] [--api=<version>] [--depth=<n>] [--clif=<file>] [--smt-dir=<dir>] [--smt-results=<file>] [--seed=<n>] [--priority=<order>] [--tree=<shape>]'. \
+                mode can be 'baseline', 'fast', 'isle', 'peepmatic', 'filetest', 'harvest', 'interp', 'automaton', 'verify', 'exhaustive', 'smt', 'selfcheck' or 'generate'. \
+                '--tree=decision' only makes fewer checks with a --priority other than 'specificity'"
         );
     }
 
@@ -40,38 +64,319 @@ fn main() {
     match mode.as_ref() {
         "fast" => {}
         "baseline" => {}
+        "isle" => {}
+        "peepmatic" => {}
+        "filetest" => {}
+        "harvest" => {}
+        "interp" => {}
+        "automaton" => {}
+        "verify" => {}
+        "exhaustive" => {}
+        "smt" => {}
+        "selfcheck" => {}
+        "generate" => {}
         _ => {
-            panic!("ERROR: Expected mode 'fast' or 'baseline'");
+            panic!("ERROR: Expected mode 'fast', 'baseline', 'isle', 'peepmatic', 'filetest', 'harvest', 'interp', 'automaton', 'verify', 'exhaustive', 'smt', 'selfcheck' or 'generate'");
         }
     }
 
+    // Optional arguments after '<file_name> <mode> <count>'
+    let mut target = targetisa::get_default_target();
+    let mut api = cliftapi::get_default_clift_api();
+    let mut header_comments = Vec::new();
+    let mut harvest_depth: usize = 3;
+    let mut clif_filename = None;
+    let mut smt_dir = "smt".to_string();
+    let mut smt_results = None;
+    let mut seed = 0;
+    let mut rule_priority = priority::Priority::Specificity;
+    let mut decision_tree = false;
+    for arg in args.iter().skip(4) {
+        if let Some(name) = arg.strip_prefix("--target=") {
+            target = match targetisa::get_target_features(name) {
+                Some(features) => features,
+                None => panic!(
+                    "ERROR: Unknown target '{}', expected one of {:?}",
+                    name, targetisa::TARGET_NAMES),
+            };
+            // Record which target the matchers were generated for
+            header_comments.push(format!("target: {}", target.name));
+        } else if let Some(name) = arg.strip_prefix("--api=") {
+            api = match cliftapi::get_clift_api(name) {
+                Some(backend) => backend,
+                None => panic!(
+                    "ERROR: Unknown Cranelift API '{}', expected one of {:?}",
+                    name, cliftapi::API_NAMES),
+            };
+            // Record which Cranelift API the matchers were generated for
+            header_comments.push(format!("cranelift api: {}", api.get_name()));
+        } else if let Some(depth) = arg.strip_prefix("--depth=") {
+            harvest_depth = match depth.parse() {
+                Ok(depth) => depth,
+                Err(_) => panic!("ERROR: Expected a number for --depth, got '{}'", depth),
+            };
+        } else if let Some(name) = arg.strip_prefix("--clif=") {
+            clif_filename = Some(name.to_string());
+        } else if let Some(dir) = arg.strip_prefix("--smt-dir=") {
+            smt_dir = dir.to_string();
+        } else if let Some(name) = arg.strip_prefix("--smt-results=") {
+            let mut results = String::new();
+            File::open(name)
+                .expect("SMT results file not found")
+                .read_to_string(&mut results)
+                .expect("something went wrong reading the SMT results file");
+            smt_results = Some(smtlib::read_smt_results(&results));
+            // Record that only the proven rules were used
+            header_comments.push(format!("rules proven in: {}", name));
+        } else if let Some(n) = arg.strip_prefix("--seed=") {
+            seed = match n.parse() {
+                Ok(n) => n,
+                Err(_) => panic!("ERROR: Expected a number for --seed, got '{}'", n),
+            };
+            // Random inputs are only reproducible with the seed
+            header_comments.push(format!("seed: {}", seed));
+        } else if let Some(name) = arg.strip_prefix("--priority=") {
+            rule_priority = match priority::get_priority(name) {
+                Some(rule_priority) => rule_priority,
+                None => panic!(
+                    "ERROR: Unknown rule priority '{}', expected one of {:?}",
+                    name, priority::PRIORITY_NAMES),
+            };
+        } else if let Some(name) = arg.strip_prefix("--tree=") {
+            decision_tree = match name {
+                "prefix" => false,
+                "decision" => true,
+                _ => panic!(
+                    "ERROR: Unknown tree shape '{}', expected one of {:?}",
+                    name, decisiontree::TREE_NAMES),
+            };
+        } else {
+            panic!("ERROR: Unknown argument '{}'", arg);
+        }
+    }
+
+    // Modes that have to pick one of several matching rules
+    let uses_merged_tree = mode == "fast" || mode == "interp" || mode == "automaton" || mode == "selfcheck";
+    if mode == "baseline" || uses_merged_tree {
+        // Record which rule wins when several match
+        header_comments.push(format!("rule priority: {}", priority::get_priority_name(rule_priority)));
+    }
+    if uses_merged_tree && decision_tree {
+        header_comments.push(String::from("tree: decision"));
+    }
+
+    // Comments have to use the syntax of the output format
+    let comment_prefix = match mode.as_ref() {
+        "isle" | "peepmatic" => ";;",
+        "filetest" | "harvest" | "interp" | "verify" | "exhaustive" | "smt" | "selfcheck" | "generate" => ";",
+        _ => "//",
+    };
+    if mode == "filetest" {
+        println!("{}", filetests::generate_filetest_header(&*api, &target.clif_target));
+    }
+    for comment in header_comments {
+        if mode == "verify" {
+            // stdout has to stay a rule file
+            eprintln!("{} {}", comment_prefix, comment);
+        } else {
+            println!("{} {}", comment_prefix, comment);
+        }
+    }
+
+    let souper_delimiter = "#########";
+
+    if mode == "generate" {
+        // <count> random rules for stress testing, the file is not read
+        let mut rng = random::Random::new(seed);
+        let rules: Vec<String> = (0..total_peepholes)
+            .map(|_| rulegen::generate_random_rule(&mut rng))
+            .collect();
+        println!("{}", rules.join(&format!("{}\n\n", souper_delimiter)));
+        return;
+    }
+
     let mut file = File::open(filename).expect("file not found");
 
     let mut contents = String::new();
     file.read_to_string(&mut contents)
         .expect("something went wrong reading the file");
 
-    let souper_delimiter = "#########";
+    if mode == "harvest" {
+        // The input is clif text, turn its instructions into Souper
+        // infer queries (at most <count> of them)
+        let funcs = clifreader::parse_clif(&contents);
+        let queries = souperquery::generate_souper_queries(&funcs, harvest_depth);
+        for query in queries.iter().take(total_peepholes as usize) {
+            println!("{}", query);
+        }
+        return;
+    }
+
+    if mode == "smt" {
+        std::fs::create_dir_all(&smt_dir).expect("something went wrong creating the SMT directory");
+    }
 
     let splitter = contents.split(souper_delimiter);
-    let mut merged_arena = MergedArena {
-        merged_tree: Vec::new(),
-        hmap: HashMap::new(),
-    };
     let mut rhs_table = HashMap::new();
+    // The path condition table of a rule (see `pctable`), and the other
+    // conditions the merged tree matchers check before its action, by
+    // the id of its last LHS node
+    let mut path_condition_table = HashMap::new();
+    let mut check_table = HashMap::new();
     let mut global_nodes_count: usize = 0;
     let mut lhs_count = 1;
+    let mut rng = random::Random::new(seed);
+    let mut sound_rules = Vec::new();
+    let mut quarantined = 0;
+    let mut verified = 0;
+    let mut ranked_rules = Vec::new();
 
     for s in splitter {
         if peep_counter >= total_peepholes {
             break;
         }
+        if let Some(results) = &smt_results {
+            // Only the rules the solver proved
+            if !smtlib::is_proven(results, peep_counter + 1) {
+                peep_counter += 1;
+                continue;
+            }
+        }
+
+        if mode == "smt" {
+            // One query file per rule, for a solver to run offline
+            if !s.trim().is_empty() {
+                let name = smtlib::get_rule_name(peep_counter + 1);
+                let (rule, pre) = preconditions::split_preconditions(s);
+                match smtlib::generate_smt_query(&parser::parse(&rule), &pre, s) {
+                    Some(query) => {
+                        let path = format!("{}/{}.smt2", smt_dir, name);
+                        File::create(&path)
+                            .and_then(|mut f| f.write_all(query.as_bytes()))
+                            .expect("something went wrong writing the SMT query");
+                        println!("; {}", path);
+                    }
+                    None => println!("; {}: no SMT encoding", name),
+                }
+            }
+            peep_counter += 1;
+            continue;
+        }
+
         //////println!("*******   Test Case   *******\n{}\n", s);
         // lexing
         //lexer::start_lexer(&s);
 
-        // Parsing
-        let souper_insts = parser::parse(&s);
+        // Parsing, with the preconditions the parser has no syntax for
+        // split off
+        let (rule, pre) = preconditions::split_preconditions(s);
+        let souper_insts = parser::parse(&rule);
+
+        if mode == "verify" {
+            // Sound rules are printed back, so the output can be used
+            // as the rule file; the others only go to stderr
+            if !s.trim().is_empty() && !pre.is_empty() {
+                // the evaluator would find inputs the rule doesn't hold on
+                eprintln!("; rule {}: not checked, has preconditions", peep_counter + 1);
+                sound_rules.push(s.trim());
+            } else if !s.trim().is_empty() {
+                match verify::verify_rule(&souper_insts, &mut rng) {
+                    verify::Verdict::Sound => sound_rules.push(s.trim()),
+                    verify::Verdict::Unsupported => {
+                        eprintln!("; rule {}: not checked, unsupported inst", peep_counter + 1);
+                        sound_rules.push(s.trim());
+                    }
+                    verify::Verdict::Counterexample(cex) => {
+                        eprintln!("; rule {}: quarantined, {}\n{}\n", peep_counter + 1, cex, s.trim());
+                        quarantined += 1;
+                    }
+                }
+            }
+            peep_counter += 1;
+            continue;
+        }
+
+        if mode == "exhaustive" {
+            if !s.trim().is_empty() && !pre.is_empty() {
+                println!("; rule {}: not checked, has preconditions", peep_counter + 1);
+            } else if !s.trim().is_empty() {
+                let rule = peep_counter + 1;
+                match verify::verify_rule_exhaustive(&souper_insts) {
+                    verify::ExhaustiveVerdict::Verified(widths) => {
+                        let widths: Vec<String> = widths.iter().map(|w| format!("i{}", w)).collect();
+                        println!("; rule {}: verified at {}", rule, widths.join(", "));
+                        verified += 1;
+                    }
+                    verify::ExhaustiveVerdict::Refuted(width, cex) => {
+                        println!("; rule {}: refuted at i{}, {}", rule, width, cex);
+                        quarantined += 1;
+                    }
+                    verify::ExhaustiveVerdict::NotScalable(why) => {
+                        println!("; rule {}: not width-scalable, {}", rule, why);
+                    }
+                    verify::ExhaustiveVerdict::Unsupported => {
+                        println!("; rule {}: not checked, unsupported inst", rule);
+                    }
+                }
+            }
+            peep_counter += 1;
+            continue;
+        }
+
+        // Souper makes oversized shifts poison where Cranelift masks the
+        // amount, only the matchers can check that an amount is in range
+        let shift_guards = match shiftsemantics::check_shift_semantics(&souper_insts) {
+            shiftsemantics::ShiftSafety::Safe => Vec::new(),
+            shiftsemantics::ShiftSafety::Guarded(guards) => {
+                let amounts: Vec<String> = guards
+                    .iter()
+                    .map(|guard| format!("{} < {}", guard.name, guard.width))
+                    .collect();
+                if mode != "baseline" && !uses_merged_tree {
+                    println!(
+                        "{} rule {}: dropped, needs a shift amount guard ({}) that '{}' can't express",
+                        comment_prefix, peep_counter + 1, amounts.join(", "), mode
+                    );
+                    peep_counter += 1;
+                    continue;
+                }
+                println!("{} rule {}: guarded, {}", comment_prefix, peep_counter + 1, amounts.join(", "));
+                guards
+            }
+            shiftsemantics::ShiftSafety::Unsound(why) => {
+                println!("{} rule {}: dropped, {}", comment_prefix, peep_counter + 1, why);
+                peep_counter += 1;
+                continue;
+            }
+        };
+
+        // A rule with a path condition only holds on the values it
+        // states are different
+        let stated_inequalities = match preconditions::get_stated_inequalities(&pre, &souper_insts) {
+            Ok(pairs) => pairs,
+            Err(_) if mode == "peepmatic" && !pre.facts.is_empty() => {
+                let (var, fact) = &pre.facts[0];
+                println!(
+                    "{} rule {}: dropped, {}",
+                    comment_prefix, peep_counter + 1, peepmaticrules::get_fact_drop_reason(var, fact)
+                );
+                peep_counter += 1;
+                continue;
+            }
+            Err(why) => {
+                println!("{} rule {}: dropped, {}", comment_prefix, peep_counter + 1, why);
+                peep_counter += 1;
+                continue;
+            }
+        };
+        if !stated_inequalities.is_empty() && mode != "baseline" && !uses_merged_tree {
+            println!(
+                "{} rule {}: dropped, needs a path condition guard that '{}' can't express",
+                comment_prefix, peep_counter + 1, mode
+            );
+            peep_counter += 1;
+            continue;
+        }
 
         // Cranelift Instruction Building
         let clift_insts = cliftinstbuilder::transform_souper_to_clift_insts(souper_insts);
@@ -108,127 +413,189 @@ fn main() {
         //////}
         //////println!("====================================\n");
 
-        // Pattern Matching - Single prefix tree
-        let lhs_single_tree = lhspatternmatcher::generate_single_tree_patterns(
-            clift_insts.clone(),
-            global_nodes_count + 1,
-        );
-
-        global_nodes_count += lhs_single_tree.len();
-
-        // Process linear prefix tree of LHS for updating arg names
-        // from parent instdata nodes to arg nodes
-        //////println!("\n---- ProcessLHS module: update arg names from parent to arg nodes\n");
-        let lhs_info = processlhs::update_arg_nodes_in_lhs(
-            lhs_single_tree.clone()
-        );
-        //////println!("\n----- ProcessLHS module end\n");
-
-        // Build path conditions hashtable for each LHS vector of nodes
-        let lhs_pc = pctable::get_path_condition_args_for_lhs(
-            lhs_info.nodes.clone()
-        );
-        //////println!("\n************* PC HASHTABLE *****************\n");
-        //////for (x, y) in lhs_pc.clone() {
-        //////    println!("Arg: {}, idx: {}", x, y);
-        //////}
-        //////println!("\n******************************\n");
-
-
-        // Separate out only RHS cranelift insts
-        let rhs_clift_insts =
-            rhscliftinsts::get_result_clift_insts_only(
-                clift_insts.clone());
-
-        let rhs_info =
-            processrhs::update_rhs_with_argnames(
-                rhs_clift_insts.clone(), lhs_info.htable.clone());
-        // Debug
-        //////println!("= = = = = = hash table index_to_argnames = = = = =");
-        //////for (x, y) in rhs_info.full_table.clone() {
-        //////    println!("idx = {}, argname = {}", x, y);
-        //////}
-        // Debug
-        // println!("- - - -  - - - - - - -\n");
-        // for ri in rhs_clift_insts.clone() {
-        //     println!("rhs inst = {}\n",
-        //         cliftinstbuilder::get_clift_opcode_name(ri.opcode));
-        // }
-        // println!("- - - - - - -  - - - -\n");
-
-        //let hash_id = lhs_single_tree[lhs_single_tree.len() - 1].id;
-        let hash_id = lhs_info.nodes[lhs_info.nodes.len() - 1].id;
-
-        // Debug
-        //println!("hash id for LHS is: {}\n", hash_id);
+        // Cranelift has opcodes (ineg, bnot, iabs, ...) with no Souper
+        // counterpart, so build one LHS variant per equivalent form
+        let first_lhs_count = lhs_count;
+        let mut dropped_for_target = false;
+        for lhs_variant in cliftinstbuilder::expand_lhs_equivalents(clift_insts) {
+            // Pattern Matching - Single prefix tree
+            let lhs_single_tree = lhspatternmatcher::generate_single_tree_patterns(
+                lhs_variant.clone(),
+                global_nodes_count + 1,
+            );
 
-        rhs_table = tablerhs::map_lhs_to_rhs(hash_id, rhs_info.rhs_insts, rhs_table.clone());
+            global_nodes_count += lhs_single_tree.len();
 
-        // Debug
-        //////println!("\n********RHS TABLE Debugger **********************\n");
-        //////for (x, y) in rhs_table.clone() {
-        //////    println!("******* For LHS ID = {}, RHS is == \n", x);
-        //////    for n in y {
-        //////        println!("RHS inst in hash table = {}, ",
-        //////            cliftinstbuilder::get_clift_opcode_name(n.opcode));
-        //////        for o in n.cops {
-        //////            println!("RHS inst arg = {}", o);
-        //////        }
-        //////    }
-        //////}
-        //////println!("\n******************************\n");
-
-        if mode == "fast" {
-            // Merged prefix tree
-            merged_arena = mergedtree::generate_merged_prefix_tree(
-                //lhs_single_tree.clone(),
-                lhs_info.nodes.clone(),
-                merged_arena.clone(),
+            // Process linear prefix tree of LHS for updating arg names
+            // from parent instdata nodes to arg nodes
+            //////println!("\n---- ProcessLHS module: update arg names from parent to arg nodes\n");
+            let lhs_info = processlhs::update_arg_nodes_in_lhs(
+                lhs_single_tree.clone()
             );
+            //////println!("\n----- ProcessLHS module end\n");
 
-            // Debug: Pretty print the merged arena
-            // println!("----- nodes in merged_tree are -----");
-            // for n in 0 .. merged_arena.merged_tree.len() {
-            //     println!("Node id = {}",
-            //         merged_arena.merged_tree[n].id);
-            //     if let Some(sub_nodes) =
-            //         merged_arena.merged_tree[n].next.clone() {
-            //             for sub_node in 0 .. sub_nodes.len() {
-            //                 println!("\t\tSub Node id: {}",
-            //                     sub_nodes[sub_node].index);
-            //             }
-            //     } else {
-            //         continue;
-            //     }
-            // }
-
-            // match merged_arena.merged_tree[0].next.clone() {
-            //     Some(nodes_list) => {
-            //         for x in 0 .. nodes_list.len() {
-            //             println!("root's next = {}",
-            //                 nodes_list[x].index);
-            //         }
-            //     },
-            //     None => {},
-            // }
-            // println!("==== hashmap entries =====");
-            // for (val, idx) in &merged_arena.hmap {
-            //     println!("{}: {}", val, idx);
+            // Build path conditions hashtable for each LHS vector of nodes
+            let lhs_pc = pctable::get_path_condition_args_for_lhs(
+                lhs_info.nodes.clone()
+            );
+            //////println!("\n************* PC HASHTABLE *****************\n");
+            //////for (x, y) in lhs_pc.clone() {
+            //////    println!("Arg: {}, idx: {}", x, y);
+            //////}
+            //////println!("\n******************************\n");
+
+
+            // Separate out only RHS cranelift insts, lowered to the
+            // best single Cranelift opcode for each shape
+            let rhs_clift_insts =
+                rhslowering::lower_rhs_clift_insts(
+                    lhs_variant.clone());
+
+            // Some RHS opcodes are not cheap on every target, drop
+            // the rule if there is no equivalent sequence for them. All
+            // LHS variants share the RHS, so the rest go with it
+            let rhs_clift_insts =
+                match rhslowering::lower_rhs_for_target(rhs_clift_insts, &target) {
+                    Ok(insts) => insts,
+                    Err(why) => {
+                        println!("{} rule {}: dropped, {}", comment_prefix, peep_counter + 1, why);
+                        dropped_for_target = true;
+                        break;
+                    }
+                };
+
+            let rhs_info =
+                processrhs::update_rhs_with_argnames(
+                    rhs_clift_insts.clone(), lhs_info.htable.clone());
+            // Debug
+            //////println!("= = = = = = hash table index_to_argnames = = = = =");
+            //////for (x, y) in rhs_info.full_table.clone() {
+            //////    println!("idx = {}, argname = {}", x, y);
+            //////}
+            // Debug
+            // println!("- - - -  - - - - - - -\n");
+            // for ri in rhs_clift_insts.clone() {
+            //     println!("rhs inst = {}\n",
+            //         cliftinstbuilder::get_clift_opcode_name(ri.opcode));
             // }
-
-            // println!("========================");
+            // println!("- - - - - - -  - - - -\n");
+
+            //let hash_id = lhs_single_tree[lhs_single_tree.len() - 1].id;
+            let hash_id = lhs_info.nodes[lhs_info.nodes.len() - 1].id;
+
+            // The values have to be bound by the LHS to be compared
+            let checks = match matcher::get_leaf_checks(&lhs_pc, &stated_inequalities, &shift_guards, &rhs_info.full_table) {
+                Some(checks) => checks,
+                None => {
+                    println!(
+                        "{} rule {}: dropped, its path condition is on a value the LHS does not bind",
+                        comment_prefix, peep_counter + 1
+                    );
+                    continue;
+                }
+            };
+            let guard = matcher::generate_checks_guard(&checks, &*api);
+            if !checks.is_empty() {
+                check_table.insert(hash_id, checks);
+            }
+            path_condition_table.insert(hash_id, lhs_pc.clone());
+
+            // Debug
+            //println!("hash id for LHS is: {}\n", hash_id);
+
+            tablerhs::map_lhs_to_rhs(hash_id, rhs_info.rhs_insts, &mut rhs_table);
+
+            // Debug
+            //////println!("\n********RHS TABLE Debugger **********************\n");
+            //////for (x, y) in rhs_table.clone() {
+            //////    println!("******* For LHS ID = {}, RHS is == \n", x);
+            //////    for n in y {
+            //////        println!("RHS inst in hash table = {}, ",
+            //////            cliftinstbuilder::get_clift_opcode_name(n.opcode));
+            //////        for o in n.cops {
+            //////            println!("RHS inst arg = {}", o);
+            //////        }
+            //////    }
+            //////}
+            //////println!("\n******************************\n");
+
+            if mode == "baseline" || uses_merged_tree {
+                // Merged into a prefix tree in priority order once all
+                // the rules are in
+                ranked_rules.push(priority::RankedRule {
+                    number: peep_counter + 1,
+                    function: lhs_count,
+                    nodes: lhs_info.nodes.clone(),
+                    cost_reduction: priority::get_cost_reduction(&lhs_variant, &rhs_clift_insts),
+                    checked: check_table.contains_key(&hash_id),
+                });
+            }
+
+            if mode == "baseline" {
+                let base_matcher = baseline_matcher::generate_baseline_matcher(
+                    lhs_info.nodes.clone(),
+                    &rhs_table,
+                    lhs_count,
+                    lhs_info.htable.clone(),
+                    lhs_pc.clone(),
+                    guard,
+                    &*api
+                );
+                lhs_count += 1;
+                println!("{}", base_matcher);
+            }
+
+            if mode == "isle" {
+                // ISLE rules for the e-graph mid-end, skipping the
+                // rules that have no ISLE form
+                if let Some(isle_rule) = islerules::generate_isle_rule(
+                    &lhs_variant,
+                    &rhs_clift_insts,
+                    lhs_count,
+                    s) {
+                    lhs_count += 1;
+                    println!("{}", isle_rule);
+                }
+            }
+
+            if mode == "peepmatic" {
+                // Peepmatic has no bnot, ineg, ..., so fall back to the
+                // unlowered RHS when the lowered one uses them. It still
+                // has to be lowered for the target
+                let peep_rule = peepmaticrules::generate_peepmatic_rule(
+                    &lhs_variant,
+                    &rhs_clift_insts,
+                    lhs_count,
+                    s).or_else(|| {
+                        let unlowered = rhscliftinsts::get_result_clift_insts_only(lhs_variant.clone());
+                        let fallback = rhslowering::lower_rhs_for_target(unlowered, &target).ok()?;
+                        peepmaticrules::generate_peepmatic_rule(&lhs_variant, &fallback, lhs_count, s)
+                    });
+                if let Some(peep_rule) = peep_rule {
+                    lhs_count += 1;
+                    println!("{}", peep_rule);
+                }
+            }
+
+            if mode == "filetest" {
+                // One filetest function per rule, checking that the
+                // optimized body is the RHS
+                if let Some(filetest) = filetests::generate_filetest(
+                    &lhs_variant,
+                    &rhs_clift_insts,
+                    lhs_count,
+                    s,
+                    &*api) {
+                    lhs_count += 1;
+                    println!("{}", filetest);
+                }
+            }
         }
-
-        if mode == "baseline" {
-            let base_matcher = baseline_matcher::generate_baseline_matcher(
-                lhs_info.nodes.clone(),
-                rhs_table.clone(),
-                lhs_count,
-                lhs_info.htable.clone(),
-                lhs_pc.clone()
-            );
-            lhs_count += 1;
-            println!("{}", base_matcher);
+        if mode == "isle" && lhs_count == first_lhs_count && !dropped_for_target {
+            println!("{} rule {}: skipped, no LHS variant has an ISLE form", comment_prefix, peep_counter + 1);
+        }
+        if mode == "peepmatic" && lhs_count == first_lhs_count && !dropped_for_target {
+            println!("{} rule {}: skipped, no LHS variant has a peepmatic form", comment_prefix, peep_counter + 1);
         }
         peep_counter += 1;
     }
@@ -400,9 +767,141 @@ fn main() {
     //    }
     //}
 
+    // The order rules are tried in, and the merged tree that has it
+    let (rule_order, mut merged_arena) = priority::order_rules(&ranked_rules, rule_priority);
+    let ordered_rules: Vec<(u32, Vec<Node>)> = rule_order
+        .iter()
+        .map(|&n| (ranked_rules[n].number, ranked_rules[n].nodes.clone()))
+        .collect();
+
+    // Nodes of the LHSs whose action is at other nodes of the tree the
+    // matchers walk
+    let mut shared_nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+    if uses_merged_tree && decision_tree {
+        // the test orders are profiled on DAGs of their own, and then
+        // both trees are measured on the next ones, so the other modes
+        // still see the same DAGs
+        let mut rng = random::Random::new(seed);
+        let (tree, leaves) = decisiontree::build_decision_tree(
+            &ordered_rules,
+            global_nodes_count + 1,
+            &rhs_table,
+            &check_table.keys().cloned().collect(),
+            &mut rng);
+        // the action of a rule is at the leaves it ends at in the tree,
+        // and refers to the args by the names they have there
+        for leaf in &leaves {
+            if let Some(rhs) = rhs_table.get(&leaf.last) {
+                let rhs = decisiontree::rename_rhs(rhs, &leaf.args);
+                rhs_table.insert(leaf.id, rhs);
+            }
+            if let Some(checks) = check_table.get(&leaf.last) {
+                let checks = checks
+                    .iter()
+                    .map(|check| check.rename(|name| decisiontree::rename_args(name, &leaf.args)))
+                    .collect();
+                check_table.insert(leaf.id, checks);
+            }
+            if let Some(pcs) = path_condition_table.get(&leaf.last) {
+                let pcs = pcs.iter().map(|(pcarg, &idx)| (decisiontree::rename_args(pcarg, &leaf.args), idx)).collect();
+                path_condition_table.insert(leaf.id, pcs);
+            }
+        }
+        let checks = decisiontree::get_checks_per_inst(&[&merged_arena, &tree], &rhs_table, &ordered_rules, &mut rng);
+        println!(
+            "{} checks per inst: {:.2} with the prefix tree, {:.2} with the decision tree",
+            comment_prefix, checks[0], checks[1]
+        );
+        merged_arena = tree;
+        for leaf in &leaves {
+            shared_nodes.entry(leaf.last).or_default().push(leaf.id);
+        }
+    }
+
+    // Subtrees that are the same test and action under different
+    // parents are kept once
+    if uses_merged_tree {
+        let action_keys = matcher::get_action_keys(&rhs_table, &path_condition_table, &check_table, &*api);
+        let dropped = mergedtree::share_equal_subtrees(&mut merged_arena, &action_keys);
+        for id in shared_nodes.values_mut().flatten() {
+            if let Some(&canonical) = dropped.get(id) {
+                *id = canonical;
+            }
+        }
+        for (id, canonical) in dropped {
+            shared_nodes.entry(id).or_insert_with(|| vec![canonical]);
+        }
+    }
+
+    if mode == "baseline" {
+        let functions: Vec<u32> = rule_order.iter().map(|&n| ranked_rules[n].function).collect();
+        println!("{}", baseline_matcher::generate_baseline_driver(&functions));
+    }
+
     if mode == "fast" {
-        let matcher_func = matcher::generate_matcher(merged_arena.clone(), rhs_table.clone());
+        let matcher_func = matcher::generate_matcher(&merged_arena, &rhs_table, &path_condition_table, &check_table, &*api);
         // Print the final generated function
         println!("{}", matcher_func);
     }
+
+    if mode == "verify" {
+        println!("{}", sound_rules.join(&format!("\n{}\n", souper_delimiter)));
+        eprintln!("; {} rules checked, {} quarantined", peep_counter, quarantined);
+    }
+
+    if mode == "exhaustive" {
+        println!("; {} rules, {} verified, {} refuted", peep_counter, verified, quarantined);
+    }
+
+    if mode == "selfcheck" {
+        // The baseline matchers (one tree per rule, tried in priority
+        // order) and the fast one have to agree on random DAGs
+        let report = selfcheck::run_self_check(
+            &ordered_rules,
+            &merged_arena,
+            &shared_nodes,
+            &rhs_table,
+            &path_condition_table,
+            &check_table,
+            &mut rng);
+        for mismatch in &report.mismatches {
+            println!("; mismatch on v{} of\n{}", mismatch.value, mismatch.dfg);
+            println!("; baseline: {}", mismatch.baseline);
+            println!("; fast: {}", mismatch.fast);
+        }
+        println!(
+            "; {} LHSs, {} DAGs, {} insts, {} rewritten, {} mismatches",
+            ordered_rules.len(),
+            selfcheck::DAGS,
+            report.insts,
+            report.rewrites,
+            report.mismatches.len()
+        );
+    }
+
+    if mode == "automaton" {
+        // Same tree as 'fast', but as data for a fixed interpreter
+        println!("{}", automaton::generate_automaton(&merged_arena, &rhs_table, &path_condition_table, &check_table, &*api));
+    }
+
+    if mode == "interp" {
+        // Run the merged tree on the functions of a clif file instead
+        // of generating code for it
+        let clif_filename = match clif_filename {
+            Some(name) => name,
+            None => panic!("ERROR: mode 'interp' needs --clif=<file>"),
+        };
+        let mut clif_contents = String::new();
+        File::open(&clif_filename)
+            .expect("clif file not found")
+            .read_to_string(&mut clif_contents)
+            .expect("something went wrong reading the clif file");
+        let interp = interpreter::Interpreter::new(&merged_arena, &rhs_table, &path_condition_table, &check_table);
+        for func in clifreader::parse_clif(&clif_contents) {
+            let mut func_dfg = dfg::build_dfg_from_clif(&func);
+            let rewrites = interp.rewrite_function(&mut func_dfg);
+            println!("; {} rewrites", rewrites);
+            println!("{}", func_dfg.display());
+        }
+    }
 }
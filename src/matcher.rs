@@ -1,332 +1,309 @@
 // Matcher
-
-use cliftinstbuilder::{self};
-use lhspatternmatcher::{self, Node, NodeType};
+//
+// Emits a single function for the merged prefix tree. Every node that
+// tests something becomes an `if` (or `if let`) around the code of its
+// children, and every rule ends in its action and a `return`. When a node
+// below a branch does not match, control falls out of its block to the
+// next sibling, so a rule with a constant or a nested inst where another
+// has a var does not hide that other rule. Siblings come in the order of
+// `next`, most specific first (see `mergedtree::order_by_specificity`).
+//
+// Before its action, a rule checks that the values its LHS uses more
+// than once matched the same `Value` (its path condition table, see
+// `pctable`) and its other guards. When they don't hold, control falls
+// through to the next sibling like any other test.
+//
+// A subtree that more than one node leads to (see
+// `mergedtree::share_equal_subtrees`) is emitted once, as a function
+// `matcher_N` that returns whether it fired. It takes the bindings it
+// reads from the path above as parameters: `entity` and `operand` for
+// the inst and value the path was at, and the `arg_N`, `imm_N`, `opcode`
+// and `cond` it refers to.
+
+use cliftapi::CliftApi;
+use cliftinstbuilder::{self, CtonOpcode};
+use lhspatternmatcher::{Node, NodeType};
 use mergedtree::MergedArena;
+use pctable;
 use processrhs::CliftInstWithArgs;
-use std::collections::HashMap;
+use shiftsemantics::{self, ShiftGuard};
+use tablerhs;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-#[derive(Clone)]
 pub struct Opt {
-    current_entity: String,
     func_str: String,
-    scope_stack: Vec<ScopeStack>,
-    const_stack: Vec<String>,
-    const_table: HashMap<usize, String>,
 }
 
-#[derive(Clone)]
-pub struct ScopeStack {
-    scope_type: ScopeType,
-    level: usize,
+/// Work left while walking the tree
+enum Step {
+    /// Emit the test of a node, then its action or children
+    Node(usize, MatchPath),
+    /// Close the blocks a node opened, once its children are done
+    Close(usize),
+}
+
+/// Type of a binding a shared subtree reads from the path above
+#[derive(Clone, Copy, PartialEq)]
+enum ParamType {
+    Inst,
+    Opcode,
+    Cond,
+    Value,
+    Values,
+    Imm,
 }
 
+impl ParamType {
+    fn get_rust_type(self) -> &'static str {
+        match self {
+            ParamType::Inst => "Inst",
+            ParamType::Opcode => "Opcode",
+            ParamType::Cond => "IntCC",
+            ParamType::Value => "Value",
+            ParamType::Values => "&[Value]",
+            ParamType::Imm => "i64",
+        }
+    }
+}
+
+/// Bindings a subtree reads before it binds them itself, by name.
+/// `entity` and `operand` stand for the entity and operand of the path.
+type FreeNames = BTreeMap<String, ParamType>;
+
+/// Most bindings a shared subtree may take as parameters. One that reads
+/// more is emitted in place at each of its parents: passing everything
+/// the path bound costs about as much as the code it would save.
+const MAX_HELPER_PARAMS: usize = 5;
+
+/// What the tree walk looks things up in
+struct TreeTables<'a> {
+    nodes: &'a HashMap<usize, &'a Node>,
+    rhs: &'a HashMap<usize, Vec<CliftInstWithArgs>>,
+    guards: &'a HashMap<usize, String>,
+    helpers: &'a HashMap<usize, FreeNames>,
+    api: &'a dyn CliftApi,
+}
+
+/// What the nodes on the path to the current one have bound
 #[derive(Clone)]
-pub enum ScopeType {
-    ScopeMatch,
-    ScopeCase,
-    ScopeFunc,
+struct MatchPath {
+    // inst whose InstructionData the next nodes match, empty below a var
+    entity: String,
+    // value or immediate selected by the last MatchArgs node
+    operand: String,
 }
 
 impl Opt {
     pub fn new() -> Opt {
         Opt {
-            current_entity: String::from("inst"),
             func_str: String::from(""),
-            scope_stack: Vec::new(),
-            const_stack: Vec::new(),
-            const_table: HashMap::new(),
         }
     }
 
     pub fn generate_header(&mut self) {
         self.func_str
-            .push_str("fn matcher(pos: &mut FuncCursor, inst: Inst)");
+            .push_str("fn matcher(pos: &mut FuncCursor, inst: Inst) -> bool");
     }
 
     pub fn append(&mut self, input: String) {
         self.func_str.push_str(&input);
     }
 
-    pub fn set_entity(&mut self, entity: String) {
-        self.current_entity = entity;
-    }
-
-    pub fn does_level_exist_in_stack(&mut self, find_level: usize) -> usize {
-        let mut index = 0;
-        for i in 0..self.scope_stack.len() {
-            if find_level == self.scope_stack[i].level {
-                index = i;
-                break;
-            } else {
-                continue;
-            }
-        }
-        index
-    }
-
-    pub fn pop_and_exit_scope_from(&mut self, from: usize) {
-        for _ in from..self.scope_stack.len() {
-            if let Some(elem) = self.scope_stack.pop() {
-                self.exit_scope(elem.scope_type, elem.level);
-            }
-        }
-    }
-
-    pub fn get_const_arg_from_hash_table(&mut self, level: usize) -> String {
-        match self.const_table.get(&level) {
-            Some(rhs) => rhs.to_string(),
-            _ => "".to_string(),
-        }
-    }
-
-    pub fn add_const_arg_to_hash_table(&mut self, arg: String, level: usize) {
-        self.const_table.insert(level, arg);
+    /// Opens the block that runs when `cond` holds
+    pub fn open_block(&mut self, cond: String) {
+        self.append(cond);
+        self.append(String::from(" {\n"));
     }
 
-    pub fn push_to_const_stack(&mut self, rhs: String) {
-        self.const_stack.push(rhs);
-    }
-
-    pub fn pop_from_const_stack(&mut self) -> String {
-        match self.const_stack.pop() {
-            Some(rhs) => rhs.to_string(),
-            None => "".to_string(),
-        }
-    }
-
-    pub fn enter_scope(&mut self, scope: ScopeType, current_level: usize) {
-        // check for the level in current stack
-        // if level is new - not found in stack - push it directly.
-        // if level already exists in stack, pop the stack until that level and then
-        // push the new level.
-        //println!("Current stack before entering scope is: -------");
-        //for x in 0 .. self.scope_stack.len() {
-        //println!("stack levels pushed so far = {}", self.scope_stack[x].level);
-        //}
-        //println!("find the level number = {} in stack", current_level);
-        let index = self.does_level_exist_in_stack(current_level);
-        //println!("Found index from stack == {}", index);
-        if index != 0 {
-            // index exists
-            // pop first
-            self.pop_and_exit_scope_from(index);
-        }
-        // push the level
-        self.scope_stack.push(ScopeStack {
-            scope_type: scope.clone(),
-            level: current_level,
-        });
-        // append the string
-        match scope {
-            ScopeType::ScopeMatch => {
-                self.append(String::from(" {\n"));
-            }
-            ScopeType::ScopeFunc => {
-                self.append(String::from(" {\n"));
-            }
-            ScopeType::ScopeCase => {
-                self.append(String::from(" => {\n"));
-            }
-        }
-    }
-
-    pub fn exit_scope(&mut self, scope: ScopeType, _level: usize) {
-        match scope {
-            ScopeType::ScopeMatch => {
-                self.append(String::from("\n}"));
-            }
-            ScopeType::ScopeFunc => {
-                self.append(String::from("\n}"));
-            }
-            ScopeType::ScopeCase => {
-                self.append(String::from("\n},"));
-            }
-        }
-    }
-
-    #[allow(dead_code)]
-    pub fn is_leaf_node(&mut self, node: Node) -> bool {
-        //println!("check leaf node =========\n\n");
-        node.next.is_none()
-    }
-
-    pub fn set_level_of_all_child_nodes(
-        &mut self,
-        arena: &mut MergedArena,
-        n: usize,
-        current: usize,
-    ) {
-        if let Some(next_nodes) = arena.merged_tree[n].next.clone() {
-            for n in 0..next_nodes.len() {
-                let id = next_nodes[n].index;
-                let next_node = arena.find_node_with_id_in_arena(id);
-                let updated_node = arena.update_node_with_level(next_node.clone(), current + 1);
-                arena.update_node_level_in_arena(updated_node.clone());
-            }
-        }
-    }
-
-//    pub fn take_action(&mut self, rhs: Vec<CliftInstWithArgs>) {
-//        // Special Case: what to do for RHS with 1 inst only?
-//        // For example: result 20:i32 (it simply returns a constant)
-//        if rhs.len() == 1 {
-//            let each_inst = rhs[0].clone();
-//            let mut rhs_const: i32 = 0;
-//
-//            match each_inst.cops {
-//                Some(ops) => {
-//                    for op in ops {
-//                        match op.const_val {
-//                            Some(c) => {
-//                                rhs_const = c;
-//                            }
-//                            None => {}
-//                        }
-//                    }
-//                }
-//                None => {}
-//            }
-//
-//            let mut replace_inst_str = "pos.func.dfg.replace(".to_owned();
-//            // FIXME: fix the inst name here
-//            replace_inst_str += &"inst".to_owned();
-//            replace_inst_str += &").".to_owned();
-//            replace_inst_str += &"iconst(".to_owned();
-//            // FIXME: fix the width part
-//            // Note: Width is not set in CliftInst operands
-//            // for constant type, only const_val exists.
-//            // Only parser has the correct width for constant, see
-//            // how you pass that information further to CliftInst struct
-//            replace_inst_str += &"width".to_owned();
-//            replace_inst_str += &", ".to_owned();
-//            replace_inst_str += &rhs_const.to_string();
-//            replace_inst_str += &"); ".to_owned();
-//            self.func_str.push_str(&replace_inst_str);
-//        } else {
-//            for inst in 0..rhs.len() - 2 {
-//                let each_inst = rhs[inst].clone();
-//                let mut insert_inst_str = "let inst".to_owned();
-//                insert_inst_str += &inst.to_string();
-//                insert_inst_str += &" = pos.ins().".to_owned();
-//                insert_inst_str += &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode);
-//                insert_inst_str += &"(".to_owned();
-//                // FIXME: fix the args names and count of args here
-//                insert_inst_str += &"args[0], args[1]".to_owned();
-//                insert_inst_str += &");\n".to_owned();
-//                self.func_str.push_str(&insert_inst_str);
-//            }
-//            for inst in rhs.len() - 2..rhs.len() - 1 {
-//                let each_inst = rhs[inst].clone();
-//                let mut replace_inst_str = "pos.func.dfg.replace(".to_owned();
-//                // FIXME: fix the inst name here
-//                replace_inst_str += &"inst".to_owned();
-//                replace_inst_str += &").".to_owned();
-//                replace_inst_str += &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode);
-//                replace_inst_str += &"(".to_owned();
-//                // FIXME: fix the args names and count of args here
-//                replace_inst_str += &"args[0], args[1]".to_owned();
-//                replace_inst_str += &");\n".to_owned();
-//                self.func_str.push_str(&replace_inst_str);
-//            }
-//        }
-//    }
-    pub fn take_action(&mut self, rhs: Vec<CliftInstWithArgs>) {
-        for i in 0..rhs.len() {
-            println!("** Inst = {}\n", cliftinstbuilder::get_clift_opcode_name(rhs[i].opcode.clone()));
+    pub fn take_action(&mut self, rhs: Vec<CliftInstWithArgs>, guard: Option<&String>, api: &dyn CliftApi) {
+        // Repeated values, and the other conditions the rule needs
+        if let Some(guard) = guard {
+            self.open_block(format!("if {}", guard));
         }
         let mut replace_inst_str = "".to_owned();
         if rhs.len() == 1 {
             let each_inst = rhs[0].clone();
-            replace_inst_str += &"pos.func.dfg.replace(".to_owned();
-            // FIXME: fix the inst name here
-            replace_inst_str += &"inst".to_owned();
-            replace_inst_str += &").".to_owned();
-            replace_inst_str += &"iconst(".to_owned();
-            // FIXME: fix the width part
-            // Note: Width is not set in CliftInst operands
-            // for constant type, only const_val exists.
-            // Only parser has the correct width for constant, see
-            // how you pass that information further to CliftInst struct
-            replace_inst_str += &"width".to_owned();
-            replace_inst_str += &", ".to_owned();
-
-            // FIX: Add the rhs.cops vector string - should be one element only.
-            for i in 0..each_inst.cops.len() {
-                if i > 0 {
-                    replace_inst_str += &", ".to_owned();
-                }
-                replace_inst_str += &each_inst.cops[i].to_owned();
+            if each_inst.cops[0].contains("arg") | each_inst.cops[0].contains("rhs") {
+                replace_inst_str += &api.alias_result(&each_inst.cops[0]);
+                replace_inst_str += "\n";
+            } else {
+                replace_inst_str += "pos.func.dfg.replace(inst).";
+                replace_inst_str += &api.const_inst(each_inst.width, &each_inst.cops[0]);
+                replace_inst_str += ";\n";
             }
-            replace_inst_str += &"); ".to_owned();
             self.func_str.push_str(&replace_inst_str);
         } else {
             for inst in 0..rhs.len() - 2 {
                 let each_inst = rhs[inst].clone();
 
-                let mut insert_inst_str = "let inst".to_owned();
-                insert_inst_str += &inst.to_string();
-                insert_inst_str += &" = pos.ins().".to_owned();
-                insert_inst_str += &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode);
-                insert_inst_str += &"(".to_owned();
-
-                // FIX: Insert the rhs.cops string args list
-                for i in 0..each_inst.cops.len() {
-                    if i > 0 {
-                        replace_inst_str += &", ".to_owned();
+                let mut insert_inst_str = "let rhs_inst_".to_owned();
+                insert_inst_str += &each_inst.lhs_index.to_string();
+                insert_inst_str += " = pos.ins().";
+                match each_inst.opcode.clone() {
+                    CtonOpcode::Iconst => {
+                        insert_inst_str += &api.const_inst(each_inst.width, &each_inst.cops[0]);
+                        insert_inst_str += ";\n";
+                    }
+                    _ => {
+                        insert_inst_str += &api.builder_method(
+                            &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode));
+                        insert_inst_str += "(";
+                        insert_inst_str += &each_inst.cops.join(", ");
+                        insert_inst_str += ");\n";
                     }
-                    replace_inst_str += &each_inst.cops[i].to_owned();
                 }
-
-                insert_inst_str += &");\n".to_owned();
                 self.func_str.push_str(&insert_inst_str);
             }
             for inst in rhs.len() - 2..rhs.len() - 1 {
                 let each_inst = rhs[inst].clone();
 
-                let mut replace_inst_str = "pos.func.dfg.replace(".to_owned();
-                // FIXME: fix the inst name here
-                replace_inst_str += &"inst".to_owned();
-                replace_inst_str += &").".to_owned();
-                replace_inst_str += &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode);
-                replace_inst_str += &"(".to_owned();
-
-                // FIX: Insert args list of rhs inst
-                for i in 0..each_inst.cops.len() {
-                    if i > 0 {
-                        replace_inst_str += &", ".to_owned();
-                    }
-                    replace_inst_str += &each_inst.cops[i].to_owned();
-                }
-
-                replace_inst_str += &");\n".to_owned();
+                let mut replace_inst_str = "pos.func.dfg.replace(inst).".to_owned();
+                replace_inst_str += &api.builder_method(
+                    &cliftinstbuilder::get_clift_opcode_name(each_inst.opcode));
+                replace_inst_str += "(";
+                replace_inst_str += &each_inst.cops.join(", ");
+                replace_inst_str += ");\n";
                 self.func_str.push_str(&replace_inst_str);
             }
         }
+        // done with this inst, don't fall through to other rules
+        self.func_str.push_str("return true;\n");
+        if guard.is_some() {
+            self.func_str.push_str("}\n");
+        }
     }
 
-
-    pub fn get_argument_counter(&mut self, mut count: u32) -> u32 {
-        count = count + 1;
-        self.append(String::from(count.to_string()));
-        count
-    }
-
-    pub fn get_const_counter(&mut self, mut count: u32) -> u32 {
-        count = count + 1;
-        count
+    /// Emits the test of every node below `start` and, inside it, its
+    /// action or children, and a call for the nodes in `helpers`. Rules
+    /// can nest a thousand tests deep, so this keeps its own stack
+    /// instead of recursing.
+    fn generate_tree(&mut self, start: usize, path: MatchPath, tables: &TreeTables) {
+        let api = tables.api;
+        let mut steps = vec![Step::Node(start, path)];
+        while let Some(step) = steps.pop() {
+            let (id, mut path) = match step {
+                Step::Node(id, path) => (id, path),
+                Step::Close(blocks) => {
+                    for _ in 0..blocks {
+                        self.append(String::from("}\n"));
+                    }
+                    continue;
+                }
+            };
+            if let (true, Some(params)) = (id != start, tables.helpers.get(&id)) {
+                // a shared subtree, that falls through when it doesn't fire
+                let mut call_args = vec![String::from("pos"), String::from("inst")];
+                for (name, param_type) in params {
+                    call_args.push(match (name.as_ref(), param_type) {
+                        ("entity", _) => path.entity.clone(),
+                        ("operand", _) => path.operand.clone(),
+                        (_, ParamType::Values) => format!("&{}", name),
+                        _ => name.clone(),
+                    });
+                }
+                self.open_block(format!("if matcher_{}({})", id, call_args.join(", ")));
+                self.append(String::from("return true;\n}\n"));
+                continue;
+            }
+            let node = match tables.nodes.get(&id) {
+                Some(node) => *node,
+                None => continue,
+            };
+            let mut blocks = 0;
+            match node.node_type {
+                // the root is the function itself, and the node after
+                // the others does the test
+                NodeType::MatchRoot | NodeType::MatchInstData | NodeType::MatchOpcode |
+                NodeType::MatchCond | NodeType::MatchPlainConst => {}
+                NodeType::InstType => match node.node_value.as_ref() {
+                    "Var" => {}
+                    kind => {
+                        let pattern = match api.inst_data_pattern(kind) {
+                            Some(pattern) => pattern,
+                            None => panic!("Error: This instruction data type is not yet handled"),
+                        };
+                        self.open_block(format!("if let {} = {}", pattern, api.inst_data(&path.entity)));
+                        blocks += 1;
+                        // bind the operands under the arg name the RHS uses
+                        match kind {
+                            "Binary" | "IntCompare" | "Ternary" => {
+                                self.append(format!("let {} = args;\n", node.arg_name));
+                            }
+                            "Unary" | "BinaryImm" | "IntCompareImm" => {
+                                self.append(format!("let {} = arg;\n", node.arg_name));
+                            }
+                            _ => {}
+                        }
+                        if let "UnaryImm" | "BinaryImm" | "IntCompareImm" = kind {
+                            self.append(format!("let {} : i64 = {};\n", get_imm_name(&node.arg_name), api.imm_to_i64()));
+                        }
+                    }
+                },
+                NodeType::MatchArgs => {
+                    path.operand = match node.node_value.as_ref() {
+                        "imm" => get_imm_name(&node.arg_name),
+                        arg => match arg.find('[') {
+                            Some(i) => format!("{}{}", node.arg_name, &arg[i..]),
+                            None => node.arg_name.clone(),
+                        },
+                    };
+                }
+                NodeType::MatchValDef => match node.node_value.as_ref() {
+                    // Var leaves are wildcards: they match a value defined
+                    // by either a block param or another instruction
+                    "Any" => path.entity = String::from(""),
+                    "Result" => {
+                        self.open_block(format!(
+                            "if let ValueDef::Result(arg_ty, _) = pos.func.dfg.value_def({})",
+                            path.operand
+                        ));
+                        blocks += 1;
+                        path.entity = String::from("arg_ty");
+                    }
+                    _ => {
+                        // FIXME - do we want error handling here for NoneType and ""
+                    }
+                },
+                NodeType::Opcode => match node.node_value.as_ref() {
+                    "Var" => {}
+                    opcode => match api.opcode_pattern(opcode) {
+                        Some(pattern) => {
+                            self.open_block(format!("if opcode == {}", pattern));
+                            blocks += 1;
+                        }
+                        None => panic!("Error: this opcode type is not yet handled"),
+                    },
+                },
+                NodeType::Cond => match node.node_value.as_ref() {
+                    "eq" | "ne" | "ult" | "ule" | "slt" | "sle" => {
+                        self.open_block(format!("if cond == {}", get_cond_name(node.node_value.clone())));
+                        blocks += 1;
+                    }
+                    _ => panic!("Error: this condition type is not yet handled"),
+                },
+                NodeType::MatchConst => {
+                    // FIXME: fix width of the constant in rhs part
+                    self.open_block(format!("if {} == {}", path.operand, node.node_value));
+                    blocks += 1;
+                }
+                NodeType::MatchNone => panic!("\n\nmatch type not handled yet!\n"),
+            }
+            steps.push(Step::Close(blocks));
+            match tables.rhs.get(&id) {
+                Some(found_rhs) => self.take_action(found_rhs.to_vec(), tables.guards.get(&id), api),
+                None => {
+                    for child in node.next.iter().flatten().rev() {
+                        steps.push(Step::Node(child.index, path.clone()));
+                    }
+                }
+            }
+        }
     }
 }
 
-pub fn is_node_actionable(
-    node_id: usize,
-    table: HashMap<usize, Vec<CliftInstWithArgs>>
-) -> bool {
-    if table.contains_key(&node_id) {
-        true
-    } else {
-        false
-    }
+/// Name the immediate of the inst bound to `arg_name` is read into
+fn get_imm_name(arg_name: &str) -> String {
+    arg_name.replacen("arg", "imm", 1)
 }
 
 #[allow(dead_code)]
@@ -360,484 +337,394 @@ pub fn get_cond_name(cmp: String) -> String {
     cond
 }
 
+/// A condition an action checks besides its path condition table, on
+/// the names the LHS binds values to
+#[derive(Clone, Debug, PartialEq)]
+pub enum LeafCheck {
+    /// The two values differ, as the path condition of the rule states
+    Differ(String, String),
+    /// The value is an `iconst` in `0..width`, for a shift amount Souper
+    /// and Cranelift only agree on in range (see `shiftsemantics`)
+    ShiftAmount(String, u32),
+}
+
+impl LeafCheck {
+    /// The same check on the names `rename` gives the values
+    pub fn rename<F: Fn(&str) -> String>(&self, rename: F) -> LeafCheck {
+        match self {
+            LeafCheck::Differ(a, b) => LeafCheck::Differ(rename(a), rename(b)),
+            LeafCheck::ShiftAmount(value, width) => LeafCheck::ShiftAmount(rename(value), *width),
+        }
+    }
+
+    /// Rust condition that holds when the check does
+    pub fn generate_guard(&self, api: &dyn CliftApi) -> String {
+        match self {
+            LeafCheck::Differ(a, b) => format!("{} != {}", a, b),
+            LeafCheck::ShiftAmount(value, width) => shiftsemantics::generate_shift_guard(value, *width, api),
+        }
+    }
+}
+
+/// The checks of a rule on the names its LHS binds in `pcs`: that the
+/// values its path condition states are different (by their index in
+/// the rule) are, and its shift amounts, which `names` gives the names
+/// of. None if the LHS does not bind a value an inequality is on.
+pub fn get_leaf_checks(
+    pcs: &HashMap<String, usize>,
+    inequalities: &[(usize, usize)],
+    shift_guards: &[ShiftGuard],
+    names: &HashMap<usize, String>,
+) -> Option<Vec<LeafCheck>> {
+    let mut checks = Vec::new();
+    for &(a, b) in inequalities {
+        let a = pctable::get_arg_of_value(pcs, a)?;
+        let b = pctable::get_arg_of_value(pcs, b)?;
+        checks.push(LeafCheck::Differ(a, b));
+    }
+    for guard in shift_guards {
+        checks.push(LeafCheck::ShiftAmount(names[&guard.value].clone(), guard.width));
+    }
+    Some(checks)
+}
+
+/// The checks of an action as one Rust condition, or `None` if there
+/// are none
+pub fn generate_checks_guard(checks: &[LeafCheck], api: &dyn CliftApi) -> Option<String> {
+    if checks.is_empty() {
+        return None;
+    }
+    let guards: Vec<String> = checks.iter().map(|check| check.generate_guard(api)).collect();
+    Some(guards.join(" && "))
+}
+
+/// The conditions checked before each action: the equalities of its
+/// path condition table `pcs`, then its checks in `checks`
+pub fn get_leaf_guards(
+    pcs: &HashMap<usize, HashMap<String, usize>>,
+    checks: &HashMap<usize, Vec<LeafCheck>>,
+    api: &dyn CliftApi,
+) -> HashMap<usize, String> {
+    let mut leaf_guards: HashMap<usize, String> = HashMap::new();
+    for (&id, table) in pcs {
+        if let Some(guard) = pctable::generate_equality_guard(table) {
+            leaf_guards.insert(id, guard);
+        }
+    }
+    for (&id, checks) in checks {
+        let guard = match generate_checks_guard(checks, api) {
+            Some(guard) => guard,
+            None => continue,
+        };
+        let leaf_guard = leaf_guards.entry(id).or_default();
+        if !leaf_guard.is_empty() {
+            *leaf_guard += " && ";
+        }
+        *leaf_guard += &guard;
+    }
+    leaf_guards
+}
+
+/// A key for the action of every node that has one, the same for two
+/// nodes when their RHS and the guards before it are (see
+/// `mergedtree::share_equal_subtrees`)
+pub fn get_action_keys(
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &HashMap<usize, HashMap<String, usize>>,
+    checks: &HashMap<usize, Vec<LeafCheck>>,
+    api: &dyn CliftApi,
+) -> HashMap<usize, String> {
+    let leaf_guards = get_leaf_guards(pcs, checks, api);
+    rhs.iter()
+        .map(|(&id, rhs)| {
+            let guard = leaf_guards.get(&id).map_or("", |guard| guard.as_str());
+            (id, format!("{} if {}", tablerhs::get_rhs_key(rhs), guard))
+        })
+        .collect()
+}
+
 pub fn generate_matcher(
-    mut arena: MergedArena,
-    rhs: HashMap<usize, Vec<CliftInstWithArgs>>
+    arena: &MergedArena,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &HashMap<usize, HashMap<String, usize>>,
+    checks: &HashMap<usize, Vec<LeafCheck>>,
+    api: &dyn CliftApi
 ) -> String {
     let mut opt_func = Opt::new();
-    let mut arg_str = String::from("");
-    let mut arg_counter: u32 = 0;
-    let mut const_counter: u32 = 0;
-
-    for node in 0..arena.merged_tree.len() {
-        let action_flag = is_node_actionable(arena.merged_tree[node].id, rhs.clone());
-        // dump: begin
-        println!("Node ==== ===================================");
-        println!("\t\t Node Id = {}", arena.merged_tree[node].id);
-        println!("\t\t Actionable? = {}", action_flag);
-        match arena.merged_tree[node].clone().var_id {
-            Some(x) => {
-                println!("Var number = {}", x);
-            },
-            None => {
-                println!("Var number = None\n");
-            },
+    let guards = &get_leaf_guards(pcs, checks, api);
+
+    let nodes: HashMap<usize, &Node> = arena.merged_tree.iter().map(|node| (node.id, node)).collect();
+    let helpers = get_helpers(&nodes, rhs, guards);
+    let path = MatchPath {
+        entity: String::from("inst"),
+        operand: String::from(""),
+    };
+    opt_func.generate_header();
+    opt_func.open_block(String::from(""));
+    let tables = TreeTables {
+        nodes: &nodes,
+        rhs,
+        guards,
+        helpers: &helpers,
+        api,
+    };
+    opt_func.generate_tree(0, path, &tables);
+    // no rule matched
+    opt_func.append(String::from("false\n}\n"));
+
+    let mut helper_ids: Vec<&usize> = helpers.keys().collect();
+    helper_ids.sort();
+    for &id in helper_ids {
+        let params = &helpers[&id];
+        let mut decls = vec![String::from("pos: &mut FuncCursor"), String::from("inst: Inst")];
+        decls.extend(params.iter().map(|(name, param_type)| format!("{}: {}", name, param_type.get_rust_type())));
+        let path = MatchPath {
+            entity: if params.contains_key("entity") { String::from("entity") } else { String::from("") },
+            operand: if params.contains_key("operand") { String::from("operand") } else { String::from("") },
+        };
+        opt_func.append(format!("\nfn matcher_{}({}) -> bool", id, decls.join(", ")));
+        opt_func.open_block(String::from(""));
+        opt_func.generate_tree(id, path, &tables);
+        opt_func.append(String::from("false\n}\n"));
+    }
+    opt_func.func_str
+}
+
+/// Adds a binding a subtree reads to `free`, or gives up on the subtree
+/// (`None`) if it reads the same name as two different types
+fn add_free_name(free: &mut Option<FreeNames>, name: String, param_type: ParamType) {
+    let conflict = match free {
+        Some(names) => *names.entry(name).or_insert(param_type) != param_type,
+        None => false,
+    };
+    if conflict {
+        *free = None;
+    }
+}
+
+/// The `arg_N`, `arg_N[i]` and `imm_N` names an RHS operand or a guard
+/// refers to
+fn get_names_in(expr: &str) -> Vec<(String, ParamType)> {
+    let mut names = Vec::new();
+    let bytes = expr.as_bytes();
+    let mut start = 0;
+    while start < bytes.len() {
+        let at_word = start == 0 || !(bytes[start - 1].is_ascii_alphanumeric() || bytes[start - 1] == b'_');
+        let prefix = &expr[start..];
+        if at_word && (prefix.starts_with("arg_") || prefix.starts_with("imm_")) {
+            let digits = prefix[4..].bytes().take_while(|b| b.is_ascii_digit()).count();
+            if digits > 0 {
+                let name = prefix[..4 + digits].to_string();
+                let param_type = if name.starts_with("imm_") {
+                    ParamType::Imm
+                } else if prefix[4 + digits..].starts_with('[') {
+                    ParamType::Values
+                } else {
+                    ParamType::Value
+                };
+                names.push((name, param_type));
+                start += 4 + digits;
+                continue;
+            }
         }
-        println!(
-            "\t\t Node Type = {}",
-            lhspatternmatcher::get_node_type(arena.merged_tree[node].clone().node_type)
-        );
-        println!("\t\t Node Level = {}", arena.merged_tree[node].level);
-        println!("\t\t Node Value = {}", arena.merged_tree[node].node_value);
-        match arena.merged_tree[node].next.clone() {
-            Some(ids) => {
-                for i in 0..ids.len() {
-                    println!("\t\t Node->next = {}", ids[i].index);
-                }
+        start += 1;
+    }
+    names
+}
+
+/// Bindings of the path above that the code of `node` reads, and the
+/// ones it makes for the nodes below it
+fn get_node_reads_and_binds(node: &Node) -> (Vec<(String, ParamType)>, Vec<String>) {
+    let mut reads = Vec::new();
+    let mut binds = Vec::new();
+    match node.node_type {
+        NodeType::InstType if node.node_value != "Var" => {
+            reads.push((String::from("entity"), ParamType::Inst));
+            binds.push(String::from("opcode"));
+            binds.push(String::from("cond"));
+            if !node.arg_name.is_empty() {
+                binds.push(node.arg_name.clone());
+                binds.push(get_imm_name(&node.arg_name));
             }
-            None => println!("No next\n"),
         }
-        match arena.merged_tree[node].idx_num {
-            Some(idx) => {
-                println!("Node op idx num = {}", idx);
-            },
-            None => {
-                println!("Node op idx num = NONE");
-            },
+        NodeType::Opcode if node.node_value != "Var" => reads.push((String::from("opcode"), ParamType::Opcode)),
+        NodeType::Cond => reads.push((String::from("cond"), ParamType::Cond)),
+        NodeType::MatchArgs => {
+            reads.push(match node.node_value.as_ref() {
+                "imm" => (get_imm_name(&node.arg_name), ParamType::Imm),
+                "arg" => (node.arg_name.clone(), ParamType::Value),
+                _ => (node.arg_name.clone(), ParamType::Values),
+            });
+            binds.push(String::from("operand"));
         }
-        println!("Node arg name = {}", arena.merged_tree[node].arg_name);
-        // dump: end
-        match arena.merged_tree[node].node_type {
-            NodeType::MatchRoot => {
-                opt_func.generate_header();
-                let current_level = arena.merged_tree[node].level;
-                opt_func.enter_scope(ScopeType::ScopeFunc, current_level);
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
-            }
-            NodeType::MatchInstData => {
-                let current_level = arena.merged_tree[node].level;
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-
-                let opt_clone = opt_func.clone();
-                let ent = opt_clone.current_entity;
-                if !ent.is_empty() {
-                    opt_func.append(String::from("match pos.func.dfg"));
-                    opt_func.append(String::from("["));
-                    // FIXME: Connect this ent string with RHS replacement part
-                    opt_func.append(ent);
-                    opt_func.append(String::from("]"));
-                    opt_func.enter_scope(ScopeType::ScopeMatch, current_level);
-                }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
-            }
-            NodeType::InstType => {
-                let current_level = arena.merged_tree[node].level;
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                // Check if there is any child node already matched at same level
-                // If yes, pop and exit scope first, and then enter into new matching case
-                let index = opt_func.does_level_exist_in_stack(current_level);
-                if index != 0 {
-                    opt_func.pop_and_exit_scope_from(index);
-                }
-                match arena.merged_tree[node].node_value.as_ref() {
-                    "Var" => {}
-                    "Binary" => {
-                        // FIXME: "args" part, make a connection between actual args and string
-                        opt_func.append(String::from("InstructionData::Binary { opcode, args }"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("opcode"));
-                        // FIXED: Generate: "let args_<counter> = args;"
-                        opt_func.append(String::from("let args_"));
-                        arg_counter = opt_func.get_argument_counter(arg_counter);
-                        opt_func.append(String::from(" = args;\n"));
-                    }
-                    "IntCompare" => {
-                        // FIXME: "args" part, make a connection
-                        // between actual args and string
-                        opt_func.append(String::from(
-                            "InstructionData::IntCompare { opcode, cond, args }",
-                        ));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("opcode"));
-                        // FIXED: Generate: "let args_<counter> = args;"
-                        opt_func.append(String::from("let args_"));
-                        arg_counter = opt_func.get_argument_counter(arg_counter);
-                        opt_func.append(String::from(" = args;\n"));
-                    }
-                    "Unary" => {
-                        // FIXME: "arg" part, make a connection b/w actual args and string
-                        opt_func.append(String::from("InstructionData::Unary { opcode, arg }"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("opcode"));
-                        // FIXED: Generate: "let args_<counter> = arg;"
-                        opt_func.append(String::from("let args_"));
-                        arg_counter = opt_func.get_argument_counter(arg_counter);
-                        opt_func.append(String::from(" = arg;\n"));
-                    }
-                    "UnaryImm" => {
-                        opt_func.append(String::from("InstructionData::UnaryImm { opcode, imm }"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("opcode"));
-                        const_counter = opt_func.get_const_counter(const_counter);
-                        let mut rhs_arg = "rhs_".to_string();
-                        rhs_arg.push_str(&const_counter.to_string());
-                        opt_func.append(String::from("let "));
-                        opt_func.append(String::from(rhs_arg.to_string()));
-                        opt_func.append(String::from(" : i64 = imm.into();\n"));
-                        opt_func.push_to_const_stack(rhs_arg.to_string());
-                    }
-                    "BinaryImm" => {
-                        // FIXME: "args" part, make a connection between actual args and string
-                        opt_func.append(String::from(
-                            "InstructionData::BinaryImm { opcode, arg, imm }",
-                        ));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("opcode"));
-                        // FIXED: Generate: "let args_<counter> = args;"
-                        opt_func.append(String::from("let args_"));
-                        arg_counter = opt_func.get_argument_counter(arg_counter);
-                        opt_func.append(String::from(" = arg;\n"));
-                        // Push the rhs_<count> to ConstStack
-                        const_counter = opt_func.get_const_counter(const_counter);
-                        let mut rhs_arg = "rhs_".to_string();
-                        rhs_arg.push_str(&const_counter.to_string());
-                        opt_func.append(String::from("let "));
-                        opt_func.append(String::from(rhs_arg.to_string()));
-                        opt_func.append(String::from(" : i64 = imm.into();\n"));
-                        opt_func.push_to_const_stack(rhs_arg.to_string());
-                    }
-                    "IntCompareImm" => {
-                        // FIXME: "args" part, make a connection
-                        // between actual args and string
-                        opt_func.append(String::from(
-                            "InstructionData::IntCompareImm { opcode, cond, arg, imm }",
-                        ));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("opcode"));
-                        // FIXED: Generate: "let args_<counter> = args;"
-                        opt_func.append(String::from("let args_"));
-                        arg_counter = opt_func.get_argument_counter(arg_counter);
-                        opt_func.append(String::from(" = arg;\n"));
-                        // Push the rhs_<count> to ConstStack
-                        const_counter = opt_func.get_const_counter(const_counter);
-                        let mut rhs_arg = "rhs_".to_string();
-                        rhs_arg.push_str(&const_counter.to_string());
-                        opt_func.append(String::from("let "));
-                        opt_func.append(String::from(rhs_arg.to_string()));
-                        opt_func.append(String::from(" : i64 = imm.into();\n"));
-                        opt_func.push_to_const_stack(rhs_arg.to_string());
-                    }
-                    _ => {
-                        panic!("Error: This instruction data type is not yet handled");
-                    }
-                }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
+        NodeType::MatchValDef => {
+            if node.node_value == "Result" {
+                reads.push((String::from("operand"), ParamType::Value));
             }
-            NodeType::MatchValDef => {
-                let current_level = arena.merged_tree[node].level;
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                // Check if there is any child node already matched at same level
-                // If yes, pop and exit scope first, and then enter into new matching case
-                let index = opt_func.does_level_exist_in_stack(current_level);
-                if index != 0 {
-                    opt_func.pop_and_exit_scope_from(index);
-                }
-                match arena.merged_tree[node].node_value.as_ref() {
-                    "Param" => {
-                        // Reset the argument match string to empty str
-                        // so that for further args, it's not appended.
-                        arg_str = String::from("");
-                        opt_func.set_entity(String::from(""));
-                    }
-                    "Result" => {
-                        // for Result type, we want to generate arg match part,
-                        // so, append it.
-                        opt_func.append(arg_str.clone());
-                        arg_str = String::from("");
-                        opt_func.enter_scope(ScopeType::ScopeMatch, current_level - 1);
-                        opt_func.append(String::from("\nValueDef::"));
-                        opt_func.append(String::from("Result(arg_ty, _)"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                        opt_func.set_entity(String::from("arg_ty"));
-                    }
-                    _ => {
-                        // FIXME - do we want error handling here for NoneType and ""
-                        println!("\t\t entering unknown valdef case\n");
-                    }
-                }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
-            }
-            NodeType::MatchOpcode => {
-                let current_level = arena.merged_tree[node].level;
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                let opt_clone = opt_func.clone();
-                let ent = opt_clone.current_entity;
-                // FIXME: Any purpose of ent here?
-                if !ent.is_empty() {
-                    opt_func.append(String::from("match opcode"));
-                    opt_func.enter_scope(ScopeType::ScopeMatch, current_level);
-                }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
+            binds.push(String::from("entity"));
+        }
+        NodeType::MatchConst => reads.push((String::from("operand"), ParamType::Imm)),
+        _ => {}
+    }
+    (reads, binds)
+}
+
+/// The nodes the walk from the root reaches through more than one
+/// parent and that are big enough to be worth a function, with the
+/// bindings each reads from above, if there are few enough of them
+fn get_helpers(
+    nodes: &HashMap<usize, &Node>,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    guards: &HashMap<usize, String>,
+) -> HashMap<usize, FreeNames> {
+    // parents of every node the walk reaches, which stops at an action,
+    // and the nodes children first
+    let mut parents: HashMap<usize, usize> = HashMap::new();
+    let mut started: HashSet<usize> = HashSet::new();
+    let mut order = Vec::new();
+    let mut stack = vec![(0, false)];
+    while let Some((id, children_done)) = stack.pop() {
+        if children_done {
+            order.push(id);
+            continue;
+        }
+        if !started.insert(id) {
+            continue;
+        }
+        stack.push((id, true));
+        if rhs.contains_key(&id) {
+            continue;
+        }
+        for child in nodes.get(&id).iter().flat_map(|node| node.next.iter().flatten()) {
+            *parents.entry(child.index).or_insert(0) += 1;
+            if !started.contains(&child.index) {
+                stack.push((child.index, false));
             }
-            NodeType::Opcode => {
-                //println!("\t\tIn specific opcode case in matcher\n");
-                let current_level = arena.merged_tree[node].level;
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                // Check if there is any child node already matched at same level
-                // If yes, pop and exit scope first, and then enter into new matching case
-                let index = opt_func.does_level_exist_in_stack(current_level);
-                if index != 0 {
-                    opt_func.pop_and_exit_scope_from(index);
-                }
-                // match the actual opcode types
-                match arena.merged_tree[node].node_value.as_ref() {
-                    "Var" => {}
-                    "iadd" => {
-                        opt_func.append(String::from("Opcode::Iadd"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "iadd_imm" => {
-                        opt_func.append(String::from("Opcode::IaddImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "imul" => {
-                        opt_func.append(String::from("Opcode::Imul"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "imul_imm" => {
-                        opt_func.append(String::from("Opcode::ImulImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "isub" => {
-                        opt_func.append(String::from("Opcode::Isub"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "irsub_imm" => {
-                        opt_func.append(String::from("Opcode::IsubImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "icmp" => {
-                        opt_func.append(String::from("Opcode::Icmp"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "icmp_imm" => {
-                        opt_func.append(String::from("Opcode::IcmpImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "band" => {
-                        opt_func.append(String::from("Opcode::Band"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "band_imm" => {
-                        opt_func.append(String::from("Opcode::BandImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bor" => {
-                        opt_func.append(String::from("Opcode::Bor"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bor_imm" => {
-                        opt_func.append(String::from("Opcode::BorImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bxor" => {
-                        opt_func.append(String::from("Opcode::Bxor"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "bxor_imm" => {
-                        opt_func.append(String::from("Opcode::BxorImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ishl" => {
-                        opt_func.append(String::from("Opcode::Ishl"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ishl_imm" => {
-                        opt_func.append(String::from("Opcode::IshlImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "sshr" => {
-                        opt_func.append(String::from("Opcode::Sshr"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "sshr_imm" => {
-                        opt_func.append(String::from("Opcode::SshrImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ushr" => {
-                        opt_func.append(String::from("Opcode::Ushr"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ushr_imm" => {
-                        opt_func.append(String::from("Opcode::UshrImm"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "popcnt" => {
-                        opt_func.append(String::from("Opcode::Popcnt"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "clz" => {
-                        opt_func.append(String::from("Opcode::Clz"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "ctz" => {
-                        opt_func.append(String::from("Opcode::Ctz"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    "iconst" => {
-                        opt_func.append(String::from("Opcode::Iconst"));
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    _ => {
-                        panic!("Error: this opcode type is not yet handled");
+        }
+    }
+
+    let mut free_names: HashMap<usize, Option<FreeNames>> = HashMap::new();
+    for &id in &order {
+        let node = match nodes.get(&id) {
+            Some(node) => *node,
+            None => continue,
+        };
+        let mut free = Some(FreeNames::new());
+        match rhs.get(&id) {
+            Some(rhs_insts) => {
+                for rhs_inst in rhs_insts {
+                    for op in &rhs_inst.cops {
+                        for (name, param_type) in get_names_in(op) {
+                            add_free_name(&mut free, name, param_type);
+                        }
                     }
                 }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
+                for (name, param_type) in guards.get(&id).map_or(Vec::new(), |guard| get_names_in(guard)) {
+                    add_free_name(&mut free, name, param_type);
                 }
             }
-            NodeType::MatchCond => {
-                let current_level = arena.merged_tree[node].level;
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                let opt_clone = opt_func.clone();
-                let ent = opt_clone.current_entity;
-                // FIXME: Any purpose of ent here?
-                if !ent.is_empty() {
-                    opt_func.append(String::from("match cond"));
-                    opt_func.enter_scope(ScopeType::ScopeMatch, current_level);
-                }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
-            }
-            NodeType::Cond => {
-                let current_level = arena.merged_tree[node].level;
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                // Check if there is any child node already
-                // matched at same level
-                // If yes, pop and exit scope first, and
-                // then enter into new matching case
-                let index = opt_func.does_level_exist_in_stack(current_level);
-                if index != 0 {
-                    opt_func.pop_and_exit_scope_from(index);
-                }
-                // match the actual opcode types
-                match arena.merged_tree[node].node_value.as_ref() {
-                    "eq" | "ne" | "ult" | "ule" | "slt" | "sle" => {
-                        let cond = get_cond_name(arena.merged_tree[node].clone().node_value);
-                        opt_func.append(cond);
-                        opt_func.enter_scope(ScopeType::ScopeCase, current_level);
-                    }
-                    _ => {
-                        panic!("Error: this condition type is not yet handled");
+            None => {
+                for child in node.next.iter().flatten() {
+                    match free_names.get(&child.index) {
+                        Some(Some(names)) => {
+                            for (name, &param_type) in names {
+                                add_free_name(&mut free, name.clone(), param_type);
+                            }
+                        }
+                        _ => free = None,
                     }
                 }
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
             }
-            NodeType::MatchArgs => {
-                let current_level = arena.merged_tree[node].level;
-                //set the level of root->next nodes to 0+1
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                // Create an optional argument matching string here
-                // we will decide later whether we need this match on args or not
-                // depending on if the argument type is Result or Param. Param
-                // type does not need this match part at all.
-                arg_str.push_str(&(String::from("match pos.func.dfg.value_def")));
-                arg_str.push_str(&(String::from("(")));
-                // make string like: args_2 or args_2[0] depending on binaryImm or binary
-                let arg_node_val = arena.merged_tree[node].node_value.clone();
-                arg_str.push_str(&(String::from("args_")));
-                arg_str.push_str(&(String::from(arg_counter.to_string())));
-                if let Some(i) = arg_node_val.find('[') {
-                    arg_str.push_str(&(arena.merged_tree[node].node_value.clone())[i..]);
-                }
-                arg_str.push_str(&(String::from(")")));
-                // FIXME: Do we want to take action here and should we
-                // append to arg_str, or opt_func?
-            }
-            NodeType::MatchPlainConst => {
-                let current_level = arena.merged_tree[node].level;
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
+        }
+        let (reads, binds) = get_node_reads_and_binds(node);
+        if let Some(ref mut names) = free {
+            for name in &binds {
+                names.remove(name);
             }
-            NodeType::MatchConst => {
-                let current_level = arena.merged_tree[node].level;
-                opt_func.set_level_of_all_child_nodes(&mut arena, node, current_level);
-                let index = opt_func.does_level_exist_in_stack(current_level);
-                if index != 0 {
-                    opt_func.pop_and_exit_scope_from(index);
-                }
-                let const_value = &arena.merged_tree[node].node_value;
-                // FIXME: fix width of the constant in rhs part
-                // Check Cranelift's instructions specifications
-
-                let mut rhs_arg = opt_func.get_const_arg_from_hash_table(current_level);
-                match rhs_arg.as_ref() {
-                    "" => {
-                        rhs_arg = opt_func.pop_from_const_stack();
-                        opt_func.add_const_arg_to_hash_table(rhs_arg.clone(), current_level);
-                        opt_func.append(String::from("if "));
-                    }
-                    _ => {
-                        opt_func.append(String::from("else if "));
-                    }
-                }
-                opt_func.append(String::from(rhs_arg.to_string()));
-                opt_func.append(String::from(" == "));
-                opt_func.append(const_value.to_string());
-                opt_func.enter_scope(ScopeType::ScopeFunc, current_level);
-                if action_flag {
-                    let found_rhs = &rhs[&arena.merged_tree[node].id];
-                    opt_func.take_action(found_rhs.to_vec());
-                }
+        }
+        for (name, param_type) in reads {
+            add_free_name(&mut free, name, param_type);
+        }
+        free_names.insert(id, free);
+    }
+
+    // roughly a line of code per node; a call or the frame of a function
+    // take about three, so a copy has to be bigger than that to save
+    // anything
+    let mut helpers: HashMap<usize, FreeNames> = HashMap::new();
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for &id in &order {
+        let size = match (nodes.get(&id), rhs.get(&id)) {
+            (_, Some(rhs_insts)) => 1 + rhs_insts.len(),
+            (Some(node), None) => {
+                1 + node
+                    .next
+                    .iter()
+                    .flatten()
+                    .map(|child| if helpers.contains_key(&child.index) { 3 } else { sizes.get(&child.index).cloned().unwrap_or(0) })
+                    .sum::<usize>()
             }
-            _ => {
-                panic!("\n\nmatch type not handled yet!\n");
+            (None, None) => 0,
+        };
+        sizes.insert(id, size);
+        let count = parents.get(&id).cloned().unwrap_or(0);
+        if let (true, Some(Some(names))) = (count > 1 && (count - 1) * size > 3 * count + 3, free_names.get(&id)) {
+            if names.len() <= MAX_HELPER_PARAMS {
+                helpers.insert(id, names.clone());
             }
         }
     }
+    helpers
+}
 
-    // exit func scope
-    while let Some(elem) = opt_func.scope_stack.pop() {
-        opt_func.exit_scope(elem.scope_type, elem.level);
-        //let elem_ty = opt_func.scope_stack.pop();
-        //match elem_ty {
-        //    Some(ty) => {
-        //        opt_func.exit_scope(ty);
-        //    },
-        //    None => {},
-        //}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cliftapi;
+    use mergedtree;
+    use testrules::compile;
+
+    /// Emits the matcher for the rules with equal subtrees shared
+    fn emit_shared(rules: &[String]) -> String {
+        let rules: Vec<&str> = rules.iter().map(|r| r.as_str()).collect();
+        let mut rules = compile(&rules);
+        let api = cliftapi::get_default_clift_api();
+        let keys = get_action_keys(&rules.rhs, &rules.pcs, &rules.checks, &*api);
+        mergedtree::share_equal_subtrees(&mut rules.arena, &keys);
+        generate_matcher(&rules.arena, &rules.rhs, &rules.pcs, &rules.checks, &*api)
     }
 
-    opt_func.func_str
+    /// One rule per opcode, all sharing the subtree below that opcode
+    fn one_per_opcode(rule: &str) -> Vec<String> {
+        ["add", "or", "xor"].iter().map(|op| rule.replace("OP", op)).collect()
+    }
+
+    #[test]
+    fn equal_subtrees_become_one_helper() {
+        let code = emit_shared(&one_per_opcode(
+            "%0:i32 = var\n%1:i32 = var\n%2:i32 = var\n\
+             %3:i32 = mul %0, %1\n%4:i32 = mul %1, %2\n%5:i32 = OP %3, %4\n\
+             %6:i32 = sub %5, %0\n%7:i32 = add %6, %2\ninfer %7\n~>\nresult %2\n",
+        ));
+        assert_eq!(code.matches("fn matcher_").count(), 1);
+        let start = code.find("fn matcher_").unwrap() + 3;
+        let name = &code[start..start + code[start..].find('(').unwrap()];
+        assert_eq!(code.matches(&format!("if {}(", name)).count(), 3);
+        assert_eq!(code.matches("Opcode::Imul").count(), 2);
+    }
+
+    #[test]
+    fn wide_subtrees_stay_inline() {
+        // The shared subtree reads the args of the five nodes above it
+        // and of its own root, one more than a helper may take
+        let code = emit_shared(&one_per_opcode(
+            "%0:i32 = var\n%1:i32 = var\n%2:i32 = var\n%3:i32 = var\n%4:i32 = var\n%5:i32 = var\n\
+             %6:i32 = mul %4, %5\n%7:i32 = mul %3, %6\n%8:i32 = mul %2, %7\n%9:i32 = mul %0, %1\n\
+             %10:i32 = OP %9, %8\n%11:i32 = add %10, %1\n%12:i32 = sub %11, %2\n\
+             %13:i32 = add %12, %3\n%14:i32 = sub %13, %4\n%15:i32 = add %14, %5\n\
+             infer %15\n~>\nresult %0\n",
+        ));
+        assert!(!code.contains("fn matcher_"));
+        assert_eq!(code.matches("Opcode::Imul").count(), 3 * 4);
+    }
 }
@@ -1,16 +1,27 @@
 // Merged prefix tree
 
-use lhspatternmatcher::{Node, NodeID, NodeType};
-use std::collections::HashMap;
+use lhspatternmatcher::{self, Node, NodeID, NodeSlot, NodeType};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 
 #[derive(Clone)]
 pub struct MergedArena {
     pub merged_tree: Vec<Node>,
     pub hmap: HashMap<String, usize>,
+    // where the node with an id is in `merged_tree`
+    slots: HashMap<usize, NodeSlot>,
 }
 
 impl MergedArena {
-    pub fn build_root_node(&mut self) -> Node {
+    pub fn new() -> MergedArena {
+        MergedArena {
+            merged_tree: Vec::new(),
+            hmap: HashMap::new(),
+            slots: HashMap::new(),
+        }
+    }
+
+    pub fn build_root_node(&self) -> Node {
         Node {
             node_type: NodeType::MatchRoot,
             node_value: "root".to_string(),
@@ -25,192 +36,75 @@ impl MergedArena {
         }
     }
 
-    pub fn get_top_node_of_opt_pattern(&mut self, opt: Vec<Node>) -> Node {
-        if opt.len() != 0 {
-            opt[0].clone()
-        } else {
-            panic!("Error: optimization pattern has no nodes")
-        }
-    }
-
-    pub fn get_value_of_node(&mut self, node: Node) -> String {
-        node.node_value
-    }
-
-    pub fn get_id_of_node(&mut self, node: Node) -> usize {
-        node.id
-    }
-
     pub fn update_hash_map(&mut self, value: String, id: usize) {
         self.hmap.insert(value, id);
     }
 
-    pub fn update_next_nodes_list(&mut self, mut node: Node, next_id: usize) -> Node {
-        if let Some(mut node_next_list) = node.next {
-            node_next_list.push(NodeID { index: next_id });
-            node.next = Some(node_next_list);
-            node
-        } else {
-            panic!("Error: Node's next list is None, should be atleast an empty vec<NodeID>");
-        }
-    }
-
-    #[allow(dead_code)]
-    pub fn update_node_with_arg_flag(&mut self, mut node: Node, val: bool) -> Node {
-        node.arg_flag = val;
-        node
-    }
-
-    pub fn update_node_with_level(&mut self, mut node: Node, level: usize) -> Node {
-        node.level = level;
-        node
-    }
-
     // add a new node to arena
     pub fn add_node_to_arena(&mut self, node: Node) {
+        // ids are unique, but if one repeats the first node keeps it
+        let slot = NodeSlot::new(self.merged_tree.len());
+        self.slots.entry(node.id).or_insert(slot);
         self.merged_tree.push(node);
     }
 
-    // when the node exists in arena already, update it
-    pub fn update_node_in_arena(&mut self, updated_node: Node) {
-        for n in 0..self.merged_tree.len() {
-            if self.merged_tree[n].id == updated_node.id {
-                self.merged_tree[n].next = updated_node.next;
-                break;
-            }
-        }
-    }
-
-    #[allow(dead_code)]
-    pub fn update_node_arg_flag_in_arena(&mut self, updated_node: Node) {
-        for n in 0..self.merged_tree.len() {
-            if self.merged_tree[n].id == updated_node.id {
-                self.merged_tree[n].arg_flag = updated_node.arg_flag;
-                break;
-            }
-        }
+    pub fn get_slot_of_id(&self, node_id: usize) -> Option<NodeSlot> {
+        self.slots.get(&node_id).cloned()
     }
 
-    pub fn update_node_level_in_arena(&mut self, updated_node: Node) {
-        for n in 0..self.merged_tree.len() {
-            if self.merged_tree[n].id == updated_node.id {
-                self.merged_tree[n].level = updated_node.level;
-                break;
-            }
-        }
+    pub fn get_node_with_id(&self, node_id: usize) -> Option<&Node> {
+        self.slots.get(&node_id).map(|slot| &self.merged_tree[slot.index()])
     }
 
-    pub fn init_dummy_node(&mut self) -> Node {
-        Node {
-            node_type: NodeType::MatchNone,
-            node_value: "dummy".to_string(),
-            width: 0,
-            id: <usize>::max_value(),
-            var_id: None,
-            arg_flag: false,
-            level: 0,
-            next: None,
-            idx_num: None,
-            arg_name: "".to_string(),
-        }
+    pub fn node(&self, slot: NodeSlot) -> &Node {
+        &self.merged_tree[slot.index()]
     }
 
-    pub fn find_node_with_id_in_arena(&mut self, node_id: usize) -> Node {
-        // FIXME: not a good implementation to traverse and exit if id is found
-        let mut found_node = self.init_dummy_node();
-        for n in 0..self.merged_tree.len() {
-            if self.merged_tree[n].id == node_id {
-                found_node = self.merged_tree[n].clone();
-                break;
-            } else {
-                continue;
-            }
-        }
-        found_node
+    // connect the node in `slot` to the node with id `next_id`
+    pub fn add_next_of_node(&mut self, slot: NodeSlot, next_id: usize) {
+        self.merged_tree[slot.index()]
+            .next
+            .get_or_insert_with(Vec::new)
+            .push(NodeID { index: next_id });
     }
 
-    pub fn node_has_any_connection(&mut self, node: Node) -> bool {
-        let mut connections = false;
-        if let Some(nodes_list) = node.next {
-            if nodes_list.len() == 0 {
-                connections = false;
-            } else {
-                connections = true;
-            }
+    // merged tree's nodes may have more than one next nodes
+    pub fn get_next_slots(&self, slot: NodeSlot) -> Vec<NodeSlot> {
+        match self.merged_tree[slot.index()].next {
+            Some(ref next) => next.iter().filter_map(|child| self.get_slot_of_id(child.index)).collect(),
+            None => Vec::new(),
         }
-        connections
     }
 
-    pub fn is_node_value_already_in_hash_map(&mut self, val: String) -> bool {
-        if self.hmap.contains_key(&val) {
-            true
-        } else {
-            false
+    // when the node exists in arena already, update it
+    #[allow(dead_code)]
+    pub fn update_node_arg_flag_in_arena(&mut self, updated_node: &Node) {
+        if let Some(slot) = self.get_slot_of_id(updated_node.id) {
+            self.merged_tree[slot.index()].arg_flag = updated_node.arg_flag;
         }
     }
 
-    pub fn get_next_node_of_single_tree(&mut self, single_tree: Vec<Node>, current: Node) -> Node {
-        let mut ret_node = self.init_dummy_node();
-        if self.node_has_any_connection(current.clone()) {
-            if let Some(nodes_list) = current.next {
-                // assuming these are always linear trees with one next entry
-                let next_id = nodes_list[0].index;
-                for n in 0..single_tree.len() {
-                    if next_id == single_tree[n].id {
-                        ret_node = single_tree[n].clone();
-                        break;
-                    }
-                }
-            }
+    #[allow(dead_code)]
+    pub fn update_node_level_in_arena(&mut self, updated_node: &Node) {
+        if let Some(slot) = self.get_slot_of_id(updated_node.id) {
+            self.merged_tree[slot.index()].level = updated_node.level;
         }
-        ret_node
     }
 
-    // merged tre's nodes may have more than one next nodes
-    pub fn get_next_node_of_merged_tree(&mut self, current: Node) -> Vec<Node> {
-        let mut ret_nodes: Vec<Node> = Vec::new();
-        if self.node_has_any_connection(current.clone()) {
-            if let Some(nodes_list) = current.next {
-                for n in 0..nodes_list.len() {
-                    let next_id = nodes_list[n].index;
-                    ret_nodes.push(self.find_node_with_id_in_arena(next_id));
-                }
-            }
-        } else {
-            ret_nodes.push(self.init_dummy_node());
+    pub fn node_has_any_connection(&self, slot: NodeSlot) -> bool {
+        match self.merged_tree[slot.index()].next {
+            Some(ref next) => !next.is_empty(),
+            None => false,
         }
-        ret_nodes
     }
 
-    pub fn is_node_dummy(&mut self, node: Node) -> bool {
-        if node.node_value == "dummy".to_string() {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn add_node_in_arena(&mut self, node: Node) {
-        self.merged_tree.push(node);
-    }
-
-    pub fn are_node_values_same(&mut self, node1: Node, node2: Node) -> bool {
+    pub fn are_node_values_same(node1: &Node, node2: &Node) -> bool {
         if node1.node_value == node2.node_value {
             // specific case for Var type nodes
             // compare the width of vars first
             // then, compare the var_id (b/c all vars are given var_number while parsing)
-            if node1.node_value == "Var".to_string() {
-                if node1.width == node2.width {
-                    if node1.var_id == node2.var_id {
-                        //println!("************Yayy!! Matched the variables here ************\n");
-                        true
-                    } else {
-                        //println!("************Noo!! Didn't Match the variables here ************\n");
-                        false
-                    }
-                } else {
-                    false
-                }
+            if node1.node_value == "Var" {
+                node1.width == node2.width && node1.var_id == node2.var_id
             } else {
                 true
             }
@@ -220,175 +114,300 @@ impl MergedArena {
     }
 }
 
-pub fn generate_merged_prefix_tree(
-    single_tree: Vec<Node>,
-    mut merged_arena: MergedArena,
-) -> MergedArena {
-    if merged_arena.merged_tree.len() == 0 {
+/// Merges a single tree into the merged tree. The nodes that make the
+/// same tests as a path of the merged tree are shared, the rest is added
+/// as a new branch where the two first differ. `checked` has the last
+/// nodes of the LHSs whose action checks more than the LHS (see
+/// `matcher::LeafCheck`): an LHS that is the same as one of those gets
+/// its own last node after it, tried when those checks fail.
+pub fn generate_merged_prefix_tree(single_tree: &[Node], merged_arena: &mut MergedArena, checked: &HashSet<usize>) {
+    if merged_arena.merged_tree.is_empty() {
         let root_node = merged_arena.build_root_node();
         merged_arena.add_node_to_arena(root_node);
     }
 
-    let top_node = merged_arena.get_top_node_of_opt_pattern(single_tree.clone());
-    let top_val = merged_arena.get_value_of_node(top_node.clone());
-    let top_id = merged_arena.get_id_of_node(top_node.clone());
-
-    let found_root = merged_arena.find_node_with_id_in_arena(0);
-    if merged_arena.is_node_dummy(found_root.clone()) {
-        panic!("Error: the node is expected to be found in merged arena");
+    let top_node = match single_tree.first() {
+        Some(node) => node,
+        None => panic!("Error: optimization pattern has no nodes"),
+    };
+    let root = match merged_arena.get_slot_of_id(0) {
+        Some(slot) => slot,
+        None => panic!("Error: the node is expected to be found in merged arena"),
+    };
+
+    let found_id = if merged_arena.node_has_any_connection(root) {
+        merged_arena.hmap.get(&top_node.node_value).cloned()
+    } else {
+        None
+    };
+    let found_id = match found_id {
+        Some(id) => id,
+        None => {
+            // no tree starts with this top node yet, the whole single
+            // tree goes under the root
+            merged_arena.update_hash_map(top_node.node_value.clone(), top_node.id);
+            merged_arena.add_next_of_node(root, top_node.id);
+            for node in single_tree {
+                merged_arena.add_node_to_arena(node.clone());
+            }
+            return;
+        }
+    };
+
+    // single trees are linear, each node has (at most) one next node
+    let position: HashMap<usize, usize> = single_tree
+        .iter()
+        .enumerate()
+        .map(|(n, node)| (node.id, n))
+        .collect();
+    let next_in_single_tree = |n: usize| {
+        single_tree[n]
+            .next
+            .as_ref()
+            .and_then(|next| next.first())
+            .and_then(|child| position.get(&child.index).cloned())
+    };
+
+    // tracking points in the merged tree and in the single tree
+    let mut mtrack = match merged_arena.get_slot_of_id(found_id) {
+        Some(slot) => slot,
+        None => panic!("Error: the node is expected to be found in merged arena, since its also added in hashmap"),
+    };
+    let mut mparent = root;
+    let mut strack = 0;
+    // if this reaches the end of the single tree, the pattern is already
+    // in the merged tree
+    while let Some(snext) = next_in_single_tree(strack) {
+        // the last of them, an LHS after a checked one has its own copy
+        let same = merged_arena
+            .get_next_slots(mtrack)
+            .into_iter()
+            .filter(|&child| MergedArena::are_node_values_same(merged_arena.node(child), &single_tree[snext]))
+            .last();
+        match same {
+            Some(child) => {
+                mparent = mtrack;
+                mtrack = child;
+                strack = snext;
+            }
+            None => {
+                // the rest of the single tree is a new branch here
+                merged_arena.add_next_of_node(mtrack, single_tree[snext].id);
+                let mut rest = Some(snext);
+                while let Some(n) = rest {
+                    merged_arena.add_node_to_arena(single_tree[n].clone());
+                    rest = next_in_single_tree(n);
+                }
+                return;
+            }
+        }
     }
+    if checked.contains(&merged_arena.node(mtrack).id) {
+        add_fallback_leaf(&single_tree[strack], mparent, merged_arena);
+    }
+}
 
-    if !merged_arena.node_has_any_connection(found_root.clone()) {
-        // case 1
-        //println!("No connection of root node ------------");
-        merged_arena.update_hash_map(top_val, top_id);
-        let updated_root = merged_arena.update_next_nodes_list(found_root.clone(), top_id);
-        merged_arena.update_node_in_arena(updated_root);
-        for n in 0..single_tree.len() {
-            merged_arena.add_node_to_arena(single_tree[n].clone());
-        }
-    } else {
-        //println!("found connection of root node ------------");
-        //println!("current val for opt2 = {}", top_val);
-        if merged_arena.is_node_value_already_in_hash_map(top_val.clone()) {
-            // case 2
-            //println!("Hash key found");
+/// Adds the last node of an LHS after the same node of a checked LHS,
+/// under their parent
+fn add_fallback_leaf(last: &Node, parent: NodeSlot, merged_arena: &mut MergedArena) {
+    merged_arena.add_next_of_node(parent, last.id);
+    merged_arena.add_node_to_arena(last.clone());
+}
 
-            // get the id of existing hashkey
-            let found_id = merged_arena.hmap[&top_val];
+/// Whether a child of a branch only matches some of what its siblings
+/// can: a value defined by an inst (a `Result` valdef), or a var that was
+/// bound further up and has to be the same value again. A fresh var
+/// (`Any`) matches anything.
+fn is_specific(node: &Node, bound_vars: &[u32]) -> bool {
+    match node.node_type {
+        NodeType::MatchValDef => node.node_value != "Any",
+        NodeType::InstType => node.node_value != "Var" || node.var_id.is_some_and(|var| bound_vars.contains(&var)),
+        _ => true,
+    }
+}
 
-            // find the node with id = found_id in arena
-            // set the mered tree tracking point
-            let mut mtrack = merged_arena.find_node_with_id_in_arena(found_id);
-            if merged_arena.is_node_dummy(found_root.clone()) {
-                panic!("Error: the node is expected to be found in merged arena, since its also added in hashmap");
+/// Puts the children of every node in the order the matchers should try
+/// them, specific before general, and otherwise in rule order. The
+/// matchers back off to the next child when one fails, so the first rule
+/// in depth-first order that matches an inst is the most specific one.
+pub fn order_by_specificity(merged_arena: &mut MergedArena) {
+    let root = match merged_arena.get_slot_of_id(0) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let mut stack: Vec<(NodeSlot, Vec<u32>)> = vec![(root, Vec::new())];
+    while let Some((slot, mut bound_vars)) = stack.pop() {
+        if let (NodeType::InstType, Some(var)) = (&merged_arena.node(slot).node_type, merged_arena.node(slot).var_id) {
+            if !bound_vars.contains(&var) {
+                bound_vars.push(var);
             }
+        }
+        let mut next = match merged_arena.merged_tree[slot.index()].next.take() {
+            Some(next) => next,
+            None => continue,
+        };
+        // stable, so siblings that are as specific keep the rule order
+        next.sort_by_key(|child| match merged_arena.get_node_with_id(child.index) {
+            Some(node) => !is_specific(node, &bound_vars),
+            None => true,
+        });
+        for child in &next {
+            if let Some(child_slot) = merged_arena.get_slot_of_id(child.index) {
+                stack.push((child_slot, bound_vars.clone()));
+            }
+        }
+        merged_arena.merged_tree[slot.index()].next = Some(next);
+    }
+}
 
-            // set a previous tracking point in merged tree,
-            // that allows to add new connections
-            let mut prev = mtrack.clone();
-
-            // keep a tracker for nodes in single_tree (current optimization pattern)
-            let mut strack = top_node;
+/// Ids of the nodes that have an action, in the order the matchers reach
+/// them. The rule of the first one whose LHS matches is the one that fires.
+pub fn get_action_order<T>(merged_arena: &MergedArena, actions: &HashMap<usize, T>) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut stack = vec![0];
+    while let Some(id) = stack.pop() {
+        // the matchers stop at an action
+        if actions.contains_key(&id) {
+            order.push(id);
+        } else if let Some(next) = merged_arena.get_node_with_id(id).and_then(|node| node.next.as_ref()) {
+            stack.extend(next.iter().rev().map(|child| child.index));
+        }
+    }
+    order
+}
 
-            // Loop and compare the merged-tree nodes values with single-tree nodes vals
-            // one-by-one and, make a decision of when to append the nodes from given
-            // single-tree optimization pattern
-            loop {
-                //////////////////////// TASK 1
-                strack =
-                    merged_arena.get_next_node_of_single_tree(single_tree.clone(), strack.clone());
-                let merged_next_nodes =
-                    merged_arena.get_next_node_of_merged_tree(mtrack.clone());
+/// Adds a single tree to the merged tree, sharing only a prefix of the
+/// path that was added last. The matchers then reach the actions in the
+/// order the trees were added, which is what a fixed rule order needs;
+/// `generate_merged_prefix_tree` shares more but can reorder rules.
+/// `checked` is as for that function.
+pub fn append_prefix_tree_in_order(single_tree: &[Node], merged_arena: &mut MergedArena, checked: &HashSet<usize>) {
+    if merged_arena.merged_tree.is_empty() {
+        let root_node = merged_arena.build_root_node();
+        merged_arena.add_node_to_arena(root_node);
+    }
 
-                // check for dummy nodes in single/merged tree
+    // follow the last child as long as it is the same test
+    let mut parent = NodeSlot::new(0);
+    let mut prev = parent;
+    let mut shared = 0;
+    while shared < single_tree.len() {
+        let last = merged_arena
+            .node(prev)
+            .next
+            .as_ref()
+            .and_then(|next| next.last())
+            .and_then(|child| merged_arena.get_slot_of_id(child.index));
+        match last {
+            Some(slot) if MergedArena::are_node_values_same(merged_arena.node(slot), &single_tree[shared]) => {
+                parent = prev;
+                prev = slot;
+                shared += 1;
+            }
+            _ => break,
+        }
+    }
+    if shared == single_tree.len() {
+        // the same LHS was added before, and wins unless its action is
+        // checked
+        if checked.contains(&merged_arena.node(prev).id) {
+            add_fallback_leaf(&single_tree[shared - 1], parent, merged_arena);
+        }
+        return;
+    }
 
-                // if merged tree got a dummy next node, its going to be the only one
-                // in this merged_next_nodes: vec<Node> list
+    merged_arena.add_next_of_node(prev, single_tree[shared].id);
+    for node in &single_tree[shared..] {
+        merged_arena.add_node_to_arena(node.clone());
+    }
+}
 
-                //////////////////////// TASK 2
-                if merged_arena.is_node_dummy(merged_next_nodes[0].clone())
-                    && !merged_arena.is_node_dummy(strack.clone())
-                {
-                    //
-                    //
-                    //  update prev->next with strack.id
-                    //  update prev node in arena
-                    //  loop {
-                    //   if strack is dummy {
-                    //       break;
-                    //   }
-                    //   add_to_arena(strack);
-                    //   strack = get_next_in_single_tree();
-                    //  }
-                    //
-                    //
-                    prev = merged_arena.update_next_nodes_list(prev, strack.id);
-                    merged_arena.update_node_in_arena(prev.clone());
-                    loop {
-                        if merged_arena.is_node_dummy(strack.clone()) {
-                            break;
-                        } else {
-                            merged_arena.add_node_in_arena(strack.clone());
-                            strack = merged_arena
-                                .get_next_node_of_single_tree(single_tree.clone(), strack.clone());
-                            continue;
-                        }
-                    }
-                }
+/// What makes two nodes the same test: everything but the id, with the
+/// children (already shared) by id and the action, if any, by key
+#[derive(PartialEq, Eq, Hash)]
+struct SubtreeKey<'a, T> {
+    node_type: String,
+    node_value: &'a str,
+    width: u32,
+    var_id: Option<u32>,
+    idx_num: Option<usize>,
+    arg_name: &'a str,
+    action: Option<&'a T>,
+    next: Vec<usize>,
+}
 
-                //////////////////////// TASK 3
-                // if single tree got a dummy next node, this means the current single tree
-                // pattern already exists in merged tree, no need to append any new nodes
-                // and, just exit
-                if merged_arena.is_node_dummy(strack.clone()) {
-                    break;
-                }
+/// Hash-conses the merged tree: subtrees that make the same tests and
+/// end in the same actions are kept once, and every parent points to
+/// that copy, which turns the tree into a DAG. `actions` has a key for
+/// the RHS (and guard) of every node with an action; equal keys must
+/// mean equal actions. The walk from the root, and so the rule that
+/// fires, is the same as before. Returns the id each dropped node was
+/// replaced by.
+pub fn share_equal_subtrees<T: Eq + Hash>(
+    merged_arena: &mut MergedArena,
+    actions: &HashMap<usize, T>,
+) -> HashMap<usize, usize> {
+    let mut shared: HashMap<usize, usize> = HashMap::new();
+    if merged_arena.merged_tree.is_empty() {
+        return shared;
+    }
 
-                //////////////////////// TASK 4
-                // compare the node values
-                //
-                // let mut flag = false;
-                // for mt in mt_list {
-                //  if merged_arena.are_node_values_same(mt.node_value, strack.node_value) {
-                //      prev = mt;
-                //      flag = true;
-                //      break;
-                //  } else {
-                //      continue;
-                //  }
-                // }
-                //
-                //
-                let mut same_nodes = false;
-                for mnode in 0..merged_next_nodes.len() {
-                    if merged_arena
-                        .are_node_values_same(merged_next_nodes[mnode].clone(), strack.clone())
-                    {
-                        prev = merged_next_nodes[mnode].clone();
-                        mtrack = merged_next_nodes[mnode].clone();
-                        same_nodes = true;
-                        break;
-                    } else {
-                        continue;
-                    }
-                }
+    // children first, so their shared copies are known; rules can nest a
+    // thousand nodes deep, so this keeps its own stack
+    let mut canonical: HashMap<usize, usize> = HashMap::new();
+    let mut seen: HashMap<SubtreeKey<T>, usize> = HashMap::new();
+    let mut stack = vec![(0, false)];
+    while let Some((id, children_done)) = stack.pop() {
+        if canonical.contains_key(&id) {
+            continue;
+        }
+        let node = match merged_arena.get_node_with_id(id) {
+            Some(node) => node,
+            None => continue,
+        };
+        let next: Vec<usize> = node.next.iter().flatten().map(|child| child.index).collect();
+        if !children_done {
+            stack.push((id, true));
+            stack.extend(next.iter().filter(|child| !canonical.contains_key(child)).map(|&child| (child, false)));
+            continue;
+        }
+        let key = SubtreeKey {
+            node_type: lhspatternmatcher::get_node_type(node.node_type.clone()),
+            node_value: &node.node_value,
+            width: node.width,
+            var_id: node.var_id,
+            idx_num: node.idx_num,
+            arg_name: &node.arg_name,
+            action: actions.get(&id),
+            next: next.iter().map(|child| canonical.get(child).cloned().unwrap_or(*child)).collect(),
+        };
+        let copy = *seen.entry(key).or_insert(id);
+        canonical.insert(id, copy);
+        if copy != id {
+            shared.insert(id, copy);
+        }
+    }
 
-                //////////////////////// TASK 5
-                //if flag {
-                //    continue;
-                //} else {
-                //    repeat task 2
-                //}
-                //
-                //
-                if same_nodes {
-                    continue;
-                } else {
-                    prev = merged_arena.update_next_nodes_list(prev, strack.id);
-                    merged_arena.update_node_in_arena(prev.clone());
-                    loop {
-                        if merged_arena.is_node_dummy(strack.clone()) {
-                            break;
-                        } else {
-                            merged_arena.add_node_in_arena(strack.clone());
-                            strack = merged_arena
-                                .get_next_node_of_single_tree(single_tree.clone(), strack.clone());
-                            continue;
-                        }
-                    }
+    // point the kept nodes to the kept children, and drop the rest
+    let nodes = std::mem::take(&mut merged_arena.merged_tree);
+    merged_arena.slots.clear();
+    for mut node in nodes {
+        if shared.contains_key(&node.id) || !canonical.contains_key(&node.id) {
+            continue;
+        }
+        if let Some(ref mut next) = node.next {
+            for child in next.iter_mut() {
+                if let Some(&copy) = canonical.get(&child.index) {
+                    child.index = copy;
                 }
             }
-        } else {
-            // case 3 - same as case 1
-            merged_arena.update_hash_map(top_val, top_id);
-            let updated_root = merged_arena.update_next_nodes_list(found_root.clone(), top_id);
-            merged_arena.update_node_in_arena(updated_root);
-            for n in 0..single_tree.len() {
-                merged_arena.add_node_to_arena(single_tree[n].clone());
-            }
         }
+        merged_arena.add_node_to_arena(node);
     }
-
-    merged_arena
+    for id in merged_arena.hmap.values_mut() {
+        if let Some(&copy) = shared.get(id) {
+            *id = copy;
+        }
+    }
+    shared
 }
@@ -39,6 +39,7 @@ pub enum InstKind {
     OrNot,
     XorNot,
     Not,
+    Select,
 }
 
 #[derive(Clone)]
@@ -194,6 +195,7 @@ impl<'a> Parser<'a> {
             "orNot" => InstKind::OrNot,
             "xorNot" => InstKind::XorNot,
             "not" => InstKind::Not,
+            "select" => InstKind::Select,
             "->" => InstKind::Implies,
             _ => InstKind::NoneType,
         }
@@ -230,6 +232,7 @@ impl<'a> Parser<'a> {
             InstKind::OrNot => "orNot".to_string(),
             InstKind::XorNot => "xorNot".to_string(),
             InstKind::Not => "not".to_string(),
+            InstKind::Select => "select".to_string(),
             _ => "Inst Kind name is not yet handled in function: get_kind_name()".to_string(),
         }
     }
@@ -385,8 +388,14 @@ impl<'a> Parser<'a> {
         const_index: usize
     ) -> Vec<Inst> {
         let mut insts = vec![];
+        // the constant of a compare has the width of the compared values,
+        // not of the i1 result
+        let const_width = match ops[const_index].width {
+            0 => width,
+            w => w,
+        };
         // create const inst for first operand
-        let const_inst0 = self.create_const_inst(ops[const_index].clone(), width);
+        let const_inst0 = self.create_const_inst(ops[const_index].clone(), const_width);
         let const_idx0 = self.total_insts;
         self.lhs_val_names_to_idx.insert(const_inst0.lhs.clone(), const_idx0);
         self.total_insts += 1;
@@ -399,7 +408,7 @@ impl<'a> Parser<'a> {
                 kind: SouperOpType::Index,
                 idx_val: Some(const_idx0),
                 const_val: None,
-                width: width,
+                width: const_width,
             });
             inst_ops.push(ops[1].clone());
         } else if const_index == 1 {
@@ -408,7 +417,7 @@ impl<'a> Parser<'a> {
                 kind: SouperOpType::Index,
                 idx_val: Some(const_idx0),
                 const_val: None,
-                width: width,
+                width: const_width,
             });
         } else {}
         insts.push(Inst {
@@ -556,14 +565,14 @@ impl<'a> Parser<'a> {
                             // of cranelift IR i.e. inst_imm X, c
                             // Subtraction c -x is represented as irsub_imm X, c
                             // However, X -c is transformed to add_imm X, -c
+                            // Without 'greater-than' conds, c < x can't be
+                            // swapped, the constant becomes an inst instead
                             if ops_info.const_index == 0 {
                                 match inst_kind.clone() {
                                     InstKind::Add | InstKind::Mul |
                                     InstKind::And | InstKind::Or |
                                     InstKind:: Xor | InstKind::Eq |
-                                    InstKind::Ne | InstKind::Slt |
-                                    InstKind::Ult | InstKind::Sle |
-                                    InstKind::Ule | InstKind::Sub => {
+                                    InstKind::Ne | InstKind::Sub => {
                                         ordered_ops.push(ops[1].clone());
                                         ordered_ops.push(ops[0].clone());
                                         insts.push(
@@ -577,7 +586,9 @@ impl<'a> Parser<'a> {
                                         insts
                                     },
                                     InstKind::AndNot | InstKind::OrNot |
-                                    InstKind::XorNot => {
+                                    InstKind::XorNot | InstKind::Slt |
+                                    InstKind::Ult | InstKind::Sle |
+                                    InstKind::Ule => {
                                         self.create_single_const_inst_sequence(
                                             inst_kind,
                                             instname,
@@ -1,5 +1,5 @@
 use lhspatternmatcher::{Node, NodeType};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 pub fn insert_to_pc_hashmap(
     table: &mut HashMap<String, usize>,
@@ -49,17 +49,27 @@ pub fn get_pcarg_from_node_id(
     table[&id].clone()
 }
 
+/// Maps the args of an LHS that have to be related in its path
+/// condition to the Souper value they stand for. Those are the leaf
+/// args, and the args defined by an inst whose value the LHS uses more
+/// than once, so that both uses are checked to be the same `Value`.
 pub fn get_path_condition_args_for_lhs(nodes: Vec<Node>) -> HashMap<String, usize> {
     //////println!("** pctable: start func\n");
     let mut pcargs_to_idx: HashMap<String, usize> = HashMap::new();
     let mut nextnode_table: HashMap<usize, String> = HashMap::new();
+    let mut uses_of_idx: HashMap<usize, usize> = HashMap::new();
+    for node in &nodes {
+        if let (NodeType::MatchArgs, Some(idx)) = (&node.node_type, node.idx_num) {
+            *uses_of_idx.entry(idx).or_insert(0) += 1;
+        }
+    }
 
     for n in 0..nodes.len() {
         //////println!("***** Node ID = {}", nodes[n].id);
         match nodes[n].node_type {
             NodeType::MatchArgs => {
-                // check if next node is Result or Param
-                // if Param, do:
+                // check if next node is Result or Any
+                // if Any (a var leaf), do:
                 // get the Some(idx_num) and arg_name of thatnode
                 // and build pcarg_name
                 // insert in hashmap -> pcargname, Some(idx_num)
@@ -92,7 +102,11 @@ pub fn get_path_condition_args_for_lhs(nodes: Vec<Node>) -> HashMap<String, usiz
                         let id = nodes[n].clone().id;
                         let pcarg_name = get_pcarg_from_node_id(nextnode_table.clone(), id);
                         //////println!("Node type is ValueDef: Result => pcarg_name = {}", pcarg_name.clone());
-                        update_pchashtable(&mut pcargs_to_idx, pcarg_name);
+                        // an inst used once is matched by its own subtree
+                        let uses = pcargs_to_idx.get(&pcarg_name).and_then(|idx| uses_of_idx.get(idx));
+                        if uses.is_none_or(|&uses| uses < 2) {
+                            update_pchashtable(&mut pcargs_to_idx, pcarg_name);
+                        }
                     },
                     _ => {},
                 }
@@ -105,3 +119,39 @@ pub fn get_path_condition_args_for_lhs(nodes: Vec<Node>) -> HashMap<String, usiz
     }
     pcargs_to_idx
 }
+
+/// An arg of `table` that stands for the Souper value `idx`
+pub fn get_arg_of_value(table: &HashMap<String, usize>, idx: usize) -> Option<String> {
+    table.iter().filter(|&(_, &i)| i == idx).map(|(pcarg, _)| pcarg.clone()).min()
+}
+
+/// The pairs of args of `table` that stand for the same value, each
+/// repeated arg paired with the first one in name order
+pub fn get_equal_args(table: &HashMap<String, usize>) -> Vec<(String, String)> {
+    let mut args_of_idx: BTreeMap<usize, Vec<&String>> = BTreeMap::new();
+    for (pcarg, idx) in table {
+        args_of_idx.entry(*idx).or_default().push(pcarg);
+    }
+    let mut pairs = Vec::new();
+    for args in args_of_idx.values_mut() {
+        args.sort();
+        for other in &args[1..] {
+            pairs.push((args[0].clone(), (*other).clone()));
+        }
+    }
+    pairs
+}
+
+/// The `==` checks between the args of `table` that stand for the same
+/// value, e.g. `arg_1[0] == arg_2[0]`, or `None` if there are none
+pub fn generate_equality_guard(table: &HashMap<String, usize>) -> Option<String> {
+    let checks: Vec<String> = get_equal_args(table)
+        .iter()
+        .map(|(a, b)| format!("{} == {}", a, b))
+        .collect();
+    if checks.is_empty() {
+        None
+    } else {
+        Some(checks.join(" && "))
+    }
+}
@@ -0,0 +1,213 @@
+// Peepmatic DSL emitter
+//
+// Writes one Souper rule in peepmatic's S-expression DSL, so that the
+// peepmatic automaton can be built from the same rules as our merged
+// prefix tree:
+//
+//   (=> (when (iadd $x0 (iadd $x0 $x0))
+//             (bit-width $x0 32))
+//       (imul_imm 3 $x0))
+//
+// Peepmatic speaks the Cranelift 0.71 opcodes, with the immediate as the
+// first operand of the `*_imm` forms. Its opcode set is small, so rules
+// that need anything else are skipped.
+
+use cliftinstbuilder::{self, CtonInst, CtonOpcode, CtonCmpCond};
+
+/// Returns the peepmatic optimization for one rule (all its cranelift
+/// insts and its RHS insts), or None if peepmatic can not express it.
+pub fn generate_peepmatic_rule(
+    all_insts: &[CtonInst],
+    rhs_insts: &[CtonInst],
+    count: u32,
+    souper_rule: &str,
+) -> Option<String> {
+    let infer_pos = all_insts.iter().position(|i| matches!(i.opcode, CtonOpcode::Infer))?;
+    let root = cliftinstbuilder::get_index_operand(&all_insts[infer_pos], 0)?;
+    if let CtonOpcode::Var = all_insts[root].opcode {
+        return None;
+    }
+    // RHS insts refer to each other by index, so look them up in a copy
+    // of the rule where `rhs_insts` has replaced the original RHS
+    let mut insts = all_insts.to_vec();
+    for inst in rhs_insts {
+        // the target lowering numbers its extra insts past the end
+        if inst.lhs_index >= insts.len() {
+            insts.resize(inst.lhs_index + 1, inst.clone());
+        }
+        insts[inst.lhs_index] = inst.clone();
+    }
+    let result = rhs_insts.iter().find(|i| matches!(i.opcode, CtonOpcode::ResultInst))?;
+
+    let lhs = get_value_term(&insts, root)?;
+    let rhs = get_inst_term(&insts, result)?;
+
+    // The RHS can only use vars the LHS binds
+    let vars = get_lhs_vars(&insts, root);
+    if !get_lhs_vars(&insts, result.lhs_index).iter().all(|var| vars.contains(var)) {
+        return None;
+    }
+
+    // The widths of its vars are the only preconditions left for a
+    // Souper rule here, rules with facts are dropped beforehand (see
+    // `get_fact_drop_reason`); constants are literals and need no
+    // is-power-of-two etc.
+    let mut preconditions = Vec::new();
+    for idx in vars {
+        let width = insts[idx].width;
+        if !matches!(width, 1 | 8 | 16 | 32 | 64 | 128) {
+            return None;
+        }
+        preconditions.push(format!("(bit-width {} {})", get_var_name(idx), width));
+    }
+
+    let mut peep_str = format!(";; superopt_{}\n", count);
+    for line in souper_rule.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        peep_str += &format!(";; {}\n", line);
+    }
+    if preconditions.is_empty() {
+        peep_str += &format!("(=> {}\n    {})\n", lhs, rhs);
+    } else {
+        peep_str += &format!(
+            "(=> (when {}\n          {})\n    {})\n",
+            lhs,
+            preconditions.join("\n          "),
+            rhs
+        );
+    }
+    Some(peep_str)
+}
+
+/// Why a rule with the dataflow fact `fact` on `var` has no peepmatic
+/// form. Peepmatic has preconditions for some of Souper's facts, e.g.
+/// `(is-power-of-two $C)` for powerOfTwo, but they only take constants,
+/// while a Souper fact is on a var, which is any runtime value.
+pub fn get_fact_drop_reason(var: &str, fact: &str) -> String {
+    let precondition = match fact {
+        "powerOfTwo" => Some("is-power-of-two"),
+        _ => None,
+    };
+    match precondition {
+        Some(precondition) => format!(
+            "needs the dataflow fact ({}) on {}, peepmatic's ({}) only holds for constants",
+            fact, var, precondition
+        ),
+        None => format!("needs the dataflow fact ({}) on {}, which peepmatic has no precondition for", fact, var),
+    }
+}
+
+/// Peepmatic has no way to name an inner value, so a value used twice
+/// is spelled out twice; the ops are pure, so this matches the same
+/// values. The RHS rebuilds inner LHS values it refers to the same way.
+fn get_value_term(insts: &[CtonInst], idx: usize) -> Option<String> {
+    match insts[idx].opcode {
+        CtonOpcode::Var => Some(get_var_name(idx)),
+        _ => get_inst_term(insts, &insts[idx]),
+    }
+}
+
+fn get_inst_term(insts: &[CtonInst], inst: &CtonInst) -> Option<String> {
+    let opcode = cliftinstbuilder::get_clift_opcode_name(inst.opcode.clone());
+    match inst.opcode {
+        CtonOpcode::Iconst => {
+            let c = cliftinstbuilder::get_const_operand(inst, 0)?;
+            Some(get_const_term(inst.width, c))
+        }
+        CtonOpcode::IaddImm | CtonOpcode::ImulImm | CtonOpcode::IsubImm |
+        CtonOpcode::BandImm | CtonOpcode::BorImm | CtonOpcode::BxorImm |
+        CtonOpcode::IshlImm | CtonOpcode::UshrImm | CtonOpcode::SshrImm => {
+            // the immediate comes first in peepmatic
+            let x = get_operand_term(insts, inst, 0)?;
+            let c = get_operand_term(insts, inst, 1)?;
+            Some(format!("({} {} {})", opcode, c, x))
+        }
+        CtonOpcode::IcmpImm => {
+            let cc = get_peepmatic_cond(inst.cond.clone()?);
+            let x = get_operand_term(insts, inst, 0)?;
+            let c = get_operand_term(insts, inst, 1)?;
+            Some(format!("(icmp_imm {} {} {})", cc, c, x))
+        }
+        CtonOpcode::Icmp => {
+            let cc = get_peepmatic_cond(inst.cond.clone()?);
+            let x = get_operand_term(insts, inst, 0)?;
+            let y = get_operand_term(insts, inst, 1)?;
+            Some(format!("(icmp {} {} {})", cc, x, y))
+        }
+        CtonOpcode::Iadd | CtonOpcode::Isub | CtonOpcode::Imul |
+        CtonOpcode::Band | CtonOpcode::Bor | CtonOpcode::Bxor |
+        CtonOpcode::Ishl | CtonOpcode::Ushr | CtonOpcode::Sshr => {
+            let x = get_operand_term(insts, inst, 0)?;
+            let y = get_operand_term(insts, inst, 1)?;
+            Some(format!("({} {} {})", opcode, x, y))
+        }
+        CtonOpcode::Select => {
+            let c = get_operand_term(insts, inst, 0)?;
+            let x = get_operand_term(insts, inst, 1)?;
+            let y = get_operand_term(insts, inst, 2)?;
+            Some(format!("(select {} {} {})", c, x, y))
+        }
+        CtonOpcode::ResultInst => {
+            match cliftinstbuilder::get_index_operand(inst, 0) {
+                Some(idx) => get_value_term(insts, idx),
+                None => {
+                    // constant folded RHS
+                    let c = cliftinstbuilder::get_const_operand(inst, 0)?;
+                    Some(get_const_term(inst.width, c))
+                }
+            }
+        }
+        // bnot, band_not, ineg, iabs, imin, popcnt, clz, ... are not
+        // in peepmatic's opcode set
+        _ => None,
+    }
+}
+
+/// Operand `n` of `inst`; constants are plain integer literals
+fn get_operand_term(insts: &[CtonInst], inst: &CtonInst, n: usize) -> Option<String> {
+    match cliftinstbuilder::get_index_operand(inst, n) {
+        Some(idx) => get_value_term(insts, idx),
+        None => cliftinstbuilder::get_const_operand(inst, n).map(|c| c.to_string()),
+    }
+}
+
+fn get_const_term(width: u32, c: i128) -> String {
+    if width == 1 {
+        format!("(bconst {})", c != 0)
+    } else {
+        format!("(iconst {})", c)
+    }
+}
+
+/// Indices of the vars that the LHS pattern binds
+fn get_lhs_vars(insts: &[CtonInst], root: usize) -> Vec<usize> {
+    let mut vars = Vec::new();
+    let mut worklist = vec![root];
+    while let Some(idx) = worklist.pop() {
+        match insts[idx].opcode {
+            CtonOpcode::Var => {
+                if !vars.contains(&idx) {
+                    vars.push(idx);
+                }
+            }
+            _ => worklist.extend(insts[idx].cops.iter().flatten().filter_map(|op| op.idx_val)),
+        }
+    }
+    vars.sort();
+    vars
+}
+
+fn get_var_name(idx: usize) -> String {
+    format!("$x{}", idx)
+}
+
+fn get_peepmatic_cond(cond: CtonCmpCond) -> String {
+    let cc = match cond {
+        CtonCmpCond::Eq => "eq",
+        CtonCmpCond::Ne => "ne",
+        CtonCmpCond::Slt => "slt",
+        CtonCmpCond::Ult => "ult",
+        CtonCmpCond::Sle => "sle",
+        CtonCmpCond::Ule => "ule",
+    };
+    cc.to_string()
+}
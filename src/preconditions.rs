@@ -0,0 +1,96 @@
+// Path conditions and dataflow facts of Souper rules
+//
+// The parser has no syntax for these, so they are split off the rule
+// text before it is parsed:
+//
+//   %0:i32 = var (nonZero) (knownBits=xxxx0)
+//   pc %1 1:i1
+//
+// Facts are kept by the name of the var they are attached to, path
+// conditions by the name of the value and the constant it equals.
+// `blockpc` needs the block structure, which the rules don't have, so
+// those lines are dropped.
+//
+// The matchers only check a path condition that two values of the LHS
+// differ, `pc %2 1:i1` with `%2 = ne %0, %1` (or `eq` and `0:i1`).
+
+use parser::{Inst, InstKind};
+
+pub struct PathCondition {
+    pub value: String,
+    pub constant: i128,
+}
+
+pub struct Preconditions {
+    /// (var name, fact text without the parentheses)
+    pub facts: Vec<(String, String)>,
+    pub path_conditions: Vec<PathCondition>,
+}
+
+impl Preconditions {
+    pub fn is_empty(&self) -> bool {
+        self.facts.is_empty() && self.path_conditions.is_empty()
+    }
+}
+
+/// Returns the rule text without its preconditions, and the
+/// preconditions
+pub fn split_preconditions(rule: &str) -> (String, Preconditions) {
+    let mut pre = Preconditions {
+        facts: Vec::new(),
+        path_conditions: Vec::new(),
+    };
+    let mut stripped = String::new();
+    for line in rule.lines() {
+        let code = line.split(';').next().unwrap_or("").trim();
+        let mut words = code.split_whitespace();
+        match words.next() {
+            Some("pc") => {
+                let value = words.next().map(|v| v.split(':').next().unwrap_or(v).to_string());
+                let constant = words
+                    .next()
+                    .and_then(|c| c.split(':').next())
+                    .and_then(|c| c.parse::<i128>().ok());
+                if let (Some(value), Some(constant)) = (value, constant) {
+                    pre.path_conditions.push(PathCondition { value, constant });
+                }
+                continue;
+            }
+            Some("blockpc") => continue,
+            _ => {}
+        }
+        match code.find('(') {
+            Some(pos) if code.contains("= var") => {
+                let name = code.split(':').next().unwrap_or("").trim().to_string();
+                for fact in code[pos..].split('(').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+                    pre.facts.push((name.clone(), fact.trim_end_matches(')').to_string()));
+                }
+                stripped += &code[..pos];
+            }
+            _ => stripped += line,
+        }
+        stripped += "\n";
+    }
+    (stripped, pre)
+}
+
+/// The pairs of values (by their index in `insts`) the path conditions
+/// of a rule state are different, or why the preconditions can't be
+/// checked by a matcher
+pub fn get_stated_inequalities(pre: &Preconditions, insts: &[Inst]) -> Result<Vec<(usize, usize)>, String> {
+    if let Some((var, fact)) = pre.facts.first() {
+        return Err(format!("needs the dataflow fact ({}) on {}", fact, var));
+    }
+    let mut pairs = Vec::new();
+    for pc in &pre.path_conditions {
+        let inst = insts.iter().find(|inst| inst.lhs == pc.value);
+        let operands = inst.and_then(|inst| inst.ops.as_ref()).map_or(Vec::new(), |ops| {
+            ops.iter().filter_map(|op| op.idx_val).collect()
+        });
+        match (inst.map(|inst| &inst.kind), pc.constant, operands.as_slice()) {
+            (Some(InstKind::Ne), 1, &[a, b]) | (Some(InstKind::Eq), 0, &[a, b]) => pairs.push((a, b)),
+            _ => return Err(format!("needs the path condition {} == {}, which is not an inequality of two values", pc.value, pc.constant)),
+        }
+    }
+    Ok(pairs)
+}
@@ -0,0 +1,130 @@
+// Rule priority
+//
+// When more than one rule matches an inst, the one that fires is the
+// first of them in priority order, chosen with `--priority`:
+//
+//   specificity  a rule that tests more where the rules first differ (an
+//                inst or a constant where the other has a var, a var
+//                that has to repeat) before one that tests less, and
+//                otherwise file order
+//   cost         the rule that saves the most, by `get_opcode_cost`,
+//                first, and otherwise file order
+//   source       file order
+//
+// `fast` (and `interp`, `automaton`, `selfcheck`) get a merged tree whose
+// depth-first order is the priority order, and `baseline` a driver that
+// calls the rule functions in it.
+
+use cliftinstbuilder::{CtonInst, CtonOpcode};
+use lhspatternmatcher::Node;
+use mergedtree::{self, MergedArena};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Priority {
+    Specificity,
+    Cost,
+    Source,
+}
+
+/// Names accepted by `--priority`
+pub const PRIORITY_NAMES: [&str; 3] = ["specificity", "cost", "source"];
+
+pub fn get_priority(name: &str) -> Option<Priority> {
+    match name {
+        "specificity" => Some(Priority::Specificity),
+        "cost" => Some(Priority::Cost),
+        "source" => Some(Priority::Source),
+        _ => None,
+    }
+}
+
+pub fn get_priority_name(priority: Priority) -> String {
+    match priority {
+        Priority::Specificity => "specificity".to_string(),
+        Priority::Cost => "cost".to_string(),
+        Priority::Source => "source".to_string(),
+    }
+}
+
+/// An LHS variant of a rule, as it goes into the matchers
+pub struct RankedRule {
+    /// Number of the rule in the input
+    pub number: u32,
+    /// Number of its `superopt_N` function in baseline mode
+    pub function: u32,
+    pub nodes: Vec<Node>,
+    /// Cost of the LHS minus cost of the RHS
+    pub cost_reduction: i64,
+    /// Whether its action checks more than the LHS (see
+    /// `matcher::LeafCheck`), so it may not fire where the LHS matches
+    pub checked: bool,
+}
+
+/// Rough relative cost of a Cranelift inst, vars and markers are free
+fn get_opcode_cost(opcode: &CtonOpcode) -> i64 {
+    match opcode {
+        CtonOpcode::Var | CtonOpcode::Infer |
+        CtonOpcode::ResultInst | CtonOpcode::NoneType => 0,
+        CtonOpcode::Imul | CtonOpcode::ImulImm => 3,
+        CtonOpcode::Popcnt | CtonOpcode::Clz | CtonOpcode::Ctz | CtonOpcode::Select => 2,
+        _ => 1,
+    }
+}
+
+/// What a rule saves: the insts of the LHS variant (the ones before
+/// `infer`) against the lowered RHS insts
+pub fn get_cost_reduction(lhs_variant: &[CtonInst], rhs_insts: &[CtonInst]) -> i64 {
+    let infer = lhs_variant
+        .iter()
+        .position(|inst| matches!(inst.opcode, CtonOpcode::Infer))
+        .unwrap_or(lhs_variant.len());
+    let lhs_cost: i64 = lhs_variant[..infer].iter().map(|inst| get_opcode_cost(&inst.opcode)).sum();
+    let rhs_cost: i64 = rhs_insts.iter().map(|inst| get_opcode_cost(&inst.opcode)).sum();
+    lhs_cost - rhs_cost
+}
+
+/// Indices of `rules` in priority order, and the merged tree of their
+/// LHSs that the matchers walk in that order
+pub fn order_rules(rules: &[RankedRule], priority: Priority) -> (Vec<usize>, MergedArena) {
+    let mut merged_arena = MergedArena::new();
+    let mut order: Vec<usize> = (0..rules.len()).collect();
+    let checked: HashSet<usize> = rules
+        .iter()
+        .filter(|rule| rule.checked)
+        .filter_map(|rule| rule.nodes.last().map(|last| last.id))
+        .collect();
+    match priority {
+        Priority::Specificity => {
+            for rule in rules {
+                mergedtree::generate_merged_prefix_tree(&rule.nodes, &mut merged_arena, &checked);
+            }
+            mergedtree::order_by_specificity(&mut merged_arena);
+            // the last LHS node of a rule is where its action is
+            let actions: HashMap<usize, usize> = rules
+                .iter()
+                .enumerate()
+                .filter_map(|(n, rule)| rule.nodes.last().map(|last| (last.id, n)))
+                .collect();
+            let mut reached: Vec<usize> = mergedtree::get_action_order(&merged_arena, &actions)
+                .iter()
+                .map(|id| actions[id])
+                .collect();
+            // an LHS that is already in the tree, after one that always
+            // fires, never fires; it goes last
+            let unreached: Vec<usize> = order.iter().cloned().filter(|n| !reached.contains(n)).collect();
+            reached.extend(unreached);
+            order = reached;
+        }
+        Priority::Cost | Priority::Source => {
+            if priority == Priority::Cost {
+                // stable, so equal savings keep the file order
+                order.sort_by_key(|&n| -rules[n].cost_reduction);
+            }
+            for &n in &order {
+                mergedtree::append_prefix_tree_in_order(&rules[n].nodes, &mut merged_arena, &checked);
+            }
+        }
+    }
+    (order, merged_arena)
+}
@@ -162,6 +162,29 @@ pub fn update_arg_nodes_in_lhs(mut nodes: Vec<Node>) -> LHSInfo {
                             process.push_to_parent_stack(nodes[node].clone());
                         }
                     },
+                    "Ternary" => {
+                        if process.ready_to_pop_from_arg_stack(3) {
+                            let parent_arg_name = &nodes[node].arg_name.clone();
+                            // pop/update all three arg nodes, n1, n2 and n3
+                            for _ in 0..3 {
+                                if let Some(n) = process.pop_from_arg_stack() {
+                                    let updated_n = process.
+                                        update_arg_name_for_node(
+                                            n,
+                                            parent_arg_name.clone());
+                                    process.update_in_lhs(updated_n.clone(), &mut nodes);
+                                    process.insert_in_hashmap(
+                                        &mut idx_to_arg_name,
+                                        updated_n.idx_num,
+                                        updated_n.arg_name,
+                                        updated_n.node_value
+                                    );
+                                }
+                            }
+                        } else {
+                            process.push_to_parent_stack(nodes[node].clone());
+                        }
+                    },
                     "Unary" | "UnaryImm" => {
                         if process.ready_to_pop_from_arg_stack(1) {
                             let parent_arg_name = &nodes[node].arg_name.clone();
@@ -0,0 +1,373 @@
+// RHS lowering
+//
+// Souper has a small instruction set, so a synthesized RHS often spells
+// out a shape that Cranelift has a single opcode for: `sub 0, x` is
+// `ineg x`, `xor x, -1` is `bnot x`, a `select` over a compare is
+// `iabs`/`imin`/..., `add x, (sub 0, y)` is `isub x, y`, and an `iconst`
+// feeding a binary inst is the `*_imm` form of that inst. This pass runs
+// on the cranelift insts of a rule, after the LHS/RHS split and before
+// `processrhs` assigns arg names, and rewrites each RHS inst into its
+// best single opcode. RHS insts that are no longer used by the result
+// are dropped, so the emitters only ever see the lowered sequence.
+
+use cliftinstbuilder::{self, CtonInst, CtonInstKind, CtonOpcode,
+    CtonCmpCond, CtonOperand, CtonValueDef};
+use rhscliftinsts;
+use targetisa::TargetFeatures;
+
+/// Upper bound on rewrites of one inst, e.g.
+/// bxor x, (iconst -1) => bxor_imm x, -1 => bnot x
+const MAX_LOWERING_STEPS: usize = 4;
+
+/// Takes all cranelift insts of a rule (LHS, infer and RHS, indexed by
+/// lhs_index) and returns only the lowered RHS insts, like
+/// `rhscliftinsts::get_result_clift_insts_only` does for the unlowered ones.
+pub fn lower_rhs_clift_insts(all_insts: Vec<CtonInst>) -> Vec<CtonInst> {
+    let mut insts = all_insts;
+    let infer_pos = match insts.iter().position(|i| matches!(i.opcode, CtonOpcode::Infer)) {
+        Some(pos) => pos,
+        None => return rhscliftinsts::get_result_clift_insts_only(insts),
+    };
+    // RHS insts only refer to earlier insts, so lowering them in order
+    // means every operand is already in its final shape
+    for i in infer_pos + 1..insts.len() {
+        for _ in 0..MAX_LOWERING_STEPS {
+            match lower_inst(&insts, &insts[i]) {
+                Some(lowered) => insts[i] = lowered,
+                None => break,
+            }
+        }
+    }
+    let used = get_used_insts(&insts, infer_pos);
+    rhscliftinsts::get_result_clift_insts_only(insts)
+        .into_iter()
+        .filter(|inst| used[inst.lhs_index])
+        .collect()
+}
+
+/// Rewrites the lowered RHS insts for what `target` has: the `*_not`
+/// opcodes become the inverted operand plus the plain op, while a rule
+/// whose RHS needs popcnt/clz/ctz is dropped for the target with the
+/// reason returned as Err. Those have no fallback sequence on purpose:
+/// without the instruction they take a dozen or more shift/mask/add insts
+/// (or a loop), which is never cheaper than the LHS a peephole replaces.
+pub fn lower_rhs_for_target(
+    rhs_insts: Vec<CtonInst>,
+    target: &TargetFeatures,
+) -> Result<Vec<CtonInst>, String> {
+    // the new bnot insts are only named by processrhs, so any index past
+    // the rule's own insts will do
+    let mut next_index = rhs_insts.iter().map(|inst| inst.lhs_index).max().unwrap_or(0) + 1;
+    let mut lowered = Vec::new();
+    for inst in rhs_insts {
+        let available = match inst.opcode {
+            CtonOpcode::Popcnt => target.has_popcnt,
+            CtonOpcode::Clz => target.has_clz,
+            CtonOpcode::Ctz => target.has_ctz,
+            _ => true,
+        };
+        if !available {
+            return Err(format!(
+                "needs {}, which '{}' lacks and which has no sequence cheaper than the LHS",
+                cliftinstbuilder::get_clift_opcode_name(inst.opcode), target.name
+            ));
+        }
+        let plain_opcode = match inst.opcode {
+            CtonOpcode::BandNot if !target.has_band_not => CtonOpcode::Band,
+            CtonOpcode::BorNot if !target.has_bor_not => CtonOpcode::Bor,
+            CtonOpcode::BxorNot if !target.has_bxor_not => CtonOpcode::Bxor,
+            _ => {
+                lowered.push(inst);
+                continue;
+            }
+        };
+        // x op_not y => x op (bnot y), the *_not opcodes are only ever
+        // built with two index operands
+        let x = cliftinstbuilder::get_index_operand(&inst, 0).unwrap();
+        let y = cliftinstbuilder::get_index_operand(&inst, 1).unwrap();
+        let not_inst = CtonInst {
+            valuedef: CtonValueDef::Result,
+            kind: CtonInstKind::Unary,
+            opcode: CtonOpcode::Bnot,
+            cond: None,
+            width: inst.width,
+            var_num: None,
+            cops: Some(vec![CtonOperand { idx_val: Some(y), const_val: None }]),
+            lhs_index: next_index,
+        };
+        next_index += 1;
+        let not_y = not_inst.lhs_index;
+        lowered.push(not_inst);
+        lowered.push(build_binary_inst(&inst, plain_opcode, x, not_y));
+    }
+    Ok(lowered)
+}
+
+/// Returns the single-opcode form of `inst`, if it has a better one
+fn lower_inst(insts: &[CtonInst], inst: &CtonInst) -> Option<CtonInst> {
+    // ineg, bnot, band_not, iabs, imin, ... are the same shapes the LHS
+    // matcher knows about, see `cliftinstbuilder::get_clift_equivalent`
+    cliftinstbuilder::get_clift_equivalent(insts, inst)
+        .or_else(|| lower_to_imm_form(insts, inst))
+        .or_else(|| lower_negated_operand(insts, inst))
+        .or_else(|| lower_imul_imm(inst))
+}
+
+/// Folds an `iconst` operand into the `*_imm` form of the inst
+fn lower_to_imm_form(insts: &[CtonInst], inst: &CtonInst) -> Option<CtonInst> {
+    let a = cliftinstbuilder::get_index_operand(inst, 0)?;
+    let b = cliftinstbuilder::get_index_operand(inst, 1)?;
+    let const_a = get_iconst_value(insts, a);
+    let const_b = get_iconst_value(insts, b);
+    match inst.opcode {
+        CtonOpcode::Iadd | CtonOpcode::Imul |
+        CtonOpcode::Band | CtonOpcode::Bor | CtonOpcode::Bxor => {
+            let opcode = match inst.opcode {
+                CtonOpcode::Iadd => CtonOpcode::IaddImm,
+                CtonOpcode::Imul => CtonOpcode::ImulImm,
+                CtonOpcode::Band => CtonOpcode::BandImm,
+                CtonOpcode::Bor => CtonOpcode::BorImm,
+                _ => CtonOpcode::BxorImm,
+            };
+            // commutative, so the constant can be on either side
+            const_b.map(|c| (a, c))
+                .or_else(|| const_a.map(|c| (b, c)))
+                .map(|(x, c)| {
+                    let c = mask_to_width(c, inst.width);
+                    build_imm_inst(inst, CtonInstKind::BinaryImm, opcode, x, c)
+                })
+        }
+        CtonOpcode::Isub => {
+            if let Some(c) = const_b {
+                // x - c => iadd_imm x, -c
+                let c = mask_to_width(-c, inst.width);
+                Some(build_imm_inst(inst, CtonInstKind::BinaryImm, CtonOpcode::IaddImm, a, c))
+            } else {
+                // c - x => irsub_imm x, c
+                const_a.map(|c| {
+                    let c = mask_to_width(c, inst.width);
+                    build_imm_inst(inst, CtonInstKind::BinaryImm, CtonOpcode::IsubImm, b, c)
+                })
+            }
+        }
+        CtonOpcode::Ishl | CtonOpcode::Sshr | CtonOpcode::Ushr => {
+            let opcode = match inst.opcode {
+                CtonOpcode::Ishl => CtonOpcode::IshlImm,
+                CtonOpcode::Sshr => CtonOpcode::SshrImm,
+                _ => CtonOpcode::UshrImm,
+            };
+            // only the shift amount has an immediate form
+            const_b.map(|c| build_imm_inst(inst, CtonInstKind::BinaryImm, opcode, a, c))
+        }
+        CtonOpcode::BandNot | CtonOpcode::BorNot | CtonOpcode::BxorNot => {
+            // x op (not c) => x op_imm ~c
+            let opcode = match inst.opcode {
+                CtonOpcode::BandNot => CtonOpcode::BandImm,
+                CtonOpcode::BorNot => CtonOpcode::BorImm,
+                _ => CtonOpcode::BxorImm,
+            };
+            const_b.map(|c| {
+                let c = mask_to_width(!c, inst.width);
+                build_imm_inst(inst, CtonInstKind::BinaryImm, opcode, a, c)
+            })
+        }
+        CtonOpcode::Icmp => {
+            // Without 'greater-than' conds the operands can only be
+            // swapped for eq and ne
+            let symmetric = matches!(inst.cond, Some(CtonCmpCond::Eq) | Some(CtonCmpCond::Ne));
+            const_b.map(|c| (a, c))
+                .or_else(|| if symmetric { const_a.map(|c| (b, c)) } else { None })
+                .map(|(x, c)| build_imm_inst(inst, CtonInstKind::IntCompareImm, CtonOpcode::IcmpImm, x, c))
+        }
+        _ => None,
+    }
+}
+
+/// iadd x, (ineg y) => isub x, y and isub x, (ineg y) => iadd x, y
+fn lower_negated_operand(insts: &[CtonInst], inst: &CtonInst) -> Option<CtonInst> {
+    let a = cliftinstbuilder::get_index_operand(inst, 0)?;
+    let b = cliftinstbuilder::get_index_operand(inst, 1)?;
+    match inst.opcode {
+        CtonOpcode::Iadd => {
+            // iadd is commutative, so the negated operand can be on either side
+            get_ineg_operand(insts, b)
+                .map(|y| (a, y))
+                .or_else(|| get_ineg_operand(insts, a).map(|y| (b, y)))
+                .map(|(x, y)| build_binary_inst(inst, CtonOpcode::Isub, x, y))
+        }
+        CtonOpcode::Isub => {
+            get_ineg_operand(insts, b).map(|y| build_binary_inst(inst, CtonOpcode::Iadd, a, y))
+        }
+        _ => None,
+    }
+}
+
+/// imul_imm x, 2^k => ishl_imm x, k
+fn lower_imul_imm(inst: &CtonInst) -> Option<CtonInst> {
+    match inst.opcode {
+        CtonOpcode::ImulImm => {
+            let x = cliftinstbuilder::get_index_operand(inst, 0)?;
+            let c = mask_to_width(cliftinstbuilder::get_const_operand(inst, 1)?, inst.width);
+            if c > 1 && c & (c - 1) == 0 {
+                let k = c.trailing_zeros() as i128;
+                Some(build_imm_inst(inst, CtonInstKind::BinaryImm, CtonOpcode::IshlImm, x, k))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the value of the inst at `idx` if it is an integer `iconst`
+fn get_iconst_value(insts: &[CtonInst], idx: usize) -> Option<i128> {
+    let inst = &insts[idx];
+    match inst.opcode {
+        // b1 constants have no immediate form
+        CtonOpcode::Iconst if inst.width > 1 => cliftinstbuilder::get_const_operand(inst, 0),
+        _ => None,
+    }
+}
+
+/// Returns x if the inst at `idx` is `ineg x`
+fn get_ineg_operand(insts: &[CtonInst], idx: usize) -> Option<usize> {
+    match insts[idx].opcode {
+        CtonOpcode::Ineg => cliftinstbuilder::get_index_operand(&insts[idx], 0),
+        _ => None,
+    }
+}
+
+/// Constants built here are emitted as `<c>_u64 as i64`, so keep them
+/// non-negative by truncating to the inst width
+fn mask_to_width(c: i128, width: u32) -> i128 {
+    if width > 0 && width < 128 {
+        c & ((1i128 << width) - 1)
+    } else {
+        c
+    }
+}
+
+fn build_imm_inst(
+    inst: &CtonInst,
+    kind: CtonInstKind,
+    opcode: CtonOpcode,
+    x: usize,
+    c: i128,
+) -> CtonInst {
+    CtonInst {
+        valuedef: CtonValueDef::Result,
+        kind,
+        opcode,
+        cond: inst.cond.clone(),
+        width: inst.width,
+        var_num: None,
+        cops: Some(vec![
+            CtonOperand { idx_val: Some(x), const_val: None },
+            CtonOperand { idx_val: None, const_val: Some(c) },
+        ]),
+        lhs_index: inst.lhs_index,
+    }
+}
+
+fn build_binary_inst(inst: &CtonInst, opcode: CtonOpcode, x: usize, y: usize) -> CtonInst {
+    CtonInst {
+        valuedef: CtonValueDef::Result,
+        kind: CtonInstKind::Binary,
+        opcode,
+        cond: None,
+        width: inst.width,
+        var_num: None,
+        cops: Some(vec![
+            CtonOperand { idx_val: Some(x), const_val: None },
+            CtonOperand { idx_val: Some(y), const_val: None },
+        ]),
+        lhs_index: inst.lhs_index,
+    }
+}
+
+/// Marks the RHS insts that the result still depends on after lowering
+fn get_used_insts(insts: &[CtonInst], infer_pos: usize) -> Vec<bool> {
+    let mut used = vec![false; insts.len()];
+    let mut worklist: Vec<usize> = insts[infer_pos + 1..]
+        .iter()
+        .filter(|inst| matches!(inst.opcode, CtonOpcode::ResultInst))
+        .map(|inst| inst.lhs_index)
+        .collect();
+    while let Some(idx) = worklist.pop() {
+        if used[idx] {
+            continue;
+        }
+        used[idx] = true;
+        if let Some(ops) = &insts[idx].cops {
+            worklist.extend(ops.iter().filter_map(|op| op.idx_val).filter(|&i| i > infer_pos));
+        }
+    }
+    used
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use targetisa;
+
+    /// The RHS of `rule` lowered for `target`, as `opcode operands` lines
+    /// with the operands by inst index (infer is one of the insts)
+    fn lower(rule: &str, target: &str) -> Result<Vec<String>, String> {
+        let insts = cliftinstbuilder::transform_souper_to_clift_insts(parser::parse(rule));
+        let target = targetisa::get_target_features(target).unwrap();
+        let lowered = lower_rhs_for_target(lower_rhs_clift_insts(insts), &target)?;
+        Ok(lowered
+            .iter()
+            .map(|inst| {
+                let mut line = cliftinstbuilder::get_clift_opcode_name(inst.opcode.clone());
+                for op in inst.cops.iter().flatten() {
+                    match (op.idx_val, op.const_val) {
+                        (Some(idx), _) => line += &format!(" %{}", idx),
+                        (None, Some(c)) => line += &format!(" {}", c),
+                        _ => {}
+                    }
+                }
+                line
+            })
+            .collect())
+    }
+
+    const BOR_NOT: &str = "%0:i32 = var\n%1:i32 = var\n%2:i32 = add %0, %1\ninfer %2\n~>\n\
+                           %3:i32 = xor %1, -1:i32\n%4:i32 = or %0, %3\nresult %4\n";
+    const BAND_NOT: &str = "%0:i32 = var\n%1:i32 = var\n%2:i32 = add %0, %1\ninfer %2\n~>\n\
+                            %3:i32 = xor %1, -1:i32\n%4:i32 = and %3, %0\nresult %4\n";
+    const POPCNT: &str = "%0:i32 = var\n%1:i32 = add %0, %0\ninfer %1\n~>\n\
+                          %2:i32 = ctpop %0\nresult %2\n";
+
+    #[test]
+    fn x86_64_baseline() {
+        assert_eq!(lower(BOR_NOT, "x86_64-baseline").unwrap(), vec!["bnot %1", "bor %0 %7", "Result %5"]);
+        assert_eq!(lower(BAND_NOT, "x86_64-baseline").unwrap(), vec!["bnot %1", "band %0 %7", "Result %5"]);
+        assert_eq!(
+            lower(POPCNT, "x86_64-baseline").unwrap_err(),
+            "needs popcnt, which 'x86_64-baseline' lacks and which has no sequence cheaper than the LHS"
+        );
+    }
+
+    #[test]
+    fn x86_64_v3() {
+        // BMI1 has andn, but nothing for orn
+        assert_eq!(lower(BOR_NOT, "x86_64-v3").unwrap(), vec!["bnot %1", "bor %0 %7", "Result %5"]);
+        assert_eq!(lower(BAND_NOT, "x86_64-v3").unwrap(), vec!["band_not %0 %1", "Result %5"]);
+        assert_eq!(lower(POPCNT, "x86_64-v3").unwrap(), vec!["popcnt %0", "Result %3"]);
+    }
+
+    #[test]
+    fn aarch64() {
+        assert_eq!(lower(BOR_NOT, "aarch64").unwrap(), vec!["bor_not %0 %1", "Result %5"]);
+        assert_eq!(lower(BAND_NOT, "aarch64").unwrap(), vec!["band_not %0 %1", "Result %5"]);
+        assert_eq!(lower(POPCNT, "aarch64").unwrap(), vec!["popcnt %0", "Result %3"]);
+    }
+
+    #[test]
+    fn folded_constant_is_masked() {
+        let rule = "%0:i8 = var\n%1:i8 = add %0, %0\ninfer %1\n~>\n%2:i8 = and -1:i8, -2:i8\nresult %2\n";
+        assert_eq!(lower(rule, "aarch64").unwrap(), vec!["iconst -1", "band_imm %3 254", "Result %5"]);
+    }
+}
@@ -0,0 +1,210 @@
+// Random Souper rules
+//
+// Generates well-typed rules in the input format, for stress testing the
+// lexer, parser, tree building and emitters. Every inst kind the parser
+// knows shows up, at i8 to i64 with i1 compares and selects mixed in, with
+// repeated operands and the constants that tend to be special cases
+// (0, 1, -1, INT_MIN, INT_MAX, width - 1, width). The RHS is random too,
+// so the rules are not sound, only valid input.
+
+use random::Random;
+
+const WIDTHS: [u32; 4] = [8, 16, 32, 64];
+/// Kinds with two operands of the value width, and with one
+const BINARY_KINDS: [&str; 12] = [
+    "add", "sub", "mul", "and", "or", "xor", "shl", "lshr", "ashr", "andNot", "orNot", "xorNot",
+];
+const UNARY_KINDS: [&str; 4] = ["ctpop", "ctlz", "cttz", "not"];
+const COMPARE_KINDS: [&str; 6] = ["eq", "ne", "slt", "ult", "sle", "ule"];
+const MAX_VARS: usize = 3;
+const MAX_LHS_INSTS: usize = 4;
+const MAX_RHS_INSTS: usize = 2;
+
+/// A value defined so far
+struct Value {
+    name: String,
+    width: u32,
+    is_var: bool,
+    /// Values it uses
+    operands: Vec<usize>,
+    /// Whether an inst uses it yet
+    used: bool,
+    /// Whether new insts may use it, the RHS only sees the LHS tree
+    /// below the root
+    visible: bool,
+}
+
+struct RuleBuilder<'a> {
+    rng: &'a mut Random,
+    width: u32,
+    values: Vec<Value>,
+    /// Operands of the inst being built
+    operands: Vec<usize>,
+    text: String,
+}
+
+impl<'a> RuleBuilder<'a> {
+    fn define(&mut self, width: u32, is_var: bool, rhs: &str) -> usize {
+        let name = format!("%{}", self.values.len());
+        self.text += &format!("{}:i{} = {}\n", name, width, rhs);
+        let operands = std::mem::take(&mut self.operands);
+        self.values.push(Value {
+            name,
+            width,
+            is_var,
+            operands,
+            used: false,
+            visible: true,
+        });
+        self.values.len() - 1
+    }
+
+    fn get_const(&mut self, width: u32) -> String {
+        if width == 1 {
+            return format!("{}:i1", self.rng.below(2));
+        }
+        let int_min = -(1i128 << (width - 1));
+        let special = [0, 1, -1, int_min, -int_min - 1, width as i128 - 1, width as i128];
+        let value = if self.rng.below(4) == 0 {
+            (self.rng.next_u64() as i64 as i128) >> (64 - width)
+        } else {
+            special[self.rng.below(special.len())]
+        };
+        format!("{}:i{}", value, width)
+    }
+
+    /// A value of the given width, preferring one that is not used yet
+    /// so the LHS stays a single tree. None if there is none.
+    fn get_value(&mut self, width: u32) -> Option<String> {
+        let fits: Vec<usize> = (0..self.values.len())
+            .filter(|&v| self.values[v].visible && self.values[v].width == width)
+            .collect();
+        if fits.is_empty() {
+            return None;
+        }
+        let unused: Vec<usize> = fits.iter().cloned().filter(|&v| !self.values[v].used && !self.values[v].is_var).collect();
+        let v = if !unused.is_empty() && self.rng.below(2) == 0 {
+            unused[self.rng.below(unused.len())]
+        } else {
+            fits[self.rng.below(fits.len())]
+        };
+        self.values[v].used = true;
+        self.operands.push(v);
+        Some(self.values[v].name.clone())
+    }
+
+    /// Operands of a binary inst or compare, at most one a constant
+    fn get_operands(&mut self, width: u32) -> Option<String> {
+        let a = self.get_value(width)?;
+        let b = if self.rng.below(3) == 0 {
+            self.get_const(width)
+        } else {
+            self.get_value(width)?
+        };
+        Some(if self.rng.below(2) == 0 { format!("{}, {}", a, b) } else { format!("{}, {}", b, a) })
+    }
+
+    /// Defines a random inst of the given width, or returns None if no
+    /// kind fits the values there are
+    fn add_inst(&mut self, width: u32) -> Option<usize> {
+        self.operands.clear();
+        let (kind_width, rhs) = if width == 1 {
+            let kind = COMPARE_KINDS[self.rng.below(COMPARE_KINDS.len())];
+            (1, format!("{} {}", kind, self.get_operands(self.width)?))
+        } else {
+            match self.rng.below(8) {
+                0 | 1 => {
+                    let kind = UNARY_KINDS[self.rng.below(UNARY_KINDS.len())];
+                    (width, format!("{} {}", kind, self.get_value(width)?))
+                }
+                2 => {
+                    let cond = self.get_value(1)?;
+                    let a = self.get_value(width)?;
+                    let b = if self.rng.below(3) == 0 { self.get_const(width) } else { self.get_value(width)? };
+                    (width, format!("select {}, {}, {}", cond, a, b))
+                }
+                _ => {
+                    let kind = BINARY_KINDS[self.rng.below(BINARY_KINDS.len())];
+                    (width, format!("{} {}", kind, self.get_operands(width)?))
+                }
+            }
+        };
+        Some(self.define(kind_width, false, &rhs))
+    }
+}
+
+/// A random rule, as it would appear between `#########` delimiters
+pub fn generate_random_rule(rng: &mut Random) -> String {
+    let width = WIDTHS[rng.below(WIDTHS.len())];
+    let mut builder = RuleBuilder {
+        rng,
+        width,
+        values: Vec::new(),
+        operands: Vec::new(),
+        text: String::new(),
+    };
+    let vars = 1 + builder.rng.below(MAX_VARS);
+    for _ in 0..vars {
+        builder.define(width, true, "var");
+    }
+    // a var of i1 now and then, for selects
+    if builder.rng.below(4) == 0 {
+        builder.define(1, true, "var");
+    }
+
+    // the LHS, its last inst is the root
+    let lhs_insts = 1 + builder.rng.below(MAX_LHS_INSTS);
+    let mut root = None;
+    while root.is_none() || builder.values.iter().filter(|v| !v.is_var).count() < lhs_insts {
+        let inst_width = if builder.rng.below(4) == 0 { 1 } else { width };
+        if let Some(inst) = builder.add_inst(inst_width) {
+            root = Some(inst);
+        }
+    }
+    let root = root.unwrap_or(0);
+    let root_width = builder.values[root].width;
+    builder.text += &format!("infer {}\n~>\n", builder.values[root].name);
+
+    // the RHS is a constant, an LHS value, or new insts, over the
+    // values of the LHS tree
+    let mut visible = vec![false; builder.values.len()];
+    let mut stack = vec![root];
+    while let Some(v) = stack.pop() {
+        if !visible[v] {
+            visible[v] = true;
+            stack.extend(builder.values[v].operands.iter().cloned());
+        }
+    }
+    for (value, visible) in builder.values.iter_mut().zip(visible) {
+        value.visible = visible;
+    }
+    // the RHS replaces the root, it can't use it
+    builder.values[root].visible = false;
+    let lhs_values: Vec<usize> = (0..builder.values.len())
+        .filter(|&v| builder.values[v].visible && builder.values[v].width == root_width)
+        .collect();
+    let result = match builder.rng.below(4) {
+        0 => builder.get_const(root_width),
+        1 if !lhs_values.is_empty() => builder.values[lhs_values[builder.rng.below(lhs_values.len())]].name.clone(),
+        _ => {
+            let mut result = None;
+            let rhs_insts = 1 + builder.rng.below(MAX_RHS_INSTS);
+            for n in 0..rhs_insts * 4 {
+                // only the last inst has to have the width of the root
+                let inst_width = if n + 1 >= rhs_insts { root_width } else { width };
+                if let Some(inst) = builder.add_inst(inst_width) {
+                    result = Some(inst);
+                    if n + 1 >= rhs_insts {
+                        break;
+                    }
+                }
+            }
+            match result {
+                Some(inst) if builder.values[inst].width == root_width => builder.values[inst].name.clone(),
+                _ => builder.get_const(root_width),
+            }
+        }
+    };
+    builder.text += &format!("result {}\n", result);
+    builder.text
+}
@@ -0,0 +1,223 @@
+// Differential check of the baseline and fast matchers
+//
+// `baseline` emits one matcher per rule, tried in rule order, and `fast`
+// a single matcher for the merged prefix tree, so both should rewrite the
+// same insts the same way. Both are run through the interpreter on random
+// DAGs: the tree of each rule on its own stands in for the baseline
+// matchers and the merged tree for the fast one. The rules are tried in
+// priority order, which the merged tree is built to follow, so any inst
+// where the two pick a different rule or produce different code points
+// at a bug in tree merging or in backing off to a sibling.
+//
+// Both sides run in the same interpreter, so this does not check the code
+// `matcher::generate_matcher` emits. The interpreter walks the tree with
+// the same sibling order and leaf guards as the emitted `if`s, which the
+// tests in `interpreter` hold it to, but a bug in how the Rust is spelled
+// only shows when that code is compiled and run.
+
+use cliftinstbuilder::{self, CtonCmpCond, CtonInstKind};
+use dfg::{self, Dfg, InstData};
+use interpreter::Interpreter;
+use lhspatternmatcher::{Node, NodeType};
+use matcher::LeafCheck;
+use mergedtree::{self, MergedArena};
+use processrhs::CliftInstWithArgs;
+use random::Random;
+use std::collections::{HashMap, HashSet};
+
+/// Random DAGs each run is made of, and insts in each of them
+pub const DAGS: usize = 1000;
+const DAG_INSTS: usize = 12;
+const DAG_PARAMS: usize = 3;
+
+const CONDS: [CtonCmpCond; 6] = [
+    CtonCmpCond::Eq, CtonCmpCond::Ne, CtonCmpCond::Slt,
+    CtonCmpCond::Ult, CtonCmpCond::Sle, CtonCmpCond::Ule,
+];
+
+/// An inst the two matchers disagree on
+pub struct Mismatch {
+    /// The DAG before the rewrite
+    pub dfg: String,
+    /// Result value of the inst
+    pub value: usize,
+    /// Rule and rewritten DAG of each side
+    pub baseline: String,
+    pub fast: String,
+}
+
+pub struct SelfCheckReport {
+    pub insts: usize,
+    pub rewrites: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Runs the check on `DAGS` random DAGs. `rules` are the rule numbers
+/// and LHS nodes that went into `merged`, in priority order, and
+/// `shared` the nodes of `merged` the action of an LHS is at instead of
+/// its last node. `pcs` are the path condition tables of the actions.
+pub fn run_self_check(
+    rules: &[(u32, Vec<Node>)],
+    merged: &MergedArena,
+    shared: &HashMap<usize, Vec<usize>>,
+    rhs: &HashMap<usize, Vec<CliftInstWithArgs>>,
+    pcs: &HashMap<usize, HashMap<String, usize>>,
+    checks: &HashMap<usize, Vec<LeafCheck>>,
+    rng: &mut Random,
+) -> SelfCheckReport {
+    // the id of the last LHS node is the key of the RHS, and what the
+    // interpreter returns; rules that end in the same shared subtree
+    // have the same action there and can't be told apart
+    let mut rules_of: HashMap<usize, Vec<u32>> = HashMap::new();
+    for (number, nodes) in rules {
+        if let Some(last) = nodes.last() {
+            match shared.get(&last.id) {
+                Some(ids) => {
+                    for &id in ids {
+                        rules_of.entry(id).or_default().push(*number);
+                    }
+                }
+                None => rules_of.entry(last.id).or_default().push(*number),
+            }
+        }
+    }
+    let singles: Vec<(u32, MergedArena)> = rules
+        .iter()
+        .map(|(number, nodes)| {
+            let mut arena = MergedArena::new();
+            mergedtree::generate_merged_prefix_tree(nodes, &mut arena, &HashSet::new());
+            (*number, arena)
+        })
+        .collect();
+    let baseline: Vec<(u32, Interpreter)> = singles
+        .iter()
+        .map(|(number, arena)| (*number, Interpreter::new(arena, rhs, pcs, checks)))
+        .collect();
+    let fast = Interpreter::new(merged, rhs, pcs, checks);
+
+    let (widths, consts) = get_widths_and_consts(rules);
+    let mut report = SelfCheckReport {
+        insts: 0,
+        rewrites: 0,
+        mismatches: Vec::new(),
+    };
+    for n in 0..DAGS {
+        let dfg = generate_random_dfg(&format!("dag{}", n), rng, &widths, &consts);
+        for &inst in &dfg.layout {
+            report.insts += 1;
+            let mut base_dfg = dfg.clone();
+            let base_rule = baseline
+                .iter()
+                .find(|(_, interp)| interp.rewrite_inst(&mut base_dfg, inst).is_some())
+                .map(|(number, _)| *number);
+            let mut fast_dfg = dfg.clone();
+            let fast_rule = fast.rewrite_inst(&mut fast_dfg, inst).map(|id| match rules_of.get(&id) {
+                Some(numbers) => match base_rule {
+                    Some(number) if numbers.contains(&number) => number,
+                    _ => numbers[0],
+                },
+                None => 0,
+            });
+            if base_rule.is_some() || fast_rule.is_some() {
+                report.rewrites += 1;
+            }
+            let base_dfg = base_dfg.display();
+            let fast_dfg = fast_dfg.display();
+            if base_rule != fast_rule || base_dfg != fast_dfg {
+                report.mismatches.push(Mismatch {
+                    dfg: dfg.display(),
+                    value: dfg.inst_result(inst),
+                    baseline: describe_rewrite(base_rule, &base_dfg),
+                    fast: describe_rewrite(fast_rule, &fast_dfg),
+                });
+            }
+        }
+    }
+    report
+}
+
+fn describe_rewrite(rule: Option<u32>, dfg: &str) -> String {
+    match rule {
+        Some(number) => format!("rule {}, giving\n{}", number, dfg),
+        None => "no rule".to_string(),
+    }
+}
+
+/// Widths of the rule roots, and the constants the rules match, so
+/// that random DAGs have a chance of matching
+pub fn get_widths_and_consts(rules: &[(u32, Vec<Node>)]) -> (Vec<u32>, Vec<i64>) {
+    let mut widths = Vec::new();
+    let mut consts = vec![0, 1, -1];
+    for (_, nodes) in rules {
+        if let Some(root) = nodes.iter().find(|node| matches!(node.node_type, NodeType::InstType)) {
+            if root.width > 1 && !widths.contains(&root.width) {
+                widths.push(root.width);
+            }
+        }
+        for node in nodes.iter().filter(|node| matches!(node.node_type, NodeType::MatchConst)) {
+            if let Ok(c) = node.node_value.parse::<i128>() {
+                if !consts.contains(&(c as i64)) {
+                    consts.push(c as i64);
+                }
+            }
+        }
+    }
+    if widths.is_empty() {
+        widths.push(32);
+    }
+    (widths, consts)
+}
+
+/// A DAG of `DAG_INSTS` random insts of one of `widths` over a few
+/// params. Operands are earlier values, so repeated operands and deep
+/// chains both come up; immediates are mostly from `consts`.
+pub fn generate_random_dfg(name: &str, rng: &mut Random, widths: &[u32], consts: &[i64]) -> Dfg {
+    let width = widths[rng.below(widths.len())];
+    let mut dfg = Dfg::new(name);
+    let mut values: Vec<usize> = (0..DAG_PARAMS).map(|_| dfg.append_param(width)).collect();
+    let mut flags: Vec<usize> = Vec::new();
+    while dfg.layout.len() < DAG_INSTS {
+        let opcode = cliftinstbuilder::CLIFT_OPCODES[rng.below(cliftinstbuilder::CLIFT_OPCODES.len())].clone();
+        let kind = dfg::get_inst_kind(&opcode);
+        let mut data = InstData {
+            kind: kind.clone(),
+            opcode,
+            cond: None,
+            width,
+            args: Vec::new(),
+            imm: None,
+        };
+        let operands = match kind {
+            CtonInstKind::Binary => 2,
+            CtonInstKind::BinaryImm | CtonInstKind::Unary => 1,
+            CtonInstKind::IntCompare => 2,
+            CtonInstKind::IntCompareImm => 1,
+            CtonInstKind::Ternary => {
+                if flags.is_empty() {
+                    continue;
+                }
+                data.args.push(flags[rng.below(flags.len())]);
+                2
+            }
+            _ => 0,
+        };
+        for _ in 0..operands {
+            data.args.push(values[rng.below(values.len())]);
+        }
+        if let CtonInstKind::UnaryImm | CtonInstKind::BinaryImm | CtonInstKind::IntCompareImm = kind {
+            data.imm = Some(if rng.below(4) == 0 {
+                rng.next_u64() as i64
+            } else {
+                consts[rng.below(consts.len())]
+            });
+        }
+        if let CtonInstKind::IntCompare | CtonInstKind::IntCompareImm = kind {
+            data.cond = Some(CONDS[rng.below(CONDS.len())].clone());
+            data.width = 1;
+            flags.push(dfg.append_inst(data));
+        } else {
+            values.push(dfg.append_inst(data));
+        }
+    }
+    dfg
+}
@@ -0,0 +1,222 @@
+// Souper vs Cranelift shift semantics
+//
+// In Souper `shl`/`lshr`/`ashr` by the width or more is poison, so a rule
+// may assume every LHS shift amount is in range. Cranelift's
+// `ishl`/`ushr`/`sshr` mask the amount instead, and the LHS it matches
+// has a value for those amounts too, which the RHS need not agree with.
+// An RHS shift is only as good as Souper's proof that its amount is in
+// range, and harvested rules do have RHS shifts by amounts that aren't.
+//
+// A shift amount that is provably in range (a constant below the width,
+// `and %y, 31`, a compare, ...) is fine. An amount that is a var gets a
+// guard in the matcher: the var has to be an `iconst` below the width.
+// Any other amount, or a constant shift by the width or more, makes the
+// rule unsound for Cranelift and it is dropped.
+
+use cliftapi::CliftApi;
+use parser::{Inst, InstKind};
+use souperinterp;
+use std::collections::HashMap;
+
+/// A var used as a shift amount, and the width it has to stay below
+pub struct ShiftGuard {
+    pub value: usize,
+    pub name: String,
+    pub width: u32,
+}
+
+pub enum ShiftSafety {
+    /// Every shift amount is in range
+    Safe,
+    /// Sound if these vars are constants below the shift width
+    Guarded(Vec<ShiftGuard>),
+    /// Why the rule can miscompile in Cranelift
+    Unsound(String),
+}
+
+pub fn check_shift_semantics(insts: &[Inst]) -> ShiftSafety {
+    let by_idx: HashMap<usize, &Inst> = insts
+        .iter()
+        .filter(|inst| !matches!(inst.kind, InstKind::Infer | InstKind::ResultInst))
+        .map(|inst| (inst.lhs_idx, inst))
+        .collect();
+
+    // insts neither side uses are not matched or built
+    let mut used: Vec<usize> = Vec::new();
+    let mut stack: Vec<usize> = insts
+        .iter()
+        .filter(|inst| matches!(inst.kind, InstKind::Infer | InstKind::ResultInst))
+        .flat_map(|inst| inst.ops.iter().flatten().filter_map(|op| op.idx_val))
+        .collect();
+    while let Some(idx) = stack.pop() {
+        if !used.contains(&idx) {
+            used.push(idx);
+            let ops = by_idx.get(&idx).and_then(|inst| inst.ops.as_ref());
+            stack.extend(ops.iter().flat_map(|ops| ops.iter()).filter_map(|op| op.idx_val));
+        }
+    }
+
+    let mut guards: Vec<ShiftGuard> = Vec::new();
+    for inst in insts {
+        if !matches!(inst.kind, InstKind::Shl | InstKind::Lshr | InstKind::Ashr) || !used.contains(&inst.lhs_idx) {
+            continue;
+        }
+        let amount = match inst.ops.as_ref().and_then(|ops| ops.get(1)) {
+            Some(amount) => amount,
+            None => continue,
+        };
+        let max = match (amount.idx_val, amount.const_val) {
+            (Some(idx), _) => get_max_value(idx, &by_idx),
+            (None, Some(c)) => Some(souperinterp::mask(c as u128, inst.width)),
+            (None, None) => continue,
+        };
+        if max.is_some_and(|max| max < inst.width as u128) {
+            continue;
+        }
+        let idx = match amount.idx_val {
+            Some(idx) => idx,
+            None => {
+                return ShiftSafety::Unsound(format!(
+                    "{} shifts by {} at i{}, which Cranelift masks",
+                    inst.lhs,
+                    max.unwrap_or(0),
+                    inst.width
+                ))
+            }
+        };
+        match by_idx.get(&idx) {
+            Some(var) if matches!(var.kind, InstKind::Var) => {
+                // one guard per var, against the narrowest shift
+                if !guards.iter().any(|guard| guard.value == idx && guard.width <= inst.width) {
+                    guards.retain(|guard| guard.value != idx);
+                    guards.push(ShiftGuard {
+                        value: idx,
+                        name: var.lhs.clone(),
+                        width: inst.width,
+                    });
+                }
+            }
+            amount => {
+                let name = amount.map(|amount| amount.lhs.clone()).unwrap_or_default();
+                return ShiftSafety::Unsound(format!(
+                    "{} shifts by {}, which is not known to be below {} and can't be guarded",
+                    inst.lhs, name, inst.width
+                ));
+            }
+        }
+    }
+    if guards.is_empty() {
+        ShiftSafety::Safe
+    } else {
+        ShiftSafety::Guarded(guards)
+    }
+}
+
+/// Largest unsigned value of an inst, when it is known to be less than
+/// all ones
+fn get_max_value(idx: usize, by_idx: &HashMap<usize, &Inst>) -> Option<u128> {
+    let inst = by_idx.get(&idx)?;
+    let op = |n: usize| inst.ops.as_ref().and_then(|ops| ops.get(n));
+    let const_op = |n: usize| {
+        op(n)
+            .and_then(|op| op.const_val)
+            .map(|c| souperinterp::mask(c as u128, inst.width))
+    };
+    let max_op = |n: usize| match op(n).and_then(|op| op.idx_val) {
+        Some(idx) => get_max_value(idx, by_idx),
+        None => const_op(n),
+    };
+    match inst.kind {
+        InstKind::Const => const_op(0),
+        InstKind::And => match (max_op(0), max_op(1)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        },
+        InstKind::Lshr => {
+            let all_ones = souperinterp::mask(!0, inst.width);
+            const_op(1)
+                .filter(|&k| k > 0 && k < inst.width as u128)
+                .map(|k| max_op(0).unwrap_or(all_ones) >> k)
+        }
+        InstKind::Zext => {
+            let from = op(0)?.idx_val.and_then(|idx| by_idx.get(&idx))?.width;
+            Some(max_op(0).unwrap_or(souperinterp::mask(!0, from)))
+        }
+        InstKind::Eq | InstKind::Ne | InstKind::Slt | InstKind::Ult |
+        InstKind::Sle | InstKind::Ule => Some(1),
+        InstKind::Select => match (max_op(1), max_op(2)) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Rust condition that holds when `value` is an `iconst` below `width`
+pub fn generate_shift_guard(value: &str, width: u32, api: &dyn CliftApi) -> String {
+    let mut guard = format!("match pos.func.dfg.value_def({}) {{\n", value);
+    guard += &format!("ValueDef::Result(amount, _) => match {} {{\n", api.inst_data("amount"));
+    guard += "InstructionData::UnaryImm { opcode: Opcode::Iconst, imm } => {\n";
+    guard += &format!("let imm: i64 = {};\n", api.imm_to_i64());
+    guard += &format!("imm >= 0 && imm < {}\n", width);
+    guard += "}\n_ => false,\n},\n_ => false,\n}";
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn check(rule: &str) -> ShiftSafety {
+        check_shift_semantics(&parser::parse(rule))
+    }
+
+    #[test]
+    fn amounts_in_range_are_safe() {
+        let constant = "%0:i32 = var\n%1:i32 = shl %0, 31:i32\ninfer %1\n~>\n%2:i32 = mul %0, 2147483648:i32\nresult %2\n";
+        assert!(matches!(check(constant), ShiftSafety::Safe));
+        let masked = "%0:i32 = var\n%1:i32 = var\n%2:i32 = and %1, 31:i32\n%3:i32 = shl %0, %2\ninfer %3\n~>\n\
+                      %4:i32 = shl %0, %2\nresult %4\n";
+        assert!(matches!(check(masked), ShiftSafety::Safe));
+    }
+
+    #[test]
+    fn var_amount_is_guarded_once() {
+        let rule = "%0:i64 = var\n%1:i64 = var\n%2:i64 = shl %0, %1\n%3:i64 = lshr %2, %1\ninfer %3\n~>\n\
+                    %4:i64 = shl %0, %1\n%5:i64 = lshr %4, %1\nresult %5\n";
+        let guards = match check(rule) {
+            ShiftSafety::Guarded(guards) => guards,
+            _ => panic!("ERROR: expected a guard"),
+        };
+        let guards: Vec<(usize, &str, u32)> = guards
+            .iter()
+            .map(|guard| (guard.value, guard.name.as_str(), guard.width))
+            .collect();
+        assert_eq!(guards, vec![(1, "%1", 64)]);
+    }
+
+    #[test]
+    fn oversized_or_unguardable_amounts_are_unsound() {
+        let oversized = "%0:i32 = var\n%1:i32 = shl %0, 32:i32\ninfer %1\n~>\nresult 0:i32\n";
+        match check(oversized) {
+            ShiftSafety::Unsound(why) => assert_eq!(why, "%1 shifts by 32 at i32, which Cranelift masks"),
+            _ => panic!("ERROR: expected the rule to be unsound"),
+        }
+        let computed = "%0:i32 = var\n%1:i32 = var\n%2:i32 = add %1, 1:i32\n%3:i32 = shl %0, %2\ninfer %3\n~>\n\
+                        %4:i32 = shl %0, %2\nresult %4\n";
+        match check(computed) {
+            ShiftSafety::Unsound(why) => {
+                assert_eq!(why, "%3 shifts by %2, which is not known to be below 32 and can't be guarded")
+            }
+            _ => panic!("ERROR: expected the rule to be unsound"),
+        }
+    }
+
+    #[test]
+    fn unused_shift_is_ignored() {
+        let rule = "%0:i32 = var\n%1:i32 = var\n%2:i32 = shl %0, %1\n%3:i32 = add %0, %0\ninfer %3\n~>\n\
+                    %4:i32 = shl %0, 1:i32\nresult %4\n";
+        assert!(matches!(check(rule), ShiftSafety::Safe));
+    }
+}
@@ -0,0 +1,374 @@
+// SMT-LIB2 export of rule refinement queries
+//
+// Encodes a parsed rule over bitvectors of the declared widths and
+// asserts that the RHS does not refine the LHS, so a solver answering
+// `unsat` proves the rule:
+//
+//   (assert (and |%3.ok| (or (not |%5.ok|) (distinct |%3| |%5|))))
+//
+// Every value gets a `.ok` flag that is false when it is poison, with
+// the same conventions as `souperinterp`. Path conditions and dataflow
+// facts become assumptions on the values they name.
+
+use parser::{Inst, InstKind, SouperOperand};
+use preconditions::Preconditions;
+use souperinterp;
+use std::collections::HashMap;
+
+/// A value and its poison flag as SMT terms
+struct Term {
+    value: String,
+    ok: String,
+}
+
+fn get_bv_sort(width: u32) -> String {
+    format!("(_ BitVec {})", width)
+}
+
+fn get_bv_const(c: i128, width: u32) -> String {
+    format!("(_ bv{} {})", souperinterp::mask(c as u128, width), width)
+}
+
+fn get_bool_bv(cond: &str) -> String {
+    format!("(ite {} #b1 #b0)", cond)
+}
+
+/// `|%N|`, a quoted symbol so Souper names can be used as they are
+fn get_symbol(name: &str, suffix: &str) -> String {
+    format!("|{}{}|", name, suffix)
+}
+
+/// Returns the query for one rule, or None if it has an inst with no
+/// encoding or no `infer`/`result`
+pub fn generate_smt_query(insts: &[Inst], pre: &Preconditions, comment: &str) -> Option<String> {
+    let mut names: HashMap<usize, (String, u32)> = HashMap::new();
+    let mut query = String::new();
+    for line in comment.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        query += &format!("; {}\n", line);
+    }
+    query += "(set-logic QF_BV)\n";
+    let mut lhs = None;
+    let mut rhs = None;
+    for inst in insts {
+        let width = inst.width;
+        match inst.kind {
+            InstKind::Var => {
+                query += &format!("(declare-const {} {})\n", get_symbol(&inst.lhs, ""), get_bv_sort(width));
+                query += &format!("(define-fun {} () Bool true)\n", get_symbol(&inst.lhs, ".ok"));
+                names.insert(inst.lhs_idx, (inst.lhs.clone(), width));
+            }
+            InstKind::Infer => lhs = Some(get_operand(inst.ops.as_ref()?.first()?, width, &names)?),
+            InstKind::ResultInst => rhs = Some(get_operand(inst.ops.as_ref()?.first()?, width, &names)?),
+            _ => {
+                let term = get_inst_term(inst, &names)?;
+                query += &format!(
+                    "(define-fun {} () {} {})\n",
+                    get_symbol(&inst.lhs, ""),
+                    get_bv_sort(width),
+                    term.value
+                );
+                query += &format!("(define-fun {} () Bool {})\n", get_symbol(&inst.lhs, ".ok"), term.ok);
+                names.insert(inst.lhs_idx, (inst.lhs.clone(), width));
+            }
+        }
+    }
+    let (lhs, rhs) = (lhs?, rhs?);
+
+    let widths: HashMap<&str, u32> = names.values().map(|(name, w)| (name.as_str(), *w)).collect();
+    for pc in &pre.path_conditions {
+        let width = *widths.get(pc.value.as_str())?;
+        query += &format!(
+            "(assert (and {} (= {} {})))\n",
+            get_symbol(&pc.value, ".ok"),
+            get_symbol(&pc.value, ""),
+            get_bv_const(pc.constant, width)
+        );
+    }
+    for (var, fact) in &pre.facts {
+        let width = *widths.get(var.as_str())?;
+        if let Some(fact) = get_fact_term(&get_symbol(var, ""), fact, width) {
+            query += &format!("(assert {})\n", fact);
+        }
+    }
+    query += &format!(
+        "(assert (and {} (or (not {}) (distinct {} {}))))\n",
+        lhs.ok, rhs.ok, lhs.value, rhs.value
+    );
+    query += "(check-sat)\n";
+    Some(query)
+}
+
+fn get_operand(op: &SouperOperand, width: u32, names: &HashMap<usize, (String, u32)>) -> Option<Term> {
+    match op.idx_val {
+        Some(idx) => {
+            let (name, _) = names.get(&idx)?;
+            Some(Term {
+                value: get_symbol(name, ""),
+                ok: get_symbol(name, ".ok"),
+            })
+        }
+        None => Some(Term {
+            value: get_bv_const(op.const_val?, width),
+            ok: "true".to_string(),
+        }),
+    }
+}
+
+fn get_inst_term(inst: &Inst, names: &HashMap<usize, (String, u32)>) -> Option<Term> {
+    let ops = inst.ops.as_ref()?;
+    let width = inst.width;
+    let width_of = |idx: usize| names.get(&idx).map(|&(_, w)| w);
+    let mut args = Vec::new();
+    for (n, op) in ops.iter().enumerate() {
+        args.push(get_operand(op, souperinterp::get_operand_width(inst, n, &width_of), names)?);
+    }
+    let op_width = souperinterp::get_operand_width(inst, 1, &width_of);
+    let all_ok = if args.len() == 1 {
+        args[0].ok.clone()
+    } else {
+        let oks: Vec<&str> = args.iter().map(|a| a.ok.as_str()).collect();
+        format!("(and {})", oks.join(" "))
+    };
+    let a = &args.first()?.value;
+    let b = || args.get(1).map(|t| t.value.clone());
+    let mut ok = all_ok.clone();
+    let value = match inst.kind {
+        InstKind::Const => a.clone(),
+        InstKind::Add => format!("(bvadd {} {})", a, b()?),
+        InstKind::AddNSW | InstKind::AddNUW | InstKind::AddNW => {
+            let b = b()?;
+            let no_overflow = |ext: &str| {
+                format!(
+                    "(= ((_ {} 1) (bvadd {} {})) (bvadd ((_ {} 1) {}) ((_ {} 1) {})))",
+                    ext, a, b, ext, a, ext, b
+                )
+            };
+            let checks = match inst.kind {
+                InstKind::AddNSW => no_overflow("sign_extend"),
+                InstKind::AddNUW => no_overflow("zero_extend"),
+                _ => format!("(and {} {})", no_overflow("sign_extend"), no_overflow("zero_extend")),
+            };
+            ok = format!("(and {} {})", all_ok, checks);
+            format!("(bvadd {} {})", a, b)
+        }
+        InstKind::Sub => {
+            // a constant second operand is `c - x`, see souperinterp
+            if ops.get(1).is_some_and(|op| op.idx_val.is_none()) {
+                format!("(bvsub {} {})", b()?, a)
+            } else {
+                format!("(bvsub {} {})", a, b()?)
+            }
+        }
+        InstKind::Mul => format!("(bvmul {} {})", a, b()?),
+        InstKind::And => format!("(bvand {} {})", a, b()?),
+        InstKind::Or => format!("(bvor {} {})", a, b()?),
+        InstKind::Xor => format!("(bvxor {} {})", a, b()?),
+        InstKind::AndNot => format!("(bvand {} (bvnot {}))", a, b()?),
+        InstKind::OrNot => format!("(bvor {} (bvnot {}))", a, b()?),
+        InstKind::XorNot => format!("(bvxor {} (bvnot {}))", a, b()?),
+        InstKind::Not => format!("(bvnot {})", a),
+        InstKind::Shl | InstKind::Lshr | InstKind::Ashr => {
+            let b = b()?;
+            ok = format!("(and {} (bvult {} {}))", all_ok, b, get_bv_const(width as i128, width));
+            let op = match inst.kind {
+                InstKind::Shl => "bvshl",
+                InstKind::Lshr => "bvlshr",
+                _ => "bvashr",
+            };
+            format!("({} {} {})", op, a, b)
+        }
+        InstKind::Ctpop => {
+            let bits: Vec<String> = (0..width)
+                .map(|i| format!("((_ zero_extend {}) ((_ extract {} {}) {}))", width - 1, i, i, a))
+                .collect();
+            if bits.len() == 1 {
+                bits[0].clone()
+            } else {
+                format!("(bvadd {})", bits.join(" "))
+            }
+        }
+        InstKind::Ctlz | InstKind::Cttz => {
+            // the last set bit checked wins: the highest for ctlz, the
+            // lowest for cttz
+            let mut term = get_bv_const(width as i128, width);
+            let order: Vec<u32> = match inst.kind {
+                InstKind::Ctlz => (0..width).collect(),
+                _ => (0..width).rev().collect(),
+            };
+            for i in order {
+                let count = match inst.kind {
+                    InstKind::Ctlz => width - 1 - i,
+                    _ => i,
+                };
+                term = format!(
+                    "(ite (= ((_ extract {} {}) {}) #b1) {} {})",
+                    i,
+                    i,
+                    a,
+                    get_bv_const(count as i128, width),
+                    term
+                );
+            }
+            term
+        }
+        InstKind::Eq => get_bool_bv(&format!("(= {} {})", a, b()?)),
+        InstKind::Ne => get_bool_bv(&format!("(distinct {} {})", a, b()?)),
+        InstKind::Slt => get_bool_bv(&format!("(bvslt {} {})", a, b()?)),
+        InstKind::Ult => get_bool_bv(&format!("(bvult {} {})", a, b()?)),
+        InstKind::Sle => get_bool_bv(&format!("(bvsle {} {})", a, b()?)),
+        InstKind::Ule => get_bool_bv(&format!("(bvule {} {})", a, b()?)),
+        InstKind::Zext => {
+            if width == op_width {
+                a.clone()
+            } else {
+                format!("((_ zero_extend {}) {})", width.checked_sub(op_width)?, a)
+            }
+        }
+        InstKind::Select => {
+            let (c, x, y) = (args.first()?, args.get(1)?, args.get(2)?);
+            let picked = format!("(= {} #b1)", c.value);
+            ok = format!("(and {} (ite {} {} {}))", c.ok, picked, x.ok, y.ok);
+            format!("(ite {} {} {})", picked, x.value, y.value)
+        }
+        _ => return None,
+    };
+    Some(Term { value, ok })
+}
+
+/// Souper dataflow facts on a var; None for the ones we don't know,
+/// which then constrain nothing
+fn get_fact_term(var: &str, fact: &str, width: u32) -> Option<String> {
+    let zero = get_bv_const(0, width);
+    if let Some(bits) = fact.strip_prefix("knownBits=") {
+        // most significant bit first, `x` for unknown
+        let mut known = Vec::new();
+        for (i, bit) in bits.chars().rev().enumerate() {
+            if bit == '0' || bit == '1' {
+                known.push(format!("(= ((_ extract {} {}) {}) #b{})", i, i, var, bit));
+            }
+        }
+        return match known.len() {
+            0 => None,
+            1 => known.pop(),
+            _ => Some(format!("(and {})", known.join(" "))),
+        };
+    }
+    if let Some(n) = fact.strip_prefix("signBits=") {
+        let n: u32 = n.parse().ok()?;
+        if n == 0 || n > width {
+            return None;
+        }
+        let top = format!("((_ extract {} {}) {})", width - 1, width - n, var);
+        return Some(format!(
+            "(or (= {} {}) (= {} {}))",
+            top,
+            get_bv_const(0, n),
+            top,
+            get_bv_const(-1, n)
+        ));
+    }
+    if let Some(range) = fact.strip_prefix("range=[") {
+        // [lo, hi), wrapping around when lo > hi
+        let mut bounds = range.trim_end_matches(')').split(',');
+        let lo: i128 = bounds.next()?.trim().parse().ok()?;
+        let hi: i128 = bounds.next()?.trim().parse().ok()?;
+        let (lo_bits, hi_bits) = (souperinterp::mask(lo as u128, width), souperinterp::mask(hi as u128, width));
+        let op = if lo_bits < hi_bits { "and" } else { "or" };
+        return Some(format!(
+            "({} (bvuge {} {}) (bvult {} {}))",
+            op,
+            var,
+            get_bv_const(lo, width),
+            var,
+            get_bv_const(hi, width)
+        ));
+    }
+    match fact {
+        "nonZero" => Some(format!("(distinct {} {})", var, zero)),
+        "nonNegative" => Some(format!("(bvsge {} {})", var, zero)),
+        "negative" => Some(format!("(bvslt {} {})", var, zero)),
+        "powerOfTwo" => Some(format!(
+            "(and (distinct {} {}) (= (bvand {} (bvsub {} {})) {}))",
+            var,
+            zero,
+            var,
+            var,
+            get_bv_const(1, width),
+            zero
+        )),
+        _ => None,
+    }
+}
+
+/// Reads a solver results file, one `<file> <result>` per line (as
+/// printed by `for f in *.smt2; do echo "$f $(z3 $f)"; done`), into
+/// rule names (`rule_N`) and their results
+pub fn read_smt_results(text: &str) -> HashMap<String, String> {
+    let mut results = HashMap::new();
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+        let (file, result) = match (words.next(), words.last()) {
+            (Some(file), Some(result)) => (file, result),
+            _ => continue,
+        };
+        let file = file.trim_end_matches(':');
+        let name = file.rsplit('/').next().unwrap_or(file).trim_end_matches(".smt2");
+        results.insert(name.to_string(), result.to_string());
+    }
+    results
+}
+
+/// Name of the n-th rule of the input, as used for its query file
+pub fn get_rule_name(n: u32) -> String {
+    format!("rule_{}", n)
+}
+
+/// Whether the solver proved the n-th rule. `sat` and `unknown` ones
+/// and the ones it never saw are not.
+pub fn is_proven(results: &HashMap<String, String>, n: u32) -> bool {
+    results.get(&get_rule_name(n)).map(String::as_str) == Some("unsat")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use preconditions;
+
+    fn query(rule: &str) -> String {
+        let (rule_text, pre) = preconditions::split_preconditions(rule);
+        generate_smt_query(&parser::parse(&rule_text), &pre, "").unwrap()
+    }
+
+    #[test]
+    fn path_condition_and_fact_are_assumed() {
+        let query = query(
+            "%0:i32 = var (nonZero)\n%1:i32 = var\n%2:i1 = ne %0, %1\npc %2 1:i1\n\
+             %3:i32 = sub %0, %1\n%4:i1 = eq %3, 0:i32\ninfer %4\n~>\nresult 0:i1\n",
+        );
+        let asserts: Vec<&str> = query.lines().filter(|line| line.starts_with("(assert")).collect();
+        assert_eq!(
+            asserts,
+            vec![
+                "(assert (and |%2.ok| (= |%2| (_ bv1 1))))",
+                "(assert (distinct |%0| (_ bv0 32)))",
+                "(assert (and |%4.ok| (or (not true) (distinct |%4| (_ bv0 1)))))",
+            ]
+        );
+        assert!(query.ends_with("(check-sat)\n"));
+    }
+
+    #[test]
+    fn only_unsat_rules_are_proven() {
+        let results = read_smt_results(
+            "smt/rule_1.smt2 unsat\n\
+             smt/rule_2.smt2: sat\n\
+             rule_3.smt2 unknown\n\
+             ; rule_4.smt2 unsat\n\
+             smt/rule_5.smt2 (error \"line 3\") unsat\n",
+        );
+        let proven: Vec<u32> = (1..7).filter(|&n| is_proven(&results, n)).collect();
+        assert_eq!(proven, vec![1, 5]);
+    }
+}
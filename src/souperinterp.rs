@@ -0,0 +1,245 @@
+// Concrete evaluator for Souper rules
+//
+// Evaluates the parsed `Inst`s of a rule on concrete inputs, at any
+// width up to 128. Values are kept zero-extended in a u128, and None is
+// poison. As in Souper, `addnsw`/`addnuw`/`addnw` are poison on the
+// overflow they rule out, shifts by the width or more are poison, and
+// poison propagates through every inst except the arm a select does not
+// pick.
+//
+// Insts are read the way the code generator reads them: the parser turns
+// `sub x, c` into `add x, -c` and swaps `sub c, x` into `sub x, c`, so a
+// `sub` with a constant second operand is `c - x` (irsub_imm). Less-than
+// compares are never swapped, `slt c, x` has a const inst as its first
+// operand, so they are evaluated in operand order.
+
+use parser::{Inst, InstKind, SouperOperand};
+use std::collections::HashMap;
+
+/// Bits of a value, or None for poison
+pub type Concrete = Option<u128>;
+
+pub fn mask(bits: u128, width: u32) -> u128 {
+    if width >= 128 {
+        bits
+    } else {
+        bits & ((1u128 << width) - 1)
+    }
+}
+
+pub fn to_signed(bits: u128, width: u32) -> i128 {
+    if width == 0 || width >= 128 {
+        bits as i128
+    } else {
+        let shift = 128 - width;
+        ((bits << shift) as i128) >> shift
+    }
+}
+
+/// Souper syntax for a value of the given width (`-1:i32`, `poison`)
+pub fn display_value(value: Concrete, width: u32) -> String {
+    match value {
+        Some(bits) if width == 1 => format!("{}:i1", bits),
+        Some(bits) => format!("{}:i{}", to_signed(bits, width), width),
+        None => "poison".to_string(),
+    }
+}
+
+/// The vars of a rule: index, Souper name and width
+pub fn get_vars(insts: &[Inst]) -> Vec<(usize, String, u32)> {
+    insts
+        .iter()
+        .filter(|inst| matches!(inst.kind, InstKind::Var))
+        .map(|inst| (inst.lhs_idx, inst.lhs.clone(), inst.width))
+        .collect()
+}
+
+/// Evaluates a rule with its vars (by index) set to `inputs`. Returns
+/// the values of the LHS and of the RHS, or None if the rule uses an
+/// inst the evaluator does not know or has no `infer` or `result`.
+pub fn evaluate_rule(insts: &[Inst], inputs: &HashMap<usize, u128>) -> Option<(Concrete, Concrete)> {
+    let mut values: HashMap<usize, (Concrete, u32)> = HashMap::new();
+    let mut lhs = None;
+    let mut rhs = None;
+    for inst in insts {
+        match inst.kind {
+            InstKind::Var => {
+                let bits = *inputs.get(&inst.lhs_idx)?;
+                values.insert(inst.lhs_idx, (Some(mask(bits, inst.width)), inst.width));
+            }
+            InstKind::Infer => {
+                let op = inst.ops.as_ref()?.first()?;
+                lhs = Some(get_operand(op, inst.width, &values)?.0);
+            }
+            InstKind::ResultInst => {
+                let op = inst.ops.as_ref()?.first()?;
+                rhs = Some(get_operand(op, inst.width, &values)?.0);
+            }
+            _ => {
+                let value = evaluate_inst(inst, &values)?;
+                values.insert(inst.lhs_idx, (value, inst.width));
+            }
+        }
+    }
+    Some((lhs?, rhs?))
+}
+
+/// Width of the n-th operand of `inst`, given the widths of the values
+/// by index. The parser gives constants the width of the inst, which is
+/// wrong for compares and zext: they work at the width of their value
+/// operand, and a select condition is an i1.
+pub fn get_operand_width(inst: &Inst, n: usize, width_of: &dyn Fn(usize) -> Option<u32>) -> u32 {
+    let ops = match inst.ops.as_ref() {
+        Some(ops) => ops,
+        None => return inst.width,
+    };
+    match inst.kind {
+        InstKind::Select if n == 0 => 1,
+        InstKind::Eq | InstKind::Ne | InstKind::Slt | InstKind::Ult |
+        InstKind::Sle | InstKind::Ule | InstKind::Zext => ops
+            .iter()
+            .filter_map(|op| op.idx_val)
+            .filter_map(width_of)
+            .next()
+            .unwrap_or(inst.width),
+        _ => inst.width,
+    }
+}
+
+/// Value and width of an operand; constants take `width`
+fn get_operand(
+    op: &SouperOperand,
+    width: u32,
+    values: &HashMap<usize, (Concrete, u32)>,
+) -> Option<(Concrete, u32)> {
+    match op.idx_val {
+        Some(idx) => values.get(&idx).cloned(),
+        None => op.const_val.map(|c| (Some(mask(c as u128, width)), width)),
+    }
+}
+
+fn evaluate_inst(inst: &Inst, values: &HashMap<usize, (Concrete, u32)>) -> Option<Concrete> {
+    let ops = inst.ops.as_ref()?;
+    let width = inst.width;
+    let width_of = |idx: usize| values.get(&idx).map(|&(_, w)| w);
+    let op_width = get_operand_width(inst, 1, &width_of);
+    let mut args = Vec::new();
+    for (n, op) in ops.iter().enumerate() {
+        args.push(get_operand(op, get_operand_width(inst, n, &width_of), values)?.0);
+    }
+
+    if let InstKind::Select = inst.kind {
+        let (c, x, y) = (*args.first()?, *args.get(1)?, *args.get(2)?);
+        return Some(c.and_then(|c| if c & 1 == 1 { x } else { y }));
+    }
+    let args: Vec<u128> = match args.iter().cloned().collect::<Option<Vec<u128>>>() {
+        Some(args) => args,
+        None => return Some(None),
+    };
+    let a = *args.first()?;
+    let b = || args.get(1).cloned();
+    let value = match inst.kind {
+        InstKind::Const => a,
+        InstKind::Add => a.wrapping_add(b()?),
+        InstKind::AddNSW | InstKind::AddNUW | InstKind::AddNW => {
+            let b = b()?;
+            let sum = mask(a.wrapping_add(b), width);
+            let signed_overflow = match to_signed(a, width).checked_add(to_signed(b, width)) {
+                Some(s) => to_signed(sum, width) != s,
+                None => true,
+            };
+            let unsigned_overflow = match a.checked_add(b) {
+                Some(s) => mask(s, width) != s,
+                None => true,
+            };
+            let overflow = match inst.kind {
+                InstKind::AddNSW => signed_overflow,
+                InstKind::AddNUW => unsigned_overflow,
+                _ => signed_overflow || unsigned_overflow,
+            };
+            if overflow {
+                return Some(None);
+            }
+            sum
+        }
+        InstKind::Sub => {
+            let b = b()?;
+            if ops.get(1).is_some_and(|op| op.idx_val.is_none()) {
+                b.wrapping_sub(a)
+            } else {
+                a.wrapping_sub(b)
+            }
+        }
+        InstKind::Mul => a.wrapping_mul(b()?),
+        InstKind::And => a & b()?,
+        InstKind::Or => a | b()?,
+        InstKind::Xor => a ^ b()?,
+        InstKind::AndNot => a & !b()?,
+        InstKind::OrNot => a | !b()?,
+        InstKind::XorNot => a ^ !b()?,
+        InstKind::Not => !a,
+        InstKind::Shl | InstKind::Lshr | InstKind::Ashr => {
+            let b = b()?;
+            if b >= width as u128 {
+                return Some(None);
+            }
+            match inst.kind {
+                InstKind::Shl => a << b,
+                InstKind::Lshr => a >> b,
+                _ => (to_signed(a, width) >> b) as u128,
+            }
+        }
+        InstKind::Ctpop => a.count_ones() as u128,
+        InstKind::Ctlz => (a.leading_zeros() - (128 - width)) as u128,
+        InstKind::Cttz => a.trailing_zeros().min(width) as u128,
+        InstKind::Eq => (a == b()?) as u128,
+        InstKind::Ne => (a != b()?) as u128,
+        InstKind::Ult => (a < b()?) as u128,
+        InstKind::Ule => (a <= b()?) as u128,
+        InstKind::Slt => (to_signed(a, op_width) < to_signed(b()?, op_width)) as u128,
+        InstKind::Sle => (to_signed(a, op_width) <= to_signed(b()?, op_width)) as u128,
+        InstKind::Zext => a,
+        _ => return None,
+    };
+    Some(Some(mask(value, width)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    /// LHS and RHS values of a rule whose only var is %0
+    fn evaluate(rule: &str, x: i128) -> (Concrete, Concrete) {
+        let insts = parser::parse(rule);
+        let mut inputs = HashMap::new();
+        inputs.insert(0, x as u128);
+        evaluate_rule(&insts, &inputs).unwrap()
+    }
+
+    #[test]
+    fn compare_with_constant_first() {
+        let rule = "%0:i8 = var\n%1:i1 = slt 0:i8, %0\ninfer %1\n~>\nresult 0:i1\n";
+        assert_eq!(evaluate(rule, 5).0, Some(1));
+        assert_eq!(evaluate(rule, -5).0, Some(0));
+        assert_eq!(evaluate(rule, 0).0, Some(0));
+        let rule = "%0:i8 = var\n%1:i1 = ule 200:i8, %0\ninfer %1\n~>\nresult 0:i1\n";
+        assert_eq!(evaluate(rule, 200).0, Some(1));
+        assert_eq!(evaluate(rule, 199).0, Some(0));
+    }
+
+    #[test]
+    fn compare_with_constant_second() {
+        let rule = "%0:i8 = var\n%1:i1 = slt %0, 0:i8\ninfer %1\n~>\nresult 0:i1\n";
+        assert_eq!(evaluate(rule, 5).0, Some(0));
+        assert_eq!(evaluate(rule, -5).0, Some(1));
+    }
+
+    #[test]
+    fn sub_with_constant_first() {
+        let rule = "%0:i8 = var\n%1:i8 = sub 10:i8, %0\ninfer %1\n~>\nresult 0:i8\n";
+        assert_eq!(evaluate(rule, 3).0, Some(7));
+        let rule = "%0:i8 = var\n%1:i8 = sub %0, 10:i8\ninfer %1\n~>\nresult 0:i8\n";
+        assert_eq!(evaluate(rule, 3).0, Some(249));
+    }
+}
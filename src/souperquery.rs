@@ -0,0 +1,270 @@
+// Souper query harvesting
+//
+// Walks the DFG model of a clif function backwards from each integer
+// instruction and prints the expression DAG it computes as a Souper
+// `infer` query:
+//
+//   ; %fn0 v3
+//   %0:i32 = var
+//   %1:i32 = add %0, 1:i32
+//   %2:i32 = mul %1, %0
+//   infer %2
+//
+// Values further than the depth limit from the root, block params and
+// instructions Souper can't express become `var`s. Constants are inlined.
+// Cranelift-only opcodes are spelled as the Souper expansion that
+// cliftinstbuilder::get_clift_equivalent matches them against.
+
+use clifreader::{ClifFunction, ClifValue};
+use cliftinstbuilder::{self, CtonOpcode, CtonOperand};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+struct SouperQuery<'a> {
+    func: &'a ClifFunction,
+    // distance of each value from the root, along its shortest path
+    depths: HashMap<usize, usize>,
+    max_depth: usize,
+    // Souper names of the values already printed
+    names: HashMap<usize, String>,
+    lines: Vec<String>,
+}
+
+impl<'a> SouperQuery<'a> {
+    fn new(func: &'a ClifFunction, root: usize, max_depth: usize) -> SouperQuery<'a> {
+        let mut depths = HashMap::new();
+        let mut worklist = VecDeque::new();
+        depths.insert(root, 0);
+        worklist.push_back(root);
+        while let Some(value) = worklist.pop_front() {
+            let depth = depths[&value];
+            if depth >= max_depth {
+                continue;
+            }
+            if let Some(def) = func.get_value(value) {
+                for op in def.args.iter().filter_map(|op| op.idx_val) {
+                    let op = func.resolve_alias(op);
+                    if let Entry::Vacant(entry) = depths.entry(op) {
+                        entry.insert(depth + 1);
+                        worklist.push_back(op);
+                    }
+                }
+            }
+        }
+        SouperQuery {
+            func,
+            depths,
+            max_depth,
+            names: HashMap::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn new_name(&self) -> String {
+        format!("%{}", self.lines.len())
+    }
+
+    fn push_line(&mut self, width: u32, rhs: String) -> String {
+        let name = self.new_name();
+        self.lines.push(format!("{}:i{} = {}", name, width, rhs));
+        name
+    }
+
+    /// Souper name of `value`, printing its definition first
+    fn get_value_name(&mut self, value: usize) -> Option<String> {
+        let value = self.func.resolve_alias(value);
+        if let Some(name) = self.names.get(&value) {
+            return Some(name.clone());
+        }
+        let def = self.func.get_value(value)?.clone();
+        let width = def.width?;
+        let expand = self.depths.get(&value).is_some_and(|&d| d < self.max_depth);
+        let saved_lines = self.lines.len();
+        let saved_names = self.names.clone();
+        let inst = if expand { self.get_inst(&def, width) } else { None };
+        let name = match inst {
+            Some(name) => name,
+            None => {
+                // drop whatever a failed expansion printed
+                self.lines.truncate(saved_lines);
+                self.names = saved_names;
+                self.push_line(width, "var".to_string())
+            }
+        };
+        self.names.insert(value, name.clone());
+        Some(name)
+    }
+
+    /// Souper operand for `op`, which has to be `width` bits wide
+    fn get_operand(&mut self, op: &CtonOperand, width: u32) -> Option<String> {
+        match op.idx_val {
+            Some(value) => {
+                let def = self.func.get_value(value)?;
+                if def.width != Some(width) {
+                    return None;
+                }
+                // constants are inlined
+                if let Some(CtonOpcode::Iconst) = def.opcode {
+                    let c = def.args.first()?.const_val?;
+                    return Some(format!("{}:i{}", c, width));
+                }
+                self.get_value_name(value)
+            }
+            None => Some(format!("{}:i{}", op.const_val?, width)),
+        }
+    }
+
+    /// The value of a constant operand or of an `iconst` operand
+    fn get_const_arg(&self, op: &CtonOperand) -> Option<i128> {
+        match op.idx_val {
+            Some(value) => {
+                let def = self.func.get_value(value)?;
+                match def.opcode {
+                    Some(CtonOpcode::Iconst) => def.args.first()?.const_val,
+                    _ => None,
+                }
+            }
+            None => op.const_val,
+        }
+    }
+
+    /// Prints the Souper insts for `def` and returns the name of its
+    /// result, or None if Souper can't express it
+    fn get_inst(&mut self, def: &ClifValue, width: u32) -> Option<String> {
+        let opcode = def.opcode.clone()?;
+        let args = &def.args;
+        match opcode {
+            CtonOpcode::Iconst => None,
+            CtonOpcode::IsubImm => {
+                // irsub_imm x, c is c - x
+                let x = self.get_operand(args.first()?, width)?;
+                let c = self.get_operand(args.get(1)?, width)?;
+                Some(self.push_line(width, format!("sub {}, {}", c, x)))
+            }
+            CtonOpcode::Icmp | CtonOpcode::IcmpImm => {
+                let name = cliftinstbuilder::get_souper_inst_name(opcode, def.cond.clone())?;
+                // the operands are as wide as the compared value
+                let op_width = args
+                    .iter()
+                    .filter_map(|op| op.idx_val)
+                    .next()
+                    .and_then(|v| self.func.get_value(v))
+                    .and_then(|v| v.width)?;
+                let x = self.get_operand(args.first()?, op_width)?;
+                let y = self.get_operand(args.get(1)?, op_width)?;
+                Some(self.push_line(1, format!("{} {}, {}", name, x, y)))
+            }
+            CtonOpcode::Select => {
+                let c = self.get_operand(args.first()?, 1)?;
+                let x = self.get_operand(args.get(1)?, width)?;
+                let y = self.get_operand(args.get(2)?, width)?;
+                Some(self.push_line(width, format!("select {}, {}, {}", c, x, y)))
+            }
+            CtonOpcode::Popcnt | CtonOpcode::Clz | CtonOpcode::Ctz => {
+                let name = cliftinstbuilder::get_souper_inst_name(opcode, None)?;
+                let x = self.get_operand(args.first()?, width)?;
+                Some(self.push_line(width, format!("{} {}", name, x)))
+            }
+            CtonOpcode::Bnot => {
+                let x = self.get_operand(args.first()?, width)?;
+                Some(self.push_line(width, format!("xor {}, -1:i{}", x, width)))
+            }
+            CtonOpcode::Ineg => {
+                let x = self.get_operand(args.first()?, width)?;
+                Some(self.push_line(width, format!("sub 0:i{}, {}", width, x)))
+            }
+            CtonOpcode::BandNot | CtonOpcode::BorNot | CtonOpcode::BxorNot => {
+                let name = match opcode {
+                    CtonOpcode::BandNot => "and",
+                    CtonOpcode::BorNot => "or",
+                    _ => "xor",
+                };
+                let x = self.get_operand(args.first()?, width)?;
+                let y = self.get_operand(args.get(1)?, width)?;
+                let not_y = self.push_line(width, format!("xor {}, -1:i{}", y, width));
+                Some(self.push_line(width, format!("{} {}, {}", name, x, not_y)))
+            }
+            CtonOpcode::Iabs => {
+                let x = self.get_operand(args.first()?, width)?;
+                let neg = self.push_line(width, format!("sub 0:i{}, {}", width, x));
+                let cmp = self.push_line(1, format!("slt {}, 0:i{}", x, width));
+                Some(self.push_line(width, format!("select {}, {}, {}", cmp, neg, x)))
+            }
+            CtonOpcode::Imin | CtonOpcode::Imax | CtonOpcode::Umin | CtonOpcode::Umax => {
+                let cond = match opcode {
+                    CtonOpcode::Imin | CtonOpcode::Imax => "slt",
+                    _ => "ult",
+                };
+                let x = self.get_operand(args.first()?, width)?;
+                let y = self.get_operand(args.get(1)?, width)?;
+                let cmp = self.push_line(1, format!("{} {}, {}", cond, x, y));
+                let (t, f) = match opcode {
+                    CtonOpcode::Imin | CtonOpcode::Umin => (x, y),
+                    _ => (y, x),
+                };
+                Some(self.push_line(width, format!("select {}, {}, {}", cmp, t, f)))
+            }
+            CtonOpcode::Ishl | CtonOpcode::Ushr | CtonOpcode::Sshr |
+            CtonOpcode::IshlImm | CtonOpcode::UshrImm | CtonOpcode::SshrImm => {
+                // Cranelift takes the shift amount modulo the width, where
+                // an oversized amount is poison to Souper
+                let name = cliftinstbuilder::get_souper_inst_name(opcode, None)?;
+                let x = self.get_operand(args.first()?, width)?;
+                let amount = match self.get_const_arg(args.get(1)?) {
+                    Some(c) => format!("{}:i{}", c.rem_euclid(width as i128), width),
+                    None => {
+                        let amount = self.get_operand(args.get(1)?, width)?;
+                        self.push_line(width, format!("and {}, {}:i{}", amount, width - 1, width))
+                    }
+                };
+                Some(self.push_line(width, format!("{} {}, {}", name, x, amount)))
+            }
+            _ => {
+                // add, mul, and, ... and their `*_imm` forms
+                let name = cliftinstbuilder::get_souper_inst_name(opcode, None)?;
+                let x = self.get_operand(args.first()?, width)?;
+                let y = self.get_operand(args.get(1)?, width)?;
+                Some(self.push_line(width, format!("{} {}, {}", name, x, y)))
+            }
+        }
+    }
+}
+
+/// Returns the Souper infer query rooted at `root`, or None if the root
+/// is not an integer instruction Souper can express
+fn generate_souper_query(func: &ClifFunction, root: usize, max_depth: usize) -> Option<String> {
+    let def = func.get_value(root)?;
+    def.width?;
+    match def.opcode {
+        None | Some(CtonOpcode::Iconst) => return None,
+        _ => {}
+    }
+    let mut query = SouperQuery::new(func, root, max_depth.max(1));
+    let name = query.get_value_name(root)?;
+    if query.lines.last()?.ends_with("= var") {
+        return None;
+    }
+    let mut query_str = String::new();
+    for line in query.lines {
+        query_str += &format!("{}\n", line);
+    }
+    query_str += &format!("infer {}\n", name);
+    Some(query_str)
+}
+
+/// Returns the Souper infer queries for every integer instruction of
+/// the given functions, skipping duplicates
+pub fn generate_souper_queries(funcs: &[ClifFunction], max_depth: usize) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut queries = Vec::new();
+    for func in funcs {
+        for &root in &func.results {
+            if let Some(query) = generate_souper_query(func, root, max_depth) {
+                if seen.insert(query.clone()) {
+                    queries.push(format!("; {} v{}\n{}", func.name, root, query));
+                }
+            }
+        }
+    }
+    queries
+}
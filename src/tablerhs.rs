@@ -1,21 +1,37 @@
 // Hash Map for LHS to RHS
 
+use cliftinstbuilder;
 use processrhs::CliftInstWithArgs;
 use std::collections::HashMap;
 
 pub fn map_lhs_to_rhs(
     id: usize,
     rhs: Vec<CliftInstWithArgs>,
-    mut table: HashMap<usize, Vec<CliftInstWithArgs>>,
-) -> HashMap<usize, Vec<CliftInstWithArgs>> {
+    table: &mut HashMap<usize, Vec<CliftInstWithArgs>>,
+) {
     // Does hashmap already have the key
-    if table.contains_key(&id) {
-        // TODO: Do we want to compare the existing RHS entry with incoming argument rhs?
-        // Ideally, for one hash_id, we should only have one unique RHS
-        // for now, don't insert anything and return the table as it is
-        table
-    } else {
-        table.insert(id, rhs);
-        table
-    }
+    // TODO: Do we want to compare the existing RHS entry with incoming argument rhs?
+    // Ideally, for one hash_id, we should only have one unique RHS
+    // for now, don't insert anything and leave the table as it is
+    table.entry(id).or_insert(rhs);
+}
+
+/// A key that is the same for two RHSs exactly when they are the same
+/// sequence of insts over the same operand names
+pub fn get_rhs_key(rhs: &[CliftInstWithArgs]) -> String {
+    let insts: Vec<String> = rhs
+        .iter()
+        .map(|inst| {
+            format!(
+                "{} {} {} {} {} [{}]",
+                cliftinstbuilder::get_clift_instdata_name(inst.kind.clone()),
+                cliftinstbuilder::get_clift_opcode_name(inst.opcode.clone()),
+                cliftinstbuilder::get_clift_cond_name(inst.cond.clone()),
+                inst.width,
+                inst.lhs_index,
+                inst.cops.join(", ")
+            )
+        })
+        .collect();
+    insts.join("; ")
 }
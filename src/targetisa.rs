@@ -0,0 +1,77 @@
+// Target ISA description
+//
+// Some Cranelift opcodes are only a single cheap machine instruction when
+// the target has the matching ISA extension. The RHS lowering asks this
+// table which of them it may emit for the target given with `--target`.
+
+#[derive(Clone)]
+pub struct TargetFeatures {
+    pub name: String,
+    /// `target` line of a clif filetest
+    pub clif_target: String,
+    /// band_not: BMI1 `andn` on x86, `bic` on aarch64
+    pub has_band_not: bool,
+    /// bor_not: `orn` on aarch64, nothing on x86
+    pub has_bor_not: bool,
+    /// bxor_not: `eon` on aarch64, nothing on x86
+    pub has_bxor_not: bool,
+    /// popcnt: POPCNT on x86, `cnt` on aarch64
+    pub has_popcnt: bool,
+    /// clz: LZCNT on x86, `clz` on aarch64
+    pub has_clz: bool,
+    /// ctz: BMI1 `tzcnt` on x86, `rbit` + `clz` on aarch64
+    pub has_ctz: bool,
+}
+
+/// Names accepted by `--target`
+pub const TARGET_NAMES: [&str; 3] = ["x86_64-baseline", "x86_64-v3", "aarch64"];
+
+/// Without `--target` every opcode is assumed to be available
+pub fn get_default_target() -> TargetFeatures {
+    TargetFeatures {
+        name: "any".to_string(),
+        clif_target: "x86_64".to_string(),
+        has_band_not: true,
+        has_bor_not: true,
+        has_bxor_not: true,
+        has_popcnt: true,
+        has_clz: true,
+        has_ctz: true,
+    }
+}
+
+pub fn get_target_features(name: &str) -> Option<TargetFeatures> {
+    match name {
+        "x86_64-baseline" => Some(TargetFeatures {
+            name: name.to_string(),
+            clif_target: "x86_64".to_string(),
+            has_band_not: false,
+            has_bor_not: false,
+            has_bxor_not: false,
+            has_popcnt: false,
+            has_clz: false,
+            has_ctz: false,
+        }),
+        "x86_64-v3" => Some(TargetFeatures {
+            name: name.to_string(),
+            clif_target: "x86_64 has_popcnt has_lzcnt has_bmi1 has_bmi2 has_avx2".to_string(),
+            has_band_not: true,
+            has_bor_not: false,
+            has_bxor_not: false,
+            has_popcnt: true,
+            has_clz: true,
+            has_ctz: true,
+        }),
+        "aarch64" => Some(TargetFeatures {
+            name: name.to_string(),
+            clif_target: "aarch64".to_string(),
+            has_band_not: true,
+            has_bor_not: true,
+            has_bxor_not: true,
+            has_popcnt: true,
+            has_clz: true,
+            has_ctz: true,
+        }),
+        _ => None,
+    }
+}
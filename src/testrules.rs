@@ -0,0 +1,68 @@
+// Rules compiled for tests
+//
+// Builds the merged tree, RHS table, path condition tables and checks of
+// a few rules the way main does, for the tests of the modules that walk
+// or emit the tree.
+
+use cliftinstbuilder;
+use lhspatternmatcher;
+use matcher::{self, LeafCheck};
+use mergedtree::{self, MergedArena};
+use parser;
+use pctable;
+use preconditions;
+use processlhs;
+use processrhs::{self, CliftInstWithArgs};
+use rhslowering;
+use shiftsemantics::{self, ShiftSafety};
+use std::collections::HashMap;
+use tablerhs;
+
+pub struct Rules {
+    pub arena: MergedArena,
+    pub rhs: HashMap<usize, Vec<CliftInstWithArgs>>,
+    pub pcs: HashMap<usize, HashMap<String, usize>>,
+    pub checks: HashMap<usize, Vec<LeafCheck>>,
+    /// id of the last LHS node of each rule, in order
+    pub leaves: Vec<usize>,
+}
+
+/// The merged tree of the rules, in the order they are given, built the
+/// way main does for one LHS variant per rule
+pub fn compile(rules: &[&str]) -> Rules {
+    let mut compiled = Rules {
+        arena: MergedArena::new(),
+        rhs: HashMap::new(),
+        pcs: HashMap::new(),
+        checks: HashMap::new(),
+        leaves: Vec::new(),
+    };
+    let mut nodes_count = 0;
+    for rule in rules {
+        let (rule, pre) = preconditions::split_preconditions(rule);
+        let souper_insts = parser::parse(&rule);
+        let inequalities = preconditions::get_stated_inequalities(&pre, &souper_insts).unwrap();
+        let shift_guards = match shiftsemantics::check_shift_semantics(&souper_insts) {
+            ShiftSafety::Guarded(guards) => guards,
+            _ => Vec::new(),
+        };
+        let insts = cliftinstbuilder::transform_souper_to_clift_insts(souper_insts);
+        let nodes = lhspatternmatcher::generate_single_tree_patterns(insts.clone(), nodes_count + 1);
+        nodes_count += nodes.len();
+        let lhs_info = processlhs::update_arg_nodes_in_lhs(nodes);
+        let lhs_pc = pctable::get_path_condition_args_for_lhs(lhs_info.nodes.clone());
+        let rhs_insts = rhslowering::lower_rhs_clift_insts(insts);
+        let rhs_info = processrhs::update_rhs_with_argnames(rhs_insts, lhs_info.htable.clone());
+        let id = lhs_info.nodes[lhs_info.nodes.len() - 1].id;
+        tablerhs::map_lhs_to_rhs(id, rhs_info.rhs_insts, &mut compiled.rhs);
+        let checks = matcher::get_leaf_checks(&lhs_pc, &inequalities, &shift_guards, &rhs_info.full_table).unwrap();
+        if !checks.is_empty() {
+            compiled.checks.insert(id, checks);
+        }
+        compiled.pcs.insert(id, lhs_pc);
+        let checked = compiled.checks.keys().cloned().collect();
+        mergedtree::generate_merged_prefix_tree(&lhs_info.nodes, &mut compiled.arena, &checked);
+        compiled.leaves.push(id);
+    }
+    compiled
+}
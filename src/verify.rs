@@ -0,0 +1,271 @@
+// Randomised soundness checking of Souper rules
+//
+// Runs every rule through `souperinterp` on edge-case inputs (0, 1, -1,
+// INT_MIN, INT_MAX and the powers of two) and on random ones, looking
+// for an input where the RHS does not refine the LHS: the LHS is defined
+// but the RHS is poison or a different value. Rules with such a
+// counterexample are quarantined instead of being turned into code.
+//
+// For exhaustive checking a rule is re-instantiated at narrow widths
+// (i4, i8) where every input can be enumerated. Width-dependent constants
+// are rescaled (-1, INT_MIN, INT_MAX, and `W - 1` or `W` used as a shift
+// amount or next to a bit count); a constant that only makes sense at the
+// original width makes the rule not width-scalable.
+
+use parser::{Inst, InstKind, SouperOperand};
+use random::Random;
+use souperinterp::{self, Concrete};
+use std::collections::HashMap;
+
+/// Combinations of edge cases tried before giving up on trying them all
+const MAX_EDGE_CASES: usize = 4096;
+const RANDOM_TRIALS: usize = 1024;
+
+/// Widths rules are shrunk to for exhaustive checking
+const NARROW_WIDTHS: [u32; 2] = [4, 8];
+/// Most input bits enumerated exhaustively
+const MAX_EXHAUSTIVE_BITS: u32 = 20;
+
+pub enum Verdict {
+    /// No counterexample found
+    Sound,
+    /// An input and what the LHS and RHS give for it
+    Counterexample(String),
+    /// The rule has an inst the evaluator does not know
+    Unsupported,
+}
+
+/// Outcome of checking a rule exhaustively at narrow widths
+pub enum ExhaustiveVerdict {
+    /// No counterexample at any of these widths
+    Verified(Vec<u32>),
+    /// The width and the counterexample found at it
+    Refuted(u32, String),
+    /// Why the rule could not be checked at any narrow width
+    NotScalable(String),
+    /// The rule has an inst the evaluator does not know
+    Unsupported,
+}
+
+/// Edge-case values of the given width
+fn get_edge_cases(width: u32) -> Vec<u128> {
+    let all_ones = souperinterp::mask(!0, width);
+    let int_min = 1u128 << (width - 1);
+    let mut values = vec![0, 1, all_ones, int_min, int_min - 1];
+    for shift in 1..width {
+        values.push(1u128 << shift);
+    }
+    values.sort_unstable();
+    values.dedup();
+    values
+}
+
+pub fn verify_rule(insts: &[Inst], rng: &mut Random) -> Verdict {
+    let vars = souperinterp::get_vars(insts);
+    if vars.iter().any(|&(_, _, width)| width == 0 || width > 128) {
+        return Verdict::Unsupported;
+    }
+    let edge_cases: Vec<Vec<u128>> = vars.iter().map(|&(_, _, width)| get_edge_cases(width)).collect();
+    let combinations = edge_cases
+        .iter()
+        .try_fold(1usize, |total, values| total.checked_mul(values.len()))
+        .unwrap_or(usize::MAX);
+
+    let mut trials: Vec<Vec<u128>> = Vec::new();
+    if combinations <= MAX_EDGE_CASES {
+        for n in 0..combinations {
+            let mut rest = n;
+            let mut trial = Vec::new();
+            for values in &edge_cases {
+                trial.push(values[rest % values.len()]);
+                rest /= values.len();
+            }
+            trials.push(trial);
+        }
+    } else {
+        for _ in 0..MAX_EDGE_CASES {
+            trials.push(edge_cases.iter().map(|values| values[rng.below(values.len())]).collect());
+        }
+    }
+    // random inputs, with an edge case mixed in now and then
+    for _ in 0..RANDOM_TRIALS {
+        let trial = edge_cases
+            .iter()
+            .map(|values| {
+                if rng.below(4) == 0 {
+                    values[rng.below(values.len())]
+                } else {
+                    rng.next_u128()
+                }
+            })
+            .collect();
+        trials.push(trial);
+    }
+
+    for trial in trials {
+        let inputs: HashMap<usize, u128> = vars.iter().map(|&(idx, _, _)| idx).zip(trial.iter().cloned()).collect();
+        let (lhs, rhs) = match souperinterp::evaluate_rule(insts, &inputs) {
+            Some(values) => values,
+            None => return Verdict::Unsupported,
+        };
+        if lhs.is_some() && lhs != rhs {
+            return Verdict::Counterexample(describe_counterexample(insts, &vars, &trial, lhs, rhs));
+        }
+    }
+    Verdict::Sound
+}
+
+fn describe_counterexample(
+    insts: &[Inst],
+    vars: &[(usize, String, u32)],
+    trial: &[u128],
+    lhs: Concrete,
+    rhs: Concrete,
+) -> String {
+    let width = insts.iter().rev().map(|inst| inst.width).find(|&w| w != 0).unwrap_or(0);
+    let inputs: Vec<String> = vars
+        .iter()
+        .zip(trial)
+        .map(|((_, name, w), &bits)| {
+            format!("{} = {}", name, souperinterp::display_value(Some(souperinterp::mask(bits, *w)), *w))
+        })
+        .collect();
+    format!(
+        "{}: LHS {}, RHS {}",
+        inputs.join(", "),
+        souperinterp::display_value(lhs, width),
+        souperinterp::display_value(rhs, width)
+    )
+}
+
+/// Checks every input assignment of the rule shrunk to each narrow width
+pub fn verify_rule_exhaustive(insts: &[Inst]) -> ExhaustiveVerdict {
+    let mut widths: Vec<u32> = insts.iter().map(|inst| inst.width).filter(|&w| w > 1).collect();
+    widths.sort_unstable();
+    widths.dedup();
+    let from = match widths.len() {
+        0 => 1,
+        1 => widths[0],
+        _ => return ExhaustiveVerdict::NotScalable("mixes bit widths".to_string()),
+    };
+    let mut targets: Vec<u32> = NARROW_WIDTHS.iter().cloned().filter(|&w| w < from).collect();
+    if from <= *NARROW_WIDTHS.last().unwrap_or(&0) {
+        targets.push(from);
+    }
+    let mut verified = Vec::new();
+    let mut reason = format!("no width below i{} to shrink to", from);
+    for to in targets {
+        let narrow = match narrow_rule(insts, from, to) {
+            Ok(narrow) => narrow,
+            Err(why) => {
+                reason = why;
+                continue;
+            }
+        };
+        let vars = souperinterp::get_vars(&narrow);
+        let bits: u32 = vars.iter().map(|&(_, _, w)| w).sum();
+        if bits > MAX_EXHAUSTIVE_BITS {
+            reason = format!("{} input bits at i{}", bits, to);
+            continue;
+        }
+        for n in 0..1u128 << bits {
+            let mut rest = n;
+            let mut trial = Vec::new();
+            for &(_, _, w) in &vars {
+                trial.push(souperinterp::mask(rest, w));
+                rest >>= w;
+            }
+            let inputs: HashMap<usize, u128> = vars.iter().map(|&(idx, _, _)| idx).zip(trial.iter().cloned()).collect();
+            let (lhs, rhs) = match souperinterp::evaluate_rule(&narrow, &inputs) {
+                Some(values) => values,
+                None => return ExhaustiveVerdict::Unsupported,
+            };
+            if lhs.is_some() && lhs != rhs {
+                let cex = describe_counterexample(&narrow, &vars, &trial, lhs, rhs);
+                return ExhaustiveVerdict::Refuted(to, cex);
+            }
+        }
+        verified.push(to);
+    }
+    if verified.is_empty() {
+        ExhaustiveVerdict::NotScalable(reason)
+    } else {
+        ExhaustiveVerdict::Verified(verified)
+    }
+}
+
+/// Re-instantiates a rule whose values are `from` bits wide at `to`
+/// bits, or says why its constants can't be rescaled
+fn narrow_rule(insts: &[Inst], from: u32, to: u32) -> Result<Vec<Inst>, String> {
+    let widths: HashMap<usize, u32> = insts.iter().map(|inst| (inst.lhs_idx, inst.width)).collect();
+    let is_bit_count = |op: &SouperOperand| {
+        op.idx_val.is_some_and(|idx| {
+            insts.iter().any(|inst| {
+                inst.lhs_idx == idx && matches!(inst.kind, InstKind::Ctpop | InstKind::Ctlz | InstKind::Cttz)
+            })
+        })
+    };
+    let mut narrow = Vec::new();
+    for inst in insts {
+        let mut inst = inst.clone();
+        let ops = inst.ops.clone().unwrap_or_default();
+        let counts_bits = ops.iter().any(&is_bit_count);
+        let mut new_ops = Vec::new();
+        for (n, op) in ops.iter().enumerate() {
+            let mut op = op.clone();
+            if op.width == from {
+                op.width = to;
+            }
+            let width = souperinterp::get_operand_width(&inst, n, &|idx| widths.get(&idx).cloned());
+            if let (None, Some(c)) = (op.idx_val, op.const_val) {
+                if width == from {
+                    let is_amount = n == 1 && matches!(inst.kind, InstKind::Shl | InstKind::Lshr | InstKind::Ashr);
+                    op.const_val = Some(rescale_const(c, from, to, is_amount || counts_bits).ok_or_else(|| {
+                        format!("constant {} has no i{} counterpart", souperinterp::to_signed(c as u128, from), to)
+                    })?);
+                }
+            }
+            new_ops.push(op);
+        }
+        if inst.ops.is_some() {
+            inst.ops = Some(new_ops);
+        }
+        if inst.width == from {
+            inst.width = to;
+        }
+        narrow.push(inst);
+    }
+    Ok(narrow)
+}
+
+/// A `from`-bit constant at `to` bits. Bit positions and counts map `W`
+/// and `W - 1` to the new width; values keep their meaning as -1,
+/// INT_MIN or INT_MAX, or their signed value if it fits.
+fn rescale_const(c: i128, from: u32, to: u32, counts_bits: bool) -> Option<i128> {
+    let bits = souperinterp::mask(c as u128, from);
+    if counts_bits {
+        return match bits {
+            b if b == from as u128 => Some(to as i128),
+            b if b == from as u128 - 1 => Some(to as i128 - 1),
+            b if b < to as u128 => Some(b as i128),
+            _ => None,
+        };
+    }
+    let int_min = 1u128 << (from - 1);
+    if bits == souperinterp::mask(!0, from) {
+        return Some(-1);
+    }
+    if bits == int_min {
+        return Some(1i128 << (to - 1));
+    }
+    if bits == int_min - 1 {
+        return Some((1i128 << (to - 1)) - 1);
+    }
+    let value = souperinterp::to_signed(bits, from);
+    let to_min = -(1i128 << (to - 1));
+    if value >= to_min && value < -to_min {
+        Some(value)
+    } else {
+        None
+    }
+}